@@ -27,7 +27,7 @@ fn compile_frontend_file(filename: &str) -> StandardProgram {
 
 fn compile_to_c(filename: &str) -> String {
     let program = compile_frontend_file(filename);
-    let c_code = targets::C.build_std(&program).unwrap();
+    let c_code = targets::C::default().build_std(&program).unwrap();
     return c_code;
 }
 
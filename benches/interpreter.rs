@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use sage::{lir::*, parse::*, vm::*};
+use std::fs::read_to_string;
+
+const CALL_STACK_SIZE: usize = 8192;
+
+fn compile_frontend_file(filename: &str) -> StandardProgram {
+    let code = read_to_string(filename).unwrap();
+    match parse_frontend(code, Some(filename))
+        .unwrap()
+        .compile(false)
+        .unwrap()
+    {
+        Ok(asm_code) => asm_code.assemble(CALL_STACK_SIZE).unwrap().into(),
+        Err(asm_code) => asm_code.assemble(CALL_STACK_SIZE).unwrap(),
+    }
+}
+
+/// Run a program one step at a time, which scans the program
+/// for the target of every jump.
+fn run_scanning(program: &StandardProgram, input: &str) -> String {
+    let mut interpreter = StandardInterpreter::new(TestingDevice::new(input));
+    while !interpreter.state().done {
+        interpreter.step(program).unwrap();
+    }
+    interpreter.device().output_str()
+}
+
+/// Run a program to the end, which resolves its control flow first.
+fn run_resolved(program: &StandardProgram, input: &str) -> String {
+    StandardInterpreter::new(TestingDevice::new(input))
+        .run(program)
+        .unwrap()
+        .output_str()
+}
+
+fn bench_interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Interpreter");
+    group.sample_size(10);
+
+    for (name, filename) in [
+        ("Sudoku", "examples/frontend/sudoku.sg"),
+        ("Mandelbrot", "examples/frontend/mandelbrot.sg"),
+        ("AES", "examples/frontend/AES.sg"),
+    ] {
+        let program = compile_frontend_file(filename);
+        assert_eq!(
+            run_scanning(&program, "hello world!"),
+            run_resolved(&program, "hello world!")
+        );
+
+        group.bench_function(format!("{name} (scanning control flow)"), |b| {
+            b.iter(|| run_scanning(&program, "hello world!"))
+        });
+        group.bench_function(format!("{name} (resolved control flow)"), |b| {
+            b.iter(|| run_resolved(&program, "hello world!"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_interpreter);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+// The glue code for the foreign functions in these examples, for the C target.
+// Each function pops its arguments off the FFI channel, and pushes its return value.
+
+#include <sys/time.h>
+
+void __unsafe_memcpy() {
+    int64_t size = (ffi_ptr--)->i;
+    cell *src = (ffi_ptr--)->p;
+    cell *dst = (ffi_ptr--)->p;
+    memcpy(dst, src, size * sizeof(cell));
+}
+
+void __microseconds_since_epoch() {
+    struct timeval tv;
+    gettimeofday(&tv, NULL);
+    (++ffi_ptr)->i = (int64_t)tv.tv_sec * 1000000 + tv.tv_usec;
+}
@@ -490,8 +490,8 @@ impl<T> WasmInterpreter<T> where T: Device {
                             self.reg_mut_vector()[i] = i64::from(self.reg_vector()[i] >= 0);
                         }
                     }
-                    CoreOp::Get(i) => *self.reg_mut_scalar() = self.device.get(i.clone())?,
-                    CoreOp::Put(o) => self.device.put(self.reg_scalar(), o.clone())?,
+                    CoreOp::Get(i) => *self.reg_mut_scalar() = self.device.get(i.clone()).map_err(|e| e.to_string())?,
+                    CoreOp::Put(o) => self.device.put(self.reg_scalar(), o.clone()).map_err(|e| e.to_string())?,
                 },
 
                 // StandardOp::Set(n) => *self.reg_mut_scalar() = as_int(*n),
@@ -619,10 +619,10 @@ impl<T> WasmInterpreter<T> where T: Device {
                 }
 
                 StandardOp::Poke => {
-                    self.device.poke(self.reg_scalar())?;
+                    self.device.poke(self.reg_scalar()).map_err(|e| e.to_string())?;
                 }
                 StandardOp::Peek => {
-                    *self.reg_mut_scalar() = self.device.peek()?;
+                    *self.reg_mut_scalar() = self.device.peek().map_err(|e| e.to_string())?;
                 }
 
                 StandardOp::Alloc => {
@@ -640,7 +640,7 @@ impl<T> WasmInterpreter<T> where T: Device {
                 }
                 StandardOp::Free => {}
                 StandardOp::Call(binding) => {
-                    self.device.ffi_call(binding, Some(&mut self.cells))?;
+                    self.device.ffi_call(binding, Some(&mut self.cells)).map_err(|e| e.to_string())?;
                 }
             }
             self.i += 1
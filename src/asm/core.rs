@@ -27,12 +27,17 @@
 //! user defined functions in the core assembly language simply
 //! using `Put`, and assuming-standard out, to display the integer in decimal.
 use super::{
+    lift::lift,
     location::{FP_STACK, TMP},
-    AssemblyProgram, Env, Error, Location, StandardOp, FP, GP, SP, STACK_START, START_OF_FP_STACK,
+    reachability::reachable_instructions,
+    registers::{allocate_registers, Access},
+    AssemblyProgram, Env, Error, Location, SourceMarkers, StandardOp, FP, GP, SP, STACK_START,
+    START_OF_FP_STACK,
 };
 use crate::{
+    parse::SourceCodeLocation,
     side_effects::{Input, InputMode, Output, OutputMode},
-    vm::{self, VirtualMachineProgram},
+    vm::{self, SourceMap, VirtualMachineProgram},
 };
 use serde_derive::{Deserialize, Serialize};
 use std::{collections::BTreeSet, fmt};
@@ -49,6 +54,9 @@ pub struct CoreProgram {
     /// that has been defined so far. This helps the LIR compiler
     /// determine if a function has been compiled yet or not.
     labels: BTreeSet<String>,
+    /// The source code of the instructions in the program.
+    #[serde(skip)]
+    source: SourceMarkers,
 }
 
 /// A default program is an empty program.
@@ -67,7 +75,73 @@ impl CoreProgram {
                 labels.insert(label.clone());
             }
         }
-        Self { code, labels }
+        Self {
+            code,
+            labels,
+            source: SourceMarkers::default(),
+        }
+    }
+
+    /// Lift a virtual machine program back into assembly code, to read it.
+    /// The Nth function is labeled `@funN`, and the bootstrap code made by the
+    /// assembler is replaced by a declaration of the globals it allocated.
+    /// Lifted code calls functions by their index, so it must not be used
+    /// with `remove_unreachable_functions`.
+    pub fn lift(program: &vm::CoreProgram) -> Result<Self, Error> {
+        let code = lift(&program.clone().into())?
+            .into_iter()
+            .map(|op| match op {
+                StandardOp::CoreOp(op) => Ok(op),
+                op => Err(Error::UnsupportedInstruction(op)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(code))
+    }
+
+    /// Get the labels of the functions in the program, in the order they
+    /// are assembled. The Nth label names the Nth function in the assembled
+    /// virtual machine program.
+    pub fn function_labels(&self) -> Vec<String> {
+        let mut labels = vec![];
+        for op in &self.code {
+            op.collect_function_labels(&mut labels);
+        }
+        labels
+    }
+
+    /// Get the names of the functions in the program, in the order they
+    /// are assembled. Functions without a known name are named by their label.
+    pub fn function_names(&self) -> Vec<String> {
+        self.function_labels()
+            .into_iter()
+            .map(|label| self.source.function_name(label))
+            .collect()
+    }
+
+    /// Remove the functions which can't be reached from the code outside of
+    /// functions, through the labels used by `CallLabel` and `SetLabel`.
+    /// Code which calls functions by their index instead of their label
+    /// must not be used with this, because the functions are renumbered.
+    pub fn remove_unreachable_functions(&mut self) {
+        let before = self.function_labels().len();
+        let keep = reachable_instructions(&self.code.iter().map(Some).collect::<Vec<_>>());
+        let mut kept = keep.iter();
+        self.code.retain(|_| *kept.next().unwrap());
+        self.source.retain(&keep);
+
+        let labels = self.function_labels();
+        self.labels.retain(|label| labels.contains(label));
+        info!(
+            "Removed unreachable functions: {before} functions before, {} after.",
+            labels.len()
+        );
+    }
+
+    /// Keep short-lived values on the stack in the general purpose registers
+    /// the program doesn't use, instead of pushing and popping them.
+    pub fn allocate_registers(&mut self) {
+        let keep = allocate_registers(&mut self.code);
+        self.source.retain(&keep);
     }
 
     /// Get the size of the globals in the program.
@@ -87,6 +161,17 @@ impl CoreProgram {
     /// Assemble a program of core assembly instructions into the
     /// core virtual machine instructions.
     pub fn assemble(&self, allowed_recursion_depth: usize) -> Result<vm::CoreProgram, Error> {
+        self.assemble_with_source_map(allowed_recursion_depth)
+            .map(|(program, _)| program)
+    }
+
+    /// Assemble the program like `assemble`, and also build the source map of
+    /// the virtual machine program, which maps its instructions back to the
+    /// source code they were compiled from.
+    pub fn assemble_with_source_map(
+        &self,
+        allowed_recursion_depth: usize,
+    ) -> Result<(vm::CoreProgram, SourceMap), Error> {
         // Create the result program.
         let mut result = vm::CoreProgram(vec![]);
         // Create the environment in which to assemble the program.
@@ -126,9 +211,13 @@ impl CoreProgram {
         // Copy the stack pointer to the frame pointer
         SP.copy_to(&FP, &mut result);
         // For all the operations in the program, assemble them.
+        // Remember where the code for each instruction starts, for the source map.
+        let mut starts = Vec::with_capacity(self.code.len() + 1);
         for (i, op) in self.code.iter().enumerate() {
+            starts.push(result.0.len());
             op.assemble(i, &mut env, &mut result)?
         }
+        starts.push(result.0.len());
 
         // If there are any unmatched instructions, return an error.
         if let Ok((unmatched, last_instruction)) = env.pop_matching(self.code.len()) {
@@ -136,7 +225,7 @@ impl CoreProgram {
         }
 
         // Return the result.
-        Ok(result.flatten())
+        Ok(result.flatten_with_source_map(&self.source.source_map(&starts)))
     }
 }
 
@@ -188,6 +277,7 @@ impl AssemblyProgram for CoreProgram {
         // If the operation is a function label, add its label to the set of defined labels.
         if let CoreOp::Fn(name) = &op {
             self.labels.insert(name.clone());
+            self.source.declare_function(name);
         }
 
         if let Some(last_core_op) = self.code.last().cloned() {
@@ -261,6 +351,18 @@ impl AssemblyProgram for CoreProgram {
         self.code.len()
     }
 
+    fn begin_location(&mut self, location: &SourceCodeLocation) {
+        self.source.begin_location(self.code.len(), location);
+    }
+
+    fn begin_function(&mut self, name: &str) {
+        self.source.begin_function(self.code.len(), name);
+    }
+
+    fn end_source(&mut self) {
+        self.source.end(self.code.len());
+    }
+
     fn get_op(&self, start: usize) -> Option<Result<CoreOp, StandardOp>> {
         self.code.get(start).cloned().map(Ok)
     }
@@ -628,6 +730,8 @@ pub enum CoreOp {
     Get(Location, Input),
     /// Put a value from a source register to the output device / interface.
     Put(Location, Output),
+    /// Stop the program, using the value at a source location as its exit code.
+    Halt(Location),
     /// Store a list of values at a source location. Then, store the address past the
     /// last value into the destination location.
     Array {
@@ -749,6 +853,172 @@ impl CoreOp {
         ])
     }
 
+    /// Add the labels of the functions declared by this instruction to `labels`.
+    pub(super) fn collect_function_labels(&self, labels: &mut Vec<String>) {
+        match self {
+            Self::Fn(name) => labels.push(name.clone()),
+            Self::Many(ops) => {
+                for op in ops {
+                    op.collect_function_labels(labels);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Add the labels of the functions used by this instruction to `labels`.
+    pub(super) fn collect_label_uses(&self, labels: &mut Vec<String>) {
+        match self {
+            Self::CallLabel(name) | Self::SetLabel(_, name) => labels.push(name.clone()),
+            Self::Many(ops) => {
+                for op in ops {
+                    op.collect_label_uses(labels);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Get the locations used by this instruction, with how it uses them,
+    /// and the number of cells it uses at each. The instructions inside of
+    /// `Many` aren't included.
+    pub(super) fn operands_mut(&mut self) -> Vec<(&mut Location, Access, usize)> {
+        use Access::*;
+        match self {
+            Self::Comment(_)
+            | Self::Many(_)
+            | Self::Global { .. }
+            | Self::CallLabel(_)
+            | Self::Return
+            | Self::Fn(_)
+            | Self::Else
+            | Self::End
+            | Self::PushConst(_) => vec![],
+
+            Self::Set(dst, _) | Self::SetLabel(dst, _) | Self::Get(dst, _) => vec![(dst, Write, 1)],
+            Self::VecSet(dst, vals) | Self::Const { dst, vals } => {
+                let size = vals.len();
+                vec![(dst, Write, size)]
+            }
+            Self::Array { src, dst, vals } => {
+                let size = vals.len();
+                vec![(src, Write, size), (dst, Write, 1)]
+            }
+            Self::GetAddress { addr, dst } => vec![(addr, Address, 1), (dst, Write, 1)],
+            Self::PushAddress(addr) => vec![(addr, Address, 1)],
+
+            Self::Call(src)
+            | Self::While(src)
+            | Self::If(src)
+            | Self::Put(src, _)
+            | Self::Halt(src) => vec![(src, Read, 1)],
+
+            Self::Move { src, dst } => vec![(src, Read, 1), (dst, Write, 1)],
+            Self::Copy { src, dst, size } => {
+                let size = *size;
+                vec![(src, Read, size), (dst, Write, size)]
+            }
+            Self::Swap(a, b) => vec![(a, ReadWrite, 1), (b, ReadWrite, 1)],
+            Self::DivRem { src, dst } => vec![(src, ReadWrite, 1), (dst, ReadWrite, 1)],
+
+            Self::Next(dst, _)
+            | Self::Prev(dst, _)
+            | Self::Inc(dst)
+            | Self::Dec(dst)
+            | Self::Neg(dst)
+            | Self::Not(dst)
+            | Self::BitwiseNot(dst) => vec![(dst, ReadWrite, 1)],
+
+            Self::Index { src, offset, dst } => {
+                vec![(src, Read, 1), (offset, Read, 1), (dst, Write, 1)]
+            }
+            Self::VecIndex {
+                size,
+                src,
+                offset,
+                dst,
+            } => {
+                let size = *size;
+                vec![(src, Read, size), (offset, Read, 1), (dst, Write, size)]
+            }
+
+            Self::Add { src, dst }
+            | Self::Sub { src, dst }
+            | Self::Mul { src, dst }
+            | Self::Div { src, dst }
+            | Self::Rem { src, dst }
+            | Self::And { src, dst }
+            | Self::Or { src, dst }
+            | Self::LeftShift { src, dst }
+            | Self::LogicalRightShift { src, dst }
+            | Self::ArithmeticRightShift { src, dst }
+            | Self::BitwiseNand { src, dst }
+            | Self::BitwiseXor { src, dst }
+            | Self::BitwiseOr { src, dst }
+            | Self::BitwiseNor { src, dst }
+            | Self::BitwiseAnd { src, dst } => vec![(src, Read, 1), (dst, ReadWrite, 1)],
+
+            Self::VecAdd { size, src, dst }
+            | Self::VecSub { size, src, dst }
+            | Self::VecMul { size, src, dst }
+            | Self::VecDiv { size, src, dst }
+            | Self::VecRem { size, src, dst }
+            | Self::VecAnd { size, src, dst }
+            | Self::VecOr { size, src, dst }
+            | Self::VecLeftShift { size, src, dst }
+            | Self::VecLogicalRightShift { size, src, dst }
+            | Self::VecArithmeticRightShift { size, src, dst }
+            | Self::VecBitwiseAnd { size, src, dst }
+            | Self::VecBitwiseOr { size, src, dst }
+            | Self::VecBitwiseXor { size, src, dst }
+            | Self::VecBitwiseNor { size, src, dst }
+            | Self::VecBitwiseNand { size, src, dst } => {
+                let size = *size;
+                vec![(src, Read, size), (dst, ReadWrite, size)]
+            }
+            Self::VecGez { size, src, dst } => {
+                let size = *size;
+                vec![(src, Read, size), (dst, Write, size)]
+            }
+            Self::VecNot { size, dst }
+            | Self::VecInc { size, dst }
+            | Self::VecDec { size, dst }
+            | Self::VecNeg { size, dst }
+            | Self::VecOffset { size, dst, .. }
+            | Self::VecBitwiseNot { size, dst } => {
+                let size = *size;
+                vec![(dst, ReadWrite, size)]
+            }
+
+            Self::Push(src, size) => {
+                let size = *size;
+                vec![(src, Read, size)]
+            }
+            Self::Pop(dst, size) => {
+                let size = *size;
+                dst.iter_mut().map(|dst| (dst, Write, size)).collect()
+            }
+            Self::PushTo { src, sp, size } => {
+                let size = *size;
+                vec![(src, Read, size), (sp, ReadWrite, 1)]
+            }
+            Self::PopFrom { sp, dst, size } => {
+                let size = *size;
+                let mut operands = vec![(sp, ReadWrite, 1)];
+                operands.extend(dst.iter_mut().map(|dst| (dst, Write, size)));
+                operands
+            }
+
+            Self::Compare { a, b, dst }
+            | Self::IsGreater { a, b, dst }
+            | Self::IsGreaterEqual { a, b, dst }
+            | Self::IsLess { a, b, dst }
+            | Self::IsLessEqual { a, b, dst }
+            | Self::IsEqual { a, b, dst }
+            | Self::IsNotEqual { a, b, dst } => vec![(a, Read, 1), (b, Read, 1), (dst, Write, 1)],
+        }
+    }
+
     pub(super) fn assemble(
         &self,
         current_instruction: usize,
@@ -1343,6 +1613,11 @@ impl CoreOp {
                 // result.put(output.clone())
                 src.put(output.clone(), result)
             }
+            CoreOp::Halt(src) => {
+                let src = env.resolve(src)?;
+                src.restore_from(result);
+                result.halt();
+            }
 
             CoreOp::Copy { src, dst, size } => {
                 let src = env.resolve(src)?;
@@ -1654,6 +1929,7 @@ impl fmt::Display for CoreOp {
                 },
             ) => write!(f, "put-float {loc}"),
             Self::Put(loc, o) => write!(f, "put {loc}, {o}"),
+            Self::Halt(loc) => write!(f, "halt {loc}"),
         }
     }
 }
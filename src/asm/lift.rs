@@ -0,0 +1,1124 @@
+//! # Lifting Virtual Machine Code
+//!
+//! The assembler lowers every assembly instruction into instructions which
+//! move the virtual machine's pointer to its operands, `Load` them into the
+//! register, operate on the register, and `Store` it back. This module goes
+//! the other way: it lifts a virtual machine program back into assembly code,
+//! so it can be read.
+//!
+//! The lifter runs the program symbolically. It keeps track of the pointer as
+//! a `Location` (following the `Move`, `Deref`, and `Refer` instructions made by
+//! `Location::to` and `Location::from`), and of the register as a value on the
+//! tape, instead of matching the instructions made for each assembly instruction.
+//! This way, it still works after the peephole optimizer has combined the
+//! instructions of neighboring assembly instructions. The instructions which
+//! compute the register, like `Load`, `Where`, `Set`, and the arithmetic, are
+//! folded into the register's value, and each `Store` writes the value with a
+//! single `Copy`, `Set`, `GetAddress`, `Next`, or arithmetic instruction when it can.
+//! Copies to and from the top of the stack are folded into `Push` and `Pop`.
+//!
+//! The `Nth` function is named `@funN`, and its frame pointer setup and the
+//! `Return` instructions are folded back into `Fn` and `Return`. The bootstrap
+//! code made by the assembler is replaced by a declaration of the global
+//! variables it allocated, since the assembler makes the bootstrap again.
+//!
+//! The lifter can't lift every program. The pointer must be at the start
+//! of the tape at the beginning and end of every block, and whenever a
+//! function is called or returns, like it is in assembled programs.
+//! `Swap` instructions aren't supported.
+//!
+//! Lifted programs assume that a pointer on the tape never points into the
+//! registers, and that the `TMP` register isn't used across the instructions
+//! which the lifter writes to it, like assembled programs do.
+use super::{
+    CoreOp, Error, Location, StandardOp, FP, FP_STACK, GP, REGISTERS, SP, STACK_START,
+    START_OF_FP_STACK, TMP,
+};
+use crate::{
+    side_effects::{FFIBinding, Input},
+    vm, NULL,
+};
+
+/// Lift a virtual machine program into assembly instructions.
+pub(super) fn lift(program: &vm::StandardProgram) -> Result<Vec<StandardOp>, Error> {
+    let mut lifter = Lifter::default();
+    for (i, op) in program.0.iter().enumerate() {
+        lifter
+            .step(op)
+            .map_err(|reason| Error::CannotLift(op.clone(), i, reason))?;
+    }
+    lifter.finish().map_err(|reason| {
+        Error::CannotLift(
+            vm::StandardOp::CoreOp(vm::CoreOp::End),
+            program.0.len(),
+            reason,
+        )
+    })
+}
+
+/// The value of the register, in terms of the cells on the tape.
+#[derive(Clone, Debug, Default, PartialEq)]
+enum Value {
+    /// A value the lifter doesn't know.
+    #[default]
+    Unknown,
+    /// Constant integers.
+    Int(Vec<i64>),
+    /// Constant floats.
+    Float(Vec<f64>),
+    /// The values of a number of cells, starting at a location.
+    Cells(Location, usize),
+    /// The address of a location.
+    Address(Location),
+    /// An instruction applied to a value, with the operand it used on the tape.
+    Op {
+        value: Box<Self>,
+        op: vm::StandardOp,
+        src: Option<Location>,
+    },
+    /// A value read from the input.
+    Input(Input),
+    /// The address of the memory allocated with a value as its size.
+    Alloc(Box<Self>),
+    /// An output of the foreign function being called.
+    ForeignOutput(usize),
+}
+
+impl Value {
+    /// The number of cells of the register the value is known for.
+    fn size(&self) -> usize {
+        match self {
+            Self::Unknown => 0,
+            Self::Int(vals) => vals.len(),
+            Self::Float(vals) => vals.len(),
+            Self::Cells(_, size) => *size,
+            Self::Op { value, .. } => value.size(),
+            Self::Address(_) | Self::Input(_) | Self::Alloc(_) | Self::ForeignOutput(_) => 1,
+        }
+    }
+
+    /// The first `size` cells of the value.
+    fn truncate(self, size: usize) -> Result<Self, String> {
+        if size == self.size() {
+            return Ok(self);
+        }
+        match self {
+            Self::Int(vals) if size < vals.len() => Ok(Self::Int(vals[..size].to_vec())),
+            Self::Float(vals) if size < vals.len() => Ok(Self::Float(vals[..size].to_vec())),
+            Self::Cells(src, n) if size < n => Ok(Self::Cells(src, size)),
+            Self::Unknown => Err("the register's value is unknown".to_string()),
+            value => Err(format!(
+                "only {} cells of the register are known, but {size} are used",
+                value.size()
+            )),
+        }
+    }
+
+    /// Does reading the value have a side effect, so it must happen exactly once?
+    fn is_effect(&self) -> bool {
+        matches!(
+            self,
+            Self::Input(_) | Self::Alloc(_) | Self::ForeignOutput(_)
+        )
+    }
+}
+
+/// The position of the pointer: an offset from the start of the tape,
+/// or from the cell pointed to by a location.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Pointer {
+    base: Option<Location>,
+    offset: isize,
+}
+
+impl Pointer {
+    /// The location the pointer is at.
+    fn location(&self) -> Result<Location, String> {
+        match &self.base {
+            Some(base) => Ok(base.deref().offset(self.offset)),
+            None if self.offset >= 0 => Ok(Location::Address(self.offset as usize)),
+            None => Err("the pointer moved before the start of the tape".to_string()),
+        }
+    }
+}
+
+/// A block of code the lifter is in.
+#[derive(Clone, Debug, PartialEq)]
+enum Block {
+    Function,
+    If,
+    Else,
+    /// A loop, with the location of its condition.
+    While(Location),
+}
+
+/// A call to a foreign function, whose outputs are being stored to the stack.
+#[derive(Clone, Debug, PartialEq)]
+struct ForeignCall {
+    binding: FFIBinding,
+    /// The number of outputs read from the foreign function so far.
+    read: usize,
+    /// The number of outputs stored to the stack so far.
+    stored: usize,
+}
+
+impl ForeignCall {
+    /// The location of an input or output cell on the stack.
+    fn cell(&self, i: usize) -> Location {
+        SP.deref()
+            .offset(1 - self.binding.input_cells as isize + i as isize)
+    }
+}
+
+/// The state of the program as it's lifted, one instruction at a time.
+#[derive(Default)]
+struct Lifter {
+    /// The lifted instructions.
+    code: Vec<StandardOp>,
+    /// Where the pointer is.
+    pointer: Pointer,
+    /// Where the pointer was before each `Deref` which hasn't been undone.
+    derefs: Vec<Pointer>,
+    /// The value of the register.
+    register: Value,
+    /// The blocks containing the current instruction.
+    blocks: Vec<Block>,
+    /// The number of functions declared so far.
+    functions: usize,
+    /// Where the code outside of functions begins in the lifted instructions.
+    main: Option<usize>,
+    /// The cells passed to the next foreign function call.
+    inputs: Vec<Location>,
+    /// The foreign function call whose outputs are being stored.
+    foreign_call: Option<ForeignCall>,
+}
+
+/// The instructions which push the frame pointer to the stack of frames.
+fn push_frame() -> [CoreOp; 2] {
+    [
+        CoreOp::Next(FP_STACK, None),
+        CoreOp::Move {
+            src: FP,
+            dst: FP_STACK.deref(),
+        },
+    ]
+}
+
+/// The instructions which pop the frame pointer from the stack of frames.
+fn pop_frame() -> [CoreOp; 2] {
+    [
+        CoreOp::Move {
+            src: FP_STACK.deref(),
+            dst: FP,
+        },
+        CoreOp::Prev(FP_STACK, None),
+    ]
+}
+
+/// Split a location into the location of the pointer it's offset from
+/// (if any), and its offset.
+fn split(loc: &Location) -> (Option<&Location>, isize) {
+    match loc {
+        Location::Address(addr) => (None, *addr as isize),
+        Location::Indirect(ptr) => (Some(ptr), 0),
+        Location::Offset(loc, offset) => {
+            let (base, start) = split(loc);
+            (base, start + offset)
+        }
+        Location::Global(_) => (Some(loc), 0),
+    }
+}
+
+/// Could `size` cells at `a` overlap with `other_size` cells at `b`?
+fn may_overlap(a: &Location, size: usize, b: &Location, other_size: usize) -> bool {
+    let (a_base, a_start) = split(a);
+    let (b_base, b_start) = split(b);
+    match (a_base, b_base) {
+        (a_base, b_base) if a_base == b_base => {
+            a_start < b_start + other_size as isize && b_start < a_start + size as isize
+        }
+        // Pointers never point into the registers.
+        (None, Some(_)) => a_start + size as isize > REGISTERS.len() as isize,
+        (Some(_), None) => b_start + other_size as isize > REGISTERS.len() as isize,
+        _ => true,
+    }
+}
+
+/// Could writing `other_size` cells at `dst` change the cells read by
+/// accessing `size` cells at `src`, including the pointers used to find them?
+fn may_change(src: &Location, size: usize, dst: &Location, other_size: usize) -> bool {
+    may_overlap(src, size, dst, other_size)
+        || match src {
+            Location::Indirect(ptr) => may_change(ptr, 1, dst, other_size),
+            Location::Offset(loc, _) => {
+                may_change(loc, 1, dst, other_size) && !matches!(**loc, Location::Address(_))
+            }
+            _ => false,
+        }
+}
+
+/// The assembly instruction which applies a virtual machine instruction to
+/// the cells at `dst`, with the cells at `src` as its operand on the tape.
+fn apply(op: &vm::StandardOp, src: Option<&Location>, dst: &Location) -> Option<StandardOp> {
+    use vm::CoreOp as Vm;
+    let dst = dst.clone();
+    Some(match (op, src.cloned()) {
+        (vm::StandardOp::CoreOp(op), src) => StandardOp::CoreOp(match (op, src) {
+            (Vm::Add(1), Some(src)) => CoreOp::Add { src, dst },
+            (Vm::Add(size), Some(src)) => CoreOp::VecAdd {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::Sub(1), Some(src)) => CoreOp::Sub { src, dst },
+            (Vm::Sub(size), Some(src)) => CoreOp::VecSub {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::Mul(1), Some(src)) => CoreOp::Mul { src, dst },
+            (Vm::Mul(size), Some(src)) => CoreOp::VecMul {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::Div(1), Some(src)) => CoreOp::Div { src, dst },
+            (Vm::Div(size), Some(src)) => CoreOp::VecDiv {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::Rem(1), Some(src)) => CoreOp::Rem { src, dst },
+            (Vm::Rem(size), Some(src)) => CoreOp::VecRem {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::And(1), Some(src)) => CoreOp::And { src, dst },
+            (Vm::And(size), Some(src)) => CoreOp::VecAnd {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::Or(1), Some(src)) => CoreOp::Or { src, dst },
+            (Vm::Or(size), Some(src)) => CoreOp::VecOr {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::BitwiseNand(1), Some(src)) => CoreOp::BitwiseNand { src, dst },
+            (Vm::BitwiseNand(size), Some(src)) => CoreOp::VecBitwiseNand {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::BitwiseAnd(1), Some(src)) => CoreOp::BitwiseAnd { src, dst },
+            (Vm::BitwiseAnd(size), Some(src)) => CoreOp::VecBitwiseAnd {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::BitwiseOr(1), Some(src)) => CoreOp::BitwiseOr { src, dst },
+            (Vm::BitwiseOr(size), Some(src)) => CoreOp::VecBitwiseOr {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::BitwiseXor(1), Some(src)) => CoreOp::BitwiseXor { src, dst },
+            (Vm::BitwiseXor(size), Some(src)) => CoreOp::VecBitwiseXor {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::LeftShift(1), Some(src)) => CoreOp::LeftShift { src, dst },
+            (Vm::LeftShift(size), Some(src)) => CoreOp::VecLeftShift {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::LogicalRightShift(1), Some(src)) => CoreOp::LogicalRightShift { src, dst },
+            (Vm::LogicalRightShift(size), Some(src)) => CoreOp::VecLogicalRightShift {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::ArithmeticRightShift(1), Some(src)) => CoreOp::ArithmeticRightShift { src, dst },
+            (Vm::ArithmeticRightShift(size), Some(src)) => CoreOp::VecArithmeticRightShift {
+                size: *size,
+                src,
+                dst,
+            },
+            (Vm::Index(1), Some(offset)) => CoreOp::Index {
+                src: dst.clone(),
+                offset,
+                dst,
+            },
+            (Vm::Index(size), Some(offset)) => CoreOp::VecIndex {
+                size: *size,
+                src: dst.clone(),
+                offset,
+                dst,
+            },
+
+            (Vm::Inc(1), None) => CoreOp::Inc(dst),
+            (Vm::Inc(size), None) => CoreOp::VecInc { size: *size, dst },
+            (Vm::Dec(1), None) => CoreOp::Dec(dst),
+            (Vm::Dec(size), None) => CoreOp::VecDec { size: *size, dst },
+            (Vm::Neg(1), None) => CoreOp::Neg(dst),
+            (Vm::Neg(size), None) => CoreOp::VecNeg { size: *size, dst },
+            (Vm::Not(1), None) => CoreOp::Not(dst),
+            (Vm::Not(size), None) => CoreOp::VecNot { size: *size, dst },
+            (Vm::BitwiseNot(1), None) => CoreOp::BitwiseNot(dst),
+            (Vm::BitwiseNot(size), None) => CoreOp::VecBitwiseNot { size: *size, dst },
+            (Vm::IsNonNegative(size), None) => CoreOp::VecGez {
+                size: *size,
+                src: dst.clone(),
+                dst,
+            },
+            (Vm::Offset(offset, size), None) => CoreOp::VecOffset {
+                size: *size,
+                dst,
+                offset: *offset,
+            },
+            _ => return None,
+        }),
+
+        (vm::StandardOp::Add(1), Some(src)) => StandardOp::Add { src, dst },
+        (vm::StandardOp::Add(size), Some(src)) => StandardOp::VecAdd {
+            src,
+            dst,
+            size: *size,
+        },
+        (vm::StandardOp::Sub(1), Some(src)) => StandardOp::Sub { src, dst },
+        (vm::StandardOp::Sub(size), Some(src)) => StandardOp::VecSub {
+            src,
+            dst,
+            size: *size,
+        },
+        (vm::StandardOp::Mul(1), Some(src)) => StandardOp::Mul { src, dst },
+        (vm::StandardOp::Mul(size), Some(src)) => StandardOp::VecMul {
+            src,
+            dst,
+            size: *size,
+        },
+        (vm::StandardOp::Div(1), Some(src)) => StandardOp::Div { src, dst },
+        (vm::StandardOp::Div(size), Some(src)) => StandardOp::VecDiv {
+            src,
+            dst,
+            size: *size,
+        },
+        (vm::StandardOp::Rem(1), Some(src)) => StandardOp::Rem { src, dst },
+        (vm::StandardOp::Rem(size), Some(src)) => StandardOp::VecRem {
+            src,
+            dst,
+            size: *size,
+        },
+        (vm::StandardOp::Pow(1), Some(src)) => StandardOp::Pow { src, dst },
+        (vm::StandardOp::Pow(size), Some(src)) => StandardOp::VecPow {
+            src,
+            dst,
+            size: *size,
+        },
+
+        (vm::StandardOp::Neg(1), None) => StandardOp::Neg(dst),
+        (vm::StandardOp::Neg(size), None) => StandardOp::VecNeg { dst, size: *size },
+        (vm::StandardOp::Sin(1), None) => StandardOp::Sin(dst),
+        (vm::StandardOp::Sin(size), None) => StandardOp::VecSin { dst, size: *size },
+        (vm::StandardOp::Cos(1), None) => StandardOp::Cos(dst),
+        (vm::StandardOp::Cos(size), None) => StandardOp::VecCos { dst, size: *size },
+        (vm::StandardOp::Tan(1), None) => StandardOp::Tan(dst),
+        (vm::StandardOp::Tan(size), None) => StandardOp::VecTan { dst, size: *size },
+        (vm::StandardOp::ASin(1), None) => StandardOp::ASin(dst),
+        (vm::StandardOp::ASin(size), None) => StandardOp::VecASin { dst, size: *size },
+        (vm::StandardOp::ACos(1), None) => StandardOp::ACos(dst),
+        (vm::StandardOp::ACos(size), None) => StandardOp::VecACos { dst, size: *size },
+        (vm::StandardOp::ATan(1), None) => StandardOp::ATan(dst),
+        (vm::StandardOp::ATan(size), None) => StandardOp::VecATan { dst, size: *size },
+        (vm::StandardOp::IsNonNegative(size), None) => StandardOp::VecGez {
+            src: dst.clone(),
+            dst,
+            size: *size,
+        },
+        (vm::StandardOp::ToInt(1), None) => StandardOp::ToInt(dst),
+        (vm::StandardOp::ToFloat(1), None) => StandardOp::ToFloat(dst),
+        _ => return None,
+    })
+}
+
+impl Lifter {
+    /// Lift the next instruction of the program.
+    fn step(&mut self, op: &vm::StandardOp) -> Result<(), String> {
+        use vm::CoreOp as Vm;
+        // Instructions with side effects must be written before anything else
+        // happens, but the value they put in the register can be written straight
+        // to where it's stored.
+        if !matches!(
+            op,
+            vm::StandardOp::CoreOp(
+                Vm::Comment(_) | Vm::Move(_) | Vm::Deref | Vm::Refer | Vm::Store(_)
+            )
+        ) {
+            self.flush()?;
+        }
+        if self.foreign_call.is_some()
+            && !matches!(
+                op,
+                vm::StandardOp::Peek
+                    | vm::StandardOp::CoreOp(
+                        Vm::Move(_)
+                            | Vm::Deref
+                            | Vm::Refer
+                            | Vm::Store(_)
+                            | Vm::Load(_)
+                            | Vm::Offset(..)
+                            | Vm::Comment(_)
+                    )
+            )
+        {
+            return Err("the outputs of a foreign function weren't stored".to_string());
+        }
+        if self.blocks.is_empty()
+            && self.main.is_none()
+            && !matches!(op, vm::StandardOp::CoreOp(Vm::Function | Vm::Comment(_)))
+        {
+            self.main = Some(self.code.len());
+        }
+
+        match op {
+            vm::StandardOp::CoreOp(op) => match op {
+                // Comments are dropped, so they don't get in the way of folding instructions.
+                Vm::Comment(_) => {}
+                Vm::Move(offset) => self.pointer.offset += offset,
+                Vm::Deref => {
+                    let ptr = self.pointer.location()?;
+                    self.derefs.push(std::mem::replace(
+                        &mut self.pointer,
+                        Pointer {
+                            base: Some(ptr),
+                            offset: 0,
+                        },
+                    ));
+                }
+                Vm::Refer => {
+                    self.pointer = self
+                        .derefs
+                        .pop()
+                        .ok_or("`Refer` without a matching `Deref`")?;
+                }
+                Vm::Where => self.register = Value::Address(self.pointer.location()?),
+                Vm::Load(size) => self.register = Value::Cells(self.pointer.location()?, *size),
+                Vm::Store(size) => self.store(*size)?,
+                Vm::Set(vals) => self.register = Value::Int(vals.clone()),
+
+                Vm::Offset(offset, 1) => {
+                    self.register = match std::mem::take(&mut self.register) {
+                        Value::Cells(ptr, _) => Value::Address(ptr.deref().offset(*offset)),
+                        Value::Address(loc) => Value::Address(loc.offset(*offset)),
+                        value => Value::Op {
+                            value: Box::new(value),
+                            op: vm::StandardOp::CoreOp(Vm::Offset(*offset, 1)),
+                            src: None,
+                        },
+                    }
+                }
+                Vm::Offset(..)
+                | Vm::Inc(_)
+                | Vm::Dec(_)
+                | Vm::Neg(_)
+                | Vm::Not(_)
+                | Vm::BitwiseNot(_)
+                | Vm::IsNonNegative(_) => self.unary(vm::StandardOp::CoreOp(op.clone())),
+                Vm::Index(_)
+                | Vm::BitwiseNand(_)
+                | Vm::BitwiseAnd(_)
+                | Vm::BitwiseOr(_)
+                | Vm::BitwiseXor(_)
+                | Vm::LeftShift(_)
+                | Vm::LogicalRightShift(_)
+                | Vm::ArithmeticRightShift(_)
+                | Vm::And(_)
+                | Vm::Or(_)
+                | Vm::Add(_)
+                | Vm::Sub(_)
+                | Vm::Mul(_)
+                | Vm::Div(_)
+                | Vm::Rem(_) => self.binary(vm::StandardOp::CoreOp(op.clone()))?,
+                Vm::Swap(_) => return Err("`Swap` can't be lifted".to_string()),
+
+                Vm::Get(input) => self.register = Value::Input(input.clone()),
+                Vm::Put(output) => {
+                    let src = self.register_location()?;
+                    self.emit(CoreOp::Put(src, output.clone()))
+                }
+                Vm::Halt => {
+                    let src = self.register_location()?;
+                    self.emit(CoreOp::Halt(src))
+                }
+
+                Vm::Function => {
+                    self.check_pointer()?;
+                    let label = format!("fun{}", self.functions);
+                    self.functions += 1;
+                    self.blocks.push(Block::Function);
+                    self.emit(CoreOp::Fn(label));
+                    self.register = Value::Unknown;
+                }
+                Vm::Call => {
+                    self.check_pointer()?;
+                    match &self.register {
+                        Value::Int(vals) if (vals[0] as usize) < self.functions => {
+                            self.emit(CoreOp::CallLabel(format!("fun{}", vals[0])))
+                        }
+                        _ => {
+                            let src = self.register_location()?;
+                            self.emit(CoreOp::Call(src))
+                        }
+                    }
+                    self.register = Value::Unknown;
+                }
+                Vm::Return => {
+                    self.check_pointer()?;
+                    if !self.pop_frame_ends_code() {
+                        self.emit_all(push_frame());
+                    }
+                    self.emit(CoreOp::Return);
+                    self.register = Value::Unknown;
+                }
+                Vm::If => {
+                    self.check_pointer()?;
+                    let src = self.register_location()?;
+                    self.blocks.push(Block::If);
+                    self.emit(CoreOp::If(src));
+                }
+                Vm::Else => {
+                    self.check_pointer()?;
+                    match self.blocks.pop() {
+                        Some(Block::If) => self.blocks.push(Block::Else),
+                        _ => return Err("`Else` without a matching `If`".to_string()),
+                    }
+                    self.emit(CoreOp::Else);
+                    self.register = Value::Unknown;
+                }
+                Vm::While => {
+                    self.check_pointer()?;
+                    let src = self.register_location()?;
+                    self.blocks.push(Block::While(src.clone()));
+                    self.emit(CoreOp::While(src));
+                }
+                Vm::End => {
+                    self.check_pointer()?;
+                    match self.blocks.pop() {
+                        Some(Block::Function) => {
+                            // The end of a function returns from it.
+                            if self.code.last() == Some(&StandardOp::CoreOp(CoreOp::Return)) {
+                                self.code.pop();
+                            } else {
+                                self.emit_all(push_frame());
+                            }
+                        }
+                        // The assembler reads the condition of the loop again at its end.
+                        Some(Block::While(src)) => match &self.register {
+                            Value::Cells(loc, _) if *loc == src => {}
+                            _ if src == TMP => self.materialize(TMP, 1)?,
+                            _ => {
+                                return Err(format!(
+                                    "the condition of the loop isn't read from {src} again"
+                                ))
+                            }
+                        },
+                        Some(Block::If | Block::Else) => {}
+                        None => return Err("`End` without a matching block".to_string()),
+                    }
+                    self.emit(CoreOp::End);
+                    self.register = Value::Unknown;
+                }
+            },
+
+            vm::StandardOp::Set(vals) => self.register = Value::Float(vals.clone()),
+            vm::StandardOp::ToInt(_)
+            | vm::StandardOp::ToFloat(_)
+            | vm::StandardOp::Neg(_)
+            | vm::StandardOp::IsNonNegative(_)
+            | vm::StandardOp::Sin(_)
+            | vm::StandardOp::Cos(_)
+            | vm::StandardOp::Tan(_)
+            | vm::StandardOp::ASin(_)
+            | vm::StandardOp::ACos(_)
+            | vm::StandardOp::ATan(_) => self.unary(op.clone()),
+            vm::StandardOp::Add(_)
+            | vm::StandardOp::Sub(_)
+            | vm::StandardOp::Mul(_)
+            | vm::StandardOp::Div(_)
+            | vm::StandardOp::Rem(_)
+            | vm::StandardOp::Pow(_) => self.binary(op.clone())?,
+
+            vm::StandardOp::Alloc => {
+                let size = std::mem::take(&mut self.register).truncate(1)?;
+                self.register = Value::Alloc(Box::new(size));
+            }
+            vm::StandardOp::Free => {
+                // Freeing the memory at a location sets the location to `NULL`,
+                // so this is folded with the `NULL` stored after it.
+                self.materialize(TMP, 1)?;
+                self.code.push(StandardOp::Free(TMP));
+                if matches!(&self.register, Value::Cells(loc, _) if *loc == TMP) {
+                    self.register = Value::Unknown;
+                }
+            }
+
+            vm::StandardOp::Poke => match &self.register {
+                Value::Cells(loc, _) => self.inputs.push(loc.clone()),
+                _ => return Err("the input of a foreign function isn't on the stack".to_string()),
+            },
+            vm::StandardOp::Call(binding) => {
+                let call = ForeignCall {
+                    binding: binding.clone(),
+                    read: 0,
+                    stored: 0,
+                };
+                let inputs = std::mem::take(&mut self.inputs);
+                if inputs.len() != binding.input_cells
+                    || inputs
+                        .iter()
+                        .enumerate()
+                        .any(|(i, input)| *input != call.cell(i))
+                {
+                    return Err(
+                        "the inputs of a foreign function aren't on the top of the stack"
+                            .to_string(),
+                    );
+                }
+                self.code.push(StandardOp::Call(binding.clone()));
+                self.foreign_call = Some(call);
+            }
+            vm::StandardOp::Peek => match &mut self.foreign_call {
+                Some(call) if call.read < call.binding.output_cells => {
+                    self.register = Value::ForeignOutput(call.read);
+                    call.read += 1;
+                }
+                _ => return Err("`Peek` without a foreign function output".to_string()),
+            },
+        }
+        Ok(())
+    }
+
+    /// Apply an instruction which only uses the register to the register's value.
+    fn unary(&mut self, op: vm::StandardOp) {
+        let value = std::mem::take(&mut self.register);
+        self.register = Value::Op {
+            value: Box::new(value),
+            op,
+            src: None,
+        };
+    }
+
+    /// Apply an instruction which uses the cells at the pointer to the register's value.
+    fn binary(&mut self, op: vm::StandardOp) -> Result<(), String> {
+        let value = std::mem::take(&mut self.register);
+        self.register = Value::Op {
+            value: Box::new(value),
+            op,
+            src: Some(self.pointer.location()?),
+        };
+        Ok(())
+    }
+
+    /// Store the register to the cells at the pointer.
+    fn store(&mut self, size: usize) -> Result<(), String> {
+        let dst = self.pointer.location()?;
+        if let Some(call) = &mut self.foreign_call {
+            // The outputs of the foreign function are stored by its `Call` instruction,
+            // and then the stack pointer is moved past them.
+            match &self.register {
+                Value::ForeignOutput(i) if size == 1 && dst == call.cell(*i) => {
+                    call.stored += 1;
+                    return Ok(());
+                }
+                Value::Address(loc)
+                    if dst == SP
+                        && call.stored == call.binding.output_cells
+                        && *loc
+                            == SP.deref().offset(
+                                call.binding.output_cells as isize
+                                    - call.binding.input_cells as isize,
+                            ) =>
+                {
+                    self.foreign_call = None;
+                    self.register = Value::Cells(SP, 1);
+                    return Ok(());
+                }
+                _ => return Err("the outputs of a foreign function weren't stored".to_string()),
+            }
+        }
+        self.materialize(dst, size)
+    }
+
+    /// Write the first `size` cells of the register's value to a location,
+    /// and remember that the register holds the cells at the location.
+    fn materialize(&mut self, dst: Location, size: usize) -> Result<(), String> {
+        let value = std::mem::take(&mut self.register).truncate(size)?;
+        if value.size() == 1 && dst != TMP && self.overwrites_operand(&value, &dst, size) {
+            // Compute the value in `TMP` first, so the operands it reads aren't overwritten.
+            if self.overwrites_operand(&value, &TMP, 1) {
+                return Err(format!(
+                    "can't write the register to {dst} without overwriting its operands"
+                ));
+            }
+            self.write(value, &TMP)?;
+            self.emit(CoreOp::Move {
+                src: TMP,
+                dst: dst.clone(),
+            });
+        } else if self.overwrites_operand(&value, &dst, size) {
+            return Err(format!(
+                "can't write the register to {dst} without overwriting its operands"
+            ));
+        } else {
+            self.write(value, &dst)?;
+        }
+        self.register = Value::Cells(dst, size);
+        Ok(())
+    }
+
+    /// Would writing a value to a location write over the cells it reads, before reading them?
+    fn overwrites_operand(&self, value: &Value, dst: &Location, size: usize) -> bool {
+        self.written_before_reads(value, dst, size).is_err()
+    }
+
+    /// Check that writing a value to a location doesn't write over the cells it
+    /// reads before reading them. Returns whether the location has been written.
+    fn written_before_reads(&self, value: &Value, dst: &Location, size: usize) -> Result<bool, ()> {
+        match value {
+            Value::Cells(src, _) => Ok(src != dst),
+            Value::Op {
+                value,
+                op: vm::StandardOp::CoreOp(vm::CoreOp::Index(1)),
+                ..
+            } if matches!(**value, Value::Cells(_, 1)) => Ok(true),
+            Value::Op {
+                value, src: None, ..
+            }
+            | Value::Alloc(value) => {
+                self.written_before_reads(value, dst, size)?;
+                Ok(true)
+            }
+            Value::Op {
+                value,
+                op,
+                src: Some(src),
+            } => {
+                if self.written_before_reads(value, dst, size)?
+                    && may_change(src, op_size(op), dst, size)
+                {
+                    return Err(());
+                }
+                Ok(true)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Write a value to a location with assembly instructions.
+    fn write(&mut self, value: Value, dst: &Location) -> Result<(), String> {
+        let dst = dst.clone();
+        match value {
+            Value::Unknown => return Err("the register's value is unknown".to_string()),
+            Value::Cells(src, _) if src == dst => {}
+            Value::Cells(src, 1) => self.emit(CoreOp::Move { src, dst }),
+            Value::Cells(src, size) => self.emit(CoreOp::Copy { src, dst, size }),
+            Value::Int(vals) if vals.len() == 1 => self.emit(CoreOp::Set(dst, vals[0])),
+            Value::Int(vals) => self.emit(CoreOp::Const { dst, vals }),
+            Value::Float(vals) if vals.len() == 1 => self.code.push(StandardOp::Set(dst, vals[0])),
+            Value::Float(vals) => self.code.push(StandardOp::Const { dst, vals }),
+            Value::Address(addr) => match split(&addr) {
+                (Some(ptr), offset) if *ptr == dst && offset >= 0 => {
+                    self.emit(CoreOp::Next(dst, (offset != 1).then_some(offset)))
+                }
+                (Some(ptr), offset) if *ptr == dst => {
+                    self.emit(CoreOp::Prev(dst, (offset != -1).then_some(-offset)))
+                }
+                _ => self.emit(CoreOp::GetAddress { addr, dst }),
+            },
+            Value::Op {
+                value,
+                op: vm::StandardOp::CoreOp(vm::CoreOp::Index(1)),
+                src: Some(offset),
+            } if matches!(*value, Value::Cells(_, 1)) => {
+                let Value::Cells(src, _) = *value else {
+                    unreachable!()
+                };
+                self.emit(CoreOp::Index { src, offset, dst })
+            }
+            Value::Op { value, op, src } => {
+                let op = apply(&op, src.as_ref(), &dst)
+                    .ok_or_else(|| format!("can't lift `{op}` with a vector of this size"))?;
+                if let (Value::Cells(src, size), StandardOp::CoreOp(CoreOp::VecGez { .. })) =
+                    (&*value, &op)
+                {
+                    // Check whether values are non-negative as they're copied.
+                    self.emit(CoreOp::VecGez {
+                        size: *size,
+                        src: src.clone(),
+                        dst,
+                    });
+                    return Ok(());
+                }
+                self.write(*value, &dst)?;
+                self.push(op);
+            }
+            Value::Input(input) => self.emit(CoreOp::Get(dst, input)),
+            Value::Alloc(size) => {
+                self.write(*size, &dst)?;
+                self.code.push(StandardOp::Alloc(dst));
+            }
+            Value::ForeignOutput(_) => {
+                return Err("the outputs of a foreign function weren't stored".to_string())
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the register's value to a location if it has a side effect, so
+    /// the side effect happens before anything else.
+    fn flush(&mut self) -> Result<(), String> {
+        if self.register.is_effect() {
+            self.materialize(TMP, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Get a location holding the first cell of the register, writing it to `TMP` if needed.
+    fn register_location(&mut self) -> Result<Location, String> {
+        match &self.register {
+            Value::Cells(loc, _) => Ok(loc.clone()),
+            _ => {
+                self.materialize(TMP, 1)?;
+                Ok(TMP)
+            }
+        }
+    }
+
+    /// Check that the pointer is at the start of the tape, like it is between
+    /// the instructions of assembled programs.
+    fn check_pointer(&self) -> Result<(), String> {
+        if self.pointer != Pointer::default() || !self.derefs.is_empty() {
+            return Err("the pointer isn't at the start of the tape".to_string());
+        }
+        Ok(())
+    }
+
+    /// If the lifted code ends by popping the frame pointer, remove those instructions.
+    fn pop_frame_ends_code(&mut self) -> bool {
+        let pop = pop_frame().map(StandardOp::CoreOp);
+        if self.code.ends_with(&pop) {
+            self.code.truncate(self.code.len() - pop.len());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Add a standard instruction to the lifted code.
+    fn push(&mut self, op: StandardOp) {
+        match op {
+            StandardOp::CoreOp(op) => self.emit(op),
+            op => self.code.push(op),
+        }
+    }
+
+    /// Add core instructions to the lifted code.
+    fn emit_all(&mut self, ops: impl IntoIterator<Item = CoreOp>) {
+        for op in ops {
+            self.emit(op)
+        }
+    }
+
+    /// Add a core instruction to the lifted code, folding the instructions
+    /// which push to and pop from the stack.
+    fn emit(&mut self, op: CoreOp) {
+        let top = SP.deref().offset(1);
+        let last = self.code.last().cloned();
+        let op = match (last, op) {
+            (Some(StandardOp::CoreOp(last)), CoreOp::Next(SP, count)) => {
+                let count = count.unwrap_or(1);
+                match last {
+                    CoreOp::Move { src, dst } if dst == top && count == 1 => CoreOp::Push(src, 1),
+                    CoreOp::Copy { src, dst, size } if dst == top && count == size as isize => {
+                        CoreOp::Push(src, size)
+                    }
+                    CoreOp::Set(dst, val) if dst == top && count == 1 => {
+                        CoreOp::PushConst(vec![val])
+                    }
+                    CoreOp::Const { dst, vals } if dst == top && count == vals.len() as isize => {
+                        CoreOp::PushConst(vals)
+                    }
+                    _ => {
+                        self.code
+                            .push(StandardOp::CoreOp(CoreOp::Next(SP, Some(count))));
+                        return;
+                    }
+                }
+            }
+            (Some(StandardOp::Set(dst, val)), CoreOp::Next(SP, count))
+                if dst == top && count.unwrap_or(1) == 1 =>
+            {
+                self.code.pop();
+                self.code.push(StandardOp::PushConst(vec![val]));
+                return;
+            }
+            (Some(StandardOp::Const { dst, vals }), CoreOp::Next(SP, count))
+                if dst == top && count.unwrap_or(1) == vals.len() as isize =>
+            {
+                self.code.pop();
+                self.code.push(StandardOp::PushConst(vals));
+                return;
+            }
+            (_, CoreOp::Prev(SP, count)) => {
+                self.code.push(StandardOp::CoreOp(CoreOp::Pop(
+                    None,
+                    count.unwrap_or(1) as usize,
+                )));
+                return;
+            }
+            (Some(StandardOp::CoreOp(CoreOp::Pop(None, 1))), CoreOp::Move { src, dst })
+                if src == top =>
+            {
+                CoreOp::Pop(Some(dst), 1)
+            }
+            (Some(StandardOp::CoreOp(CoreOp::Pop(None, n))), CoreOp::Copy { src, dst, size })
+                if src == top && size == n =>
+            {
+                CoreOp::Pop(Some(dst), size)
+            }
+            (Some(StandardOp::Free(TMP)), CoreOp::Set(dst, NULL))
+                if self.code.len() >= 2
+                    && self.code[self.code.len() - 2]
+                        == StandardOp::CoreOp(CoreOp::Move {
+                            src: dst.clone(),
+                            dst: TMP,
+                        }) =>
+            {
+                self.code.truncate(self.code.len() - 2);
+                self.code.push(StandardOp::Free(dst));
+                return;
+            }
+            (_, op) => {
+                self.code.push(StandardOp::CoreOp(op));
+                return;
+            }
+        };
+        self.code.pop();
+        self.code.push(StandardOp::CoreOp(op));
+    }
+
+    /// Finish lifting the program, and clean up the lifted code.
+    fn finish(mut self) -> Result<Vec<StandardOp>, String> {
+        self.flush()?;
+        if !self.blocks.is_empty() {
+            return Err("the program has unmatched blocks".to_string());
+        }
+        if self.foreign_call.is_some() {
+            return Err("the outputs of a foreign function weren't stored".to_string());
+        }
+
+        let mut code = self.code;
+        // Replace the bootstrap code with a declaration of the globals it allocated.
+        if let Some(main) = self.main {
+            if let Some(size) = bootstrap_globals(&code[main..]) {
+                code.splice(
+                    main..main + 5,
+                    (size > 0).then(|| {
+                        StandardOp::CoreOp(CoreOp::Global {
+                            name: "globals".to_string(),
+                            size,
+                        })
+                    }),
+                );
+            }
+        }
+
+        // Fold the instructions which set up the frame pointer into each function.
+        let prologue = push_frame()
+            .into_iter()
+            .chain([CoreOp::Move { src: SP, dst: FP }])
+            .map(StandardOp::CoreOp)
+            .collect::<Vec<_>>();
+        let mut result = Vec::with_capacity(code.len());
+        let mut i = 0;
+        while i < code.len() {
+            result.push(code[i].clone());
+            i += 1;
+            if let StandardOp::CoreOp(CoreOp::Fn(_)) = &result[result.len() - 1] {
+                if code[i..].starts_with(&prologue) {
+                    i += prologue.len();
+                } else {
+                    // The function doesn't set up the frame pointer, so undo it.
+                    result.extend(pop_frame().map(StandardOp::CoreOp));
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// The number of cells an instruction operates on.
+fn op_size(op: &vm::StandardOp) -> usize {
+    use vm::CoreOp as Vm;
+    match op {
+        vm::StandardOp::CoreOp(
+            Vm::Index(size)
+            | Vm::BitwiseNand(size)
+            | Vm::BitwiseAnd(size)
+            | Vm::BitwiseOr(size)
+            | Vm::BitwiseXor(size)
+            | Vm::LeftShift(size)
+            | Vm::LogicalRightShift(size)
+            | Vm::ArithmeticRightShift(size)
+            | Vm::And(size)
+            | Vm::Or(size)
+            | Vm::Add(size)
+            | Vm::Sub(size)
+            | Vm::Mul(size)
+            | Vm::Div(size)
+            | Vm::Rem(size),
+        )
+        | vm::StandardOp::Add(size)
+        | vm::StandardOp::Sub(size)
+        | vm::StandardOp::Mul(size)
+        | vm::StandardOp::Div(size)
+        | vm::StandardOp::Rem(size)
+        | vm::StandardOp::Pow(size) => *size,
+        _ => 1,
+    }
+}
+
+/// If the code starts with the bootstrap code made by the assembler,
+/// get the number of cells it allocated for global variables.
+fn bootstrap_globals(code: &[StandardOp]) -> Option<usize> {
+    let mut addresses = vec![];
+    for op in code.get(..4)? {
+        match op {
+            StandardOp::CoreOp(CoreOp::GetAddress {
+                addr: Location::Address(addr),
+                dst,
+            }) => addresses.push((dst.clone(), *addr)),
+            _ => return None,
+        }
+    }
+    if code.get(4)? != &StandardOp::CoreOp(CoreOp::Move { src: SP, dst: FP }) {
+        return None;
+    }
+    // The stack starts after the globals, and the assemblers for each
+    // variant set the stack pointer and the start of the stack in either order.
+    addresses.sort();
+    match addresses[..] {
+        [(SP, sp), (FP_STACK, fp_stack), (GP, gp), (STACK_START, stack_start)]
+            if Location::Address(fp_stack) == START_OF_FP_STACK
+                && sp == stack_start
+                && gp <= stack_start =>
+        {
+            Some(stack_start - gp)
+        }
+        _ => None,
+    }
+}
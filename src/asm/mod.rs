@@ -13,6 +13,7 @@
 //! 2. [Standard Assembly](./std)
 //! 3. [Assembly Memory Model](./location)
 //! 4. [Global Variable Management](./globals)
+//! 5. [Lifting Virtual Machine Code](./lift)
 //!
 //! ## The Core Variant
 //!
@@ -26,14 +27,22 @@
 //! The standard variant is intended to be used with the standard
 //! variant of the virtual machine. It is very portable: it only adds
 //! instructions for float operations, memory allocation, and I/O.
+use crate::{
+    parse::SourceCodeLocation,
+    side_effects::FFISignature,
+    vm::{SourceFrame, SourceMap},
+};
 use ::core::fmt::{Display, Formatter, Result as FmtResult};
-use ::std::collections::HashMap;
+use ::std::collections::{BTreeMap, HashMap};
 
 use log::{debug, error, trace, warn};
 
 pub mod core;
 pub mod globals;
+mod lift;
 pub mod location;
+mod reachability;
+mod registers;
 pub mod std;
 
 pub use self::core::{CoreOp, CoreProgram};
@@ -66,6 +75,21 @@ pub trait AssemblyProgram {
     /// Get the current instruction number.
     fn current_instruction(&self) -> usize;
 
+    /// Mark the instructions inserted after this as compiled from the given
+    /// location in the source code, until the matching call to `end_source`.
+    fn begin_location(&mut self, location: &SourceCodeLocation);
+    /// Mark the instructions inserted after this as the body of the function
+    /// with the given name, until the matching call to `end_source`.
+    fn begin_function(&mut self, name: &str);
+    /// Stop marking instructions with the source code given to the last
+    /// unmatched call to `begin_location` or `begin_function`.
+    fn end_source(&mut self);
+
+    /// Record the typed signature of a foreign function called by the program,
+    /// so targets can bind it to a native function. Programs which can't call
+    /// foreign functions ignore it.
+    fn declare_foreign_function(&mut self, _signature: FFISignature) {}
+
     /// Log all the instructions after the given instruction number.
     /// The `target` is an identifier for the instructions being logged. This is used
     /// to search for the log messages in the output.
@@ -88,6 +112,104 @@ pub trait AssemblyProgram {
     fn get_op(&self, start: usize) -> Option<Result<CoreOp, StandardOp>>;
 }
 
+/// The source code of the instructions in an assembly program,
+/// recorded by the LIR compiler while it compiles the program.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct SourceMarkers {
+    /// The source code currently being compiled, innermost last.
+    stack: Vec<SourceFrame>,
+    /// The source code of the instructions starting at each index,
+    /// until the next marker.
+    markers: BTreeMap<usize, SourceFrame>,
+    /// The names of the functions with each label.
+    names: BTreeMap<String, String>,
+    /// The label of the last function declared with `Fn`.
+    label: Option<String>,
+}
+
+impl SourceMarkers {
+    /// Start marking instructions from the given index with a location in the source code.
+    fn begin_location(&mut self, instruction: usize, location: &SourceCodeLocation) {
+        let function = self.stack.last().and_then(|frame| frame.function.clone());
+        self.begin(
+            instruction,
+            SourceFrame {
+                function,
+                location: Some(location.clone()),
+            },
+        );
+    }
+
+    /// Remember the label of a function declared with `Fn`, to name the function
+    /// when its body begins.
+    fn declare_function(&mut self, label: &str) {
+        self.label = Some(label.to_string());
+    }
+
+    /// Start marking instructions from the given index as the body of a function.
+    /// The function is named after the label of the last function declared, if any.
+    fn begin_function(&mut self, instruction: usize, name: &str) {
+        if let Some(label) = &self.label {
+            self.names.insert(label.clone(), name.to_string());
+        }
+        self.begin(
+            instruction,
+            SourceFrame {
+                function: Some(name.to_string()),
+                location: None,
+            },
+        );
+    }
+
+    fn begin(&mut self, instruction: usize, frame: SourceFrame) {
+        self.stack.push(frame.clone());
+        self.markers.insert(instruction, frame);
+    }
+
+    /// Go back to marking instructions from the given index with the enclosing source code.
+    fn end(&mut self, instruction: usize) {
+        self.stack.pop();
+        let frame = self.stack.last().cloned().unwrap_or_default();
+        self.markers.insert(instruction, frame);
+    }
+
+    /// Get the name of the function with the given label, or the label itself
+    /// if the function's name is unknown.
+    fn function_name(&self, label: String) -> String {
+        self.names.get(&label).cloned().unwrap_or(label)
+    }
+
+    /// Keep only the markers of the instructions which are kept, and move
+    /// them to the instructions' new indices.
+    fn retain(&mut self, keep: &[bool]) {
+        let mut new_index = 0;
+        let mut markers = BTreeMap::new();
+        for (i, &kept) in keep.iter().enumerate() {
+            if let Some(frame) = self.markers.remove(&i) {
+                markers.insert(new_index, frame);
+            }
+            new_index += kept as usize;
+        }
+        // Markers after the last instruction mark the end of the program.
+        for (_, frame) in ::std::mem::take(&mut self.markers) {
+            markers.insert(new_index, frame);
+        }
+        self.markers = markers;
+    }
+
+    /// Build the source map of an assembled program. The `ranges` are the
+    /// start of the virtual machine code assembled from each instruction.
+    fn source_map(&self, ranges: &[usize]) -> SourceMap {
+        let mut result = SourceMap::default();
+        for (i, frame) in &self.markers {
+            if let Some(&start) = ranges.get(*i) {
+                result.insert(start, frame.clone());
+            }
+        }
+        result
+    }
+}
+
 /// An environment used to assemble a program.
 /// This stores information about labels and their IDs in the virtual machine,
 /// as well as information about matching instructions to their `End` statements.
@@ -174,6 +296,8 @@ pub enum Error {
     Unmatched(CoreOp, usize),
     /// The given instruction was not expected, or cannot be used in this context.
     Unexpected(CoreOp, usize),
+    /// The given virtual machine instruction could not be lifted into assembly code.
+    CannotLift(crate::vm::StandardOp, usize, String),
 }
 
 impl From<crate::vm::Error> for Error {
@@ -193,6 +317,9 @@ impl Display for Error {
             Self::UndefinedGlobal(name) => write!(f, "Undefined global {}", name),
             Self::Unmatched(op, i) => write!(f, "Unmatched {} at instruction #{}", op, i),
             Self::Unexpected(op, i) => write!(f, "Unexpected {} at instruction #{}", op, i),
+            Self::CannotLift(op, i, reason) => {
+                write!(f, "Cannot lift {} at instruction #{}: {}", op, i, reason)
+            }
         }
     }
 }
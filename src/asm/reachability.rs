@@ -0,0 +1,148 @@
+//! # Dead Function Elimination
+//!
+//! The LIR compiler emits a function for every procedure it compiles,
+//! whether or not the program ever calls it. This pass finds the functions
+//! which can be reached from the code outside of functions, by following
+//! the labels used by `CallLabel` and `SetLabel` instructions, and marks the
+//! instructions of every other function to be removed.
+//!
+//! Loading a function's address with `SetLabel` doesn't make the function
+//! reachable on its own: the address has to be used. When the address is loaded
+//! into a register, the straight-line code after it is followed, and if the
+//! register is overwritten before it's used, the `SetLabel` is ignored (and
+//! removed along with the function). Addresses stored anywhere else, or
+//! still in a register at a call or control flow, might be called later.
+//!
+//! A function declared inside an unreachable function can still be reachable.
+//! Its instructions are kept in place, so functions are still declared in the
+//! same order relative to the instructions which use their labels.
+//!
+//! Functions declared inside a `Many` instruction are always kept, along with
+//! `Global` declarations, which are visible to the whole program.
+use super::{
+    registers::{fixed_address, uses_register, Access},
+    CoreOp, Location,
+};
+use std::collections::BTreeMap;
+
+/// Find which instructions of a program to keep, given the core instruction
+/// for each instruction in the program (if it's a core instruction).
+/// If the program's blocks are unmatched, every instruction is kept,
+/// so the assembler can report the error.
+pub(super) fn reachable_instructions(code: &[Option<&CoreOp>]) -> Vec<bool> {
+    // The indices of the functions declared with each label.
+    let mut functions: BTreeMap<&String, Vec<usize>> = BTreeMap::new();
+    let mut function_count = 0;
+    // The innermost function containing each instruction, if any.
+    let mut owners = Vec::with_capacity(code.len());
+    // The blocks containing the current instruction, with the functions' indices.
+    let mut blocks: Vec<Option<usize>> = vec![];
+
+    for op in code {
+        let owner = blocks.iter().rev().find_map(|block| *block);
+        match op {
+            Some(CoreOp::Fn(label)) => {
+                functions.entry(label).or_default().push(function_count);
+                blocks.push(Some(function_count));
+                owners.push(Some(function_count));
+                function_count += 1;
+                continue;
+            }
+            Some(CoreOp::If(_) | CoreOp::While(_)) => blocks.push(None),
+            Some(CoreOp::End) => {
+                let Some(_) = blocks.pop() else {
+                    return vec![true; code.len()];
+                };
+            }
+            _ => {}
+        }
+        owners.push(owner);
+    }
+    if !blocks.is_empty() {
+        return vec![true; code.len()];
+    }
+
+    // The labels used by the code outside of functions, and by each function.
+    let mut uses: BTreeMap<Option<usize>, Vec<String>> = BTreeMap::new();
+    for (i, (op, owner)) in code.iter().zip(&owners).enumerate() {
+        match op {
+            Some(CoreOp::SetLabel(..)) if !is_address_used(code, i) => {}
+            Some(op) => op.collect_label_uses(uses.entry(*owner).or_default()),
+            None => {}
+        }
+    }
+
+    // Follow the labels from the code outside of functions.
+    let mut reachable = vec![false; function_count];
+    let mut pending = uses.remove(&None).unwrap_or_default();
+    while let Some(label) = pending.pop() {
+        for &i in functions.get(&label).into_iter().flatten() {
+            if !reachable[i] {
+                reachable[i] = true;
+                pending.extend(uses.remove(&Some(i)).unwrap_or_default());
+            }
+        }
+    }
+
+    let is_reachable = |label: &String| functions[label].iter().any(|&i| reachable[i]);
+    code.iter()
+        .zip(owners)
+        .map(|(op, owner)| match op {
+            Some(CoreOp::Global { .. }) => true,
+            // Drop the unused addresses of the functions being removed.
+            Some(CoreOp::SetLabel(_, label))
+                if functions.contains_key(label) && !is_reachable(label) =>
+            {
+                false
+            }
+            _ => owner.is_none_or(|i| reachable[i]),
+        })
+        .collect()
+}
+
+/// Is the function address loaded by the `SetLabel` instruction at index `i`
+/// used before it's overwritten? Only addresses loaded into registers are
+/// followed, until a call or control flow, which might use any register.
+fn is_address_used(code: &[Option<&CoreOp>], i: usize) -> bool {
+    let Some(CoreOp::SetLabel(dst @ Location::Address(_), _)) = code[i] else {
+        return true;
+    };
+    let address = fixed_address(dst);
+    for op in &code[i + 1..] {
+        let Some(op) = op else {
+            // Standard instructions are not followed.
+            return true;
+        };
+        if matches!(
+            op,
+            CoreOp::Fn(_)
+                | CoreOp::If(_)
+                | CoreOp::While(_)
+                | CoreOp::Else
+                | CoreOp::End
+                | CoreOp::Call(_)
+                | CoreOp::CallLabel(_)
+                | CoreOp::Return
+                | CoreOp::Many(_)
+        ) {
+            return true;
+        }
+        let mut op = (*op).clone();
+        let mut overwritten = false;
+        for (location, access, size) in op.operands_mut() {
+            let covers = fixed_address(location)
+                .zip(address)
+                .is_some_and(|(start, address)| (start..start + size as isize).contains(&address));
+            if covers && access == Access::Write {
+                overwritten = true;
+            } else if covers || uses_register(location, dst) {
+                return true;
+            }
+        }
+        if overwritten {
+            return false;
+        }
+    }
+    // The address is never used before the program ends.
+    false
+}
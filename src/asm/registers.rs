@@ -0,0 +1,561 @@
+//! # Register Allocation
+//!
+//! The LIR compiler passes almost every intermediate value through the stack:
+//! it pushes the value, works on it through locations like `[SP - 1]`, and pops
+//! it off again. This pass keeps short-lived stack cells in the general purpose
+//! registers instead, when the program never uses those registers itself.
+//!
+//! The pass works on straight-line code, between the instructions which use the
+//! stack pointer in ways it can't follow, like calls and control flow. Here, it
+//! knows how deep the stack is at each instruction, so it knows which cell each
+//! `[SP + n]` location refers to. A cell pushed by `Push(_, 1)` or a single cell
+//! `PushConst`, and popped off in the same straight-line code, is kept in a
+//! register if:
+//!
+//! * every instruction uses it as a single cell while it's on the stack,
+//! * no instruction uses the frame pointer while it's on the stack, because the
+//!   local variables are stack cells too, which `[FP + n]` locations refer to, and
+//! * the cells above it are written before they're read, while it's on the stack,
+//!   and after it's popped off until the end of the straight-line code.
+//!
+//! The cells above a cell kept in a register move down to take its place, so
+//! code must not keep pointers to the cells above the top of the stack.
+use super::{CoreOp, Location, StandardOp, A, B, C, D, E, F, FP, SP};
+use std::collections::BTreeSet;
+
+use log::info;
+
+/// How an instruction uses one of its locations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Access {
+    /// The instruction reads the cells at the location.
+    Read,
+    /// The instruction writes the cells at the location.
+    Write,
+    /// The instruction reads the cells at the location, and then writes them.
+    ReadWrite,
+    /// The instruction only uses the address of the location.
+    Address,
+}
+
+/// How an instruction moves the stack pointer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Stack {
+    /// It doesn't move the stack pointer, and only uses the stack through its locations.
+    Unmoved,
+    /// It pushes a number of cells read from its location.
+    Push(usize),
+    /// It pushes a number of constant cells.
+    PushConst(usize),
+    /// It pops a number of cells, and writes them to its location if it has one.
+    Pop(usize),
+    /// It moves the stack pointer by a number of cells.
+    Move(isize),
+    /// It uses the stack pointer in ways the register allocator can't follow.
+    Unknown,
+}
+
+/// An instruction the register allocator can rewrite.
+pub(super) trait Instruction: Clone {
+    /// Convert a core instruction to this kind of instruction.
+    fn from_core_op(op: CoreOp) -> Self;
+    /// How does this instruction move the stack pointer?
+    fn stack(&self) -> Stack;
+    /// Get the locations used by this instruction, with how it uses them,
+    /// and the number of cells it uses at each.
+    fn operands_mut(&mut self) -> Vec<(&mut Location, Access, usize)>;
+    /// Does this instruction use the given register?
+    fn uses_register(&self, register: &Location) -> bool;
+    /// Set a location to the constant this instruction pushes, if it's
+    /// a `PushConst` of a single cell.
+    fn set_pushed_const(&self, dst: Location) -> Option<Self>;
+}
+
+impl Instruction for CoreOp {
+    fn from_core_op(op: CoreOp) -> Self {
+        op
+    }
+
+    fn stack(&self) -> Stack {
+        match self {
+            Self::Push(_, size) => Stack::Push(*size),
+            Self::Pop(_, size) => Stack::Pop(*size),
+            Self::PushConst(vals) => Stack::PushConst(vals.len()),
+            Self::Next(sp, count) if *sp == SP => Stack::Move(count.unwrap_or(1)),
+            Self::Prev(sp, count) if *sp == SP => Stack::Move(-count.unwrap_or(1)),
+            Self::Many(_)
+            | Self::Fn(_)
+            | Self::If(_)
+            | Self::While(_)
+            | Self::Else
+            | Self::End
+            | Self::Call(_)
+            | Self::CallLabel(_)
+            | Self::Return
+            | Self::GetAddress { .. }
+            | Self::PushAddress(_)
+            | Self::Array { .. }
+            | Self::VecIndex { .. }
+            | Self::PushTo { .. }
+            | Self::PopFrom { .. } => Stack::Unknown,
+            _ => Stack::Unmoved,
+        }
+    }
+
+    fn operands_mut(&mut self) -> Vec<(&mut Location, Access, usize)> {
+        CoreOp::operands_mut(self)
+    }
+
+    fn uses_register(&self, register: &Location) -> bool {
+        match self {
+            Self::Many(ops) => ops.iter().any(|op| op.uses_register(register)),
+            _ => operands_use_register(self.clone(), register),
+        }
+    }
+
+    fn set_pushed_const(&self, dst: Location) -> Option<Self> {
+        match self {
+            Self::PushConst(vals) if vals.len() == 1 => Some(Self::Set(dst, vals[0])),
+            _ => None,
+        }
+    }
+}
+
+impl Instruction for StandardOp {
+    fn from_core_op(op: CoreOp) -> Self {
+        Self::CoreOp(op)
+    }
+
+    fn stack(&self) -> Stack {
+        match self {
+            Self::CoreOp(op) => op.stack(),
+            Self::PushConst(vals) => Stack::PushConst(vals.len()),
+            Self::Call(_) => Stack::Unknown,
+            _ => Stack::Unmoved,
+        }
+    }
+
+    fn operands_mut(&mut self) -> Vec<(&mut Location, Access, usize)> {
+        StandardOp::operands_mut(self)
+    }
+
+    fn uses_register(&self, register: &Location) -> bool {
+        match self {
+            Self::CoreOp(op) => op.uses_register(register),
+            _ => operands_use_register(self.clone(), register),
+        }
+    }
+
+    fn set_pushed_const(&self, dst: Location) -> Option<Self> {
+        match self {
+            Self::CoreOp(op) => op.set_pushed_const(dst).map(Self::CoreOp),
+            Self::PushConst(vals) if vals.len() == 1 => Some(Self::Set(dst, vals[0])),
+            _ => None,
+        }
+    }
+}
+
+/// Keep short-lived stack cells in the general purpose registers the program
+/// doesn't use. Instructions which are no longer needed are removed.
+/// Returns whether each of the original instructions was kept.
+pub(super) fn allocate_registers<T: Instruction>(code: &mut Vec<T>) -> Vec<bool> {
+    let registers: Vec<Location> = [A, B, C, D, E, F]
+        .into_iter()
+        .filter(|register| !code.iter().any(|op| op.uses_register(register)))
+        .collect();
+
+    let mut keep = vec![true; code.len()];
+    let mut allocated = 0;
+    let mut start = 0;
+    while start < code.len() {
+        // Find the straight-line code starting here.
+        let mut block = Block::default();
+        for op in &code[start..] {
+            if !block.push(op) {
+                break;
+            }
+        }
+        let end = start + block.steps.len();
+        let assignments = block.assign(&registers);
+        allocated += assignments.iter().flatten().count();
+        block.rewrite(&mut code[start..end], &assignments, &mut keep[start..end]);
+        // Skip the instruction which ended the straight-line code.
+        start = end + 1;
+    }
+
+    let mut kept = keep.iter();
+    code.retain(|_| *kept.next().unwrap());
+    info!("Kept {allocated} stack cells in the registers {registers:?}.");
+    keep
+}
+
+/// Something an instruction does with the stack, in the order it does it.
+/// The cells are numbered by their depth in the stack, relative to the top
+/// of the stack at the start of the straight-line code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    /// Read a number of cells starting at a cell.
+    Read(isize, usize),
+    /// Write a number of cells starting at a cell.
+    Write(isize, usize),
+    /// Move the top of the stack to a cell.
+    Depth(isize),
+}
+
+/// An instruction in straight-line code.
+struct Step {
+    /// How the instruction moves the stack pointer.
+    stack: Stack,
+    /// The depth of the stack before the instruction.
+    depth: isize,
+    /// Does the instruction use the frame pointer?
+    uses_frame: bool,
+    /// What the instruction does with the stack.
+    events: Vec<Event>,
+}
+
+/// A stretch of straight-line code.
+#[derive(Default)]
+struct Block {
+    steps: Vec<Step>,
+    depth: isize,
+}
+
+impl Block {
+    /// Add the next instruction to the straight-line code.
+    /// Returns false if the instruction ends the straight-line code instead.
+    fn push(&mut self, op: &impl Instruction) -> bool {
+        let stack = op.stack();
+        if stack == Stack::Unknown {
+            return false;
+        }
+        let depth = self.depth;
+        let mut op = op.clone();
+        let operands = op.operands_mut();
+        let has_location = !operands.is_empty();
+        let mut reads = vec![];
+        let mut writes = vec![];
+        // Where the instruction's locations are evaluated.
+        let at = match stack {
+            Stack::Pop(size) => depth - size as isize,
+            _ => depth,
+        };
+        for (location, access, size) in operands {
+            if visit(location, access, size, at, &mut reads, &mut writes).is_err() {
+                return false;
+            }
+        }
+
+        let mut events = vec![];
+        match stack {
+            Stack::Unmoved => {
+                events.extend(reads);
+                events.extend(writes);
+            }
+            Stack::Push(size) | Stack::PushConst(size) => {
+                events.extend(reads);
+                events.push(Event::Write(depth + 1, size));
+                self.depth += size as isize;
+                events.push(Event::Depth(self.depth));
+            }
+            Stack::Pop(size) => {
+                self.depth -= size as isize;
+                events.push(Event::Depth(self.depth));
+                if has_location {
+                    events.push(Event::Read(self.depth + 1, size));
+                }
+                events.extend(reads);
+                events.extend(writes);
+            }
+            Stack::Move(count) => {
+                self.depth += count;
+                events.push(Event::Depth(self.depth));
+            }
+            Stack::Unknown => unreachable!(),
+        }
+        self.steps.push(Step {
+            stack,
+            depth,
+            uses_frame: op.uses_register(&FP),
+            events,
+        });
+        true
+    }
+
+    /// Can the cell pushed by the instruction at the given index be kept in a register?
+    /// If so, return the index of the instruction which pops it off.
+    fn lifetime(&self, start: usize) -> Option<usize> {
+        let step = &self.steps[start];
+        if !matches!(step.stack, Stack::Push(1) | Stack::PushConst(1)) {
+            return None;
+        }
+        let cell = step.depth + 1;
+
+        // The cells above the pushed cell which have been written since it was pushed,
+        // or since it was popped off.
+        let mut written = BTreeSet::new();
+        let mut end = None;
+        let mut depth = cell;
+        for (i, step) in self.steps.iter().enumerate().skip(start + 1) {
+            for event in &step.events {
+                match *event {
+                    Event::Depth(new_depth) => {
+                        if end.is_none() && new_depth < cell {
+                            // The cell is popped off by this instruction.
+                            if step.stack == Stack::Pop(1) || step.events.len() == 1 {
+                                end = Some(i);
+                                written.clear();
+                            } else {
+                                return None;
+                            }
+                        }
+                        depth = new_depth;
+                    }
+                    // The instruction which pops the cell off reads it into its location.
+                    Event::Read(read, 1) if Some(i) == end && read == cell => {}
+                    Event::Read(read, size) => {
+                        let range = read..read + size as isize;
+                        if end.is_none() && range.contains(&cell) && size != 1 {
+                            return None;
+                        }
+                        let threshold = if end.is_none() { cell + 1 } else { cell };
+                        if range
+                            .filter(|read| *read >= threshold)
+                            .any(|read| !written.contains(&read))
+                        {
+                            return None;
+                        }
+                    }
+                    Event::Write(write, size) => {
+                        let range = write..write + size as isize;
+                        if end.is_none() && range.contains(&cell) && size != 1 {
+                            return None;
+                        }
+                        written.extend(range);
+                    }
+                }
+            }
+            // The cell might be a local variable, or be below one.
+            if step.uses_frame && end.is_none_or(|end| end == i) {
+                return None;
+            }
+        }
+        // The cells left on the stack must not depend on where the cell was.
+        let end = end?;
+        if (cell..=depth).all(|cell| written.contains(&cell)) {
+            Some(end)
+        } else {
+            None
+        }
+    }
+
+    /// Choose a register for each pushed cell which can be kept in one.
+    /// The cells which live the shortest are chosen first.
+    fn assign(&self, registers: &[Location]) -> Vec<Option<Location>> {
+        let mut lifetimes: Vec<(usize, usize)> = (0..self.steps.len())
+            .filter_map(|start| Some((start, self.lifetime(start)?)))
+            .collect();
+        lifetimes.sort_by_key(|(start, end)| end - start);
+
+        let mut assignments = vec![None; self.steps.len()];
+        let mut assigned: Vec<(usize, usize, &Location)> = vec![];
+        for (start, end) in lifetimes {
+            let free = registers.iter().find(|register| {
+                !assigned
+                    .iter()
+                    .any(|(s, e, r)| r == register && *s <= end && start <= *e)
+            });
+            if let Some(register) = free {
+                assigned.push((start, end, register));
+                assignments[start] = Some(register.clone());
+            }
+        }
+        assignments
+    }
+
+    /// Rewrite the straight-line code to keep the assigned cells in their registers.
+    fn rewrite<T: Instruction>(
+        &self,
+        code: &mut [T],
+        assignments: &[Option<Location>],
+        keep: &mut [bool],
+    ) {
+        // The cells on the stack kept in registers.
+        let mut cells = Cells::default();
+        for (i, step) in self.steps.iter().enumerate() {
+            let op = &mut code[i];
+            let depth = step.depth;
+            match (step.stack, &assignments[i]) {
+                (Stack::Push(_), Some(register)) => {
+                    let mut src = op.operands_mut()[0].0.clone();
+                    cells.rewrite(&mut src, depth);
+                    *op = T::from_core_op(CoreOp::Move {
+                        src,
+                        dst: register.clone(),
+                    });
+                    cells.0.push((depth + 1, register.clone()));
+                }
+                (Stack::PushConst(_), Some(register)) => {
+                    *op = op.set_pushed_const(register.clone()).unwrap();
+                    cells.0.push((depth + 1, register.clone()));
+                }
+                (Stack::Pop(size), _) => {
+                    let depth = depth - size as isize;
+                    let popped = cells.pop(depth);
+                    let dst = op.operands_mut().pop().map(|(dst, _, _)| dst.clone());
+                    match (dst, popped.last()) {
+                        (Some(mut dst), Some(register)) => {
+                            cells.rewrite(&mut dst, depth);
+                            *op = T::from_core_op(CoreOp::Move {
+                                src: register.clone(),
+                                dst,
+                            });
+                        }
+                        (Some(_), None) => {
+                            for (location, _, _) in op.operands_mut() {
+                                cells.rewrite(location, depth);
+                            }
+                        }
+                        (None, _) if popped.len() == size => keep[i] = false,
+                        (None, _) if !popped.is_empty() => {
+                            *op = T::from_core_op(CoreOp::Pop(None, size - popped.len()));
+                        }
+                        (None, _) => {}
+                    }
+                }
+                (Stack::Move(count), _) => {
+                    let popped = cells.pop(depth + count).len() as isize;
+                    let count = count + popped;
+                    if popped == 0 {
+                        continue;
+                    } else if count == 0 {
+                        keep[i] = false;
+                    } else if count > 0 {
+                        *op = T::from_core_op(CoreOp::Next(SP, Some(count)));
+                    } else {
+                        *op = T::from_core_op(CoreOp::Prev(SP, Some(-count)));
+                    }
+                }
+                _ => {
+                    for (location, _, _) in op.operands_mut() {
+                        cells.rewrite(location, depth);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The cells on the stack kept in registers, with their depths, from the bottom up.
+#[derive(Default)]
+struct Cells(Vec<(isize, Location)>);
+
+impl Cells {
+    /// Remove the cells above the given depth, because they were popped off.
+    fn pop(&mut self, depth: isize) -> Vec<Location> {
+        let mut popped = vec![];
+        while matches!(self.0.last(), Some((cell, _)) if *cell > depth) {
+            popped.push(self.0.pop().unwrap().1);
+        }
+        popped
+    }
+
+    /// Rewrite a location used at the given depth of the stack, so that it
+    /// uses the registers for the cells kept in them, and refers to the same
+    /// cells as before otherwise.
+    fn rewrite(&self, location: &mut Location, depth: isize) {
+        if let Some(offset) = stack_offset(location) {
+            let cell = depth + offset;
+            if let Some((_, register)) = self.0.iter().find(|(c, _)| *c == cell) {
+                *location = register.clone();
+                return;
+            }
+            // The cells kept in registers below this one are no longer on the stack.
+            let below = self.0.iter().filter(|(c, _)| *c < cell).count() as isize;
+            *location = SP.deref().offset(offset - below + self.0.len() as isize);
+            return;
+        }
+        match location {
+            Location::Indirect(inner) | Location::Offset(inner, _) => self.rewrite(inner, depth),
+            Location::Address(_) | Location::Global(_) => {}
+        }
+    }
+}
+
+/// Get the offset from the top of the stack of a location like `[SP + n]`.
+fn stack_offset(location: &Location) -> Option<isize> {
+    match location {
+        Location::Indirect(inner) if **inner == SP => Some(0),
+        Location::Offset(inner, offset) => Some(stack_offset(inner)? + offset),
+        _ => None,
+    }
+}
+
+/// Get the address of a location which is a fixed position in the tape.
+pub(super) fn fixed_address(location: &Location) -> Option<isize> {
+    match location {
+        Location::Address(address) => Some(*address as isize),
+        Location::Offset(inner, offset) => Some(fixed_address(inner)? + offset),
+        _ => None,
+    }
+}
+
+/// Do the locations used by the instruction use the given register?
+fn operands_use_register(mut op: impl Instruction, register: &Location) -> bool {
+    let uses = op
+        .operands_mut()
+        .into_iter()
+        .any(|(location, _, _)| uses_register(location, register));
+    uses
+}
+
+/// Does the location use the given register?
+pub(super) fn uses_register(location: &Location, register: &Location) -> bool {
+    if fixed_address(location).is_some() && fixed_address(location) == fixed_address(register) {
+        return true;
+    }
+    match location {
+        Location::Indirect(inner) | Location::Offset(inner, _) => uses_register(inner, register),
+        Location::Address(_) | Location::Global(_) => false,
+    }
+}
+
+/// Record the stack cells a location is used to read and write, at the given
+/// depth of the stack. Returns an error if the location uses the stack pointer
+/// itself, instead of the cells it points to.
+fn visit(
+    location: &Location,
+    access: Access,
+    size: usize,
+    depth: isize,
+    reads: &mut Vec<Event>,
+    writes: &mut Vec<Event>,
+) -> Result<(), ()> {
+    if let Some(offset) = stack_offset(location) {
+        let cell = depth + offset;
+        match access {
+            Access::Read => reads.push(Event::Read(cell, size)),
+            Access::Write => writes.push(Event::Write(cell, size)),
+            Access::ReadWrite => {
+                reads.push(Event::Read(cell, size));
+                writes.push(Event::Write(cell, size));
+            }
+            Access::Address => return Err(()),
+        }
+        return Ok(());
+    }
+    if let Some(address) = fixed_address(location) {
+        // Any use of the stack pointer itself can't be followed.
+        return match address <= 0 && 0 < address + size as isize {
+            true => Err(()),
+            false => Ok(()),
+        };
+    }
+    match location {
+        // Read the pointer to the cells.
+        Location::Indirect(inner) => visit(inner, Access::Read, 1, depth, reads, writes),
+        // Compute the address of the cells.
+        Location::Offset(inner, _) => visit(inner, Access::Address, 1, depth, reads, writes),
+        Location::Address(_) | Location::Global(_) => Ok(()),
+    }
+}
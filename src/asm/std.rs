@@ -7,11 +7,16 @@
 //!
 //! [***Click here to view opcodes!***](./enum.StandardOp.html)
 use super::{
-    location::*, AssemblyProgram, CoreOp, CoreProgram, Env, Error, Location, FP, GP, SP,
+    lift::lift,
+    location::*,
+    reachability::reachable_instructions,
+    registers::{allocate_registers, Access},
+    AssemblyProgram, CoreOp, CoreProgram, Env, Error, Location, SourceMarkers, FP, GP, SP,
     START_OF_FP_STACK,
 };
-use crate::side_effects::ffi::FFIBinding;
-use crate::vm::{self, VirtualMachineProgram};
+use crate::parse::SourceCodeLocation;
+use crate::side_effects::ffi::{FFIBinding, FFISignature};
+use crate::vm::{self, SourceMap, VirtualMachineProgram};
 use std::{collections::BTreeSet, fmt};
 
 use log::info;
@@ -27,6 +32,12 @@ pub struct StandardProgram {
     /// that has been defined so far. This helps the LIR compiler
     /// determine if a function has been compiled yet or not.
     labels: BTreeSet<String>,
+    /// The source code of the instructions in the program.
+    #[serde(skip)]
+    source: SourceMarkers,
+    /// The typed signatures of the foreign functions the program calls.
+    #[serde(default)]
+    foreign_functions: BTreeSet<FFISignature>,
 }
 
 /// A default program is an empty program.
@@ -46,7 +57,94 @@ impl StandardProgram {
                 labels.insert(label.clone());
             }
         }
-        Self { code, labels }
+        Self {
+            code,
+            labels,
+            source: SourceMarkers::default(),
+            foreign_functions: BTreeSet::new(),
+        }
+    }
+
+    /// Lift a virtual machine program back into assembly code, to read it.
+    /// The Nth function is labeled `@funN`, and the bootstrap code made by the
+    /// assembler is replaced by a declaration of the globals it allocated.
+    /// Lifted code calls functions by their index, so it must not be used
+    /// with `remove_unreachable_functions`.
+    pub fn lift(program: &vm::StandardProgram) -> Result<Self, Error> {
+        Ok(Self::new(lift(program)?))
+    }
+
+    /// Get the labels of the functions in the program, in the order they
+    /// are assembled. The Nth label names the Nth function in the assembled
+    /// virtual machine program.
+    pub fn function_labels(&self) -> Vec<String> {
+        let mut labels = vec![];
+        for op in &self.code {
+            if let StandardOp::CoreOp(op) = op {
+                op.collect_function_labels(&mut labels);
+            }
+        }
+        labels
+    }
+
+    /// Get the names of the functions in the program, in the order they
+    /// are assembled. Functions without a known name are named by their label.
+    pub fn function_names(&self) -> Vec<String> {
+        self.function_labels()
+            .into_iter()
+            .map(|label| self.source.function_name(label))
+            .collect()
+    }
+
+    /// Get the typed signatures of the foreign functions the program calls,
+    /// for targets which bind them to native functions.
+    pub fn foreign_functions(&self) -> Vec<FFISignature> {
+        self.foreign_functions.iter().cloned().collect()
+    }
+
+    /// Remove the functions which can't be reached from the code outside of
+    /// functions, through the labels used by `CallLabel` and `SetLabel`,
+    /// along with the signatures of the foreign functions no longer called.
+    /// Code which calls functions by their index instead of their label
+    /// must not be used with this, because the functions are renumbered.
+    pub fn remove_unreachable_functions(&mut self) {
+        let before = self.function_labels().len();
+        let core_ops = self
+            .code
+            .iter()
+            .map(|op| match op {
+                StandardOp::CoreOp(op) => Some(op),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let keep = reachable_instructions(&core_ops);
+        let mut kept = keep.iter();
+        self.code.retain(|_| *kept.next().unwrap());
+        self.source.retain(&keep);
+
+        let labels = self.function_labels();
+        self.labels.retain(|label| labels.contains(label));
+        let called = self
+            .code
+            .iter()
+            .filter_map(|op| match op {
+                StandardOp::Call(binding) => Some(&binding.name),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        self.foreign_functions
+            .retain(|signature| called.contains(&signature.name));
+        info!(
+            "Removed unreachable functions: {before} functions before, {} after.",
+            labels.len()
+        );
+    }
+
+    /// Keep short-lived values on the stack in the general purpose registers
+    /// the program doesn't use, instead of pushing and popping them.
+    pub fn allocate_registers(&mut self) {
+        let keep = allocate_registers(&mut self.code);
+        self.source.retain(&keep);
     }
 
     /// Get the size of the globals.
@@ -68,6 +166,17 @@ impl StandardProgram {
     /// The frame pointer stack is used to keep track of the frame pointers
     /// of each function call.
     pub fn assemble(&self, allowed_recursion_depth: usize) -> Result<vm::StandardProgram, Error> {
+        self.assemble_with_source_map(allowed_recursion_depth)
+            .map(|(program, _)| program)
+    }
+
+    /// Assemble the program like `assemble`, and also build the source map of
+    /// the virtual machine program, which maps its instructions back to the
+    /// source code they were compiled from.
+    pub fn assemble_with_source_map(
+        &self,
+        allowed_recursion_depth: usize,
+    ) -> Result<(vm::StandardProgram, SourceMap), Error> {
         let mut result = vm::StandardProgram(vec![]);
         let mut env = Env::default();
 
@@ -98,15 +207,19 @@ impl StandardProgram {
         starting_sp_addr.copy_address_to(&STACK_START, &mut result);
 
         SP.copy_to(&FP, &mut result);
+        // Remember where the code for each instruction starts, for the source map.
+        let mut starts = Vec::with_capacity(self.code.len() + 1);
         for (i, op) in self.code.iter().enumerate() {
+            starts.push(result.0.len());
             op.assemble(i, &mut env, &mut result)?
         }
+        starts.push(result.0.len());
 
         if let Ok((unmatched, last_instruction)) = env.pop_matching(self.code.len()) {
             return Err(Error::Unmatched(unmatched, last_instruction));
         }
 
-        Ok(result.flatten())
+        Ok(result.flatten_with_source_map(&self.source.source_map(&starts)))
     }
 }
 
@@ -162,6 +275,7 @@ impl AssemblyProgram for StandardProgram {
         // If the operation is a function label, add its label to the set of defined labels.
         if let CoreOp::Fn(label) = &op {
             self.labels.insert(label.clone());
+            self.source.declare_function(label);
         }
 
         if let Some(last_op) = self.code.last().cloned() {
@@ -239,6 +353,7 @@ impl AssemblyProgram for StandardProgram {
         // If the operation is a function label, add its label to the set of defined labels.
         if let StandardOp::CoreOp(CoreOp::Fn(label)) = &op {
             self.labels.insert(label.clone());
+            self.source.declare_function(label);
         }
         self.code.push(op);
         Ok(())
@@ -254,6 +369,26 @@ impl AssemblyProgram for StandardProgram {
         self.code.len()
     }
 
+    /// Mark the following instructions with a location in the source code.
+    fn begin_location(&mut self, location: &SourceCodeLocation) {
+        self.source.begin_location(self.code.len(), location);
+    }
+
+    /// Mark the following instructions as the body of a function.
+    fn begin_function(&mut self, name: &str) {
+        self.source.begin_function(self.code.len(), name);
+    }
+
+    /// Stop marking the following instructions with the last source code.
+    fn end_source(&mut self) {
+        self.source.end(self.code.len());
+    }
+
+    /// Record the signature of a foreign function the program calls.
+    fn declare_foreign_function(&mut self, signature: FFISignature) {
+        self.foreign_functions.insert(signature);
+    }
+
     /// Get the operation at the given instruction number.
     fn get_op(&self, start: usize) -> Option<Result<CoreOp, StandardOp>> {
         self.code.get(start).cloned().map(Err)
@@ -462,6 +597,68 @@ fn unsupported(op: StandardOp) -> Result<(), Error> {
 }
 
 impl StandardOp {
+    /// Get the locations used by this instruction, with how it uses them,
+    /// and the number of cells it uses at each.
+    pub(super) fn operands_mut(&mut self) -> Vec<(&mut Location, Access, usize)> {
+        use Access::*;
+        match self {
+            Self::CoreOp(op) => op.operands_mut(),
+            Self::PushConst(_) | Self::Call(_) => vec![],
+
+            Self::Set(dst, _) => vec![(dst, Write, 1)],
+            Self::VecSet(dst, vals) | Self::Const { dst, vals } => {
+                let size = vals.len();
+                vec![(dst, Write, size)]
+            }
+            Self::Free(src) => vec![(src, Read, 1)],
+            Self::ToFloat(dst)
+            | Self::ToInt(dst)
+            | Self::Sqrt(dst)
+            | Self::Neg(dst)
+            | Self::Sin(dst)
+            | Self::Cos(dst)
+            | Self::Tan(dst)
+            | Self::ASin(dst)
+            | Self::ACos(dst)
+            | Self::ATan(dst)
+            | Self::Alloc(dst) => vec![(dst, ReadWrite, 1)],
+
+            Self::Pow { src, dst }
+            | Self::Add { src, dst }
+            | Self::Sub { src, dst }
+            | Self::Mul { src, dst }
+            | Self::Div { src, dst }
+            | Self::Rem { src, dst } => vec![(src, Read, 1), (dst, ReadWrite, 1)],
+            Self::IsGreater { a, b, dst } | Self::IsLess { a, b, dst } => {
+                vec![(a, Read, 1), (b, Read, 1), (dst, Write, 1)]
+            }
+
+            Self::VecAdd { src, dst, size }
+            | Self::VecSub { src, dst, size }
+            | Self::VecMul { src, dst, size }
+            | Self::VecDiv { src, dst, size }
+            | Self::VecRem { src, dst, size }
+            | Self::VecPow { src, dst, size } => {
+                let size = *size;
+                vec![(src, Read, size), (dst, ReadWrite, size)]
+            }
+            Self::VecGez { src, dst, size } => {
+                let size = *size;
+                vec![(src, Read, size), (dst, Write, size)]
+            }
+            Self::VecNeg { dst, size }
+            | Self::VecSin { dst, size }
+            | Self::VecCos { dst, size }
+            | Self::VecTan { dst, size }
+            | Self::VecASin { dst, size }
+            | Self::VecACos { dst, size }
+            | Self::VecATan { dst, size } => {
+                let size = *size;
+                vec![(dst, ReadWrite, size)]
+            }
+        }
+    }
+
     #[allow(unused_variables)]
     pub(super) fn assemble(
         &self,
@@ -813,7 +1010,7 @@ impl fmt::Display for StandardOp {
 
             // Self::Peek(loc) => write!(f, "peek {loc}"),
             // Self::Poke(loc) => write!(f, "poke {loc}"),
-            Self::Call(binding) => write!(f, "call {:?}", binding),
+            Self::Call(binding) => write!(f, "call {}", binding),
 
             Self::VecSet(dst, vals) => {
                 write!(f, "vset-f {dst}, {vals:?}")
@@ -7,13 +7,14 @@ use clap::*;
 use sage::{
     lir::*,
     parse::*,
-    targets::{self, CompiledTarget},
+    side_effects::FFISignature,
+    targets::{self, Architecture, CompiledTarget},
     vm::*,
     LOGO_WITH_COLOR, *,
 };
 use std::{
     fmt,
-    fs::{read_to_string, write},
+    fs::{read, read_to_string, write},
 };
 
 use log::error;
@@ -44,6 +45,8 @@ enum LogLevel {
 enum TargetType {
     /// Execute the source code in the interpreter.
     Run,
+    /// Execute the source code in the interactive debugger.
+    Debug,
     /// Compile to the core variant of the assembly language.
     CoreASM,
     /// Compile to the standard variant of the assembly language.
@@ -52,12 +55,26 @@ enum TargetType {
     CoreVM,
     /// Compile to the standard variant of the virtual machine.
     StdVM,
-    // /// Compile to My OS source code (GCC only).
-    // SageOS,
+    /// Compile to the core variant of the virtual machine, as bytecode.
+    CoreBytecode,
+    /// Compile to the standard variant of the virtual machine, as bytecode.
+    StdBytecode,
+    /// Compile to C source code for SageOS (GCC only).
+    #[cfg(feature = "sage-os")]
+    SageOS,
     /// Compile to C source code (GCC only).
     C,
-    // /// Compile to x86 assembly code.
-    // X86,
+    /// Compile to safe Rust source code.
+    Rust,
+    /// Compile to a Python 3 script.
+    Python,
+    /// Compile to a JavaScript script for Node.js.
+    #[value(name = "javascript")]
+    JavaScript,
+    /// Compile to a WebAssembly text format module.
+    Wasm,
+    /// Compile to x86-64 assembly code (GNU assembler, System V ABI).
+    X86,
     /// Compile using the Sage-Lisp backend provided by the user.
     SageLisp
 }
@@ -77,15 +94,47 @@ enum SourceType {
     CoreVM,
     /// Compile standard variant virtual machine code.
     StdVM,
+    /// Compile core variant virtual machine bytecode.
+    CoreBytecode,
+    /// Compile standard variant virtual machine bytecode.
+    StdBytecode,
+}
+
+/// The subcommands of the CLI.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check whether a target can build the input file, and report everything it can't build.
+    Check {
+        /// The input file to check.
+        #[clap(value_parser)]
+        input: String,
+
+        /// The source language to check.
+        #[clap(short, value_parser, default_value = "sage")]
+        source_type: SourceType,
+
+        /// The target to check the program against.
+        #[clap(short, value_parser)]
+        target_type: TargetType,
+
+        /// The number of cells allocated for the call stack.
+        #[clap(short, long, value_parser, default_value = "12582912")]
+        call_stack_size: usize,
+    },
 }
 
 /// The argument parser for the CLI.
 #[derive(Parser, Debug)]
 #[clap(author, version, about = Some(LOGO_WITH_COLOR), long_about = Some(LOGO_WITH_COLOR), max_term_width=90)]
+#[clap(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    /// The subcommand to run instead of compiling the input file.
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// The input file to compiler.
-    #[clap(value_parser)]
-    input: String,
+    #[clap(value_parser, required = true)]
+    input: Option<String>,
 
     /// The file to write the output of the compiler to.
     #[clap(short, long, value_parser, default_value = "out")]
@@ -103,6 +152,14 @@ struct Args {
     #[clap(short, long, value_parser, default_value = "12582912")]
     call_stack_size: usize,
 
+    /// The optimization level. At level 1, unreachable functions are removed
+    /// from the assembly code compiled from LIR or Sage code (hand-written
+    /// assembly is kept as is), and the virtual machine code is cleaned up with
+    /// peephole optimizations. At level 2, short-lived values on the stack are
+    /// also kept in the general purpose registers the assembly code doesn't use.
+    #[clap(short = 'O', long, value_parser = clap::value_parser!(u8).range(0..=2), default_value = "0")]
+    opt_level: u8,
+
     /// The log level to use.
     #[clap(short, long, value_parser, default_value = "off")]
     log_level: LogLevel,
@@ -111,6 +168,159 @@ struct Args {
     /// also enable debug logging.
     #[clap(short, long, value_parser)]
     debug: Option<String>,
+
+    /// The maximum number of instructions the interpreter may execute.
+    #[clap(long, value_parser)]
+    max_instructions: Option<usize>,
+
+    /// The maximum number of tape cells the interpreter may use.
+    #[clap(long, value_parser)]
+    max_tape_cells: Option<usize>,
+
+    /// The maximum depth of the interpreter's call stack.
+    #[clap(long, value_parser)]
+    max_call_depth: Option<usize>,
+
+    /// The maximum depth of the interpreter's stack of dereferences.
+    #[clap(long, value_parser)]
+    max_refs_depth: Option<usize>,
+
+    /// Report double frees, invalid frees, and uses of freed memory
+    /// when running a program with the standard interpreter.
+    #[clap(long)]
+    check_heap: bool,
+
+    /// Profile the program while running it. This prints the instructions
+    /// executed by each function and of each kind, and writes the call stacks
+    /// to `<output>.folded` for flamegraph tools.
+    #[clap(long)]
+    profile: bool,
+
+    /// Write a snapshot of the interpreter to this file when the program
+    /// fails, to inspect it or resume the program from it later.
+    #[clap(long, value_parser)]
+    snapshot: Option<String>,
+
+    /// Also write the snapshot when the program first reaches the
+    /// instruction at this index.
+    #[clap(long, value_parser, requires = "snapshot")]
+    snapshot_at: Option<usize>,
+
+    /// Resume the program from a snapshot written by `--snapshot`.
+    #[clap(long, value_parser)]
+    resume: Option<String>,
+
+    /// The number of cells on the tape of a program compiled to C, including
+    /// the cells of the call stack at the start of the tape.
+    #[clap(long, value_parser)]
+    c_tape_cells: Option<usize>,
+
+    /// The maximum depth of the stack of dereferences of a program compiled to C.
+    #[clap(long, value_parser)]
+    c_refs_depth: Option<usize>,
+
+    /// The number of cells in the vector register of a program compiled to C.
+    #[clap(long, value_parser)]
+    c_register_cells: Option<usize>,
+
+    /// The number of cells in the FFI channel of a program compiled to C.
+    #[clap(long, value_parser)]
+    c_ffi_channel_cells: Option<usize>,
+
+    /// The number of entries in the function table of a program compiled to C.
+    #[clap(long, value_parser)]
+    c_max_functions: Option<usize>,
+
+    /// Make a program compiled to C abort with a message when it overflows
+    /// its tape, stack of dereferences, FFI channel, or function table.
+    #[clap(long)]
+    c_checked: bool,
+
+    /// Call the C functions with the same names as the foreign functions declared
+    /// with `extern fun`, instead of the hand-written `__name()` shims in `ffi.h`.
+    #[clap(long)]
+    c_native_ffi: bool,
+
+    /// A Sage-Lisp backend file to build the program with, for the `sage-lisp`
+    /// target. This can be given more than once: the files are loaded in order
+    /// into a fresh environment, instead of using the program's attributes.
+    #[clap(long, value_parser)]
+    lisp_backend: Vec<String>,
+
+    /// The file extension of the output of the `sage-lisp` target.
+    #[clap(long, value_parser, default_value = "txt")]
+    lisp_extension: String,
+}
+
+/// Where to write snapshots of the interpreter, and where to resume from.
+struct Snapshots {
+    /// The file to write snapshots to.
+    path: Option<String>,
+    /// The instruction index to write a snapshot at, when it's first reached.
+    at: Option<usize>,
+    /// The file with the snapshot to resume the program from.
+    resume: Option<String>,
+}
+
+impl Snapshots {
+    /// Read the snapshot to resume the program from, if there is one.
+    fn read_resume(&self) -> Result<Option<Snapshot>, Error> {
+        match &self.resume {
+            Some(path) => Snapshot::from_json(&read_file(path)?)
+                .map(Some)
+                .map_err(Error::Parse),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The options for compiling a program, and for running it in the interpreter.
+struct CompileOptions {
+    /// The size of the call stack, in cells.
+    call_stack_size: usize,
+    /// The optimization level.
+    opt_level: u8,
+    /// Whether to write the output with debug information.
+    debug: bool,
+    /// The limits on the interpreter's resources.
+    limits: Limits,
+    /// Whether to check heap accesses in the interpreter.
+    check_heap: bool,
+    /// Whether to profile the program in the interpreter.
+    profile: bool,
+    /// Where to write snapshots of the interpreter, and where to resume from.
+    snapshots: Snapshots,
+    /// The C target to build programs with.
+    c_target: targets::C,
+    /// Whether to call foreign functions natively in the C target.
+    c_native_ffi: bool,
+    /// The Sage-Lisp backend to build programs with.
+    lisp_backend: LispBackend,
+}
+
+/// The Sage-Lisp backend to build programs with, and where to write their output.
+struct LispBackend {
+    /// The backend files to load, in order.
+    files: Vec<String>,
+    /// The file extension of the output.
+    extension: String,
+}
+
+impl LispBackend {
+    /// Load the backend files into a fresh environment, if there are any.
+    fn load(&self) -> Result<Option<targets::SageLisp>, Error> {
+        if self.files.is_empty() {
+            return Ok(None);
+        }
+        let files = self
+            .files
+            .iter()
+            .map(|path| Ok((path.clone(), read_file(path)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        targets::SageLisp::load(sage::frontend::new_lisp_env(), &files)
+            .map(Some)
+            .map_err(Error::BuildError)
+    }
 }
 
 /// The types of errors returned by the CLI.
@@ -130,11 +340,13 @@ enum Error {
     /// Error generated when assembling input code.
     AsmError(asm::Error),
     /// Error generated by the interpreter executing input code.
-    InterpreterError(String),
+    InterpreterError(vm::RuntimeError),
     /// Error when building the virtual machine code for a given target.
     BuildError(String),
     /// Invalid source code (expected core but got standard).
     InvalidSource(String),
+    /// Error decoding virtual machine bytecode.
+    BytecodeError(vm::BytecodeError),
 }
 
 impl Error {
@@ -151,6 +363,17 @@ impl Error {
                     self
                 }
             }
+            // Point runtime errors at the innermost frame with a known location.
+            Self::InterpreterError(ref err) => {
+                match err.trace().frames.iter().find_map(|f| f.location.clone()) {
+                    Some(loc) => Self::WithSourceCode {
+                        loc,
+                        source_code: code.to_owned(),
+                        err: Box::new(self),
+                    },
+                    None => self,
+                }
+            }
             _ => self,
         }
     }
@@ -161,6 +384,7 @@ impl fmt::Debug for Error {
         match self {
             Error::IO(e) => write!(f, "IO error: {:?}", e),
             Error::Parse(e) => write!(f, "Parse error: {}", e),
+            Error::BytecodeError(e) => write!(f, "Bytecode error: {}", e),
             Error::AsmError(e) => write!(f, "Assembly error: {:?}", e),
             Error::LirError(e) => write!(f, "LIR error: {}", e),
             Error::WithSourceCode {
@@ -202,13 +426,44 @@ impl fmt::Debug for Error {
                 // let code = format!("{}\n{}^", code, " ".repeat(*column - 1));
                 // write!(f, "Error at {}:\n{}\n{:?}", loc, code, err)?
 
-                let diagnostic = Diagnostic::error()
+                let mut diagnostic = Diagnostic::error()
                     .with_message(format!("Error at {}", loc))
                     .with_labels(vec![Label::primary(
                         file_id,
                         *offset..*offset + length.unwrap_or(0),
                     )
-                    .with_message(format!("{err:?}"))]);
+                    .with_message(match err.as_ref() {
+                        Error::InterpreterError(e) => e.message(),
+                        err => format!("{err:?}"),
+                    })]);
+
+                // Show where each function in the stack trace was called from.
+                if let Error::InterpreterError(e) = err.as_ref() {
+                    let frames = &e.trace().frames;
+                    let mut labeled = std::collections::BTreeSet::new();
+                    for frame in frames.iter().skip(1) {
+                        if let Some(caller) = &frame.location {
+                            // Only label each call once, even if it was made recursively.
+                            if caller.filename.as_deref().unwrap_or("unknown") == filename
+                                && labeled.insert(caller.offset)
+                            {
+                                diagnostic = diagnostic.with_labels(vec![Label::secondary(
+                                    file_id,
+                                    caller.offset..caller.offset + caller.length.unwrap_or(0),
+                                )
+                                .with_message(format!("called from {}", frame.function_name()))]);
+                            }
+                        }
+                    }
+                    diagnostic = diagnostic.with_notes(vec![format!(
+                        "stack trace:\n{}",
+                        frames
+                            .iter()
+                            .map(|frame| format!("  at {frame}"))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    )]);
+                }
 
                 let writer = StandardStream::stderr(ColorChoice::Always);
                 let config = codespan_reporting::term::Config::default();
@@ -230,18 +485,77 @@ fn compile_source_to_vm(
     src: String,
     src_type: SourceType,
     call_stack_size: usize,
+    opt_level: u8,
     default_to_core: bool,
 ) -> Result<Result<sage::vm::CoreProgram, sage::vm::StandardProgram>, Error> {
-    match src_type {
+    compile_source_to_vm_with_debug_info(
+        filename,
+        src,
+        src_type,
+        call_stack_size,
+        opt_level,
+        default_to_core,
+    )
+    .map(|(vm_code, _)| vm_code)
+}
+
+/// The information used to debug a compiled virtual machine program,
+/// and to show where it failed in the source code.
+#[derive(Default)]
+struct DebugInfo {
+    /// The assembly labels of the program's functions (indexed by function number).
+    labels: Vec<String>,
+    /// The names of the program's functions in the source code, where they're known
+    /// (indexed by function number).
+    names: Vec<String>,
+    /// The source code each instruction was compiled from.
+    source_map: SourceMap,
+    /// The typed signatures of the foreign functions the program calls.
+    foreign_functions: Vec<FFISignature>,
+}
+
+/// Compile a given source language to virtual machine code, along with
+/// its debug information. Virtual machine source code has no debug information.
+/// The code is optimized if the optimization level is above 0.
+#[allow(clippy::type_complexity)]
+fn compile_source_to_vm_with_debug_info(
+    filename: Option<&str>,
+    src: String,
+    src_type: SourceType,
+    call_stack_size: usize,
+    opt_level: u8,
+    default_to_core: bool,
+) -> Result<
+    (
+        Result<sage::vm::CoreProgram, sage::vm::StandardProgram>,
+        DebugInfo,
+    ),
+    Error,
+> {
+    let (vm_code, info) = match src_type {
+        SourceType::StdBytecode => {
+            // Decode the bytecode, which is a core or standard program
+            decode_bytecode(&read_bytecode(filename)?)
+                .map_err(Error::BytecodeError)
+                .map(|prog| (prog, DebugInfo::default()))
+        }
+        SourceType::CoreBytecode => {
+            // Decode the bytecode, and make sure it's a core program
+            CoreProgram::from_bytecode(&read_bytecode(filename)?)
+                .map_err(Error::BytecodeError)
+                .map(|prog| (Ok(prog), DebugInfo::default()))
+        }
         SourceType::StdVM => {
             // Simply parse the virtual machine code
-            parse_vm(src).map_err(Error::Parse)
+            parse_vm(src)
+                .map_err(Error::Parse)
+                .map(|prog| (prog, DebugInfo::default()))
         }
         SourceType::CoreVM => {
             // Parse the virtual machine code
             match parse_vm(src).map_err(Error::Parse)? {
                 // If we got a core program back, return it.
-                Ok(prog) => Ok(Ok(prog)),
+                Ok(prog) => Ok((Ok(prog), DebugInfo::default())),
                 // Otherwise, our core program was actually a standard program. Throw an error.
                 Err(_) => Err(Error::InvalidSource(
                     "expected core VM program, got standard VM program".to_string(),
@@ -252,22 +566,14 @@ fn compile_source_to_vm(
             // Parse the assembly code.
             // Then, assembly the program with the given recursion depth,
             // and return the virtual machine output.
-            match parse_asm(src).map_err(Error::Parse)? {
-                Ok(prog) => Ok(Ok(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(prog) => Ok(Err(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-            }
+            let asm_code = parse_asm(src).map_err(Error::Parse)?;
+            assemble_with_debug_info(asm_code, call_stack_size, opt_level, false)
         }
         SourceType::CoreASM => {
             // Parse the assembly code.
             match parse_asm(src).map_err(Error::Parse)? {
                 // If we got back a core program, assembly it and return the virtual machine code.
-                Ok(prog) => Ok(Ok(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
+                Ok(prog) => assemble_with_debug_info(Ok(prog), call_stack_size, opt_level, false),
                 // Otherwise, our core program was actually a standard program. Throw an error.
                 Err(_) => Err(Error::InvalidSource(
                     "expected core assembly program, got standard assembly program".to_string(),
@@ -275,36 +581,97 @@ fn compile_source_to_vm(
             }
         }
         SourceType::LowIR => {
-            // Parse the lower intermediate representation code.
-            match parse_lir(src)
+            // Parse the lower intermediate representation code, and assemble the result.
+            let asm_code = parse_lir(src)
                 .map_err(Error::Parse)?
                 .compile(true)
-                .map_err(Error::LirError)?
-            {
-                // If we got back a valid program, assemble it and return the result.
-                Ok(asm_code) => Ok(Ok(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(asm_code) => Ok(Err(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-            }
+                .map_err(Error::LirError)?;
+            assemble_with_debug_info(asm_code, call_stack_size, opt_level, true)
         }
         SourceType::Sage => {
-            match parse_frontend(&src, filename)
+            let asm_code = parse_frontend(&src, filename)
                 .map_err(Error::Parse)?
                 .compile(default_to_core)
                 .map_err(Error::LirError)
-                .map_err(|e| e.annotate_with_source(&src))?
-            {
-                // If we got back a valid program, assemble it and return the result.
-                Ok(asm_code) => Ok(Ok(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(asm_code) => Ok(Err(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
+                .map_err(|e| e.annotate_with_source(&src))?;
+            assemble_with_debug_info(asm_code, call_stack_size, opt_level, true)
+        }
+    }?;
+
+    // Clean up the assembled code, keeping the debug information in step with it.
+    if opt_level == 0 {
+        return Ok((vm_code, info));
+    }
+    let (vm_code, source_map) = match vm_code {
+        Ok(vm_code) => {
+            let (vm_code, source_map) = vm_code.optimize_with_source_map(&info.source_map);
+            (Ok(vm_code), source_map)
+        }
+        Err(vm_code) => {
+            let (vm_code, source_map) = vm_code.optimize_with_source_map(&info.source_map);
+            (Err(vm_code), source_map)
+        }
+    };
+    Ok((vm_code, DebugInfo { source_map, ..info }))
+}
+
+/// Assemble an assembly program into virtual machine code, along with its debug
+/// information. Above optimization level 1, the stack cells are kept in registers.
+/// Unreachable functions are removed above level 0 if `remove_unreachable` is set;
+/// it's only set for compiled code, since assembly code may call functions by index.
+#[allow(clippy::type_complexity)]
+fn assemble_with_debug_info(
+    asm_code: Result<sage::asm::CoreProgram, sage::asm::StandardProgram>,
+    call_stack_size: usize,
+    opt_level: u8,
+    remove_unreachable: bool,
+) -> Result<
+    (
+        Result<sage::vm::CoreProgram, sage::vm::StandardProgram>,
+        DebugInfo,
+    ),
+    Error,
+> {
+    match asm_code {
+        Ok(mut asm_code) => {
+            if opt_level > 0 && remove_unreachable {
+                asm_code.remove_unreachable_functions();
+            }
+            if opt_level > 1 {
+                asm_code.allocate_registers();
             }
+            let (vm_code, source_map) = asm_code
+                .assemble_with_source_map(call_stack_size)
+                .map_err(Error::AsmError)?;
+            Ok((
+                Ok(vm_code),
+                DebugInfo {
+                    labels: asm_code.function_labels(),
+                    names: asm_code.function_names(),
+                    source_map,
+                    foreign_functions: vec![],
+                },
+            ))
+        }
+        Err(mut asm_code) => {
+            if opt_level > 0 && remove_unreachable {
+                asm_code.remove_unreachable_functions();
+            }
+            if opt_level > 1 {
+                asm_code.allocate_registers();
+            }
+            let (vm_code, source_map) = asm_code
+                .assemble_with_source_map(call_stack_size)
+                .map_err(Error::AsmError)?;
+            Ok((
+                Err(vm_code),
+                DebugInfo {
+                    labels: asm_code.function_labels(),
+                    names: asm_code.function_names(),
+                    source_map,
+                    foreign_functions: asm_code.foreign_functions(),
+                },
+            ))
         }
     }
 }
@@ -339,10 +706,17 @@ fn compile_source_to_asm(
             .map_err(Error::LirError)
             .map_err(|e| e.annotate_with_source(&src)),
         // If the source language is a virtual machine program,
-        // then we cannot compile it to assembly. Throw an error.
-        SourceType::CoreVM | SourceType::StdVM => Err(Error::InvalidSource(
-            "cannot compile a core VM program to assembly".to_string(),
-        )),
+        // then lift it back into assembly code.
+        SourceType::CoreVM
+        | SourceType::StdVM
+        | SourceType::CoreBytecode
+        | SourceType::StdBytecode => {
+            match compile_source_to_vm(filename, src, src_type, 0, 0, false)? {
+                Ok(vm_code) => sage::asm::CoreProgram::lift(&vm_code).map(Ok),
+                Err(vm_code) => sage::asm::StandardProgram::lift(&vm_code).map(Err),
+            }
+            .map_err(Error::AsmError)
+        }
     }
 }
 
@@ -353,54 +727,307 @@ fn compile(
     src_type: SourceType,
     target: TargetType,
     output: String,
-    call_stack_size: usize,
-    debug: bool,
+    options: CompileOptions,
 ) -> Result<(), Error> {
+    let CompileOptions {
+        call_stack_size,
+        opt_level,
+        debug,
+        limits,
+        check_heap,
+        profile,
+        snapshots,
+        mut c_target,
+        c_native_ffi,
+        lisp_backend,
+    } = options;
     match target {
         // If the target is `Run`, then compile the code and execute it with the interpreter.
-        TargetType::Run => match compile_source_to_vm(filename, src, src_type, call_stack_size, false)? {
-            // If the code is core variant virtual machine code
-            Ok(vm_code) => {
-                CoreInterpreter::new(StandardDevice::default())
-                    .run(&vm_code)
-                    .map_err(Error::InterpreterError)?;
+        TargetType::Run => {
+            let (vm_code, info) = compile_source_to_vm_with_debug_info(
+                filename,
+                src.clone(),
+                src_type,
+                call_stack_size,
+                opt_level,
+                false,
+            )?;
+            let resume = snapshots.read_resume()?;
+            let (exit_code, profile) = match vm_code {
+                // If the code is core variant virtual machine code
+                Ok(vm_code) => {
+                    let mut interpreter = CoreInterpreter::new(StandardDevice::default())
+                        .with_limits(limits)
+                        .with_source_map(info.source_map);
+                    if profile {
+                        interpreter = interpreter.with_profiling();
+                    }
+                    if let Some(snapshot) = resume {
+                        interpreter
+                            .restore(snapshot)
+                            .map_err(Error::InvalidSource)?;
+                    }
+                    run_with_snapshots(&mut interpreter, &vm_code, &snapshots)
+                        .map(|exit_code| (exit_code, interpreter.profile().cloned()))
+                }
+                // If the code is standard variant virtual machine code
+                Err(vm_code) => {
+                    let mut interpreter = StandardInterpreter::new(StandardDevice::default())
+                        .with_limits(limits)
+                        .with_source_map(info.source_map);
+                    if check_heap {
+                        interpreter = interpreter.with_heap_checks();
+                    }
+                    if profile {
+                        interpreter = interpreter.with_profiling();
+                    }
+                    if let Some(snapshot) = resume {
+                        interpreter
+                            .restore(snapshot)
+                            .map_err(Error::InvalidSource)?;
+                    }
+                    run_with_snapshots(&mut interpreter, &vm_code, &snapshots)
+                        .map(|exit_code| (exit_code, interpreter.profile().cloned()))
+                }
             }
-            // If the code is standard variant virtual machine code
-            Err(vm_code) => {
-                StandardInterpreter::new(StandardDevice::default())
-                    .run(&vm_code)
-                    .map_err(Error::InterpreterError)?;
+            // Show where the program failed in the source code.
+            .map_err(|e| e.annotate_with_source(&src))?;
+            // Report the profile, and write the call stacks for flamegraph tools.
+            if let Some(profile) = profile {
+                eprint!("{}", profile.report(&info.names));
+                write_file(
+                    format!("{output}.folded"),
+                    profile.collapsed_stacks(&info.names),
+                )?;
             }
-        },
+            // If the program halted with an exit code, return it to the shell.
+            if exit_code != 0 {
+                std::process::exit(exit_code as i32);
+            }
+        }
+
+        // If the target is `Debug`, then compile the code and execute it with the
+        // interpreter under the interactive debugger.
+        TargetType::Debug => {
+            let (vm_code, info) = compile_source_to_vm_with_debug_info(
+                filename,
+                src,
+                src_type,
+                call_stack_size,
+                opt_level,
+                false,
+            )?;
+            let resume = snapshots.read_resume()?;
+            match vm_code {
+                Ok(vm_code) => {
+                    let mut interpreter = CoreInterpreter::new(StandardDevice::default())
+                        .with_limits(limits)
+                        .with_source_map(info.source_map);
+                    if let Some(snapshot) = resume {
+                        interpreter
+                            .restore(snapshot)
+                            .map_err(Error::InvalidSource)?;
+                    }
+                    Debugger::new(interpreter, &vm_code)
+                        .with_labels(info.labels)
+                        .run_interactive(std::io::stdin().lock(), std::io::stdout())
+                        .map_err(Error::IO)?;
+                }
+                Err(vm_code) => {
+                    let mut interpreter = StandardInterpreter::new(StandardDevice::default())
+                        .with_limits(limits)
+                        .with_source_map(info.source_map);
+                    if check_heap {
+                        interpreter = interpreter.with_heap_checks();
+                    }
+                    if let Some(snapshot) = resume {
+                        interpreter
+                            .restore(snapshot)
+                            .map_err(Error::InvalidSource)?;
+                    }
+                    Debugger::new(interpreter, &vm_code)
+                        .with_labels(info.labels)
+                        .run_interactive(std::io::stdin().lock(), std::io::stdout())
+                        .map_err(Error::IO)?;
+                }
+            }
+        }
 
         // If the target is C source code, then compile the code to virtual machine code,
         // and then use the C target implementation to build the output source code.
-        TargetType::C => write_file(
-            format!("{output}.c"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size, false)? {
-                Ok(vm_code) => targets::C.build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::C.build_std(&vm_code.flatten()),
+        TargetType::C => {
+            let (vm_code, info) = compile_source_to_vm_with_debug_info(
+                filename,
+                src,
+                src_type,
+                call_stack_size,
+                opt_level,
+                false,
+            )?;
+            c_target.reserve_call_stack(call_stack_size);
+            // Bind the foreign functions declared in the source code to native functions.
+            if c_native_ffi {
+                c_target.foreign_functions = info.foreign_functions;
+            }
+            write_file(
+                format!("{output}.c"),
+                match vm_code {
+                    Ok(vm_code) => c_target.build_core(&vm_code.flatten()),
+                    Err(vm_code) => c_target.build_std(&vm_code.flatten()),
+                }
+                .map_err(Error::BuildError)?,
+            )?
+        }
+
+        // If the target is SageOS, then compile the code to virtual machine code,
+        // and then use the SageOS target implementation to build the output source code.
+        #[cfg(feature = "sage-os")]
+        TargetType::SageOS => {
+            let mut sage_os_target = targets::SageOS::default().with_call_stack(call_stack_size);
+            write_file(
+                format!("{output}.c"),
+                match compile_source_to_vm(
+                    filename,
+                    src,
+                    src_type,
+                    call_stack_size,
+                    opt_level,
+                    false,
+                )? {
+                    Ok(vm_code) => sage_os_target.build_core(&vm_code.flatten()),
+                    Err(vm_code) => sage_os_target.build_std(&vm_code.flatten()),
+                }
+                .map_err(Error::BuildError)?,
+            )?
+        }
+
+        // If the target is Rust source code, then compile the code to virtual machine code,
+        // and then use the Rust target implementation to build the output source code.
+        TargetType::Rust => {
+            let mut rust_target = targets::Rust::default().with_call_stack(call_stack_size);
+            write_file(
+                format!("{output}.rs"),
+                match compile_source_to_vm(
+                    filename,
+                    src,
+                    src_type,
+                    call_stack_size,
+                    opt_level,
+                    false,
+                )? {
+                    Ok(vm_code) => rust_target.build_core(&vm_code.flatten()),
+                    Err(vm_code) => rust_target.build_std(&vm_code.flatten()),
+                }
+                .map_err(Error::BuildError)?,
+            )?
+        }
+
+        // If the target is a Python script, then compile the code to virtual machine code,
+        // and then use the Python target implementation to build the output script.
+        TargetType::Python => {
+            let mut python_target = targets::Python::default().with_call_stack(call_stack_size);
+            write_file(
+                format!("{output}.py"),
+                match compile_source_to_vm(
+                    filename,
+                    src,
+                    src_type,
+                    call_stack_size,
+                    opt_level,
+                    false,
+                )? {
+                    Ok(vm_code) => python_target.build_core(&vm_code.flatten()),
+                    Err(vm_code) => python_target.build_std(&vm_code.flatten()),
+                }
+                .map_err(Error::BuildError)?,
+            )?
+        }
+
+        // If the target is a JavaScript script, then compile the code to virtual machine code,
+        // and then use the JavaScript target implementation to build the output script.
+        TargetType::JavaScript => {
+            let mut javascript_target =
+                targets::JavaScript::default().with_call_stack(call_stack_size);
+            write_file(
+                format!("{output}.js"),
+                match compile_source_to_vm(
+                    filename,
+                    src,
+                    src_type,
+                    call_stack_size,
+                    opt_level,
+                    false,
+                )? {
+                    Ok(vm_code) => javascript_target.build_core(&vm_code.flatten()),
+                    Err(vm_code) => javascript_target.build_std(&vm_code.flatten()),
+                }
+                .map_err(Error::BuildError)?,
+            )?
+        }
+
+        // If the target is x86-64 assembly, then compile the code to virtual machine code,
+        // and then use the x86-64 target implementation to build the output assembly.
+        TargetType::X86 => write_file(
+            format!("{output}.s"),
+            match compile_source_to_vm(filename, src, src_type, call_stack_size, opt_level, false)?
+            {
+                Ok(vm_code) => targets::X86::default()
+                    .with_call_stack(call_stack_size)
+                    .build_core(&vm_code.flatten()),
+                Err(vm_code) => targets::X86::default()
+                    .with_call_stack(call_stack_size)
+                    .build_std(&vm_code.flatten()),
+            }
+            .map_err(Error::BuildError)?,
+        )?,
+
+        // If the target is WebAssembly, then compile the code to virtual machine code,
+        // and then use the WebAssembly target implementation to build the output module.
+        TargetType::Wasm => write_file(
+            format!("{output}.wat"),
+            match compile_source_to_vm(filename, src, src_type, call_stack_size, opt_level, false)?
+            {
+                Ok(vm_code) => targets::Wasm::default()
+                    .with_call_stack(call_stack_size)
+                    .build_core(&vm_code.flatten()),
+                Err(vm_code) => targets::Wasm::default()
+                    .with_call_stack(call_stack_size)
+                    .build_std(&vm_code.flatten()),
             }
             .map_err(Error::BuildError)?,
         )?,
 
         // If the target is C source code, then compile the code to virtual machine code,
         // and then use the C target implementation to build the output source code.
+        // If the target is a Sage-Lisp backend, then compile the code to virtual machine code,
+        // and then use the backend's `build-core` or `build-std` function to build the output.
         TargetType::SageLisp => {
-
-            write_file(format!("output.txt"), match compile_source_to_vm(filename, src, src_type, call_stack_size, false)? {
-                Ok(vm_code) => targets::SageLisp::new(sage::frontend::get_lisp_env()).build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::SageLisp::new(sage::frontend::get_lisp_env()).build_std(&vm_code.flatten()),
-            }.map_err(Error::BuildError)?)?
-            // write_file(
-            //     format!("{output}.c"),
-            //     .map_err(Error::BuildError)?,
-            // )?
+            // Backend files are loaded first, so a broken backend is reported before compiling.
+            let backend = lisp_backend.load()?;
+            let vm_code =
+                compile_source_to_vm(filename, src, src_type, call_stack_size, opt_level, false)?;
+            // Without backend files, the backend is defined by the program's attributes.
+            let mut backend = match backend {
+                Some(backend) => backend,
+                None => {
+                    let backend = targets::SageLisp::new(sage::frontend::get_lisp_env());
+                    backend.validate().map_err(Error::BuildError)?;
+                    backend
+                }
+            };
+            write_file(
+                format!("{output}.{}", lisp_backend.extension),
+                match vm_code {
+                    Ok(vm_code) => backend.build_core(&vm_code.flatten()),
+                    Err(vm_code) => backend.build_std(&vm_code.flatten()),
+                }
+                .map_err(Error::BuildError)?,
+            )?
         }
 
         // If the target is core virtual machine code, then try to compile the source to the core variant.
         // If not possible, throw an error.
-        TargetType::CoreVM => match compile_source_to_vm(filename, src, src_type, call_stack_size, true)?
+        TargetType::CoreVM => match compile_source_to_vm(filename, src, src_type, call_stack_size, opt_level, true)?
         {
             Ok(vm_code) if debug => write_file(
                 format!("{output}.vm.sg"),
@@ -408,20 +1035,41 @@ fn compile(
             ),
             Ok(vm_code) => write_file(format!("{output}.vm.sg"), vm_code.flatten().to_string()),
             Err(_) => Err(Error::InvalidSource(
-                "expected core VM program, got standard VM program".to_string(),
+                "cannot compile a standard program to core VM code".to_string(),
             )),
         }?,
         // If the target is standard virtual machine code, the compile it to virtual machine code.
         // If the result is core variant, we don't care. Just return the generated code.
         TargetType::StdVM => write_file(
             format!("{output}.vm.sg"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size, false)? {
+            match compile_source_to_vm(filename, src, src_type, call_stack_size, opt_level, false)?
+            {
                 Ok(vm_code) if debug => format!("{:#}", vm_code.flatten()),
                 Err(vm_code) if debug => format!("{:#}", vm_code.flatten()),
                 Ok(vm_code) => vm_code.flatten().to_string(),
                 Err(vm_code) => vm_code.flatten().to_string(),
             },
         )?,
+        // If the target is core virtual machine bytecode, then try to compile the source to the core variant.
+        // If not possible, throw an error.
+        TargetType::CoreBytecode => {
+            match compile_source_to_vm(filename, src, src_type, call_stack_size, opt_level, true)? {
+                Ok(vm_code) => write_file(format!("{output}.sgb"), vm_code.flatten().to_bytecode()),
+                Err(_) => Err(Error::InvalidSource(
+                    "cannot compile a standard program to core bytecode".to_string(),
+                )),
+            }?
+        }
+        // If the target is standard virtual machine bytecode, then compile the source to virtual machine code.
+        // If the result is core variant, it's written as a core program.
+        TargetType::StdBytecode => write_file(
+            format!("{output}.sgb"),
+            match compile_source_to_vm(filename, src, src_type, call_stack_size, opt_level, false)?
+            {
+                Ok(vm_code) => vm_code.flatten().to_bytecode(),
+                Err(vm_code) => vm_code.flatten().to_bytecode(),
+            },
+        )?,
         // If the target is core assembly code, then try to compile the source to the core variant.
         // If not possible, throw an error.
         TargetType::CoreASM => match compile_source_to_asm(filename, src, src_type)? {
@@ -430,7 +1078,7 @@ fn compile(
             }
             Ok(asm_code) => write_file(format!("{output}.asm.sg"), asm_code.to_string()),
             Err(_) => Err(Error::InvalidSource(
-                "expected core assembly program, got standard assembly program".to_string(),
+                "cannot compile a standard program to core assembly".to_string(),
             )),
         }?,
         // If the target is standard assembly code, then try to compile the source to the standard variant.
@@ -448,11 +1096,122 @@ fn compile(
     Ok(())
 }
 
+/// Check whether a target can build code in a given source language, and print
+/// everything it can't build. Returns whether the target can build the code.
+fn check(
+    filename: Option<&str>,
+    src: String,
+    src_type: SourceType,
+    target: TargetType,
+    call_stack_size: usize,
+) -> Result<bool, Error> {
+    let (vm_code, info) =
+        compile_source_to_vm_with_debug_info(filename, src, src_type, call_stack_size, 0, false)?;
+    // Targets build flattened programs, so check the flattened program.
+    let (program, source_map) = match vm_code {
+        Ok(vm_code) => {
+            let (vm_code, source_map) = vm_code.flatten_with_source_map(&info.source_map);
+            (StandardProgram::from(vm_code), source_map)
+        }
+        Err(vm_code) => vm_code.flatten_with_source_map(&info.source_map),
+    };
+
+    let report = match target {
+        TargetType::C => {
+            targets::check_compatibility(&targets::C::default(), &program, &source_map)
+        }
+        #[cfg(feature = "sage-os")]
+        TargetType::SageOS => {
+            targets::check_compatibility(&targets::SageOS::default(), &program, &source_map)
+        }
+        TargetType::Rust => {
+            targets::check_compatibility(&targets::Rust::default(), &program, &source_map)
+        }
+        TargetType::Python => {
+            targets::check_compatibility(&targets::Python::default(), &program, &source_map)
+        }
+        TargetType::JavaScript => {
+            targets::check_compatibility(&targets::JavaScript::default(), &program, &source_map)
+        }
+        TargetType::Wasm => {
+            targets::check_compatibility(&targets::Wasm::default(), &program, &source_map)
+        }
+        TargetType::X86 => {
+            targets::check_compatibility(&targets::X86::default(), &program, &source_map)
+        }
+        TargetType::SageLisp => targets::check_compatibility(
+            &targets::SageLisp::new(sage::frontend::get_lisp_env()),
+            &program,
+            &source_map,
+        ),
+        TargetType::Run => targets::check_interpreter_compatibility(
+            &StandardDevice::default(),
+            &program,
+            &source_map,
+        ),
+        // The intermediate languages can represent any program.
+        other => {
+            return Err(Error::BuildError(format!(
+                "cannot check compatibility with {other:?}, which is not a compiled target"
+            )))
+        }
+    };
+    println!("{report}");
+    Ok(report.is_compatible())
+}
+
+/// Run a program until it finishes, and return its exit code. Snapshots
+/// of the interpreter are written when the program reaches the requested
+/// instruction, and when it fails.
+fn run_with_snapshots<I: Debuggable>(
+    interpreter: &mut I,
+    code: &I::Program,
+    snapshots: &Snapshots,
+) -> Result<i64, Error> {
+    let mut at = snapshots.at;
+    let result = loop {
+        if at.is_none() {
+            // Without a snapshot to take partway through, run the program at full speed.
+            break interpreter.run_to_end(code);
+        }
+        if interpreter.state().done {
+            break Ok(interpreter.state().exit_code.unwrap_or(0));
+        }
+        if let Some(path) = &snapshots.path {
+            if at == Some(interpreter.state().i) {
+                write_file(path.clone(), interpreter.snapshot().to_json())?;
+                at = None;
+                continue;
+            }
+        }
+        if let Err(e) = interpreter.step(code) {
+            break Err(e);
+        }
+    };
+    result.or_else(|e| {
+        if let Some(path) = &snapshots.path {
+            write_file(path.clone(), interpreter.snapshot().to_json())?;
+        }
+        Err(Error::InterpreterError(e))
+    })
+}
+
 /// Write some contents to a file.
-fn write_file(file: String, contents: String) -> Result<(), Error> {
+fn write_file(file: String, contents: impl AsRef<[u8]>) -> Result<(), Error> {
     write(file, contents).map_err(Error::IO)
 }
 
+/// Read the bytecode in the input file. Bytecode isn't text, so
+/// it's read from the file when it's decoded instead of beforehand.
+fn read_bytecode(filename: Option<&str>) -> Result<Vec<u8>, Error> {
+    match filename {
+        Some(filename) => read(filename).map_err(Error::IO),
+        None => Err(Error::InvalidSource(
+            "bytecode must be read from a file".to_string(),
+        )),
+    }
+}
+
 /// Read the contents of a file.
 fn read_file(name: &str) -> Result<String, Error> {
     read_to_string(name).map_err(Error::IO)
@@ -482,17 +1241,92 @@ fn cli() {
 
     builder.init();
 
+    if let Some(Command::Check {
+        input,
+        source_type,
+        target_type,
+        call_stack_size,
+    }) = args.command
+    {
+        // Bytecode is read when it's decoded, since it isn't text.
+        let result = match source_type {
+            SourceType::CoreBytecode | SourceType::StdBytecode => Ok(String::new()),
+            _ => read_file(&input),
+        }
+        .and_then(|contents| {
+            check(
+                Some(&input),
+                contents,
+                source_type,
+                target_type,
+                call_stack_size,
+            )
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{e:#?}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // Without a subcommand, the input file is required.
+    let input = args.input.unwrap_or_default();
+
+    // Bytecode is read when it's decoded, since it isn't text.
+    let contents = match args.source_type {
+        SourceType::CoreBytecode | SourceType::StdBytecode => Ok(String::new()),
+        _ => read_file(&input),
+    };
+
     // Set the directory of the current executable to be that of the file
-    match read_file(&args.input) {
+    match contents {
         Ok(file_contents) => {
             match compile(
-                Some(&args.input),
+                Some(&input),
                 file_contents,
                 args.source_type,
                 args.target_type,
                 args.output,
-                args.call_stack_size,
-                args.debug.is_some(),
+                CompileOptions {
+                    call_stack_size: args.call_stack_size,
+                    opt_level: args.opt_level,
+                    debug: args.debug.is_some(),
+                    limits: Limits {
+                        max_instructions: args.max_instructions,
+                        max_tape_cells: args.max_tape_cells,
+                        max_call_depth: args.max_call_depth,
+                        max_refs_depth: args.max_refs_depth,
+                    },
+                    check_heap: args.check_heap,
+                    profile: args.profile,
+                    snapshots: Snapshots {
+                        path: args.snapshot,
+                        at: args.snapshot_at,
+                        resume: args.resume,
+                    },
+                    c_target: {
+                        let default = targets::C::default();
+                        targets::C {
+                            tape_cells: args.c_tape_cells.unwrap_or(default.tape_cells),
+                            refs_depth: args.c_refs_depth.unwrap_or(default.refs_depth),
+                            register_cells: args.c_register_cells.unwrap_or(default.register_cells),
+                            ffi_channel_cells: args
+                                .c_ffi_channel_cells
+                                .unwrap_or(default.ffi_channel_cells),
+                            max_functions: args.c_max_functions.unwrap_or(default.max_functions),
+                            checked: args.c_checked,
+                            foreign_functions: vec![],
+                        }
+                    },
+                    c_native_ffi: args.c_native_ffi,
+                    lisp_backend: LispBackend {
+                        files: args.lisp_backend,
+                        extension: args.lisp_extension,
+                    },
+                },
             ) {
                 Ok(_) => {}
                 Err(e) => {
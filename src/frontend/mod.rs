@@ -1,6 +1,6 @@
 mod parse;
 use crate::lir::Expr;
-pub use parse::{parse_module, parse_source, get_lisp_env};
+pub use parse::{parse_module, parse_source, get_lisp_env, new_lisp_env};
 
 fn without_comments(code: impl ToString) -> String {
     use no_comment::{languages, IntoWithoutComments};
@@ -49,6 +49,15 @@ pub fn parse(
                 crate::asm::StandardOp::CoreOp(crate::asm::CoreOp::Pop(None, 1)),
             ],
         });
+        let halt = crate::lir::ConstExpr::CoreBuiltin(crate::lir::CoreBuiltin {
+            name: "halt".to_string(),
+            args: vec![("code".to_string(), crate::lir::Type::Int)],
+            ret: crate::lir::Type::Never,
+            body: vec![
+                crate::asm::CoreOp::Halt(crate::asm::SP.deref()),
+                crate::asm::CoreOp::Pop(None, 1),
+            ],
+        });
         use crate::asm::CoreOp::*;
 
         use crate::asm::*;
@@ -190,6 +199,7 @@ pub fn parse(
             vec![
                 ("free", free),
                 ("alloc", alloc),
+                ("halt", halt),
                 // ("realloc_fp_stack", realloc_fp_stack),
                 // ("realloc_stack", realloc_stack),
                 ("debug", debug),
@@ -13,7 +13,10 @@ use std::{
     collections::BTreeMap, sync::{Arc, RwLock}
 };
 
-use crate::{lir::*, parse::SourceCodeLocation};
+use crate::{
+    lir::*,
+    parse::{CoreProgramParser, SourceCodeLocation, StandardProgramParser},
+};
 use nom::{
     character::complete::{alpha1, alphanumeric1},
     combinator::value,
@@ -961,8 +964,12 @@ fn make_env() -> sage_lisp::Env {
                 .unwrap();
 
                 let c_code = match vm_code {
-                    Ok(vm_code) => crate::targets::C.build_core(&vm_code.flatten()).unwrap(),
-                    Err(vm_code) => crate::targets::C.build_std(&vm_code.flatten()).unwrap(),
+                    Ok(vm_code) => crate::targets::C::default()
+                        .build_core(&vm_code.flatten())
+                        .unwrap(),
+                    Err(vm_code) => crate::targets::C::default()
+                        .build_std(&vm_code.flatten())
+                        .unwrap(),
                 };
 
                 Expr::String(c_code)
@@ -1183,6 +1190,12 @@ pub fn get_lisp_env() -> sage_lisp::Env {
     return LISP_ENV.read().unwrap().clone();
 }
 
+/// A fresh Lisp environment with only the builtins, and none of the
+/// definitions from the attributes of the programs parsed so far.
+pub fn new_lisp_env() -> sage_lisp::Env {
+    make_env()
+}
+
 fn save_source_code_setup() {
     let mut saves = FILE_SAVES.write().unwrap();
 
@@ -1929,6 +1942,7 @@ fn parse_long_stmt<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
         context("match", parse_match_stmt),
         context("while", parse_while_stmt),
         context("for", parse_for_stmt),
+        context("function", parse_asm_fun_stmt),
         context("function", parse_quick_fun_stmt),
         context("function", parse_fun_stmt),
         context("impl", parse_impl_stmt),
@@ -2094,6 +2108,7 @@ fn parse_decl<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
 ) -> IResult<&'a str, Declaration, E> {
     let (input, _) = whitespace(input)?;
     let (input, decl) = alt((
+        context("function", parse_asm_fun_stmt),
         context("function", parse_fun_stmt),
         context("type", parse_type_stmt),
         context("enum", parse_enum_stmt),
@@ -2373,6 +2388,99 @@ fn parse_quick_fun_stmt<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     }
 }
 
+/// Take the body of an assembly function, up to the brace which closes it.
+/// Braces in string and character literals are skipped, and any others
+/// must be balanced.
+fn parse_asm_body<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str, &'a str, E> {
+    let mut depth = 0;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                // Skip to the end of the literal, past any escaped quotes.
+                while let Some((_, next)) = chars.next() {
+                    if next == '\\' {
+                        chars.next();
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            '{' => depth += 1,
+            '}' if depth == 0 => return Ok((&input[i..], &input[..i])),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    Ok(("", input))
+}
+
+/// Parse a function whose body is written in core or standard assembly, like
+/// `fun get_sp(): &Cell = core { push SP }`. The arguments are pushed to the
+/// stack, and the body must pop them and push the return value in their place.
+fn parse_asm_fun_stmt<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    input: &'a str,
+) -> IResult<&'a str, Statement, E> {
+    let (input, _) = tag("fun")(input)?;
+    trace!("Parsing assembly function");
+    let (input, _) = whitespace(input)?;
+    let (input, name) = parse_symbol(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, (params, ret)) = parse_fun_params(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("=")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, variant) = alt((tag("core"), tag("std")))(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("{")(input)?;
+    let (input, code) = parse_asm_body(input)?;
+    let (input, _) = cut(tag("}"))(input)?;
+
+    let args = params
+        .into_iter()
+        .map(|(name, _mutability, ty)| (name, ty))
+        .collect();
+    let body = match variant {
+        "core" => CoreProgramParser::new().parse(code).map(|prog| {
+            ConstExpr::CoreBuiltin(CoreBuiltin {
+                name: name.to_owned(),
+                args,
+                ret,
+                body: prog.code,
+            })
+        }),
+        _ => StandardProgramParser::new().parse(code).map(|prog| {
+            ConstExpr::StandardBuiltin(StandardBuiltin {
+                name: name.to_owned(),
+                args,
+                ret,
+                body: prog.code,
+            })
+        }),
+    };
+    let body = body.map_err(|e| {
+        // Point the error at the instruction which couldn't be parsed.
+        use lalrpop_util::ParseError::*;
+        let offset = match e {
+            InvalidToken { location } | UnrecognizedEof { location, .. } => location,
+            UnrecognizedToken { token, .. } | ExtraToken { token } => token.0,
+            User { .. } => 0,
+        };
+        let rest = &code[offset.min(code.len())..];
+        nom::Err::Failure(E::add_context(
+            rest,
+            "assembly instruction",
+            E::from_error_kind(rest, ErrorKind::Verify),
+        ))
+    })?;
+    trace!("Parsed assembly function `{name}`");
+
+    Ok((
+        input,
+        Statement::Declaration(Declaration::Const(name.to_owned(), body), None),
+    ))
+}
+
 fn parse_fun_params<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     input: &'a str,
 ) -> IResult<&'a str, (Vec<(String, Mutability, Type)>, Type), E> {
@@ -4339,11 +4447,15 @@ mod tests {
 
                     let device = match vm_code {
                         Ok(vm_code) => {
-                            CoreInterpreter::new(TestingDevice::new(input)).run(&vm_code)?
+                            CoreInterpreter::new(TestingDevice::new(input))
+                                .run(&vm_code)
+                                .map_err(|e| e.to_string())?
                         }
                         // .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
                         Err(vm_code) => {
-                            StandardInterpreter::new(TestingDevice::new(input)).run(&vm_code)?
+                            StandardInterpreter::new(TestingDevice::new(input))
+                                .run(&vm_code)
+                                .map_err(|e| e.to_string())?
                         } // .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
                     };
 
@@ -90,9 +90,17 @@ impl Compile for Expr {
         // Compile the expression.
         match self {
             Self::Annotated(expr, metadata) => {
+                // Mark the compiled code with the expression's location in the source code.
+                let location = metadata.location().cloned();
+                if let Some(location) = &location {
+                    output.begin_location(location);
+                }
                 // Compile the expression.
                 expr.compile_expr(env, output)
                     .map_err(|e| e.annotate(metadata))?;
+                if location.is_some() {
+                    output.end_source();
+                }
             }
 
             Self::Match(expr, branches) => {
@@ -1142,8 +1150,15 @@ impl Compile for ConstExpr {
                 }
             }
             Self::Annotated(expr, metadata) => {
+                let location = metadata.location().cloned();
+                if let Some(location) = &location {
+                    output.begin_location(location);
+                }
                 expr.compile_expr(env, output)
                     .map_err(|err| err.annotate(metadata))?;
+                if location.is_some() {
+                    output.end_source();
+                }
             }
             Self::Declare(bindings, body) => {
                 debug!("Compiling declaration {bindings} with body {body} in environment {env}");
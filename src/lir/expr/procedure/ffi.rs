@@ -6,7 +6,7 @@
 //! size of the cells for the arguments and return value.
 use crate::asm::{AssemblyProgram, StandardOp};
 use crate::lir::{Compile, Env, Error, GetSize, GetType, Type, TypeCheck};
-use crate::side_effects::FFIBinding;
+use crate::side_effects::{FFIBinding, FFISignature, FFIType};
 use core::fmt::{Display, Formatter, Result as FmtResult};
 use serde_derive::{Deserialize, Serialize};
 
@@ -31,6 +31,35 @@ impl FFIProcedure {
     pub fn new(name: String, args: Vec<Type>, ret: Type) -> Self {
         Self { name, args, ret }
     }
+
+    /// Get the typed signature of the foreign function, if all of its
+    /// arguments and its return value can be passed to native code.
+    fn signature(&self, env: &Env) -> Option<FFISignature> {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| ffi_type(arg, env))
+            .collect::<Option<Vec<_>>>()?;
+        let ret = match self.ret.simplify_until_concrete(env, false).ok()? {
+            Type::None | Type::Never => None,
+            ret => Some(ffi_type(&ret, env)?),
+        };
+        Some(FFISignature::new(self.name.clone(), args, ret))
+    }
+}
+
+/// Get the native type a value of the given type is passed as, if it has one.
+fn ffi_type(ty: &Type, env: &Env) -> Option<FFIType> {
+    match ty.simplify_until_concrete(env, false).ok()? {
+        Type::Int | Type::Enum(_) => Some(FFIType::Int),
+        Type::Float => Some(FFIType::Float),
+        Type::Char => Some(FFIType::Char),
+        Type::Bool => Some(FFIType::Bool),
+        Type::Cell => Some(FFIType::Cell),
+        Type::Pointer(..) => Some(FFIType::Pointer),
+        Type::Unit(_, ty) => ffi_type(&ty, env),
+        _ => None,
+    }
 }
 
 impl TypeCheck for FFIProcedure {
@@ -64,6 +93,9 @@ impl Compile for FFIProcedure {
         }
         let ret_size = self.ret.get_size(env)?;
 
+        if let Some(signature) = self.signature(env) {
+            output.declare_foreign_function(signature);
+        }
         output.std_op(StandardOp::Call(FFIBinding::new(
             self.name, args_size, ret_size,
         )))?;
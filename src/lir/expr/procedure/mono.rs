@@ -233,6 +233,8 @@ impl Compile for Procedure {
         }
         let current_instruction = output.current_instruction();
 
+        // Mark the body with the name of the procedure for stack traces.
+        output.begin_function(self.common_name.as_deref().unwrap_or("<anonymous>"));
         // Execute the body to leave the return value
         self.body.compile_expr(&mut new_env, output)?;
 
@@ -246,6 +248,7 @@ impl Compile for Procedure {
         // arguments and return value, to leave the return value on the stack.
         output.op(CoreOp::Pop(None, args_size));
         // End the function body
+        output.end_source();
         output.op(CoreOp::End);
 
         output.comment(format!("push {} onto the stack", self.mangled_name));
@@ -30,13 +30,8 @@ impl FFIBinding {
     }
 }
 
+/// Display the binding the way it's written in assembly and virtual machine code.
 impl Display for FFIBinding {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        write!(f, "{}", self.name)
-    }
-}
-
-impl Debug for FFIBinding {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(
             f,
@@ -45,3 +40,66 @@ impl Debug for FFIBinding {
         )
     }
 }
+
+impl Debug for FFIBinding {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{self}")
+    }
+}
+
+/// The type of an argument or return value of a foreign function, as
+/// it's passed to native code. Each of these fits in a single cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum FFIType {
+    /// A signed 64-bit integer. Enums are passed as their integer value.
+    Int,
+    /// A double precision float.
+    Float,
+    /// A character.
+    Char,
+    /// A boolean.
+    Bool,
+    /// A pointer to memory.
+    Pointer,
+    /// A raw cell.
+    Cell,
+}
+
+/// The typed signature of a foreign function, which lets a target call
+/// a native function directly instead of through the FFI channel.
+///
+/// Only foreign functions whose arguments and return value each fit in
+/// a single cell have a signature.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FFISignature {
+    /// The symbol for the foreign function.
+    pub name: String,
+    /// The types of the arguments, in the order they're pushed to the FFI channel.
+    pub args: Vec<FFIType>,
+    /// The return type, if the function returns a value.
+    pub ret: Option<FFIType>,
+}
+
+impl FFISignature {
+    /// Create a new FFI signature.
+    pub fn new(name: String, args: Vec<FFIType>, ret: Option<FFIType>) -> Self {
+        Self { name, args, ret }
+    }
+}
+
+impl Display for FFISignature {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}(", self.name)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:?}", arg)?;
+        }
+        write!(f, ")")?;
+        if let Some(ret) = &self.ret {
+            write!(f, " -> {:?}", ret)?;
+        }
+        Ok(())
+    }
+}
@@ -3,19 +3,68 @@
 //! An implementation of the virtual machine for the C language.
 //!
 //! This allows the virtual machine to target C programs.
-use super::{Architecture, CompiledTarget};
+use super::{Architecture, CompiledTarget, SPARE_CELLS_PER_BLOCK};
 use crate::{
-    side_effects::{Input, InputMode, Output, OutputMode},
+    side_effects::{FFISignature, FFIType, Input, InputMode, Output, OutputMode},
     vm::{CoreOp, StandardOp},
 };
 use log::warn;
 
 /// The type for the C target which implements the `Target` trait.
 /// This allows the compiler to target the C language.
-#[derive(Default)]
-pub struct C;
+///
+/// The target is configured with the sizes of the statically allocated
+/// memory of the generated program. Programs which use more than they're
+/// given corrupt their memory, unless the target is `checked`: then, the
+/// program aborts with a message naming the memory it ran out of.
+///
+/// Foreign functions are called through the FFI channel: the arguments are
+/// pushed to the channel in order, and then a shim is called which pops the
+/// arguments off the channel and pushes the return value. The shims are named
+/// `__name()`, and must be written by hand in an `ffi.h` header, which is
+/// included if it exists. For each of the `foreign_functions` (none by default),
+/// the target instead generates a shim `sage_ffi_name()` which calls the native
+/// function `name`, and declares `name` with the C types of its signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct C {
+    /// The number of cells on the turing tape, including the call stack at its start.
+    pub tape_cells: usize,
+    /// The maximum depth of the stack of dereferences.
+    pub refs_depth: usize,
+    /// The number of cells in the vector register.
+    pub register_cells: usize,
+    /// The number of cells in the channel for foreign function calls.
+    pub ffi_channel_cells: usize,
+    /// The number of entries in the table of functions.
+    pub max_functions: usize,
+    /// Check for overflows of the tape, the stack of dereferences,
+    /// the FFI channel, and the table of functions.
+    pub checked: bool,
+    /// The typed signatures of the foreign functions to bind to native functions.
+    pub foreign_functions: Vec<FFISignature>,
+}
+
+impl Default for C {
+    fn default() -> Self {
+        Self {
+            tape_cells: 67108864,
+            refs_depth: 1024,
+            register_cells: 1024,
+            ffi_channel_cells: 256,
+            max_functions: 10000,
+            checked: false,
+            foreign_functions: vec![],
+        }
+    }
+}
 
 impl Architecture for C {
+    fn reserve_call_stack(&mut self, cells: usize) {
+        // The call stack is carved out of the tape, which is allocated statically,
+        // so only grow the tape if it can't hold the call stack at all.
+        self.tape_cells = self.tape_cells.max(cells);
+    }
+
     fn supports_input(&self, i: &Input) -> bool {
         matches!(
             i.mode,
@@ -26,12 +75,17 @@ impl Architecture for C {
     fn supports_output(&self, o: &Output) -> bool {
         matches!(
             o.mode,
-            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+            OutputMode::StdoutChar
+                | OutputMode::StdoutFloat
+                | OutputMode::StdoutInt
+                | OutputMode::StderrChar
+                | OutputMode::StderrFloat
+                | OutputMode::StderrInt
         )
     }
 
     fn op(&mut self, op: &CoreOp) -> String {
-        match op {
+        let code = match op {
             CoreOp::Comment(text) => {
                 format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
                 // let mut comment = String::new();
@@ -56,26 +110,34 @@ impl Architecture for C {
                 tmp
                 // format!("scalar_reg.i = {};", n)
             }
+            CoreOp::Call if self.checked => "sage_call(scalar_reg.i);".to_string(),
             CoreOp::Call => "funs[scalar_reg.i]();".to_string(),
             CoreOp::Return => "return;".to_string(),
+            CoreOp::Halt => "exit(scalar_reg.i);".to_string(),
             CoreOp::Store(1) => "*ptr = scalar_reg;".to_string(),
             CoreOp::Load(1) => "scalar_reg = *ptr; vector_reg[0] = scalar_reg;".to_string(),
             CoreOp::Store(n) => {
-                if *n > 1024 {
-                    warn!("Store with n > 1024 not supported by C target");
+                if *n > self.register_cells {
+                    warn!("Store with n > {} not supported by C target", self.register_cells);
                 }
                 format!("vector_reg[0] = scalar_reg; memcpy(ptr, vector_reg, {n} * sizeof(cell));")
             }
             CoreOp::Load(n) => {
-                if *n > 1024 {
-                    warn!("Load with n > 1024 not supported by C target");
+                if *n > self.register_cells {
+                    warn!("Load with n > {} not supported by C target", self.register_cells);
                 }
                 format!("memcpy(vector_reg, ptr, {n} * sizeof(cell)); scalar_reg = ptr[0];")
             }
 
             CoreOp::Move(n) => format!("ptr += {};", n),
             CoreOp::Where => "scalar_reg.p = ptr;".to_string(),
+            CoreOp::Deref if self.checked => {
+                "if (ref_ptr >= SAGE_REFS_DEPTH) sage_overflow(\"ref stack\"); refs[ref_ptr++] = ptr; ptr = ptr->p; sage_ptr_on_tape = sage_on_tape(ptr);".to_string()
+            }
             CoreOp::Deref => "refs[ref_ptr++] = ptr; ptr = ptr->p;".to_string(),
+            CoreOp::Refer if self.checked => {
+                "ptr = refs[--ref_ptr]; sage_ptr_on_tape = sage_on_tape(ptr);".to_string()
+            }
             CoreOp::Refer => "ptr = refs[--ref_ptr];".to_string(),
             CoreOp::Offset(n, 1) => format!("scalar_reg.p += {};", n),
             CoreOp::Offset(n, size) => format!("for (int i = 0; i < {size}; i++) vector_reg[i].p += {n};"),
@@ -153,11 +215,15 @@ impl Architecture for C {
             CoreOp::End | CoreOp::Function | CoreOp::Put(_) | CoreOp::Get(_) => {
                 unreachable!("Invalid core op for C target")
             }
-        }
+        };
+        format!("{}{code}", self.check_tape(op.tape_access()))
     }
 
     fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
-        Ok(match op {
+        let code = match op {
+            StandardOp::Call(ffi) if self.is_bound(&ffi.name) => {
+                format!("sage_ffi_{}();", ffi.name)
+            }
             StandardOp::Call(ffi) => format!("__{}();", ffi.name),
             StandardOp::Peek => self.peek()?,
             StandardOp::Poke => self.poke()?,
@@ -244,12 +310,13 @@ impl Architecture for C {
                 format!("for (int i = 0; i < {n}; i++) vector_reg[i].i = vector_reg[i].f >= 0;")
             }
 
-            StandardOp::Alloc => {
-                "scalar_reg.p = (cell*)malloc(scalar_reg.i * sizeof(cell));".to_string()
-            }
+            StandardOp::Alloc => format!(
+                "scalar_reg.p = (cell*)malloc((scalar_reg.i + {SPARE_CELLS_PER_BLOCK}) * sizeof(cell));"
+            ),
             StandardOp::Free => "free(scalar_reg.p);".to_string(),
             _ => return Err(format!("Invalid standard op for C target {op:?}")),
-        })
+        };
+        Ok(format!("{}{code}", self.check_tape(op.tape_access())))
     }
 
     fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
@@ -275,17 +342,16 @@ impl Architecture for C {
     }
 
     fn get(&mut self, src: &Input) -> Result<String, String> {
-        let ch = src.channel.0;
         match src.mode {
-            InputMode::StdinChar => Ok("tmp = getchar(); scalar_reg.i = tmp == EOF? 0 : tmp;".to_string()),
-            InputMode::StdinInt => Ok("scanf(\"%ld\", &tmp_scalar_reg.i); scalar_reg = tmp_scalar_reg;".to_string()),
-            InputMode::StdinFloat => Ok("scanf(\"%lf\", &tmp_scalar_reg.f); scalar_reg = tmp_scalar_reg;".to_string()),
-            InputMode::Thermometer => Ok("scalar_reg.f = 293.15;".to_string()),
-            InputMode::Clock => Ok("scalar_reg.i = time(NULL);".to_string()),
-            InputMode::Random => Ok("scalar_reg.i = rand();".to_string()),
-            InputMode::Button => Ok(format!(
-                "printf(\"Button #{ch}: \"); scalar_reg.i = getchar() == 'y'; while (getchar() != 10);"
-            )),
+            InputMode::StdinChar => {
+                Ok("tmp = getchar(); scalar_reg.i = tmp == EOF? 0 : tmp;".to_string())
+            }
+            InputMode::StdinInt => {
+                Ok("scanf(\"%ld\", &tmp_scalar_reg.i); scalar_reg = tmp_scalar_reg;".to_string())
+            }
+            InputMode::StdinFloat => {
+                Ok("scanf(\"%lf\", &tmp_scalar_reg.f); scalar_reg = tmp_scalar_reg;".to_string())
+            }
             _ => Err("Input not supported by this target".to_string()),
         }
     }
@@ -298,8 +364,6 @@ impl Architecture for C {
             OutputMode::StderrChar => Ok("fprintf(stderr, \"%c\", scalar_reg.i);".to_string()),
             OutputMode::StderrInt => Ok("fprintf(stderr, \"%lld\", scalar_reg.i);".to_string()),
             OutputMode::StderrFloat => Ok("fprintf(stderr, \"%.1lf\", scalar_reg.f);".to_string()),
-            OutputMode::Heater => Ok("printf(\"Heating...\");".to_string()),
-            OutputMode::Cooler => Ok("printf(\"Cooling...\");".to_string()),
             _ => Err("Output not supported by this target".to_string()),
         }
     }
@@ -307,43 +371,100 @@ impl Architecture for C {
         Ok("scalar_reg = *(ffi_ptr--); vector_reg[0] = scalar_reg;".to_string())
     }
     fn poke(&mut self) -> Result<String, String> {
-        Ok("*(++ffi_ptr) = scalar_reg;".to_string())
+        Ok(if self.checked {
+            "if (ffi_ptr + 1 >= ffi_channel + SAGE_FFI_CHANNEL_CELLS) sage_overflow(\"FFI channel\"); *(++ffi_ptr) = scalar_reg;"
+        } else {
+            "*(++ffi_ptr) = scalar_reg;"
+        }
+        .to_string())
     }
-    fn prelude(&self, is_core: bool) -> Option<String> {
-        let mut result = r#"#include <stdint.h>
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        let mut result = format!(
+            r#"#include <stdint.h>
+#include <stdbool.h>
+#include <stdlib.h>
 #include <stdio.h>
 #include <math.h>
 #include <string.h>
 
-typedef union cell {
+typedef union cell {{
     int64_t i;
     double f;
     union cell *p;
-} cell;
+}} cell;
+
+#define SAGE_TAPE_CELLS {tape_cells}
+#define SAGE_REFS_DEPTH {refs_depth}
+#define SAGE_REGISTER_CELLS {register_cells}
+#define SAGE_FFI_CHANNEL_CELLS {ffi_channel_cells}
+#define SAGE_MAX_FUNCTIONS {max_functions}
 
-cell tape[67108864], *refs[1024], *ptr = tape, **ref = refs, scalar_reg, vector_reg[1024], tmp_reg, ffi_channel[256], *ffi_ptr = ffi_channel;
+cell tape[SAGE_TAPE_CELLS], *refs[SAGE_REFS_DEPTH], *ptr = tape, **ref = refs, scalar_reg, vector_reg[SAGE_REGISTER_CELLS], tmp_reg, ffi_channel[SAGE_FFI_CHANNEL_CELLS], *ffi_ptr = ffi_channel;
 
 unsigned int ref_ptr = 0;
-void (*funs[10000])(void);
+void (*funs[SAGE_MAX_FUNCTIONS])(void);
+"#,
+            tape_cells = self.tape_cells,
+            refs_depth = self.refs_depth,
+            register_cells = self.register_cells,
+            ffi_channel_cells = self.ffi_channel_cells,
+            max_functions = self.max_functions,
+        );
+
+        if self.checked {
+            result += r#"
+void sage_overflow(const char *memory) {
+    fflush(stdout);
+    fprintf(stderr, "\nerror: %s overflow\n", memory);
+    abort();
+}
+
+// Is the address on the tape (or just past its end), instead of on the heap?
+bool sage_on_tape(cell *p) {
+    return p >= tape && p <= tape + SAGE_TAPE_CELLS;
+}
+
+// Does the pointer point into the tape? It starts on the tape, and moving it
+// (even past the ends) doesn't change that. Addresses loaded by `Deref` and
+// `Refer` point into the heap unless they're on the tape.
+bool sage_ptr_on_tape = true;
+
+// Check that the `n` cells starting at the pointer are on the tape. This is
+// done when the cells are used, so the pointer may move off the tape and back.
+// Pointers to memory allocated on the heap aren't checked.
+void sage_check_tape(int64_t n) {
+    if (sage_ptr_on_tape && (ptr < tape || n > tape + SAGE_TAPE_CELLS - ptr)) sage_overflow("tape");
+}
 
+void sage_call(int64_t n) {
+    if (n < 0 || n >= SAGE_MAX_FUNCTIONS || !funs[n]) sage_overflow("function table");
+    funs[n]();
+}
+"#;
+        }
+
+        result += r#"
 #if __has_include("ffi.h")
 #include "ffi.h"
 #endif
-
-int tmp;
-"#
-        .to_string();
-
-        if !is_core {
-            result = "#include <stdlib.h>\n".to_string() + &result;
+"#;
+        for signature in &self.foreign_functions {
+            result += &self.ffi_shim(signature);
         }
+        result += "\nint tmp;\n";
 
         Some(result)
     }
 
     fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
         let mut result = String::from("int main () {\n");
+        if self.checked && funs.len() > self.max_functions {
+            result += "\tsage_overflow(\"function table\");\n";
+        }
         for fun in funs {
+            if self.checked && fun as usize >= self.max_functions {
+                continue;
+            }
             result += &format!("\tfuns[{fun}] = f{fun};\n", fun = fun)
         }
         Some(result)
@@ -358,4 +479,87 @@ int tmp;
     }
 }
 
+impl C {
+    /// Is the foreign function with this name bound to a native function?
+    fn is_bound(&self, name: &str) -> bool {
+        self.foreign_functions.iter().any(|f| f.name == name)
+    }
+
+    /// Declare a foreign function, and define the shim which calls it with the
+    /// arguments on the FFI channel. If `ffi.h` defines `SAGE_FFI_<name>`, the
+    /// shim calls the hand-written `__<name>()` instead.
+    fn ffi_shim(&self, signature: &FFISignature) -> String {
+        let name = &signature.name;
+        let n = signature.args.len();
+        let ret = signature.ret.map(c_type).unwrap_or("void");
+        let params = if n == 0 {
+            "void".to_string()
+        } else {
+            signature
+                .args
+                .iter()
+                .map(|&ty| c_type(ty))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        // After popping the arguments, the Nth argument is `N + 1` cells above the pointer.
+        let args = signature
+            .args
+            .iter()
+            .enumerate()
+            .map(|(i, &ty)| match ty {
+                FFIType::Cell => format!("ffi_ptr[{}]", i + 1),
+                FFIType::Float => format!("ffi_ptr[{}].f", i + 1),
+                FFIType::Pointer => format!("ffi_ptr[{}].p", i + 1),
+                FFIType::Int | FFIType::Char | FFIType::Bool => format!("ffi_ptr[{}].i", i + 1),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let call = format!("{name}({args})");
+
+        let mut body = format!("\tffi_ptr -= {n};\n");
+        match signature.ret {
+            None => body += &format!("\t{call};\n"),
+            Some(ty) => {
+                // The arguments are read before the return value is pushed over them.
+                body += &format!("\t{ret} result = {call};\n");
+                if self.checked {
+                    body += "\tif (ffi_ptr + 1 >= ffi_channel + SAGE_FFI_CHANNEL_CELLS) sage_overflow(\"FFI channel\");\n";
+                }
+                body += match ty {
+                    FFIType::Cell => "\t*(++ffi_ptr) = result;\n",
+                    FFIType::Float => "\t(++ffi_ptr)->f = result;\n",
+                    FFIType::Pointer => "\t(++ffi_ptr)->p = result;\n",
+                    FFIType::Int | FFIType::Char | FFIType::Bool => "\t(++ffi_ptr)->i = result;\n",
+                };
+            }
+        }
+
+        format!(
+            "\n#ifdef SAGE_FFI_{name}\nvoid sage_ffi_{name}(void) {{ __{name}(); }}\n#else\nextern {ret} {name}({params});\nvoid sage_ffi_{name}(void) {{\n{body}}}\n#endif\n"
+        )
+    }
+
+    /// Check that an instruction accessing `n` cells stays on the tape, if the target is checked.
+    fn check_tape(&self, n: usize) -> String {
+        if self.checked && n > 0 {
+            format!("sage_check_tape({n}); ")
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// The C type of a value passed to or returned from a foreign function.
+fn c_type(ty: FFIType) -> &'static str {
+    match ty {
+        FFIType::Int => "int64_t",
+        FFIType::Float => "double",
+        FFIType::Char => "char",
+        FFIType::Bool => "bool",
+        FFIType::Pointer => "void *",
+        FFIType::Cell => "cell",
+    }
+}
+
 impl CompiledTarget for C {}
@@ -0,0 +1,185 @@
+//! # Compatibility Module
+//!
+//! This module checks whether a target can build a program before building it.
+//!
+//! Building a program stops at the first instruction the target can't handle,
+//! without saying where it came from in the source code. Checking a program
+//! instead walks all of it, and reports every input and output the target
+//! doesn't support (even with the fallbacks from the `fallback` module),
+//! every floating point instruction on a target without floats, and every
+//! foreign function the target cannot provide. When the compiler recorded
+//! a source map for the program, each problem is reported with the source
+//! code it was compiled from. The interpreter can be checked too, for the
+//! foreign functions its device has no bindings for.
+
+use super::{input_fallback, output_fallback, Architecture};
+use crate::{
+    side_effects::{FFIBinding, Input, Output},
+    vm::{CoreOp, SourceFrame, SourceMap, StandardDevice, StandardOp, StandardProgram},
+};
+use core::fmt;
+
+/// Something in a program that a target cannot build.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Incompatibility {
+    /// An input the target doesn't support, and can't fall back from.
+    Input(Input),
+    /// An output the target doesn't support, and can't fall back from.
+    Output(Output),
+    /// A floating point instruction on a target without floats.
+    Float(StandardOp),
+    /// A foreign function the target cannot provide.
+    Ffi(FFIBinding),
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Input(src) => write!(f, "input `{src}` is not supported"),
+            Self::Output(dst) => write!(f, "output `{dst}` is not supported"),
+            Self::Float(op) => write!(f, "floating point instruction `{op}` is not supported"),
+            Self::Ffi(binding) => {
+                write!(f, "foreign function `{}` cannot be provided", binding.name)
+            }
+        }
+    }
+}
+
+/// An incompatibility, and where it is in the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompatibilityIssue {
+    /// The index of the first instruction with the problem.
+    pub instruction: usize,
+    /// The source code the instruction was compiled from, if it's known.
+    pub frame: Option<SourceFrame>,
+    /// What the target cannot build.
+    pub incompatibility: Incompatibility,
+}
+
+impl fmt::Display for CompatibilityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.incompatibility)?;
+        match &self.frame {
+            Some(frame) => write!(f, " at {frame}"),
+            None => write!(f, " at instruction #{}", self.instruction),
+        }
+    }
+}
+
+/// All of the problems a target has with building a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompatibilityReport {
+    /// The name of the target.
+    pub target: String,
+    /// The problems, in the order they appear in the program.
+    pub issues: Vec<CompatibilityIssue>,
+}
+
+impl CompatibilityReport {
+    /// Can the target build the program?
+    pub fn is_compatible(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for CompatibilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_compatible() {
+            return write!(f, "The program is compatible with target {}", self.target);
+        }
+        write!(
+            f,
+            "Found {} {} with target {}:",
+            self.issues.len(),
+            if self.issues.len() == 1 {
+                "incompatibility"
+            } else {
+                "incompatibilities"
+            },
+            self.target
+        )?;
+        for issue in &self.issues {
+            write!(f, "\n  {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check whether a target can build a program (flattened, to line up with its source map).
+/// A core program can be checked by converting it into a standard program first.
+///
+/// A problem is only reported once for each piece of source code it was compiled from.
+pub fn check_compatibility<A: Architecture + ?Sized>(
+    target: &A,
+    program: &StandardProgram,
+    source_map: &SourceMap,
+) -> CompatibilityReport {
+    report(target.name(), program, source_map, |op| {
+        Some(match op {
+            StandardOp::CoreOp(CoreOp::Get(src))
+                if !target.supports_input(src) && input_fallback(target, src).is_none() =>
+            {
+                Incompatibility::Input(src.clone())
+            }
+            StandardOp::CoreOp(CoreOp::Put(dst))
+                if !target.supports_output(dst) && output_fallback(target, dst).is_none() =>
+            {
+                Incompatibility::Output(dst.clone())
+            }
+            StandardOp::Call(binding) if !target.supports_ffi(binding) => {
+                Incompatibility::Ffi(binding.clone())
+            }
+            op if op.is_float() && !target.supports_floats() => Incompatibility::Float(op.clone()),
+            _ => return None,
+        })
+    })
+}
+
+/// Check whether the interpreter can run a program with the given device. The
+/// interpreter can run any instruction, but it can only call the foreign
+/// functions the device has bindings for.
+pub fn check_interpreter_compatibility(
+    device: &StandardDevice,
+    program: &StandardProgram,
+    source_map: &SourceMap,
+) -> CompatibilityReport {
+    report("interpreter", program, source_map, |op| match op {
+        StandardOp::Call(binding) if !device.supports_ffi(binding) => {
+            Some(Incompatibility::Ffi(binding.clone()))
+        }
+        _ => None,
+    })
+}
+
+/// Report the incompatibility of each instruction in a program, if it has one.
+fn report(
+    target: &str,
+    program: &StandardProgram,
+    source_map: &SourceMap,
+    incompatibility: impl Fn(&StandardOp) -> Option<Incompatibility>,
+) -> CompatibilityReport {
+    let mut issues: Vec<CompatibilityIssue> = vec![];
+    for (instruction, op) in program.0.iter().enumerate() {
+        let Some(incompatibility) = incompatibility(op) else {
+            continue;
+        };
+
+        let frame = source_map.get(instruction).cloned();
+        if frame.is_none()
+            || !issues
+                .iter()
+                .any(|issue| issue.incompatibility == incompatibility && issue.frame == frame)
+        {
+            issues.push(CompatibilityIssue {
+                instruction,
+                frame,
+                incompatibility,
+            });
+        }
+    }
+
+    CompatibilityReport {
+        target: target.to_string(),
+        issues,
+    }
+}
@@ -0,0 +1,187 @@
+//! # Fallback Module
+//!
+//! This module implements the "catch all" lowering of I/O for targets.
+//! Before a target builds a program, every `Get` and `Put` whose mode the
+//! target does not support is rewritten into a mode it does support, if
+//! there is a sensible substitute.
+//!
+//! For example, a target without a display can still draw characters and
+//! pixels by writing them to standard output, a target without sensors can
+//! read their measurements from standard input, and a target without any
+//! actuators can log the values written to them on standard error (or on
+//! standard output, if the target doesn't have standard error either).
+//!
+//! Rewriting an operation never changes the register or the tape, so a
+//! program behaves the same way apart from where its I/O goes. Operations
+//! without a supported substitute are left alone, so the target still
+//! reports them as unsupported.
+
+use super::Architecture;
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, CoreProgram, StandardOp, StandardProgram},
+};
+use log::warn;
+use std::collections::BTreeSet;
+
+/// The substitutes for an input, in order of preference.
+pub fn input_fallbacks(src: &Input) -> Vec<Input> {
+    match src.mode {
+        // Standard input is the last resort, so it has nothing to fall back on.
+        InputMode::StdinChar | InputMode::StdinInt | InputMode::StdinFloat => vec![],
+        // A custom input can only be understood by its own device.
+        InputMode::Custom(_) => vec![],
+
+        // Typed characters are read from standard input.
+        InputMode::Keyboard => vec![Input::stdin_char()],
+        // Discrete user and electrical inputs are read as integers.
+        InputMode::Random
+        | InputMode::DPad(_)
+        | InputMode::Button
+        | InputMode::JoyStick(_)
+        | InputMode::DigitalPin => vec![Input::stdin_int()],
+        // Every other input is a measurement, which is read as a float.
+        _ => vec![Input::stdin_float()],
+    }
+}
+
+/// The substitutes for an output, in order of preference.
+pub fn output_fallbacks(dst: &Output) -> Vec<Output> {
+    match dst.mode {
+        // Standard output and error have nothing to fall back on.
+        OutputMode::StdoutChar
+        | OutputMode::StdoutInt
+        | OutputMode::StdoutFloat
+        | OutputMode::StderrChar
+        | OutputMode::StderrInt
+        | OutputMode::StderrFloat => vec![],
+        // A custom output can only be understood by its own device.
+        OutputMode::Custom(_) => vec![],
+
+        // Printed and displayed text goes to standard output.
+        OutputMode::PrinterChar => vec![Output::stdout_char()],
+        OutputMode::PrinterInt => vec![Output::stdout_int()],
+        OutputMode::PrinterFloat => vec![Output::stdout_float()],
+        // Pixels are drawn as text, one character per pixel.
+        OutputMode::SetCursorChar(_) | OutputMode::SetCursorPixel(_) => {
+            vec![Output::stdout_char()]
+        }
+
+        // Switches and display commands are logged as integers.
+        OutputMode::DigitalPin
+        | OutputMode::Solenoid
+        | OutputMode::Valve
+        | OutputMode::Heater
+        | OutputMode::Cooler
+        | OutputMode::UpdateDisplay
+        | OutputMode::ClearDisplay
+        | OutputMode::SetCursorRow
+        | OutputMode::SetCursorColumn
+        | OutputMode::MoveCursorUp
+        | OutputMode::MoveCursorDown
+        | OutputMode::MoveCursorLeft
+        | OutputMode::MoveCursorRight => vec![Output::stderr_int(), Output::stdout_int()],
+        // Every other output drives an actuator, and is logged as a float.
+        _ => vec![Output::stderr_float(), Output::stdout_float()],
+    }
+}
+
+/// The first substitute for an input which the target supports, if the target
+/// doesn't support the input itself.
+pub fn input_fallback<A: Architecture + ?Sized>(target: &A, src: &Input) -> Option<Input> {
+    if target.supports_input(src) {
+        return None;
+    }
+    input_fallbacks(src)
+        .into_iter()
+        .find(|i| target.supports_input(i))
+}
+
+/// The first substitute for an output which the target supports, if the target
+/// doesn't support the output itself.
+pub fn output_fallback<A: Architecture + ?Sized>(target: &A, dst: &Output) -> Option<Output> {
+    if target.supports_output(dst) {
+        return None;
+    }
+    output_fallbacks(dst)
+        .into_iter()
+        .find(|o| target.supports_output(o))
+}
+
+/// Rewrite the I/O of a core program that the target does not support.
+pub fn lower_core_io<A: Architecture + ?Sized>(target: &A, program: &CoreProgram) -> CoreProgram {
+    let mut lowering = Lowering::new(target);
+    CoreProgram(program.0.iter().map(|op| lowering.op(op)).collect())
+}
+
+/// Rewrite the I/O of a standard program that the target does not support.
+pub fn lower_std_io<A: Architecture + ?Sized>(
+    target: &A,
+    program: &StandardProgram,
+) -> StandardProgram {
+    let mut lowering = Lowering::new(target);
+    StandardProgram(
+        program
+            .0
+            .iter()
+            .map(|op| match op {
+                StandardOp::CoreOp(op) => StandardOp::CoreOp(lowering.op(op)),
+                other => other.clone(),
+            })
+            .collect(),
+    )
+}
+
+/// The state of lowering a single program's I/O for a target.
+struct Lowering<'a, A: Architecture + ?Sized> {
+    target: &'a A,
+    /// The input modes which have already been reported as rewritten.
+    warned_inputs: BTreeSet<InputMode>,
+    /// The output modes which have already been reported as rewritten.
+    warned_outputs: BTreeSet<OutputMode>,
+}
+
+impl<'a, A: Architecture + ?Sized> Lowering<'a, A> {
+    fn new(target: &'a A) -> Self {
+        Self {
+            target,
+            warned_inputs: BTreeSet::new(),
+            warned_outputs: BTreeSet::new(),
+        }
+    }
+
+    /// Rewrite a single instruction, if it uses unsupported I/O.
+    fn op(&mut self, op: &CoreOp) -> CoreOp {
+        match op {
+            CoreOp::Get(src) => {
+                let Some(fallback) = input_fallback(self.target, src) else {
+                    return op.clone();
+                };
+                if self.warned_inputs.insert(src.mode.clone()) {
+                    warn!(
+                        "Input {} is not supported on target {}, using {} instead",
+                        src.mode,
+                        self.target.name(),
+                        fallback.mode
+                    );
+                }
+                CoreOp::Get(fallback)
+            }
+            CoreOp::Put(dst) => {
+                let Some(fallback) = output_fallback(self.target, dst) else {
+                    return op.clone();
+                };
+                if self.warned_outputs.insert(dst.mode.clone()) {
+                    warn!(
+                        "Output {} is not supported on target {}, using {} instead",
+                        dst.mode,
+                        self.target.name(),
+                        fallback.mode
+                    );
+                }
+                CoreOp::Put(fallback)
+            }
+            other => other.clone(),
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! # JavaScript Target
+//!
+//! An implementation of the virtual machine for the JavaScript language.
+//!
+//! This allows the virtual machine to target JavaScript scripts for Node.js,
+//! which can be run with no dependencies outside of its standard library:
+//!
+//! ```bash
+//! $ node out.js
+//! ```
+//!
+//! ## Machine State
+//!
+//! The tape and the register are `BigInt64Array`s, so the results of integer
+//! instructions wrap to 64 bits when they're stored, and pointers are indices
+//! into the tape, like in the interpreter. Each of them is also viewed as a
+//! `Float64Array` (`ftape` and `freg`) for the float instructions. `Alloc`
+//! allocates blocks at the end of the tape, growing it as needed.
+//!
+//! Output is buffered, and written when the program reads input or ends.
+//! Foreign functions are called from a `sage_ffi.js` module next to the
+//! script, with the FFI channel and the tape as their arguments. A foreign
+//! function named after a property every object inherits (like `toString`)
+//! isn't supported, because a module which doesn't export it would call the
+//! inherited property instead of failing.
+use super::{Architecture, CompiledTarget, SPARE_CELLS_PER_BLOCK};
+use crate::{
+    side_effects::{FFIBinding, Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The properties every JavaScript object inherits from `Object.prototype`.
+const INHERITED_PROPERTIES: &[&str] = &[
+    "constructor",
+    "hasOwnProperty",
+    "isPrototypeOf",
+    "propertyIsEnumerable",
+    "toLocaleString",
+    "toString",
+    "valueOf",
+    "__proto__",
+    "__defineGetter__",
+    "__defineSetter__",
+    "__lookupGetter__",
+    "__lookupSetter__",
+];
+
+/// The type for the JavaScript target which implements the `Target` trait.
+/// This allows the compiler to target JavaScript scripts for Node.js.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JavaScript {
+    /// The number of cells on the turing tape, before any are allocated.
+    pub tape_cells: usize,
+    /// The number of cells in the vector register.
+    pub register_cells: usize,
+
+    /// Does the program call any foreign functions?
+    uses_ffi: bool,
+}
+
+impl Default for JavaScript {
+    fn default() -> Self {
+        Self {
+            tape_cells: 1048576,
+            register_cells: 1024,
+            uses_ffi: false,
+        }
+    }
+}
+
+impl JavaScript {
+    /// Apply an operation to the first `n` cells of the register.
+    ///
+    /// The operation is a statement using `{r}` for a cell of the register,
+    /// and `{m}` for the matching cell on the tape. `{fr}` and `{fm}` are the
+    /// same cells, viewed as floats.
+    fn vector(n: usize, op: &str) -> String {
+        if n == 1 {
+            op.replace("{r}", "reg[0]")
+                .replace("{m}", "tape[ptr]")
+                .replace("{fr}", "freg[0]")
+                .replace("{fm}", "ftape[ptr]")
+        } else {
+            format!(
+                "for (let i = 0; i < {n}; i++) {}",
+                op.replace("{r}", "reg[i]")
+                    .replace("{m}", "tape[ptr + i]")
+                    .replace("{fr}", "freg[i]")
+                    .replace("{fm}", "ftape[ptr + i]")
+            )
+        }
+    }
+
+    /// A JavaScript literal for the given float.
+    fn float(value: f64) -> String {
+        if value.is_nan() {
+            "NaN".to_string()
+        } else if value.is_infinite() {
+            format!("{}Infinity", if value < 0.0 { "-" } else { "" })
+        } else {
+            format!("{value:?}")
+        }
+    }
+}
+
+impl Architecture for JavaScript {
+    fn reserve_call_stack(&mut self, cells: usize) {
+        self.tape_cells += cells;
+    }
+
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar
+                | OutputMode::StdoutFloat
+                | OutputMode::StdoutInt
+                | OutputMode::StderrChar
+                | OutputMode::StderrFloat
+                | OutputMode::StderrInt
+        )
+    }
+
+    fn supports_ffi(&self, binding: &FFIBinding) -> bool {
+        !INHERITED_PROPERTIES.contains(&binding.name.as_str())
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
+            }
+            CoreOp::While => "while (reg[0] !== 0n) {".to_string(),
+            CoreOp::If => "if (reg[0] !== 0n) {".to_string(),
+            CoreOp::Else => "} else {".to_string(),
+            CoreOp::Set(n) if n.len() == 1 => format!("reg[0] = {}n;", n[0]),
+            CoreOp::Set(n) => format!(
+                "reg.set([{}]);",
+                n.iter()
+                    .map(|val| format!("{val}n"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            CoreOp::Call => "funs[Number(reg[0])]();".to_string(),
+            CoreOp::Return => "return;".to_string(),
+            CoreOp::Halt => "halt(reg[0]);".to_string(),
+            CoreOp::Store(1) => "tape[ptr] = reg[0];".to_string(),
+            CoreOp::Store(n) => format!("tape.set(reg.subarray(0, {n}), ptr);"),
+            CoreOp::Load(1) => "reg[0] = tape[ptr];".to_string(),
+            CoreOp::Load(n) => format!("reg.set(tape.subarray(ptr, ptr + {n}));"),
+
+            CoreOp::Move(n) => format!("ptr += {n};"),
+            CoreOp::Where => "reg[0] = BigInt(ptr);".to_string(),
+            CoreOp::Deref => "refs.push(ptr); ptr = Number(tape[ptr]);".to_string(),
+            CoreOp::Refer => "ptr = refs.pop();".to_string(),
+            // Pointers are indices into the tape, so offsets aren't scaled.
+            CoreOp::Offset(offset, n) => Self::vector(*n, &format!("{{r}} += {offset}n;")),
+            CoreOp::Index(n) => Self::vector(*n, "{r} += {m};"),
+
+            CoreOp::Add(n) => Self::vector(*n, "{r} += {m};"),
+            CoreOp::Sub(n) => Self::vector(*n, "{r} -= {m};"),
+            CoreOp::Mul(n) => Self::vector(*n, "{r} *= {m};"),
+            // Dividing by zero leaves the register unchanged, like in the interpreter.
+            CoreOp::Div(n) => Self::vector(*n, "if ({m} !== 0n) {r} /= {m};"),
+            CoreOp::Rem(n) => Self::vector(*n, "if ({m} !== 0n) {r} %= {m};"),
+            CoreOp::Neg(n) => Self::vector(*n, "{r} = -{r};"),
+            CoreOp::Inc(n) => Self::vector(*n, "{r} += 1n;"),
+            CoreOp::Dec(n) => Self::vector(*n, "{r} -= 1n;"),
+
+            CoreOp::Swap(n) => Self::vector(*n, "[{r}, {m}] = [{m}, {r}];"),
+
+            CoreOp::And(n) => Self::vector(*n, "{r} = {r} !== 0n && {m} !== 0n ? 1n : 0n;"),
+            CoreOp::Or(n) => Self::vector(*n, "{r} = {r} !== 0n || {m} !== 0n ? 1n : 0n;"),
+            CoreOp::Not(n) => Self::vector(*n, "{r} = {r} === 0n ? 1n : 0n;"),
+
+            CoreOp::BitwiseNand(n) => Self::vector(*n, "{r} = ~({r} & {m});"),
+            CoreOp::BitwiseAnd(n) => Self::vector(*n, "{r} &= {m};"),
+            CoreOp::BitwiseOr(n) => Self::vector(*n, "{r} |= {m};"),
+            CoreOp::BitwiseXor(n) => Self::vector(*n, "{r} ^= {m};"),
+            CoreOp::BitwiseNot(n) => Self::vector(*n, "{r} = ~{r};"),
+            CoreOp::LeftShift(n) => Self::vector(*n, "{r} <<= {m} & 63n;"),
+            CoreOp::LogicalRightShift(n) => {
+                Self::vector(*n, "{r} = BigInt.asUintN(64, {r}) >> ({m} & 63n);")
+            }
+            CoreOp::ArithmeticRightShift(n) => Self::vector(*n, "{r} >>= {m} & 63n;"),
+            CoreOp::IsNonNegative(n) => Self::vector(*n, "{r} = {r} >= 0n ? 1n : 0n;"),
+
+            CoreOp::End | CoreOp::Function | CoreOp::Put(_) | CoreOp::Get(_) => {
+                unreachable!("Invalid core op for JavaScript target")
+            }
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => {
+                self.uses_ffi = true;
+                format!("sageFfi.{}(channel, tape);", ffi.name)
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) if n.len() == 1 => format!("freg[0] = {};", Self::float(n[0])),
+            StandardOp::Set(n) => format!(
+                "freg.set([{}]);",
+                n.iter()
+                    .map(|val| Self::float(*val))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+
+            StandardOp::ToInt(n) => Self::vector(*n, "{r} = toInt({fr});"),
+            StandardOp::ToFloat(n) => Self::vector(*n, "{fr} = Number({r});"),
+
+            StandardOp::Add(n) => Self::vector(*n, "{fr} += {fm};"),
+            StandardOp::Sub(n) => Self::vector(*n, "{fr} -= {fm};"),
+            StandardOp::Mul(n) => Self::vector(*n, "{fr} *= {fm};"),
+            StandardOp::Div(n) => Self::vector(*n, "{fr} /= {fm};"),
+            StandardOp::Rem(n) => Self::vector(*n, "{fr} %= {fm};"),
+            StandardOp::Pow(n) => Self::vector(*n, "{fr} = Math.pow({fr}, {fm});"),
+            StandardOp::Neg(n) => Self::vector(*n, "{fr} = -{fr};"),
+            StandardOp::IsNonNegative(n) => Self::vector(*n, "{r} = {fr} >= 0 ? 1n : 0n;"),
+
+            StandardOp::Sin(n)
+            | StandardOp::Cos(n)
+            | StandardOp::Tan(n)
+            | StandardOp::ASin(n)
+            | StandardOp::ACos(n)
+            | StandardOp::ATan(n) => {
+                Self::vector(*n, &format!("{{fr}} = Math.{}({{fr}});", op.name()))
+            }
+
+            StandardOp::Alloc => "reg[0] = BigInt(alloc(Number(reg[0])));".to_string(),
+            StandardOp::Free => "free(Number(reg[0]));".to_string(),
+            StandardOp::CoreOp(_) => unreachable!("Core ops are built by `op`"),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        match matching {
+            CoreOp::Function | CoreOp::While | CoreOp::If | CoreOp::Else => "}".to_string(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("function f{label_id}() {{")
+    }
+
+    fn name(&self) -> &str {
+        "JavaScript"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn indentation(&self) -> Option<String> {
+        Some("    ".to_string())
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => Ok("reg[0] = BigInt(getChar());".to_string()),
+            InputMode::StdinInt => Ok("reg[0] = getInt();".to_string()),
+            InputMode::StdinFloat => Ok("freg[0] = getFloat();".to_string()),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        let (stream, value) = match dst.mode {
+            OutputMode::StdoutChar => ("stdout", "String.fromCharCode(Number(reg[0] & 255n))"),
+            OutputMode::StdoutInt => ("stdout", "reg[0].toString()"),
+            OutputMode::StdoutFloat => ("stdout", "fmtFloat(freg[0])"),
+            OutputMode::StderrChar => ("stderr", "String.fromCharCode(Number(reg[0] & 255n))"),
+            OutputMode::StderrInt => ("stderr", "reg[0].toString()"),
+            OutputMode::StderrFloat => ("stderr", "fmtFloat(freg[0])"),
+            _ => return Err("Output not supported by this target".to_string()),
+        };
+        Ok(format!("{stream} += {value};"))
+    }
+
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("reg[0] = channel.shift();".to_string())
+    }
+
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("channel.push(reg[0]);".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(format!(
+            r#"// This file was generated by the Sage compiler.
+"use strict";
+const fs = require("fs");
+{ffi}
+const TAPE_CELLS = {tape_cells};
+const REGISTER_CELLS = {register_cells};
+const SPARE_CELLS_PER_BLOCK = {spare_cells};
+
+let tape = new BigInt64Array(TAPE_CELLS);
+let ftape = new Float64Array(tape.buffer);
+let ptr = 0;
+const reg = new BigInt64Array(REGISTER_CELLS);
+const freg = new Float64Array(reg.buffer);
+const refs = [];
+const channel = [];
+const freeBlocks = [];
+// The number of cells used by the tape and the blocks allocated after it.
+let heapEnd = TAPE_CELLS;
+
+let stdout = "";
+let stderr = "";
+
+function flush() {{
+    if (stdout) fs.writeSync(1, stdout);
+    if (stderr) fs.writeSync(2, stderr);
+    stdout = stderr = "";
+}}
+
+function halt(code) {{
+    flush();
+    process.exit(Number(code));
+}}
+
+// Convert a float to an integer, saturating like Rust's `as`.
+function toInt(value) {{
+    if (Number.isNaN(value)) return 0n;
+    if (value >= 9223372036854775807) return 9223372036854775807n;
+    if (value <= -9223372036854775808) return -9223372036854775808n;
+    return BigInt(Math.trunc(value));
+}}
+
+function fmtFloat(value) {{
+    if (!Number.isFinite(value)) return Number.isNaN(value) ? "NaN" : value > 0 ? "inf" : "-inf";
+    if (Number.isInteger(value) && Math.abs(value) < 1e16) return value.toFixed(1);
+    return String(value).replace("e+", "e");
+}}
+
+const inputByte = Buffer.alloc(1);
+
+function getChar() {{
+    flush();
+    try {{
+        return fs.readSync(0, inputByte, 0, 1) === 1 ? inputByte[0] : 0;
+    }} catch (e) {{
+        return 0;
+    }}
+}}
+
+function getInt() {{
+    let byte = getChar();
+    while (byte === 32 || (byte >= 9 && byte <= 13)) byte = getChar();
+    let result = 0n;
+    while (byte >= 48 && byte <= 57) {{
+        result = BigInt.asIntN(64, result * 10n + BigInt(byte - 48));
+        byte = getChar();
+    }}
+    return result;
+}}
+
+function getFloat() {{
+    let line = "";
+    for (let byte = getChar(); byte !== 0 && byte !== 10; byte = getChar()) {{
+        line += String.fromCharCode(byte);
+    }}
+    const value = Number(line.trim());
+    return line.trim() === "" || Number.isNaN(value) ? 0 : value;
+}}
+
+// Allocate a block of cells at the end of the tape. The size of each
+// block is kept in the cell before it, so freed blocks can be reused.
+function alloc(size) {{
+    size = Math.max(size, 1);
+    for (let i = 0; i < freeBlocks.length; i++) {{
+        if (tape[freeBlocks[i] - 1] >= BigInt(size)) return freeBlocks.splice(i, 1)[0];
+    }}
+    const addr = heapEnd + 1;
+    heapEnd = addr + size;
+    const end = heapEnd + SPARE_CELLS_PER_BLOCK;
+    if (end > tape.length) {{
+        const grown = new BigInt64Array(Math.max(end, tape.length * 2));
+        grown.set(tape);
+        tape = grown;
+        ftape = new Float64Array(tape.buffer);
+    }}
+    tape[addr - 1] = BigInt(size);
+    tape.fill(0n, addr, end);
+    return addr;
+}}
+
+function free(addr) {{
+    if (addr > TAPE_CELLS && addr < heapEnd) freeBlocks.push(addr);
+}}
+
+"#,
+            ffi = if self.uses_ffi {
+                "const sageFfi = require(\"./sage_ffi.js\");\n"
+            } else {
+                ""
+            },
+            tape_cells = self.tape_cells,
+            register_cells = self.register_cells,
+            spare_cells = SPARE_CELLS_PER_BLOCK,
+        ))
+    }
+
+    fn post_funs(&self, mut funs: Vec<i32>) -> Option<String> {
+        funs.sort();
+        let funs = funs.iter().map(|fun| format!("f{fun}")).collect::<Vec<_>>();
+        Some(format!(
+            "\nconst funs = [{}];\n\nfunction main() {{\n",
+            funs.join(", ")
+        ))
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some("return;\n}\n\nmain();\nflush();\n".to_string())
+    }
+}
+
+impl CompiledTarget for JavaScript {}
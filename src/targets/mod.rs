@@ -35,25 +35,53 @@
 //! as ASCII/UNICODE with `PutChar. A hardware specific implementation may
 //! also *choose* to fail under unsupported targets to prevent use where
 //! not intended.
+//!
+//! The `fallback` module is the start of this: before a program is built,
+//! any `Get` or `Put` with a mode the target doesn't support is rewritten
+//! into a mode it does support, such as drawing pixels as characters on
+//! standard output.
 
 pub mod c;
 pub use c::*;
+pub mod compatibility;
+pub use compatibility::*;
+pub mod fallback;
+pub use fallback::*;
+pub mod javascript;
+pub use javascript::*;
+pub mod python;
+pub use python::*;
+pub mod rust;
+pub use rust::*;
 pub mod sage_lisp;
 pub use sage_lisp::*;
+pub mod wasm;
+pub use wasm::*;
 
-// pub mod sage_os;
-// pub use sage_os::*;
+#[cfg(feature = "sage-os")]
+pub mod sage_os;
+#[cfg(feature = "sage-os")]
+pub use sage_os::*;
 
-// pub mod x86;
-// pub use x86::*;
+pub mod x86;
+pub use x86::*;
 
 use log::info;
 
 use crate::{
-    side_effects::{Input, Output},
+    side_effects::{FFIBinding, Input, Output},
     vm::{self, *},
 };
 
+/// The number of spare cells every target keeps past the end of each block
+/// allocated by an `Alloc` instruction.
+///
+/// The interpreter makes room for a block by growing the tape up to one cell
+/// past its end, so a program may read that cell without going off the tape.
+/// Every target's allocator reserves the same spare cell, so programs which
+/// read it behave the same everywhere instead of failing on some targets.
+pub const SPARE_CELLS_PER_BLOCK: usize = 1;
+
 /// A trait for a target architecture to be compiled to.
 pub trait Architecture {
     /// The name of the target architecture.
@@ -67,6 +95,22 @@ pub trait Architecture {
     fn supports_input(&self, src: &Input) -> bool;
     /// Whether or not the target architecture supports the given output (mode + channel).
     fn supports_output(&self, dst: &Output) -> bool;
+    /// Make room on the tape for a call stack of the given number of cells.
+    /// The assembler puts the call stack at the start of the tape, before the
+    /// globals and the heap, so targets with a fixed size tape lengthen it.
+    fn reserve_call_stack(&mut self, _cells: usize) {}
+    /// Make room on the tape for a call stack of the given number of cells.
+    fn with_call_stack(mut self, cells: usize) -> Self
+    where
+        Self: Sized,
+    {
+        self.reserve_call_stack(cells);
+        self
+    }
+    /// Whether or not the target architecture can provide the given foreign function.
+    fn supports_ffi(&self, _binding: &FFIBinding) -> bool {
+        true
+    }
 
     /// Get a value from the given input stream (mode + channel).
     fn get(&mut self, src: &Input) -> Result<String, String>;
@@ -77,6 +121,9 @@ pub trait Architecture {
     /// Poke a value to the device connected to the program.
     fn poke(&mut self) -> Result<String, String>;
 
+    /// Forget what the last program built declared, before building another.
+    fn reset(&mut self) {}
+
     /// The code before the program starts.
     fn prelude(&self, _is_core: bool) -> Option<String> {
         None
@@ -204,6 +251,16 @@ pub trait CompiledTarget: Architecture {
             StandardOp::CoreOp(op) => {
                 self.build_op(op, matching_ops, matching_funs, current_fun, indent)
             }
+            StandardOp::Call(binding) if !self.supports_ffi(binding) => Err(format!(
+                "Foreign function {} not supported on target {}",
+                binding.name,
+                self.name()
+            )),
+            other if other.is_float() && !self.supports_floats() => Err(format!(
+                "Floating point instruction {} not supported on target {}",
+                other,
+                self.name()
+            )),
             other => self.std_op(other),
         }
     }
@@ -211,8 +268,9 @@ pub trait CompiledTarget: Architecture {
     /// Compile the core variant of the machine code (must be implemented for every target).
     fn build_core(&mut self, program: &vm::CoreProgram) -> Result<String, String> {
         info!("Compiling core program for target {}", self.name());
-        let (main_ops, function_defs) = program.clone().get_main_and_functions();
-        let mut result = self.prelude(true).unwrap_or("".to_string());
+        self.reset();
+        let (main_ops, function_defs) = lower_core_io(self, program).get_main_and_functions();
+        let mut result = String::new();
 
         let mut matching_ops = vec![];
         let mut matching_funs = vec![];
@@ -256,14 +314,17 @@ pub trait CompiledTarget: Architecture {
         }
 
         info!("Finished compiling core program for target {}", self.name());
-        Ok(result + &tab + self.postlude(true).unwrap_or("".to_string()).as_str())
+        // The prelude is built last, so that it can declare what the program uses.
+        let prelude = self.prelude(true).unwrap_or("".to_string());
+        Ok(prelude + &result + &tab + self.postlude(true).unwrap_or("".to_string()).as_str())
     }
 
     /// Compile the standard variant of the machine code (should be implemented for every target possible).
     fn build_std(&mut self, program: &vm::StandardProgram) -> Result<String, String> {
         info!("Compiling standard program for target {}", self.name());
-        let (main_ops, function_defs) = program.clone().get_main_and_functions();
-        let mut result = self.prelude(false).unwrap_or("".to_string());
+        self.reset();
+        let (main_ops, function_defs) = lower_std_io(self, program).get_main_and_functions();
+        let mut result = String::new();
 
         let mut matching_ops = vec![];
         let mut matching_funs = vec![];
@@ -309,6 +370,8 @@ pub trait CompiledTarget: Architecture {
             "Finished compiling standard program for target {}",
             self.name()
         );
-        Ok(result + &tab + self.postlude(false).unwrap_or("".to_string()).as_str())
+        // The prelude is built last, so that it can declare what the program uses.
+        let prelude = self.prelude(false).unwrap_or("".to_string());
+        Ok(prelude + &result + &tab + self.postlude(false).unwrap_or("".to_string()).as_str())
     }
 }
@@ -0,0 +1,478 @@
+//! # Python Target
+//!
+//! An implementation of the virtual machine for the Python language.
+//!
+//! This allows the virtual machine to target Python 3 scripts, which can
+//! be run with no dependencies outside of the standard library:
+//!
+//! ```bash
+//! $ python3 out.py
+//! ```
+//!
+//! ## Machine State
+//!
+//! The tape and the register are lists of integers, and pointers are indices
+//! into the tape, like in the interpreter. Python's integers don't overflow,
+//! so the results of integer instructions are wrapped to 64 bits. Floats are
+//! stored in cells by their bits, and converted with `struct` when they're used.
+//! `Alloc` allocates blocks at the end of the tape, growing it as needed.
+//!
+//! ## Blocks
+//!
+//! Python uses indentation instead of braces for its blocks, so every block is
+//! ended with a `pass` statement (in case it's empty). The target keeps track
+//! of how deeply nested the current block is, so it can indent `else` to match
+//! its `if`.
+//!
+//! Foreign functions are called from a `sage_ffi` module next to the script,
+//! with the FFI channel and the tape as their arguments. A foreign function
+//! named after a Python keyword can't be called.
+use super::{Architecture, CompiledTarget, SPARE_CELLS_PER_BLOCK};
+use crate::{
+    side_effects::{FFIBinding, Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+
+/// The keywords of Python 3, which can't be used as the names of functions.
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+/// The type for the Python target which implements the `Target` trait.
+/// This allows the compiler to target Python 3 scripts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Python {
+    /// The number of cells on the turing tape, before any are allocated.
+    pub tape_cells: usize,
+    /// The number of cells in the vector register.
+    pub register_cells: usize,
+
+    /// The number of `While`, `If` and `Else` blocks which haven't ended yet.
+    blocks: usize,
+    /// Does the program call any foreign functions?
+    uses_ffi: bool,
+}
+
+impl Default for Python {
+    fn default() -> Self {
+        Self {
+            tape_cells: 1048576,
+            register_cells: 1024,
+            blocks: 0,
+            uses_ffi: false,
+        }
+    }
+}
+
+impl Python {
+    /// Apply an operation to the first `n` cells of the register.
+    ///
+    /// The operation is a statement using `{r}` for a cell of the register,
+    /// and `{m}` for the matching cell on the tape.
+    fn vector(n: usize, op: &str) -> String {
+        if n == 1 {
+            op.replace("{r}", "reg[0]").replace("{m}", "tape[ptr]")
+        } else {
+            format!(
+                "for i in range({n}): {}",
+                op.replace("{r}", "reg[i]").replace("{m}", "tape[ptr + i]")
+            )
+        }
+    }
+
+    /// Set the first cells of the register to the given values.
+    fn set(values: Vec<i64>) -> String {
+        if values.len() == 1 {
+            format!("reg[0] = {}", values[0])
+        } else {
+            format!(
+                "reg[0:{}] = [{}]",
+                values.len(),
+                values
+                    .iter()
+                    .map(|val| val.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+    }
+
+    /// The indentation of a line `depth` blocks deep in a function.
+    fn indent(&self, depth: usize) -> String {
+        self.indentation().unwrap_or_default().repeat(depth)
+    }
+}
+
+impl Architecture for Python {
+    fn reserve_call_stack(&mut self, cells: usize) {
+        self.tape_cells += cells;
+    }
+
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar
+                | OutputMode::StdoutFloat
+                | OutputMode::StdoutInt
+                | OutputMode::StderrChar
+                | OutputMode::StderrFloat
+                | OutputMode::StderrInt
+        )
+    }
+
+    fn supports_ffi(&self, binding: &FFIBinding) -> bool {
+        !KEYWORDS.contains(&binding.name.as_str())
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!("# {}", text.replace('\n', "\n# ").replace('\r', ""))
+            }
+            CoreOp::While => {
+                self.blocks += 1;
+                "while reg[0]:".to_string()
+            }
+            CoreOp::If => {
+                self.blocks += 1;
+                "if reg[0]:".to_string()
+            }
+            // The `else` is indented to match its `if`, one block out.
+            CoreOp::Else => format!("pass\n{}else:", self.indent(self.blocks)),
+            CoreOp::Set(n) => Self::set(n.clone()),
+            CoreOp::Call => "funs[reg[0]]()".to_string(),
+            CoreOp::Return => "return".to_string(),
+            CoreOp::Halt => "halt(reg[0])".to_string(),
+            CoreOp::Store(1) => "tape[ptr] = reg[0]".to_string(),
+            CoreOp::Store(n) => format!("tape[ptr:ptr + {n}] = reg[0:{n}]"),
+            CoreOp::Load(1) => "reg[0] = tape[ptr]".to_string(),
+            CoreOp::Load(n) => format!("reg[0:{n}] = tape[ptr:ptr + {n}]"),
+
+            CoreOp::Move(n) => format!("ptr += {n}"),
+            CoreOp::Where => "reg[0] = ptr".to_string(),
+            CoreOp::Deref => "refs.append(ptr); ptr = tape[ptr]".to_string(),
+            CoreOp::Refer => "ptr = refs.pop()".to_string(),
+            // Pointers are indices into the tape, so offsets aren't scaled.
+            CoreOp::Offset(offset, n) => {
+                Self::vector(*n, &format!("{{r}} = wrap({{r}} + {offset})"))
+            }
+            CoreOp::Index(n) => Self::vector(*n, "{r} = wrap({r} + {m})"),
+
+            CoreOp::Add(n) => Self::vector(*n, "{r} = wrap({r} + {m})"),
+            CoreOp::Sub(n) => Self::vector(*n, "{r} = wrap({r} - {m})"),
+            CoreOp::Mul(n) => Self::vector(*n, "{r} = wrap({r} * {m})"),
+            CoreOp::Div(n) => Self::vector(*n, "{r} = div({r}, {m})"),
+            CoreOp::Rem(n) => Self::vector(*n, "{r} = rem({r}, {m})"),
+            CoreOp::Neg(n) => Self::vector(*n, "{r} = wrap(-{r})"),
+            CoreOp::Inc(n) => Self::vector(*n, "{r} = wrap({r} + 1)"),
+            CoreOp::Dec(n) => Self::vector(*n, "{r} = wrap({r} - 1)"),
+
+            CoreOp::Swap(n) => Self::vector(*n, "{r}, {m} = {m}, {r}"),
+
+            CoreOp::And(n) => Self::vector(*n, "{r} = int({r} != 0 and {m} != 0)"),
+            CoreOp::Or(n) => Self::vector(*n, "{r} = int({r} != 0 or {m} != 0)"),
+            CoreOp::Not(n) => Self::vector(*n, "{r} = int({r} == 0)"),
+
+            CoreOp::BitwiseNand(n) => Self::vector(*n, "{r} = ~({r} & {m})"),
+            CoreOp::BitwiseAnd(n) => Self::vector(*n, "{r} &= {m}"),
+            CoreOp::BitwiseOr(n) => Self::vector(*n, "{r} |= {m}"),
+            CoreOp::BitwiseXor(n) => Self::vector(*n, "{r} ^= {m}"),
+            CoreOp::BitwiseNot(n) => Self::vector(*n, "{r} = ~{r}"),
+            CoreOp::LeftShift(n) => Self::vector(*n, "{r} = wrap({r} << ({m} & 63))"),
+            CoreOp::LogicalRightShift(n) => {
+                Self::vector(*n, "{r} = wrap(({r} & MASK) >> ({m} & 63))")
+            }
+            CoreOp::ArithmeticRightShift(n) => Self::vector(*n, "{r} >>= {m} & 63"),
+            CoreOp::IsNonNegative(n) => Self::vector(*n, "{r} = int({r} >= 0)"),
+
+            CoreOp::End | CoreOp::Function | CoreOp::Put(_) | CoreOp::Get(_) => {
+                unreachable!("Invalid core op for Python target")
+            }
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => {
+                self.uses_ffi = true;
+                format!("sage_ffi.{}(channel, tape)", ffi.name)
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            // Floats are stored by their bits.
+            StandardOp::Set(n) => Self::set(n.iter().map(|val| val.to_bits() as i64).collect()),
+
+            StandardOp::ToInt(n) => Self::vector(*n, "{r} = to_int(as_float({r}))"),
+            StandardOp::ToFloat(n) => Self::vector(*n, "{r} = as_cell(float({r}))"),
+
+            StandardOp::Add(n) => Self::vector(*n, "{r} = as_cell(as_float({r}) + as_float({m}))"),
+            StandardOp::Sub(n) => Self::vector(*n, "{r} = as_cell(as_float({r}) - as_float({m}))"),
+            StandardOp::Mul(n) => Self::vector(*n, "{r} = as_cell(as_float({r}) * as_float({m}))"),
+            StandardOp::Div(n) => {
+                Self::vector(*n, "{r} = as_cell(fdiv(as_float({r}), as_float({m})))")
+            }
+            StandardOp::Rem(n) => Self::vector(
+                *n,
+                "{r} = as_cell(fmath(math.fmod, as_float({r}), as_float({m})))",
+            ),
+            StandardOp::Pow(n) => Self::vector(
+                *n,
+                "{r} = as_cell(fmath(math.pow, as_float({r}), as_float({m})))",
+            ),
+            StandardOp::Neg(n) => Self::vector(*n, "{r} = as_cell(-as_float({r}))"),
+            StandardOp::IsNonNegative(n) => Self::vector(*n, "{r} = int(as_float({r}) >= 0)"),
+
+            StandardOp::Sin(n)
+            | StandardOp::Cos(n)
+            | StandardOp::Tan(n)
+            | StandardOp::ASin(n)
+            | StandardOp::ACos(n)
+            | StandardOp::ATan(n) => Self::vector(
+                *n,
+                &format!(
+                    "{{r}} = as_cell(fmath(math.{}, as_float({{r}})))",
+                    op.name()
+                ),
+            ),
+
+            StandardOp::Alloc => "reg[0] = alloc(reg[0])".to_string(),
+            StandardOp::Free => "free(reg[0])".to_string(),
+            StandardOp::CoreOp(_) => unreachable!("Core ops are built by `op`"),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        match matching {
+            CoreOp::Function => "pass".to_string(),
+            CoreOp::While | CoreOp::If | CoreOp::Else => {
+                self.blocks -= 1;
+                "pass".to_string()
+            }
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("def f{label_id}():\n{}global ptr", self.indent(1))
+    }
+
+    fn name(&self) -> &str {
+        "Python"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn indentation(&self) -> Option<String> {
+        Some("    ".to_string())
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        match src.mode {
+            InputMode::StdinChar => Ok("reg[0] = get_char()".to_string()),
+            InputMode::StdinInt => Ok("reg[0] = get_int()".to_string()),
+            InputMode::StdinFloat => Ok("reg[0] = as_cell(get_float())".to_string()),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        let (stream, value) = match dst.mode {
+            OutputMode::StdoutChar => ("stdout", "chr(reg[0] & 255)"),
+            OutputMode::StdoutInt => ("stdout", "str(reg[0])"),
+            OutputMode::StdoutFloat => ("stdout", "fmt_float(as_float(reg[0]))"),
+            OutputMode::StderrChar => ("stderr", "chr(reg[0] & 255)"),
+            OutputMode::StderrInt => ("stderr", "str(reg[0])"),
+            OutputMode::StderrFloat => ("stderr", "fmt_float(as_float(reg[0]))"),
+            _ => return Err("Output not supported by this target".to_string()),
+        };
+        Ok(format!("sys.{stream}.write({value})"))
+    }
+
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("reg[0] = channel.pop(0)".to_string())
+    }
+
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("channel.append(reg[0])".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(format!(
+            r#"# This file was generated by the Sage compiler.
+import math
+import struct
+import sys
+{ffi}
+TAPE_CELLS = {tape_cells}
+REGISTER_CELLS = {register_cells}
+SPARE_CELLS_PER_BLOCK = {spare_cells}
+MASK = (1 << 64) - 1
+
+tape = [0] * TAPE_CELLS
+ptr = 0
+reg = [0] * REGISTER_CELLS
+refs = []
+channel = []
+free_blocks = []
+
+sys.setrecursionlimit(1 << 20)
+
+
+def wrap(n):
+    """Wrap an integer to a signed 64 bit cell."""
+    n &= MASK
+    return n - (1 << 64) if n >> 63 else n
+
+
+def div(a, b):
+    """Divide, truncating. Dividing by zero leaves the value unchanged."""
+    if b == 0:
+        return a
+    q = abs(a) // abs(b)
+    return wrap(q if (a < 0) == (b < 0) else -q)
+
+
+def rem(a, b):
+    """The remainder of a truncating division, with the sign of `a`."""
+    if b == 0:
+        return a
+    r = abs(a) % abs(b)
+    return -r if a < 0 else r
+
+
+def as_float(cell):
+    return struct.unpack("<d", struct.pack("<q", cell))[0]
+
+
+def as_cell(value):
+    return struct.unpack("<q", struct.pack("<d", value))[0]
+
+
+def to_int(value):
+    """Convert a float to an integer, saturating like Rust's `as`."""
+    if math.isnan(value):
+        return 0
+    if math.isinf(value):
+        return MASK >> 1 if value > 0 else -(MASK >> 1) - 1
+    return max(min(int(value), MASK >> 1), -(MASK >> 1) - 1)
+
+
+def fdiv(a, b):
+    if b == 0:
+        if a == 0 or math.isnan(a):
+            return math.nan
+        return math.copysign(math.inf, a) * math.copysign(1, b)
+    return a / b
+
+
+def fmath(f, *args):
+    """Call a function from `math`, with IEEE results instead of exceptions."""
+    try:
+        return f(*args)
+    except ValueError:
+        return math.nan
+    except OverflowError:
+        return math.inf
+
+
+def fmt_float(value):
+    if math.isnan(value):
+        return "NaN"
+    return repr(value).replace("e+", "e").replace("e-0", "e-")
+
+
+def get_char():
+    sys.stdout.flush()
+    byte = sys.stdin.buffer.read(1)
+    return byte[0] if byte else 0
+
+
+def get_int():
+    byte = get_char()
+    while chr(byte).isspace():
+        byte = get_char()
+    result = 0
+    while 48 <= byte <= 57:
+        result = wrap(result * 10 + byte - 48)
+        byte = get_char()
+    return result
+
+
+def get_float():
+    sys.stdout.flush()
+    try:
+        return float(sys.stdin.buffer.readline())
+    except ValueError:
+        return 0.0
+
+
+def alloc(size):
+    """Allocate a block of cells at the end of the tape. The size of each
+    block is kept in the cell before it, so freed blocks can be reused."""
+    size = max(size, 1)
+    for i, addr in enumerate(free_blocks):
+        if tape[addr - 1] >= size:
+            return free_blocks.pop(i)
+    tape.append(size)
+    addr = len(tape)
+    tape.extend([0] * (size + SPARE_CELLS_PER_BLOCK))
+    return addr
+
+
+def free(addr):
+    if TAPE_CELLS < addr < len(tape):
+        free_blocks.append(addr)
+
+
+def halt(code):
+    sys.stdout.flush()
+    sys.exit(code)
+
+
+"#,
+            ffi = if self.uses_ffi {
+                "\nimport sage_ffi\n"
+            } else {
+                ""
+            },
+            tape_cells = self.tape_cells,
+            register_cells = self.register_cells,
+            spare_cells = SPARE_CELLS_PER_BLOCK,
+        ))
+    }
+
+    fn post_funs(&self, mut funs: Vec<i32>) -> Option<String> {
+        funs.sort();
+        let funs = funs.iter().map(|fun| format!("f{fun}")).collect::<Vec<_>>();
+        Some(format!(
+            "\nfuns = [{}]\n\n\ndef main():\n{}global ptr\n",
+            funs.join(", "),
+            self.indent(1)
+        ))
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some("pass\n\n\nmain()\nsys.stdout.flush()\n".to_string())
+    }
+}
+
+impl CompiledTarget for Python {}
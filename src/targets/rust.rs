@@ -0,0 +1,512 @@
+//! # Rust Target
+//!
+//! An implementation of the virtual machine for the Rust language.
+//!
+//! This allows the virtual machine to target a single file of safe Rust
+//! source code, which can be compiled on its own with `rustc`, or included
+//! as a module in another crate.
+//!
+//! ## Machine State
+//!
+//! The generated code defines a `Machine` which holds the state of the
+//! virtual machine: the tape is a `Vec<Cell>`, and pointers are indices
+//! into it, like in the interpreter. `Alloc` allocates blocks at the end of
+//! the tape, growing it as needed. Every access to the tape is bounds checked,
+//! so a program which walks off its memory panics instead of corrupting it.
+//!
+//! ## Functions
+//!
+//! Functions are compiled to Rust functions of type `fn(&mut Machine)`, and
+//! are called through the table `FUNS`, indexed by the register. `Halt` stops
+//! the machine and returns from every function on the call stack, so that the
+//! generated code can run inside a larger program without exiting it.
+//!
+//! ## Input and Output
+//!
+//! The machine talks to the outside world through a `Device` trait which
+//! mirrors the interpreter's `vm::Device`: `Get` and `Put` are passed the
+//! name and channel of their mode, and `Peek` and `Poke` use the device's
+//! FFI channel. Foreign functions become the methods of an `Ffi` trait,
+//! which the user implements for their device. When the program doesn't
+//! call any foreign functions, the generated file also defines a `StdDevice`
+//! using the standard input and output of the process, and a `main` function.
+use super::{Architecture, CompiledTarget, SPARE_CELLS_PER_BLOCK};
+use crate::{
+    side_effects::{FFIBinding, Input, Output},
+    vm::{CoreOp, StandardOp},
+};
+use std::collections::BTreeMap;
+
+/// The type for the Rust target which implements the `Target` trait.
+/// This allows the compiler to target safe Rust source code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rust {
+    /// The number of cells on the turing tape, before any are allocated.
+    pub tape_cells: usize,
+    /// The number of cells in the vector register.
+    pub register_cells: usize,
+
+    /// The foreign functions called by the program, by name.
+    ffi: BTreeMap<String, FFIBinding>,
+}
+
+impl Default for Rust {
+    fn default() -> Self {
+        Self {
+            tape_cells: 1048576,
+            register_cells: 1024,
+            ffi: BTreeMap::new(),
+        }
+    }
+}
+
+impl Rust {
+    /// Apply an operation to the first `n` cells of the register.
+    ///
+    /// The operation is a statement using `{r}` for a cell of the register,
+    /// and `{m}` for the matching cell on the tape.
+    fn vector(n: usize, op: &str) -> String {
+        if n == 1 {
+            op.replace("{r}", "m.reg[0]")
+                .replace("{m}", "m.tape[m.ptr]")
+        } else {
+            format!(
+                "for i in 0..{n} {{ {} }}",
+                op.replace("{r}", "m.reg[i]")
+                    .replace("{m}", "m.tape[m.ptr + i]")
+            )
+        }
+    }
+
+    /// Set the first cells of the register to the given values.
+    fn set(values: Vec<String>) -> String {
+        if values.len() == 1 {
+            format!("m.reg[0] = {};", values[0])
+        } else {
+            format!(
+                "m.reg[..{}].copy_from_slice(&[{}]);",
+                values.len(),
+                values.join(", ")
+            )
+        }
+    }
+
+    /// A Rust expression for the cell holding the given float.
+    fn float(value: f64) -> String {
+        if value.is_nan() {
+            "to_cell(f64::NAN)".to_string()
+        } else if value.is_infinite() {
+            format!(
+                "to_cell({}f64::INFINITY)",
+                if value < 0.0 { "-" } else { "" }
+            )
+        } else {
+            format!("to_cell({value:?})")
+        }
+    }
+}
+
+impl Architecture for Rust {
+    fn reserve_call_stack(&mut self, cells: usize) {
+        self.tape_cells += cells;
+    }
+
+    fn supports_input(&self, _i: &Input) -> bool {
+        // All input is passed to the device supplied by the user.
+        true
+    }
+
+    fn supports_output(&self, _o: &Output) -> bool {
+        // All output is passed to the device supplied by the user.
+        true
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
+            }
+            CoreOp::While => "while m.reg[0] != 0 {".to_string(),
+            CoreOp::If => "if m.reg[0] != 0 {".to_string(),
+            CoreOp::Else => "} else {".to_string(),
+            CoreOp::Set(n) => Self::set(n.iter().map(|val| format!("{val}")).collect()),
+            // A function which halts the machine returns from every function calling it.
+            CoreOp::Call => {
+                "FUNS[m.reg[0] as usize](m); if m.halted.is_some() { return; }".to_string()
+            }
+            CoreOp::Return => "return;".to_string(),
+            CoreOp::Halt => "m.halted = Some(m.reg[0]); return;".to_string(),
+            CoreOp::Store(1) => "m.tape[m.ptr] = m.reg[0];".to_string(),
+            CoreOp::Store(n) => {
+                format!("m.tape[m.ptr..m.ptr + {n}].copy_from_slice(&m.reg[..{n}]);")
+            }
+            CoreOp::Load(1) => "m.reg[0] = m.tape[m.ptr];".to_string(),
+            CoreOp::Load(n) => {
+                format!("m.reg[..{n}].copy_from_slice(&m.tape[m.ptr..m.ptr + {n}]);")
+            }
+
+            CoreOp::Move(n) => format!("m.ptr = (m.ptr as Cell).wrapping_add({n}) as usize;"),
+            CoreOp::Where => "m.reg[0] = m.ptr as Cell;".to_string(),
+            CoreOp::Deref => "m.refs.push(m.ptr); m.ptr = m.tape[m.ptr] as usize;".to_string(),
+            CoreOp::Refer => {
+                "m.ptr = m.refs.pop().expect(\"refer without a matching deref\");".to_string()
+            }
+            // Pointers are indices into the tape, so offsets aren't scaled.
+            CoreOp::Offset(offset, n) => {
+                Self::vector(*n, &format!("{{r}} = {{r}}.wrapping_add({offset});"))
+            }
+            CoreOp::Index(n) => Self::vector(*n, "{r} = {r}.wrapping_add({m});"),
+
+            CoreOp::Add(n) => Self::vector(*n, "{r} = {r}.wrapping_add({m});"),
+            CoreOp::Sub(n) => Self::vector(*n, "{r} = {r}.wrapping_sub({m});"),
+            CoreOp::Mul(n) => Self::vector(*n, "{r} = {r}.wrapping_mul({m});"),
+            // Dividing by zero leaves the register unchanged, like in the interpreter.
+            CoreOp::Div(n) => Self::vector(*n, "if {m} != 0 { {r} = {r}.wrapping_div({m}); }"),
+            CoreOp::Rem(n) => Self::vector(*n, "if {m} != 0 { {r} = {r}.wrapping_rem({m}); }"),
+            CoreOp::Neg(n) => Self::vector(*n, "{r} = {r}.wrapping_neg();"),
+            CoreOp::Inc(n) => Self::vector(*n, "{r} = {r}.wrapping_add(1);"),
+            CoreOp::Dec(n) => Self::vector(*n, "{r} = {r}.wrapping_sub(1);"),
+
+            CoreOp::Swap(n) => Self::vector(*n, "std::mem::swap(&mut {r}, &mut {m});"),
+
+            CoreOp::And(n) => Self::vector(*n, "{r} = ({r} != 0 && {m} != 0) as Cell;"),
+            CoreOp::Or(n) => Self::vector(*n, "{r} = ({r} != 0 || {m} != 0) as Cell;"),
+            CoreOp::Not(n) => Self::vector(*n, "{r} = ({r} == 0) as Cell;"),
+
+            CoreOp::BitwiseNand(n) => Self::vector(*n, "{r} = !({r} & {m});"),
+            CoreOp::BitwiseAnd(n) => Self::vector(*n, "{r} &= {m};"),
+            CoreOp::BitwiseOr(n) => Self::vector(*n, "{r} |= {m};"),
+            CoreOp::BitwiseXor(n) => Self::vector(*n, "{r} ^= {m};"),
+            CoreOp::BitwiseNot(n) => Self::vector(*n, "{r} = !{r};"),
+            CoreOp::LeftShift(n) => Self::vector(*n, "{r} = {r}.wrapping_shl({m} as u32);"),
+            CoreOp::LogicalRightShift(n) => {
+                Self::vector(*n, "{r} = ({r} as u64).wrapping_shr({m} as u32) as Cell;")
+            }
+            CoreOp::ArithmeticRightShift(n) => {
+                Self::vector(*n, "{r} = {r}.wrapping_shr({m} as u32);")
+            }
+            CoreOp::IsNonNegative(n) => Self::vector(*n, "{r} = ({r} >= 0) as Cell;"),
+
+            CoreOp::End | CoreOp::Function | CoreOp::Put(_) | CoreOp::Get(_) => {
+                unreachable!("Invalid core op for Rust target")
+            }
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => {
+                self.ffi.insert(ffi.name.clone(), ffi.clone());
+                format!("m.device.{}(&mut m.tape);", ffi.name)
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) => Self::set(n.iter().map(|val| Self::float(*val)).collect()),
+
+            StandardOp::ToInt(n) => Self::vector(*n, "{r} = to_float({r}) as Cell;"),
+            StandardOp::ToFloat(n) => Self::vector(*n, "{r} = to_cell({r} as f64);"),
+
+            StandardOp::Add(n) => Self::vector(*n, "{r} = to_cell(to_float({r}) + to_float({m}));"),
+            StandardOp::Sub(n) => Self::vector(*n, "{r} = to_cell(to_float({r}) - to_float({m}));"),
+            StandardOp::Mul(n) => Self::vector(*n, "{r} = to_cell(to_float({r}) * to_float({m}));"),
+            StandardOp::Div(n) => Self::vector(*n, "{r} = to_cell(to_float({r}) / to_float({m}));"),
+            StandardOp::Rem(n) => Self::vector(*n, "{r} = to_cell(to_float({r}) % to_float({m}));"),
+            StandardOp::Pow(n) => {
+                Self::vector(*n, "{r} = to_cell(to_float({r}).powf(to_float({m})));")
+            }
+            StandardOp::Neg(n) => Self::vector(*n, "{r} = to_cell(-to_float({r}));"),
+            StandardOp::IsNonNegative(n) => {
+                Self::vector(*n, "{r} = (to_float({r}) >= 0.0) as Cell;")
+            }
+
+            StandardOp::Sin(n)
+            | StandardOp::Cos(n)
+            | StandardOp::Tan(n)
+            | StandardOp::ASin(n)
+            | StandardOp::ACos(n)
+            | StandardOp::ATan(n) => Self::vector(
+                *n,
+                &format!("{{r}} = to_cell(to_float({{r}}).{}());", op.name()),
+            ),
+
+            StandardOp::Alloc => "m.reg[0] = m.alloc(m.reg[0]) as Cell;".to_string(),
+            StandardOp::Free => "m.free(m.reg[0] as usize);".to_string(),
+            StandardOp::CoreOp(_) => unreachable!("Core ops are built by `op`"),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        match matching {
+            CoreOp::Function | CoreOp::While | CoreOp::If | CoreOp::Else => "}".to_string(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("fn f{label_id}(m: &mut Machine) {{")
+    }
+
+    fn name(&self) -> &str {
+        "Rust"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn indentation(&self) -> Option<String> {
+        Some("    ".to_string())
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        Ok(format!(
+            "m.reg[0] = m.device.get({:?}, {});",
+            src.mode.to_string(),
+            src.channel.0
+        ))
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        Ok(format!(
+            "m.device.put(m.reg[0], {:?}, {});",
+            dst.mode.to_string(),
+            dst.channel.0
+        ))
+    }
+
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("m.reg[0] = m.device.peek();".to_string())
+    }
+
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("m.device.poke(m.reg[0]);".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        let mut result = format!(
+            r#"// This file was generated by the Sage compiler.
+#![allow(dead_code, unused_mut, unused_imports, unreachable_code, clippy::all)]
+
+use std::collections::VecDeque;
+use std::io::{{Read, Write}};
+
+/// A cell of the machine's memory. Floats are stored by their bits.
+pub type Cell = i64;
+
+/// The number of cells on the tape, before any are allocated.
+pub const TAPE_CELLS: usize = {tape_cells};
+/// The number of spare cells kept past the end of each allocated block.
+const SPARE_CELLS_PER_BLOCK: usize = {spare_cells};
+/// The number of cells in the vector register.
+pub const REGISTER_CELLS: usize = {register_cells};
+
+/// Interpret a cell as a float.
+pub fn to_float(cell: Cell) -> f64 {{
+    f64::from_bits(cell as u64)
+}}
+
+/// Store a float in a cell.
+pub fn to_cell(float: f64) -> Cell {{
+    float.to_bits() as Cell
+}}
+
+/// The device the program uses for input and output.
+pub trait Device {{
+    /// Get the next input from the given source (mode + channel).
+    fn get(&mut self, mode: &str, channel: usize) -> Cell;
+    /// Put the given value to the given destination (mode + channel).
+    fn put(&mut self, val: Cell, mode: &str, channel: usize);
+    /// Take the next value from the FFI channel.
+    fn peek(&mut self) -> Cell;
+    /// Give a value to the FFI channel.
+    fn poke(&mut self, val: Cell);
+}}
+
+/// The foreign functions called by the program. Their arguments and results
+/// are passed through the FFI channel of the device.
+pub trait Ffi: Device {{
+"#,
+            tape_cells = self.tape_cells,
+            register_cells = self.register_cells,
+            spare_cells = SPARE_CELLS_PER_BLOCK,
+        );
+        for ffi in self.ffi.values() {
+            result += &format!(
+                "    /// Takes {} cell(s) from the FFI channel, and gives back {}.\n    fn {}(&mut self, tape: &mut Vec<Cell>);\n",
+                ffi.input_cells, ffi.output_cells, ffi.name
+            );
+        }
+        result += r#"}
+
+/// The state of the virtual machine.
+pub struct Machine<'a> {
+    /// The turing tape, followed by the blocks allocated on the heap.
+    pub tape: Vec<Cell>,
+    /// The index of the cell under the pointer.
+    pub ptr: usize,
+    /// The vector register.
+    pub reg: Vec<Cell>,
+    /// The pointers saved by `Deref`, to be restored by `Refer`.
+    pub refs: Vec<usize>,
+    /// The exit code of the program, if it has halted.
+    pub halted: Option<Cell>,
+    /// The device used for input, output, and foreign functions.
+    pub device: &'a mut dyn Ffi,
+    /// The addresses of the blocks which have been freed.
+    free_blocks: Vec<usize>,
+}
+
+impl<'a> Machine<'a> {
+    /// Create a new machine using the given device.
+    pub fn new(device: &'a mut dyn Ffi) -> Self {
+        Self {
+            tape: vec![0; TAPE_CELLS],
+            ptr: 0,
+            reg: vec![0; REGISTER_CELLS],
+            refs: vec![],
+            halted: None,
+            device,
+            free_blocks: vec![],
+        }
+    }
+
+    /// Run the program to the end, and return its exit code.
+    pub fn run(&mut self) -> Cell {
+        sage_main(self);
+        self.halted.unwrap_or(0)
+    }
+
+    /// Allocate a block of cells at the end of the tape. The size of each
+    /// block is kept in the cell before it, so freed blocks can be reused.
+    fn alloc(&mut self, size: Cell) -> usize {
+        let size = size.max(1) as usize;
+        let tape = &self.tape;
+        if let Some(i) = self
+            .free_blocks
+            .iter()
+            .position(|&addr| tape[addr - 1] as usize >= size)
+        {
+            return self.free_blocks.swap_remove(i);
+        }
+        let addr = self.tape.len() + 1;
+        self.tape.push(size as Cell);
+        self.tape.resize(addr + size + SPARE_CELLS_PER_BLOCK, 0);
+        addr
+    }
+
+    /// Free a block of cells allocated by `alloc`.
+    fn free(&mut self, addr: usize) {
+        if addr > TAPE_CELLS && addr < self.tape.len() {
+            self.free_blocks.push(addr);
+        }
+    }
+}
+
+/// Run the program with the given device, and return its exit code.
+pub fn run(device: &mut dyn Ffi) -> Cell {
+    Machine::new(device).run()
+}
+
+"#;
+        Some(result)
+    }
+
+    fn post_funs(&self, mut funs: Vec<i32>) -> Option<String> {
+        funs.sort();
+        let funs = funs.iter().map(|fun| format!("f{fun}")).collect::<Vec<_>>();
+        Some(format!(
+            "/// The table of functions, indexed by the register when they're called.\nstatic FUNS: [fn(&mut Machine); {}] = [{}];\n\nfn sage_main(m: &mut Machine) {{\n",
+            funs.len(),
+            funs.join(", ")
+        ))
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        let mut result = String::from("}\n");
+        // Without foreign functions, the program can run on its own.
+        if self.ffi.is_empty() {
+            result += r#"
+/// A device using the standard input and output of the process.
+#[derive(Debug, Default)]
+pub struct StdDevice {
+    /// The FFI channel.
+    pub channel: VecDeque<Cell>,
+}
+
+impl StdDevice {
+    fn get_byte(&mut self) -> u8 {
+        let mut buf = [0];
+        std::io::stdout().flush().ok();
+        std::io::stdin().read(&mut buf).ok();
+        buf[0]
+    }
+}
+
+impl Device for StdDevice {
+    fn get(&mut self, mode: &str, _channel: usize) -> Cell {
+        match mode {
+            "stdin.char" => self.get_byte() as Cell,
+            "stdin.int" => {
+                let mut byte = self.get_byte();
+                while byte.is_ascii_whitespace() {
+                    byte = self.get_byte();
+                }
+                let mut result: Cell = 0;
+                while byte.is_ascii_digit() {
+                    result = result.wrapping_mul(10).wrapping_add((byte - b'0') as Cell);
+                    byte = self.get_byte();
+                }
+                result
+            }
+            "stdin.float" => {
+                let mut line = String::new();
+                std::io::stdout().flush().ok();
+                std::io::stdin().read_line(&mut line).ok();
+                to_cell(line.trim().parse().unwrap_or(0.0))
+            }
+            _ => 0,
+        }
+    }
+
+    fn put(&mut self, val: Cell, mode: &str, _channel: usize) {
+        match mode {
+            "stdout.char" => print!("{}", val as u8 as char),
+            "stdout.int" => print!("{val}"),
+            "stdout.float" => print!("{:?}", to_float(val)),
+            "stderr.char" => eprint!("{}", val as u8 as char),
+            "stderr.int" => eprint!("{val}"),
+            "stderr.float" => eprint!("{:?}", to_float(val)),
+            _ => {}
+        }
+    }
+
+    fn peek(&mut self) -> Cell {
+        self.channel.pop_front().expect("peeked from an empty FFI channel")
+    }
+
+    fn poke(&mut self, val: Cell) {
+        self.channel.push_back(val);
+    }
+}
+
+impl Ffi for StdDevice {}
+
+fn main() {
+    let code = run(&mut StdDevice::default());
+    std::io::stdout().flush().ok();
+    std::process::exit(code as i32);
+}
+"#;
+        }
+        Some(result)
+    }
+}
+
+impl CompiledTarget for Rust {}
@@ -1,23 +1,105 @@
-//! # C Target
+//! # Sage Lisp Target
 //!
-//! An implementation of the virtual machine for the C language.
+//! A target whose backend is written in Sage Lisp.
 //!
-//! This allows the virtual machine to target C programs.
+//! The backend is an environment which defines `build-core` and `build-std`
+//! functions. It can come from the attributes of the program being compiled,
+//! or be loaded from separate backend files, so that a backend can be reused
+//! across programs.
 use super::{Architecture, CompiledTarget};
 use crate::{
     side_effects::{Input, Output},
     vm::{CoreOp, StandardOp},
 };
-
-/// The type for the C target which implements the `Target` trait.
-/// This allows the compiler to target the C language.
+use nom::error::{convert_error, VerboseError};
+use sage_lisp::{Env, Expr};
+
+/// The type for the Sage Lisp target which implements the `Target` trait.
+/// This allows the compiler to target anything a Sage Lisp backend can build.
+///
+/// Programs are passed to the environment's `build-core` and `build-std`
+/// functions as serialized instructions, so they must handle every instruction
+/// themselves (including `Halt`, which stops the program with an exit code).
+/// A backend which only defines `build-std` is also given core programs,
+/// converted into standard programs.
 #[derive(Default)]
-pub struct SageLisp(sage_lisp::Env);
+pub struct SageLisp(Env);
 
 impl SageLisp {
-    pub fn new(env: sage_lisp::Env) -> Self {
+    pub fn new(env: Env) -> Self {
         Self(env)
     }
+
+    /// Create a backend by evaluating backend files (given as their names
+    /// and contents) in the environment, in order. The files must define
+    /// `build-core` or `build-std`.
+    pub fn load(mut env: Env, files: &[(String, String)]) -> Result<Self, String> {
+        for (name, src) in files {
+            eval_file(&mut env, name, src)?;
+        }
+        let backend = Self(env);
+        backend.validate()?;
+        Ok(backend)
+    }
+
+    /// Check that the backend defines a function to build programs with.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.defines("build-core") || self.defines("build-std") {
+            Ok(())
+        } else {
+            Err("Sage Lisp backend does not define `build-core` or `build-std`".to_string())
+        }
+    }
+
+    /// Does the environment define the given symbol?
+    fn defines(&self, name: &str) -> bool {
+        self.0.get(&Expr::symbol(name)).is_some()
+    }
+
+    /// Call one of the backend's functions with a program, and return the code it built.
+    fn build<T: serde::Serialize>(&mut self, function: &str, program: T) -> Result<String, String> {
+        let arg = Expr::serialize(program);
+        match self.0.eval(Expr::symbol(function).apply(&[arg.quote()])) {
+            Expr::String(code) => Ok(code),
+            result @ Expr::Err(_) => Err(format!("Sage Lisp backend failed: {result}")),
+            result => Ok(result.to_string()),
+        }
+    }
+}
+
+/// Evaluate every expression in a backend file.
+fn eval_file(env: &mut Env, name: &str, src: &str) -> Result<(), String> {
+    let mut input = skip_comments(src);
+    while !input.is_empty() {
+        let (rest, expr) = match sage_lisp::parse_expr::<VerboseError<&str>>(input) {
+            Ok(result) => result,
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                return Err(format!(
+                    "Could not parse `{name}`: {}",
+                    convert_error(src, e)
+                ))
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                return Err(format!("Could not parse `{name}`: unexpected end of file"))
+            }
+        };
+        if let result @ Expr::Err(_) = env.eval(expr) {
+            return Err(format!("Error while loading `{name}`: {result}"));
+        }
+        input = skip_comments(rest);
+    }
+    Ok(())
+}
+
+/// Skip the whitespace and `;` line comments at the start of the input.
+fn skip_comments(mut input: &str) -> &str {
+    loop {
+        input = input.trim_start();
+        match input.strip_prefix(';') {
+            Some(comment) => input = comment.find('\n').map_or("", |end| &comment[end..]),
+            None => return input,
+        }
+    }
 }
 
 impl Architecture for SageLisp {
@@ -88,26 +170,21 @@ impl Architecture for SageLisp {
 
 impl CompiledTarget for SageLisp {
     fn build_core(&mut self, program: &crate::vm::CoreProgram) -> Result<String, String> {
-        // Try to evaluate the `build-core` function from the environment on the input.
-        let arg = sage_lisp::Expr::serialize(program);
-        let c = sage_lisp::Expr::symbol("build-core");
-        let s = sage_lisp::Expr::symbol("build-std");
-        let result = if self.0.get(&c).is_some() {
-            self.0.eval(c.apply(&[arg.quote()]))
+        // Use the `build-core` function from the environment if there is one.
+        if self.defines("build-core") {
+            self.build("build-core", program)
         } else {
-            self.0.eval(s.apply(&[sage_lisp::Expr::serialize(crate::vm::StandardProgram::from(program.clone())).quote()]))
-        };
-
-        Ok(result.to_string())
+            self.build_std(&crate::vm::StandardProgram::from(program.clone()))
+        }
     }
 
     fn build_std(&mut self, program: &crate::vm::StandardProgram) -> Result<String, String> {
-        // Try to evaluate the `build-core` function from the environment on the input.
-        let arg = sage_lisp::Expr::serialize(program);
-        let call = sage_lisp::Expr::symbol("build-std").apply(&[arg.quote()]);
-
-        let result = self.0.eval(call);
-
-        Ok(result.to_string())
+        if !self.defines("build-std") {
+            return Err(
+                "Sage Lisp backend does not define `build-std`, so it can't build programs with standard instructions"
+                    .to_string(),
+            );
+        }
+        self.build("build-std", program)
     }
 }
@@ -1,36 +1,98 @@
-//! # C Target
+//! # SageOS Target
 //!
-//! An implementation of the virtual machine for the C language.
+//! An implementation of the virtual machine for programs running on
+//! [SageOS](https://github.com/adam-mcdaniel/sage-os), an operating system
+//! with a userspace written in Sage.
 //!
-//! This allows the virtual machine to target C programs.
+//! This target builds freestanding C source code: instead of the C standard
+//! library, the program uses the system calls declared in SageOS's `sage.h`.
+//! The header is expected to provide `putchar`, `getchar`, `printf`, `exit`,
+//! `memset`, `memcpy`, `strlen` and the math functions, along with the
+//! SageOS system calls for the filesystem, processes, environment variables,
+//! the screen, and the keyboard and tablet.
+//!
+//! All of the program's memory, including the turing tape, is allocated from
+//! a single statically allocated heap. The console gives the program one
+//! character at a time, so input is read a line at a time, and echoed back
+//! as it's typed.
 //!
 //! ## Portability
 //!
-//! Right now, this target only supports GCC due to a quirk
-//! with the way this implementation compiles functions.
-//! For some reason, Clang doesn't like nested functions,
-//! even though the function's addresses can still be known
-//! as labels at compile time. I'm really not sure why Clang
-//! *chooses* not to compile nested functions. This can be
-//! fixed by this implementations by just moving function definitions
-//! code outside of the `main` function, since the virtual machine
-//! does not depend on defining functions at runtime.
-use super::{Architecture, CompiledTarget};
+//! Like the C target, this target only supports GCC.
+use super::{Architecture, CompiledTarget, C, SPARE_CELLS_PER_BLOCK};
 use crate::{
-    side_effects::{Input, InputMode, Output, OutputMode},
+    side_effects::{FFIBinding, Input, InputMode, Output, OutputMode},
     vm::{CoreOp, StandardOp},
 };
 
-/// The type for the C target which implements the `Target` trait.
-/// This allows the compiler to target the C language.
-#[derive(Default)]
-pub struct SageOS;
+/// The type for the SageOS target which implements the `Target` trait.
+/// This allows the compiler to target programs for SageOS.
+///
+/// The target is configured with the sizes of the memory of the generated
+/// program, which is all allocated from a heap of `heap_bytes` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SageOS {
+    /// The number of cells on the turing tape.
+    pub tape_cells: usize,
+    /// The maximum depth of the stack of dereferences.
+    pub refs_depth: usize,
+    /// The number of cells in the vector register.
+    pub register_cells: usize,
+    /// The number of cells in the channel for foreign function calls.
+    pub ffi_channel_cells: usize,
+    /// The number of entries in the table of functions.
+    pub max_functions: usize,
+    /// The number of bytes in the heap.
+    pub heap_bytes: usize,
+    /// Echo the characters typed on the console back to it.
+    pub echo: bool,
+}
+
+impl Default for SageOS {
+    fn default() -> Self {
+        Self {
+            tape_cells: 262144,
+            refs_depth: 1024,
+            register_cells: 1024,
+            ffi_channel_cells: 256,
+            max_functions: 10000,
+            heap_bytes: 0x1000000,
+            echo: true,
+        }
+    }
+}
+
+impl SageOS {
+    /// The C target with the same memory, which builds the instructions
+    /// that don't depend on the operating system.
+    fn c(&self) -> C {
+        C {
+            tape_cells: self.tape_cells,
+            refs_depth: self.refs_depth,
+            register_cells: self.register_cells,
+            ffi_channel_cells: self.ffi_channel_cells,
+            max_functions: self.max_functions,
+            checked: false,
+            foreign_functions: vec![],
+        }
+    }
+}
 
 impl Architecture for SageOS {
+    fn reserve_call_stack(&mut self, cells: usize) {
+        self.tape_cells += cells;
+        // The tape is allocated from the heap.
+        self.heap_bytes += cells * std::mem::size_of::<i64>();
+    }
+
     fn supports_input(&self, i: &Input) -> bool {
         matches!(
             i.mode,
-            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+            InputMode::StdinChar
+                | InputMode::StdinFloat
+                | InputMode::StdinInt
+                | InputMode::Clock
+                | InputMode::Random
         )
     }
 
@@ -41,80 +103,42 @@ impl Architecture for SageOS {
         )
     }
 
+    fn supports_ffi(&self, binding: &FFIBinding) -> bool {
+        // The only foreign functions are the ones the runtime defines, and their
+        // arguments and return values have to fit in the FFI channel.
+        RUNTIME.contains(&format!("\nvoid __{}() {{", binding.name))
+            && binding.input_cells.max(binding.output_cells) <= self.ffi_channel_cells
+    }
+
     fn op(&mut self, op: &CoreOp) -> String {
-        match op {
-            CoreOp::Comment(text) => {
-                // format!("// {}", text.replace('\n', "\n// ").replace('\r', ""))
-                let mut comment = String::new();
-                for line in text.split('\n') {
-                    comment += &format!("// {}", line.trim());
-                }
-                comment
-            }
-            CoreOp::While => "while (reg.i) {".to_string(),
-            CoreOp::If => "if (reg.i) {".to_string(),
-            CoreOp::Else => "} else {".to_string(),
-            CoreOp::Set(n) => format!("reg.i = {};", n),
-            CoreOp::Call => "funs[reg.i]();".to_string(),
-            CoreOp::Return => "return;".to_string(),
-            CoreOp::Save => "*ptr = reg;".to_string(),
-            CoreOp::Restore => "reg = *ptr;".to_string(),
-            CoreOp::Move(n) => format!("ptr += {};", n),
-            CoreOp::Where => "reg.p = ptr;".to_string(),
-            CoreOp::Deref => "*ref++ = ptr; ptr = ptr->p;".to_string(),
-            CoreOp::Refer => "ptr = *--ref;".to_string(),
-            CoreOp::Index => "reg.p += ptr->i;".to_string(),
-            CoreOp::BitwiseNand => "reg.i = ~(reg.i & ptr->i);".to_string(),
-            CoreOp::Add => "reg.i += ptr->i;".to_string(),
-            CoreOp::Sub => "reg.i -= ptr->i;".to_string(),
-            CoreOp::Mul => "reg.i *= ptr->i;".to_string(),
-            CoreOp::Div => "reg.i /= ptr->i;".to_string(),
-            CoreOp::Rem => "reg.i %= ptr->i;".to_string(),
-            CoreOp::IsNonNegative => "reg.i = reg.i >= 0;".to_string(),
-            _ => unreachable!("Invalid op for SageOS target {op:?}"),
-        }
+        self.c().op(op)
     }
 
     fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
         Ok(match op {
-            StandardOp::Call(ffi) => format!("__{}();", ffi.name),
+            StandardOp::Alloc => format!(
+                "scalar_reg.p = (cell*)salloc((scalar_reg.i + {SPARE_CELLS_PER_BLOCK}) * sizeof(cell));"
+            ),
+            StandardOp::Free => "sfree(scalar_reg.p);".to_string(),
             StandardOp::Peek => self.peek()?,
             StandardOp::Poke => self.poke()?,
-            StandardOp::Set(n) => format!("reg.f = {};", n),
-            StandardOp::ToInt => "reg.i = reg.f;".to_string(),
-            StandardOp::ToFloat => "reg.f = reg.i;".to_string(),
-            StandardOp::ACos => "reg.f = acos(reg.f);".to_string(),
-            StandardOp::ASin => "reg.f = asin(reg.f);".to_string(),
-            StandardOp::ATan => "reg.f = atan(reg.f);".to_string(),
-            StandardOp::Sin => "reg.f = sin(reg.f);".to_string(),
-            StandardOp::Cos => "reg.f = cos(reg.f);".to_string(),
-            StandardOp::Tan => "reg.f = tan(reg.f);".to_string(),
-            StandardOp::Add => "reg.f += ptr->f;".to_string(),
-            StandardOp::Sub => "reg.f -= ptr->f;".to_string(),
-            StandardOp::Mul => "reg.f *= ptr->f;".to_string(),
-            StandardOp::Div => "reg.f /= ptr->f;".to_string(),
-            StandardOp::Rem => "reg.f = fmod(reg.f, ptr->f);".to_string(),
-            StandardOp::Pow => "reg.f = pow(reg.f, ptr->f);".to_string(),
-            StandardOp::IsNonNegative => "reg.i = reg.f >= 0;".to_string(),
-            StandardOp::Alloc => "reg.p = (cell*)salloc(reg.i * sizeof(reg));".to_string(),
-            StandardOp::Free => "sfree((void*)reg.p);".to_string(),
-            _ => return Err(format!("Invalid standard op for SageOS target {op:?}")),
+            op => self
+                .c()
+                .std_op(op)
+                .map_err(|_| format!("Invalid standard op for SageOS target {op:?}"))?,
         })
     }
 
     fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
-        match (matching, fun) {
-            (CoreOp::Function | CoreOp::While | CoreOp::If | CoreOp::Else, _) => "}".to_string(),
-            _ => unreachable!("Invalid matching op for end"),
-        }
+        self.c().end(matching, fun)
     }
 
     fn declare_proc(&mut self, label_id: usize) -> String {
-        format!("void f{label_id}() {{")
+        self.c().declare_proc(label_id)
     }
 
     fn name(&self) -> &str {
-        "C"
+        "SageOS"
     }
     fn version(&self) -> &str {
         "1.0"
@@ -125,135 +149,182 @@ impl Architecture for SageOS {
     }
 
     fn get(&mut self, src: &Input) -> Result<String, String> {
-        let ch = src.channel.0;
         match src.mode {
-            InputMode::StdinChar => Ok("reg.i = sgetchar();".to_string()),
-            InputMode::StdinInt => Ok("scanf(\"%lld\", &reg.i);".to_string()),
-            InputMode::StdinFloat => Ok("scanf(\"%lf\", &reg.f);".to_string()),
-            InputMode::Thermometer => Ok("reg.f = 293.15;".to_string()),
-            InputMode::Clock => Ok("reg.i = time(NULL);".to_string()),
-            InputMode::Random => Ok("reg.i = rand();".to_string()),
-            InputMode::Button => Ok(format!(
-                "printf(\"Button #{ch}: \"); reg.i = getchar() == 'y'; while (getchar() != 10);"
-            )),
+            InputMode::StdinChar => Ok("scalar_reg.i = sgetchar();".to_string()),
+            InputMode::StdinInt => Ok("scalar_reg.i = sgetint(10);".to_string()),
+            InputMode::StdinFloat => Ok("scalar_reg.f = sgetfloat();".to_string()),
+            InputMode::Clock => Ok("scalar_reg.i = get_time();".to_string()),
+            InputMode::Random => Ok("scalar_reg.i = sage_rand();".to_string()),
             _ => Err("Input not supported by this target".to_string()),
         }
     }
 
     fn put(&mut self, dst: &Output) -> Result<String, String> {
         match dst.mode {
-            OutputMode::StdoutChar => Ok("putchar(reg.i);".to_string()),
-            OutputMode::StdoutInt => Ok("putint(reg.i, 10);".to_string()),
-            OutputMode::StdoutFloat => Ok("putfloat(reg.f);".to_string()),
-            OutputMode::StderrChar => Ok("fprintf(stderr, \"%c\", reg.i);".to_string()),
-            OutputMode::StderrInt => Ok("fprintf(stderr, \"%lld\", reg.i);".to_string()),
-            OutputMode::StderrFloat => Ok("fprintf(stderr, \"%lf\", reg.f);".to_string()),
-            OutputMode::Heater => Ok("printf(\"Heating...\");".to_string()),
-            OutputMode::Cooler => Ok("printf(\"Cooling...\");".to_string()),
+            OutputMode::StdoutChar => Ok("putchar(scalar_reg.i);".to_string()),
+            OutputMode::StdoutInt => Ok("putint(scalar_reg.i, 10);".to_string()),
+            OutputMode::StdoutFloat => Ok("putfloat(scalar_reg.f);".to_string()),
             _ => Err("Output not supported by this target".to_string()),
         }
     }
     fn peek(&mut self) -> Result<String, String> {
-        Ok("reg = *(ffi_ptr--);".to_string())
+        self.c().peek()
     }
     fn poke(&mut self) -> Result<String, String> {
-        Ok("*(++ffi_ptr) = reg;".to_string())
+        self.c().poke()
     }
     fn prelude(&self, _is_core: bool) -> Option<String> {
-        /*
-                let mut result = r#"#include <stdlib.h>
-                #include <stdio.h>
-                #include <stdlib.h>
-        #include <math.h>
-        #include <string.h>
-
-        union cell {
-        long long int i;
-        double f;
-        union cell *p;
-        } tape[200000], *refs[1024], *ptr = tape, **ref = refs, reg, ffi_channel[256], *ffi_ptr = ffi_channel;
-
-        void __unsafe_memcpy() {
-            union cell *dst = ffi_ptr[-2].p, *src = ffi_ptr[-1].p;
-            long long int n = ffi_ptr[0].i;
-            memcpy(dst, src, n * sizeof(union cell));
-        }
-
-        unsigned int ref_ptr = 0;
-        void (*funs[10000])(void);
-        "#
-                .to_string();
-            */
-        let result = r#"#include <stdio.h>
+        let result = format!(
+            r#"#include <stdint.h>
+#include <stdbool.h>
+#include <stdio.h>
 #include <string.h>
-#include <stdint.h>
-#include <ctype.h>
+#include <math.h>
 #include <sage.h>
 
+#define SAGE_TAPE_CELLS {tape_cells}
+#define SAGE_REFS_DEPTH {refs_depth}
+#define SAGE_REGISTER_CELLS {register_cells}
+#define SAGE_FFI_CHANNEL_CELLS {ffi_channel_cells}
+#define SAGE_MAX_FUNCTIONS {max_functions}
+#define SAGE_HEAP_BYTES {heap_bytes}
+#define SAGE_ECHO {echo}
+
+"#,
+            tape_cells = self.tape_cells,
+            refs_depth = self.refs_depth,
+            register_cells = self.register_cells,
+            ffi_channel_cells = self.ffi_channel_cells,
+            max_functions = self.max_functions,
+            heap_bytes = self.heap_bytes,
+            echo = self.echo as u8,
+        );
+
+        Some(result + RUNTIME)
+    }
+
+    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
+        let mut result = String::from("int main () {\n\tif (!sage_init()) return 1;\n");
+        for fun in funs {
+            result += &format!("\tfuns[{fun}] = f{fun};\n", fun = fun)
+        }
+        Some(result)
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some("return 0;\n}".to_string())
+    }
+}
+
+impl CompiledTarget for SageOS {}
+
+/// The runtime which SageOS programs are built on: the memory of the
+/// virtual machine, the heap, console I/O, and the foreign functions.
+const RUNTIME: &str = r#"
 typedef union cell {
     int64_t i;
     double f;
     union cell *p;
 } cell;
 
-union cell *tape, *refs, *ptr, **ref, reg, *ffi_channel, *ffi_ptr;
-uint8_t *heap_start, *heap_end;
-uint64_t heap_remaining;
+cell *tape, **refs, *ptr, scalar_reg, *vector_reg, tmp_reg, *ffi_channel, *ffi_ptr;
 unsigned int ref_ptr = 0;
 void (**funs)(void);
 
 /*
-void __get_file_size() {
-    const cell *file_name_cells = ffi_ptr[0].p;
-
-    char file_name[1024];
-    uint64_t i = 0;
-    for (; file_name_cells[i].i; i++) {
-        file_name[i] = file_name_cells[i].i;
-    }
-    file_name[i] = '\0';
+ * The heap which all of the program's memory is allocated from.
+ * Every block on the heap starts with a header: its size in words
+ * (including the header), with the top bit set while it's in use.
+ */
+#define SAGE_USED ((uint64_t)1 << 63)
+uint64_t sage_heap[SAGE_HEAP_BYTES / sizeof(uint64_t)];
+uint64_t *heap_start, *heap_end;
+uint64_t heap_remaining;
 
-    FILE *fp = fopen(file_name, "r");
-	if (fp == NULL) {
-		ffi_ptr[0].i = -1;
-		return;
-	}
+void salloc_init(uint64_t *start, uint64_t *end) {
+    heap_start = start;
+    heap_end = end;
+    *heap_start = end - start;
+    heap_remaining = (uintptr_t)end - (uintptr_t)start;
+}
 
-    fseek(fp, 0L, SEEK_END);
-    long long int size = ftell(fp);
-    fclose(fp);
-    ffi_ptr[0].i = size;
+void *salloc(uint64_t bytes) {
+    uint64_t words = (bytes + sizeof(uint64_t) - 1) / sizeof(uint64_t) + 1;
+    for (uint64_t *block = heap_start; block < heap_end; block += *block & ~SAGE_USED) {
+        if (*block & SAGE_USED) {
+            continue;
+        }
+        // Merge the free blocks after this one into it.
+        while (block + *block < heap_end && !(block[*block] & SAGE_USED)) {
+            *block += block[*block];
+        }
+        if (*block < words) {
+            continue;
+        }
+        // Split off the rest of the block.
+        if (*block > words) {
+            block[words] = *block - words;
+            *block = words;
+        }
+        heap_remaining -= words * sizeof(uint64_t);
+        memset(block + 1, 0, (words - 1) * sizeof(uint64_t));
+        *block |= SAGE_USED;
+        return block + 1;
+    }
+    return NULL;
 }
 
+void sfree(void *ptr) {
+    uint64_t *block = (uint64_t*)ptr - 1;
+    if (block < heap_start || block >= heap_end || !(*block & SAGE_USED)) {
+        return;
+    }
+    *block &= ~SAGE_USED;
+    heap_remaining += *block * sizeof(uint64_t);
+}
 
-void __read_file() {
-    const cell *file_name_cells = ffi_ptr[-2].p;
-    cell *buffer = ffi_ptr[-1].p;
-    long long int size = ffi_ptr[0].i;
-    ffi_ptr -= 3;
+int sage_init() {
+    salloc_init(sage_heap, sage_heap + sizeof(sage_heap) / sizeof(uint64_t));
 
-    char file_name[1024];
-    uint64_t i = 0;
-    for (; file_name_cells[i].i; i++) {
-        file_name[i] = file_name_cells[i].i;
+    funs = (void(**)(void))salloc(SAGE_MAX_FUNCTIONS * sizeof(void*));
+    ffi_channel = (cell*)salloc(SAGE_FFI_CHANNEL_CELLS * sizeof(cell));
+    tape = (cell*)salloc(SAGE_TAPE_CELLS * sizeof(cell));
+    refs = (cell**)salloc(SAGE_REFS_DEPTH * sizeof(cell*));
+    vector_reg = (cell*)salloc(SAGE_REGISTER_CELLS * sizeof(cell));
+    if (!funs || !ffi_channel || !tape || !refs || !vector_reg) {
+        printf("error: the heap is too small for the program\n");
+        return 0;
     }
-    file_name[i] = '\0';
 
-    FILE *fp = fopen(file_name, "r");
-	if (fp == NULL) {
-		ffi_ptr[0].i = -1;
-		return;
-	}
-    char tmp_buffer[size];
-    fread(tmp_buffer, sizeof(char), size, fp);
-    fclose(fp);
-    for (int i = 0; i < size; i++) {
-        buffer[i].i = tmp_buffer[i];
-    }
+    ptr = tape;
+    ffi_ptr = ffi_channel;
+    return 1;
+}
 
-    ffi_ptr[0].i = size;
+void putint(int64_t num, int64_t base) {
+    char buf[65];
+    int i = sizeof(buf);
+    uint64_t n = num < 0 ? -(uint64_t)num : (uint64_t)num;
+    if (base < 2 || base > 36) {
+        base = 10;
+    }
+    buf[--i] = '\0';
+    do {
+        buf[--i] = "0123456789abcdefghijklmnopqrstuvwxyz"[n % base];
+        n /= base;
+    } while (n);
+    if (num < 0) {
+        putchar('-');
+    }
+    while (buf[i]) {
+        putchar(buf[i++]);
+    }
 }
-*/
+
 void __memcpy() {
     union cell *dst = ffi_ptr[-2].p, *src = ffi_ptr[-1].p;
     long long int n = ffi_ptr[0].i;
@@ -340,23 +411,6 @@ void __puts() {
     }
 }
 
-void putint(int64_t num, int64_t base) {
-	if (base == 10) {
-		printf("%ld", num);
-	}
-	else if (base == 16) {
-		printf("%lx", num);
-	}
-	else if (base == 8) {
-		printf("%lo", num);
-	}
-	else if (base == 2) {
-		printf("%lx", num);
-	}
-	else {
-		printf("%ld", num);
-	}
-}
 
 void __putint() {
     int64_t num = ffi_ptr[-1].i;
@@ -473,56 +527,162 @@ char *ftoa(double f, char * buf, int precision)
 void putfloat(double num) {
     char buf[256];
     ftoa(num, buf, 6);
-    for (int i = 0; i < 256; i++) {
-        if (buf[i] == 0) {
-            break;
-        }
+    // Drop the trailing zeros, but keep a digit after the decimal point.
+    int len = strlen(buf);
+    while (len > 2 && buf[len - 1] == '0' && buf[len - 2] != '.') {
+        buf[--len] = '\0';
+    }
+    for (int i = 0; buf[i]; i++) {
         putchar(buf[i]);
     }
 }
 
-void salloc_init(uint8_t *start, uint8_t *end) {
-    heap_start = start;
-    heap_end = end;
-    heap_remaining = ((uintptr_t)end - (uintptr_t)start);
-    // printf("salloc_init: %p - %p\n", heap_start, heap_end);
-}
-
-void *salloc(uint32_t bytes) {
-	// printf("salloc: %d\n", bytes);
-	uint64_t words = (bytes + 128) / 8;
-    uint64_t *ret = (uint64_t*)heap_start;
-    while (*ret != 0) {
-		// printf("salloc: %p taken with reserved words=%ld, bytes=%ld\n", ret, *ret, *ret * 8);
-        ret += *ret;
-        if ((uintptr_t)ret >= (uintptr_t)heap_end) {
-            // printf("salloc: out of memory\n");
-            // printf("salloc: tried to allocate %d bytes\n", bytes);
-            return NULL;
+/*
+ * The console gives the program one character at a time, so input
+ * is read a line at a time and edited as it's typed.
+ */
+char line[256];
+int line_len = 0, line_at = 0, at_eof = 0;
+
+void readline() {
+    uint32_t c;
+    line_len = line_at = 0;
+    do {
+        c = getchar();
+        if (c == '\r' || c == '\n') {
+            line[line_len++] = '\n';
+#if SAGE_ECHO
+            putchar('\r');
+            putchar('\n');
+#endif
+            return;
+        }
+        else if (c == '\b' || c == 127) {
+            // Backspace or "delete"
+            if (line_len > 0) {
+#if SAGE_ECHO
+                // Erase character
+                printf("\b \b");
+#endif
+                line_len--;
+            }
+        }
+        else if (c == 0x1B) {
+            // Escape sequences (like the arrow keys) are ignored
+            getchar();
+            getchar();
+        }
+        else if (c == 4 || c == (uint32_t)-1) {
+            // End of transmission, or the end of the file
+            at_eof = 1;
+            return;
+        }
+        else if (c != 255) {
+            if (line_len < (int)sizeof(line) - 1) {
+                line[line_len++] = c;
+#if SAGE_ECHO
+                // Echo it back out to the user
+                putchar(c);
+#endif
+            }
+        }
+    } while (1);
+}
+
+char sgetchar() {
+    if (line_at >= line_len) {
+        if (at_eof) {
+            return '\0';
+        }
+        readline();
+        if (line_at >= line_len) {
+            return '\0';
         }
     }
-    *ret = words;
-    ret++;
-    uint64_t amount_allocated = words * 8;
-    heap_remaining -= amount_allocated;
-	// printf("salloc: %p (remaining=%d)\n", ret, (uint32_t)(heap_end - ((uint8_t*)ret + bytes)));
-    return ret;
+    return line[line_at++];
 }
 
-void sfree(void *ptr) {
-	// printf("sfree: %p\n", ptr);
-	if ((uintptr_t)ptr < (uintptr_t)heap_start || (uintptr_t)ptr >= (uintptr_t)heap_end) {
-		// printf("sfree: invalid pointer\n");
-		return;
-	}
+void sungetchar(char c) {
+    if (c && line_at > 0) {
+        line_at--;
+    }
+}
+
+int isws(char c) {
+    return c == ' ' || c == '\t' || c == '\n' || c == '\r';
+}
+
+int64_t digit_value(char c) {
+    if (c >= '0' && c <= '9') {
+        return c - '0';
+    }
+    else if (c >= 'a' && c <= 'z') {
+        return c - 'a' + 10;
+    }
+    else if (c >= 'A' && c <= 'Z') {
+        return c - 'A' + 10;
+    }
+    return 36;
+}
+
+int64_t sgetint(uint64_t radix) {
+    int64_t num = 0;
+    int64_t sign = 1;
+    char c = sgetchar();
+    while (isws(c)) {
+        c = sgetchar();
+    }
+    if (c == '-') {
+        sign = -1;
+        c = sgetchar();
+    }
+    else if (c == '+') {
+        c = sgetchar();
+    }
+    while (digit_value(c) < (int64_t)radix) {
+        num = num * radix + digit_value(c);
+        c = sgetchar();
+    }
+    sungetchar(c);
+    return num * sign;
+}
 
-    uint64_t *p = ptr;
-    uint64_t words = *(p - 1);
-    memset(p - 1, 0, words / 8);
+double sgetfloat() {
+    double num = 0, scale = 1, sign = 1;
+    char c = sgetchar();
+    while (isws(c)) {
+        c = sgetchar();
+    }
+    if (c == '-') {
+        sign = -1;
+        c = sgetchar();
+    }
+    else if (c == '+') {
+        c = sgetchar();
+    }
+    while (c >= '0' && c <= '9') {
+        num = num * 10 + (c - '0');
+        c = sgetchar();
+    }
+    if (c == '.') {
+        c = sgetchar();
+        while (c >= '0' && c <= '9') {
+            scale /= 10;
+            num += (c - '0') * scale;
+            c = sgetchar();
+        }
+    }
+    sungetchar(c);
+    return num * sign;
+}
 
-    heap_remaining += words * 8;
+uint64_t rand_state = 0x2545F4914F6CDD1D;
 
-    return;
+int64_t sage_rand() {
+    rand_state ^= rand_state << 13;
+    rand_state ^= rand_state >> 7;
+    rand_state ^= rand_state << 17;
+    return rand_state >> 1;
 }
 
 void __malloc() {
@@ -597,7 +757,7 @@ void __path_list_dir() {
 }
 
 void __exit() {
-    exit();
+    exit(0);
 }
 
 void __spawn_process() {
@@ -609,139 +769,6 @@ void __spawn_process() {
     ffi_ptr[0].i = spawn_process(path_str);
 }
 
-char *line;
-int at, len, max;
-
-void sgetchar_init() {
-    line = (char*)salloc(1024);
-    at = 0;
-    len = 0;
-    max = 1024;
-}
-
-void readline() {
-    uint32_t c = '\0';
-    int at = 0;
-    do {
-        c = getchar();
-        if (c == '\r' || c == '\n') {
-            line[at++] = '\n';
-            line[at] = '\0';
-            putchar('\r');
-            putchar('\n');
-            len = at;
-            return;
-        }
-        else if (c == '\b' || c == 127) {
-            // Backspace or "delete"
-            if (at > 0) {
-                // Erase character 
-                printf("\b \b");
-                at--;
-            }
-        }
-        else if (c == 0x1B) {
-            // Escape sequence
-            char esc1 = getchar();
-            char esc2 = getchar();
-            if (esc1 == 0x5B) {
-                switch (esc2) {
-                    case 0x41:
-                        printf("UP\n");
-                    break;
-                    case 0x42:
-                        printf("DOWN\n");
-                    break;
-                    case 0x43:
-                        printf("RIGHT\n");
-                    break;
-                    case 0x44:
-                        printf("LEFT\n");
-                    break;
-                }
-            }
-        }
-        else if (c == 4) {
-            // EOF
-            printf("\nEOF\n");
-            break;
-        }
-        else if (c != 255) {
-            if (at < 255) {
-                line[at++] = c;
-                // Echo it back out to the user
-                putchar(c);
-            }
-        }
-        // else {
-        // 	// sleep(10);
-        // }
-    } while (1);
-}
-
-int cursor = 0;
-
-char sgetchar() {
-    if (len == 0 || cursor >= len || line[at] == '\0') {
-        readline();
-        cursor = 0;
-    }
-
-    if (cursor >= len) {
-        return '\n';
-    }
-
-    if (line[cursor]) {
-        return line[cursor++];
-    }
-
-    return '\0';
-}
-
-int isalpha(char c) {
-    return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z');
-}
-
-int isdigit(char c) {
-    return c >= '0' && c <= '9';
-}
-
-int isalnum(char c) {
-    return isalpha(c) || isdigit(c);
-}
-
-int isws(char c) {
-    return c == ' ' || c == '\t' || c == '\n' || c == '\r';
-}
-
-int64_t sgetint(uint64_t radix) {
-    int64_t num = 0;
-    int64_t sign = 1;
-    int64_t c = sgetchar();
-    while (isws(c)) {
-        c = sgetchar();
-    }
-    if (c == '-') {
-        sign = -1;
-        c = sgetchar();
-    }
-    else if (c == '+') {
-        c = sgetchar();
-    }
-    while (isalnum(c)) {
-        int64_t digit = 0;
-        if (isdigit(c)) {
-            digit = c - '0';
-        }
-        else if (isalpha(c)) {
-            digit = c - 'a' + 10;
-        }
-
-        num = num * radix + digit;
-        c = sgetchar();
-    }
-    return num * sign;
-}
 
 void __getchar() {
     ffi_ptr++;
@@ -931,48 +958,4 @@ void __get_tablet_event() {
     ffi_ptr[-1].i = event.code;
     ffi_ptr[0].i = event.type;
 }
-"#
-        .to_string();
-
-        // if !is_core {
-        //     result = "#include <stdlib.h>\n".to_string() + &result;
-        // }
-
-        Some(result)
-    }
-
-    fn post_funs(&self, funs: Vec<i32>) -> Option<String> {
-        let mut result = String::from(
-            r#"int main () {
-    uint8_t buf[0x800000] = {0};
-    salloc_init(buf, buf + sizeof(buf));
-
-    funs = (void(**)(void))salloc(200 * sizeof(void*));
-    ffi_channel = (cell*)salloc(256 * sizeof(cell));
-    tape = (cell*)salloc(50000 * sizeof(cell));
-    refs = (cell*)salloc(128 * sizeof(cell));
-
-    sgetchar_init();
-
-    ptr = tape;
-    ref = (cell**)refs;
-    reg.i = 0;
-    ffi_ptr = ffi_channel;
-"#,
-        );
-        for fun in funs {
-            result += &format!("\tfuns[{fun}] = f{fun};\n", fun = fun)
-        }
-        Some(result)
-    }
-
-    fn postop(&self) -> Option<String> {
-        Some("\n".to_string())
-    }
-
-    fn postlude(&self, _is_core: bool) -> Option<String> {
-        Some("return 0;\n}".to_string())
-    }
-}
-
-impl CompiledTarget for SageOS {}
+"#;
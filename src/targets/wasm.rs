@@ -0,0 +1,535 @@
+//! # WebAssembly Target
+//!
+//! An implementation of the virtual machine for WebAssembly.
+//!
+//! This allows the virtual machine to target self-contained WebAssembly
+//! modules, written in the WebAssembly text format (WAT).
+//!
+//! ## Memory Layout
+//!
+//! Everything the machine uses is stored in the module's linear memory as
+//! 64-bit cells. Pointers are cell indices (not byte addresses), so pointer
+//! arithmetic is the same as in the interpreter. The memory is laid out as:
+//!
+//! 1. A null cell at index zero, which is never used.
+//! 2. The cells of the vector register after the first (the first cell of the
+//!    register is the `$reg` global).
+//! 3. The stack of pointers saved by `Deref` instructions.
+//! 4. The FFI channel.
+//! 5. The turing tape, where the pointer starts.
+//! 6. The heap used by `Alloc` and `Free`, which grows the memory as needed.
+//!
+//! ## Interface
+//!
+//! The module exports its `memory`, the `ffi_ptr` global (the index of the
+//! top of the FFI channel, relative to its start), and a `main` function which
+//! runs the program.
+//!
+//! The module imports a host function for each kind of I/O the program uses,
+//! keyed by the I/O mode: `sage.get_<mode>` takes the channel and returns the
+//! input, and `sage.put_<mode>` takes the output and the channel. The values are
+//! `f64` for the float modes (such as `StdinFloat`), and `i64` for every other mode.
+//! A `Halt` instruction calls `sage.halt` with the exit code (the host function
+//! should stop the program, and the module traps if it returns). Foreign functions
+//! are imported from the `ffi` module by name, and take no parameters: they use
+//! the FFI channel in the exported memory, so their arguments and return values
+//! have to fit in its `ffi_channel_cells` cells. The trigonometric functions
+//! and `pow` are imported from the `math` module.
+use super::{Architecture, CompiledTarget, SPARE_CELLS_PER_BLOCK};
+use crate::{
+    side_effects::{FFIBinding, Input, Output},
+    vm::{CoreOp, StandardOp},
+};
+use std::collections::BTreeMap;
+
+/// The value of the first cell of the register.
+const REG: &str = "(global.get $reg)";
+/// The cell under the pointer.
+const CELL: &str = "(call $get (global.get $ptr))";
+
+/// The type for the WebAssembly target which implements the `Target` trait.
+/// This allows the compiler to target WebAssembly text format modules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Wasm {
+    /// The number of cells on the turing tape.
+    pub tape_cells: usize,
+    /// The maximum depth of the stack of dereferences.
+    pub refs_depth: usize,
+    /// The number of cells in the vector register.
+    pub register_cells: usize,
+    /// The number of cells in the channel for foreign function calls.
+    pub ffi_channel_cells: usize,
+    /// The host functions imported by the program, by their names.
+    imports: BTreeMap<String, String>,
+}
+
+impl Default for Wasm {
+    fn default() -> Self {
+        Self {
+            tape_cells: 67108864,
+            refs_depth: 1024,
+            register_cells: 1024,
+            ffi_channel_cells: 256,
+            imports: BTreeMap::new(),
+        }
+    }
+}
+
+impl Wasm {
+    /// The index of the second cell of the vector register.
+    const REGISTER_START: usize = 1;
+
+    /// The index of the stack of dereferences.
+    fn refs_start(&self) -> usize {
+        Self::REGISTER_START + self.register_cells
+    }
+
+    /// The index of the FFI channel.
+    fn ffi_channel_start(&self) -> usize {
+        self.refs_start() + self.refs_depth
+    }
+
+    /// The index of the turing tape.
+    fn tape_start(&self) -> usize {
+        self.ffi_channel_start() + self.ffi_channel_cells
+    }
+
+    /// The index of the heap.
+    fn heap_start(&self) -> usize {
+        self.tape_start() + self.tape_cells
+    }
+
+    /// Import a host function, and get its name in the module.
+    fn import(&mut self, module: &str, name: &str, signature: &str) -> String {
+        let id = format!("${module}_{name}");
+        self.imports.insert(
+            id.clone(),
+            format!("(import \"{module}\" \"{name}\" (func {id} {signature}))"),
+        );
+        id
+    }
+
+    /// Apply an operation to the first `n` cells of the register.
+    /// The operation is an expression using `REG` for a cell of the register,
+    /// and `CELL` for the matching cell on the tape.
+    fn vector(n: usize, op: &str) -> String {
+        let mut result = format!(
+            "(global.set $reg {})",
+            op.replace("REG", REG).replace("CELL", CELL)
+        );
+        if n > 1 {
+            let reg = format!(
+                "(i64.add (i64.const {}) (local.get $i))",
+                Self::REGISTER_START - 1
+            );
+            let op = op.replace("REG", &format!("(call $get {reg})")).replace(
+                "CELL",
+                "(call $get (i64.add (global.get $ptr) (local.get $i)))",
+            );
+            result += &Self::for_each_cell(n, &format!("(call $set {reg} {op})"));
+        }
+        result
+    }
+
+    /// Apply a float operation to the first `n` cells of the register.
+    /// The operation is an `f64` expression using `REG` and `CELL` like `vector`.
+    fn float_vector(n: usize, op: &str) -> String {
+        Self::vector(
+            n,
+            &format!(
+                "(i64.reinterpret_f64 {})",
+                op.replace("REG", "(f64.reinterpret_i64 REG)")
+                    .replace("CELL", "(f64.reinterpret_i64 CELL)")
+            ),
+        )
+    }
+
+    /// Execute some code for each cell of the register after the first,
+    /// where `$i` is the index of the cell.
+    fn for_each_cell(n: usize, code: &str) -> String {
+        format!(
+            " (local.set $i (i64.const 1)) (block (loop (br_if 1 (i64.ge_u (local.get $i) (i64.const {n}))) {code} (local.set $i (i64.add (local.get $i) (i64.const 1))) (br 0)))"
+        )
+    }
+
+    /// Copy `n` cells between the register and the tape, after the first cell.
+    fn copy(&self, n: usize, to_tape: bool) -> String {
+        if n <= 1 {
+            return String::new();
+        }
+        let register = format!("(i32.const {})", Self::REGISTER_START * 8);
+        let tape = "(call $addr (i64.add (global.get $ptr) (i64.const 1)))";
+        let (dst, src) = if to_tape {
+            (tape, register.as_str())
+        } else {
+            (register.as_str(), tape)
+        };
+        format!(" (memory.copy {dst} {src} (i32.const {}))", (n - 1) * 8)
+    }
+
+    /// Write a float in the text format.
+    fn float(n: f64) -> String {
+        if n.is_nan() {
+            "nan".to_string()
+        } else if n.is_infinite() {
+            if n > 0.0 { "inf" } else { "-inf" }.to_string()
+        } else {
+            format!("{n:?}")
+        }
+    }
+
+    /// Get the name of an I/O mode for the name of its host function:
+    /// the name of the mode (and its arguments) in snake case.
+    fn mode_name(mode: &impl std::fmt::Debug) -> String {
+        let mut result = String::new();
+        let mut previous = '_';
+        for ch in format!("{mode:?}").chars() {
+            if ch.is_ascii_uppercase() {
+                if previous.is_ascii_lowercase() || previous.is_ascii_digit() {
+                    result.push('_');
+                }
+                result.push(ch.to_ascii_lowercase());
+            } else if ch.is_ascii_alphanumeric() {
+                result.push(ch);
+            } else if !result.ends_with('_') {
+                result.push('_');
+            }
+            previous = ch;
+        }
+        result.trim_end_matches('_').to_string()
+    }
+}
+
+impl Architecture for Wasm {
+    fn reserve_call_stack(&mut self, cells: usize) {
+        self.tape_cells += cells;
+    }
+
+    fn supports_input(&self, _i: &Input) -> bool {
+        true
+    }
+
+    fn supports_output(&self, _o: &Output) -> bool {
+        true
+    }
+
+    fn supports_ffi(&self, binding: &FFIBinding) -> bool {
+        binding.input_cells.max(binding.output_cells) <= self.ffi_channel_cells
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        match op {
+            CoreOp::Comment(text) => {
+                format!(";; {}", text.replace('\n', "\n;; ").replace('\r', ""))
+            }
+            CoreOp::While => "(block (loop (br_if 1 (i64.eqz (global.get $reg)))".to_string(),
+            CoreOp::If => "(if (i64.ne (global.get $reg) (i64.const 0)) (then".to_string(),
+            CoreOp::Else => ") (else".to_string(),
+            CoreOp::Set(n) => {
+                let mut result = format!("(global.set $reg (i64.const {}))", n[0]);
+                for (i, val) in n.iter().enumerate().skip(1) {
+                    result += &format!(
+                        " (call $set (i64.const {}) (i64.const {val}))",
+                        Self::REGISTER_START + i - 1
+                    );
+                }
+                result
+            }
+            CoreOp::Call => {
+                "(call_indirect (type $proc) (i32.wrap_i64 (global.get $reg)))".to_string()
+            }
+            CoreOp::Return => "(return)".to_string(),
+            CoreOp::Halt => {
+                let halt = self.import("sage", "halt", "(param i64)");
+                format!("(call {halt} (global.get $reg)) (unreachable)")
+            }
+            CoreOp::Store(n) => format!("(call $set (global.get $ptr) (global.get $reg)){}", self.copy(*n, true)),
+            CoreOp::Load(n) => format!("(global.set $reg {CELL}){}", self.copy(*n, false)),
+
+            CoreOp::Move(n) => {
+                format!("(global.set $ptr (i64.add (global.get $ptr) (i64.const {n})))")
+            }
+            CoreOp::Where => "(global.set $reg (global.get $ptr))".to_string(),
+            CoreOp::Deref => format!(
+                "(call $set (i64.add (i64.const {}) (global.get $ref_ptr)) (global.get $ptr)) (global.set $ref_ptr (i64.add (global.get $ref_ptr) (i64.const 1))) (global.set $ptr {CELL})",
+                self.refs_start()
+            ),
+            CoreOp::Refer => format!(
+                "(global.set $ref_ptr (i64.sub (global.get $ref_ptr) (i64.const 1))) (global.set $ptr (call $get (i64.add (i64.const {}) (global.get $ref_ptr))))",
+                self.refs_start()
+            ),
+            CoreOp::Offset(offset, n) => Self::vector(*n, &format!("(i64.add REG (i64.const {offset}))")),
+            CoreOp::Index(n) => Self::vector(*n, "(i64.add REG CELL)"),
+
+            CoreOp::Add(n) => Self::vector(*n, "(i64.add REG CELL)"),
+            CoreOp::Sub(n) => Self::vector(*n, "(i64.sub REG CELL)"),
+            CoreOp::Mul(n) => Self::vector(*n, "(i64.mul REG CELL)"),
+            CoreOp::Div(n) => Self::vector(*n, "(call $div REG CELL)"),
+            CoreOp::Rem(n) => Self::vector(*n, "(call $rem REG CELL)"),
+            CoreOp::Neg(n) => Self::vector(*n, "(i64.sub (i64.const 0) REG)"),
+            CoreOp::Inc(n) => Self::vector(*n, "(i64.add REG (i64.const 1))"),
+            CoreOp::Dec(n) => Self::vector(*n, "(i64.sub REG (i64.const 1))"),
+
+            CoreOp::Swap(n) => {
+                let mut result = format!("(global.set $tmp {REG}) (global.set $reg {CELL}) (call $set (global.get $ptr) (global.get $tmp))");
+                if *n > 1 {
+                    let reg = format!("(i64.add (i64.const {}) (local.get $i))", Self::REGISTER_START - 1);
+                    let cell = "(i64.add (global.get $ptr) (local.get $i))";
+                    result += &Self::for_each_cell(
+                        *n,
+                        &format!("(global.set $tmp (call $get {reg})) (call $set {reg} (call $get {cell})) (call $set {cell} (global.get $tmp))"),
+                    );
+                }
+                result
+            }
+
+            CoreOp::And(n) => Self::vector(*n, "(i64.extend_i32_u (i32.and (i64.ne REG (i64.const 0)) (i64.ne CELL (i64.const 0))))"),
+            CoreOp::Or(n) => Self::vector(*n, "(i64.extend_i32_u (i32.or (i64.ne REG (i64.const 0)) (i64.ne CELL (i64.const 0))))"),
+            CoreOp::Not(n) => Self::vector(*n, "(i64.extend_i32_u (i64.eqz REG))"),
+
+            CoreOp::BitwiseNand(n) => Self::vector(*n, "(i64.xor (i64.and REG CELL) (i64.const -1))"),
+            CoreOp::BitwiseAnd(n) => Self::vector(*n, "(i64.and REG CELL)"),
+            CoreOp::BitwiseOr(n) => Self::vector(*n, "(i64.or REG CELL)"),
+            CoreOp::BitwiseXor(n) => Self::vector(*n, "(i64.xor REG CELL)"),
+            CoreOp::BitwiseNot(n) => Self::vector(*n, "(i64.xor REG (i64.const -1))"),
+            CoreOp::LeftShift(n) => Self::vector(*n, "(i64.shl REG CELL)"),
+            CoreOp::LogicalRightShift(n) => Self::vector(*n, "(i64.shr_u REG CELL)"),
+            CoreOp::ArithmeticRightShift(n) => Self::vector(*n, "(i64.shr_s REG CELL)"),
+            CoreOp::IsNonNegative(n) => Self::vector(*n, "(i64.extend_i32_u (i64.ge_s REG (i64.const 0)))"),
+
+            CoreOp::End | CoreOp::Function | CoreOp::Put(_) | CoreOp::Get(_) => {
+                unreachable!("Invalid core op for WebAssembly target")
+            }
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        Ok(match op {
+            StandardOp::Call(ffi) => {
+                let name = self.import("ffi", &ffi.name, "");
+                format!("(call {name})")
+            }
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) => {
+                let mut result = format!(
+                    "(global.set $reg (i64.reinterpret_f64 (f64.const {})))",
+                    Self::float(n[0])
+                );
+                for (i, val) in n.iter().enumerate().skip(1) {
+                    result += &format!(
+                        " (call $set (i64.const {}) (i64.reinterpret_f64 (f64.const {})))",
+                        Self::REGISTER_START + i - 1,
+                        Self::float(*val)
+                    );
+                }
+                result
+            }
+
+            StandardOp::ToInt(n) => {
+                Self::vector(*n, "(i64.trunc_sat_f64_s (f64.reinterpret_i64 REG))")
+            }
+            StandardOp::ToFloat(n) => {
+                Self::vector(*n, "(i64.reinterpret_f64 (f64.convert_i64_s REG))")
+            }
+
+            StandardOp::Add(n) => Self::float_vector(*n, "(f64.add REG CELL)"),
+            StandardOp::Sub(n) => Self::float_vector(*n, "(f64.sub REG CELL)"),
+            StandardOp::Mul(n) => Self::float_vector(*n, "(f64.mul REG CELL)"),
+            StandardOp::Div(n) => Self::float_vector(*n, "(f64.div REG CELL)"),
+            StandardOp::Rem(n) => Self::float_vector(*n, "(call $fmod REG CELL)"),
+            StandardOp::Neg(n) => Self::float_vector(*n, "(f64.neg REG)"),
+            StandardOp::IsNonNegative(n) => Self::vector(
+                *n,
+                "(i64.extend_i32_u (f64.ge (f64.reinterpret_i64 REG) (f64.const 0)))",
+            ),
+
+            StandardOp::Sin(n)
+            | StandardOp::Cos(n)
+            | StandardOp::Tan(n)
+            | StandardOp::ASin(n)
+            | StandardOp::ACos(n)
+            | StandardOp::ATan(n) => {
+                let name = self.import("math", op.name(), "(param f64) (result f64)");
+                Self::float_vector(*n, &format!("(call {name} REG)"))
+            }
+            StandardOp::Pow(n) => {
+                let name = self.import("math", "pow", "(param f64 f64) (result f64)");
+                Self::float_vector(*n, &format!("(call {name} REG CELL)"))
+            }
+
+            StandardOp::Alloc => format!(
+                "(global.set $reg (call $alloc (i64.add (global.get $reg) (i64.const {SPARE_CELLS_PER_BLOCK}))))"
+            ),
+            StandardOp::Free => "(call $free (global.get $reg))".to_string(),
+            StandardOp::CoreOp(_) => unreachable!("Core ops are built by `op`"),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        match matching {
+            CoreOp::While => "(br 0)))".to_string(),
+            CoreOp::If | CoreOp::Else => "))".to_string(),
+            CoreOp::Function => ")".to_string(),
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        format!("(func $f{label_id} (local $i i64)")
+    }
+
+    fn name(&self) -> &str {
+        "WebAssembly"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        let mode = Self::mode_name(&src.mode);
+        let ch = src.channel.0;
+        Ok(if mode.ends_with("float") {
+            let name = self.import("sage", &format!("get_{mode}"), "(param i64) (result f64)");
+            format!("(global.set $reg (i64.reinterpret_f64 (call {name} (i64.const {ch}))))")
+        } else {
+            let name = self.import("sage", &format!("get_{mode}"), "(param i64) (result i64)");
+            format!("(global.set $reg (call {name} (i64.const {ch})))")
+        })
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        let mode = Self::mode_name(&dst.mode);
+        let ch = dst.channel.0;
+        Ok(if mode.ends_with("float") {
+            let name = self.import("sage", &format!("put_{mode}"), "(param f64 i64)");
+            format!("(call {name} (f64.reinterpret_i64 (global.get $reg)) (i64.const {ch}))")
+        } else {
+            let name = self.import("sage", &format!("put_{mode}"), "(param i64 i64)");
+            format!("(call {name} (global.get $reg) (i64.const {ch}))")
+        })
+    }
+
+    fn peek(&mut self) -> Result<String, String> {
+        Ok(format!(
+            "(global.set $reg (call $get (i64.add (i64.const {}) (global.get $ffi_ptr)))) (global.set $ffi_ptr (i64.sub (global.get $ffi_ptr) (i64.const 1)))",
+            self.ffi_channel_start()
+        ))
+    }
+
+    fn poke(&mut self) -> Result<String, String> {
+        Ok(format!(
+            "(global.set $ffi_ptr (i64.add (global.get $ffi_ptr) (i64.const 1))) (call $set (i64.add (i64.const {}) (global.get $ffi_ptr)) (global.get $reg))",
+            self.ffi_channel_start()
+        ))
+    }
+
+    fn reset(&mut self) {
+        // Only import the host functions the next program uses.
+        self.imports.clear();
+    }
+
+    fn prelude(&self, is_core: bool) -> Option<String> {
+        let mut result = String::from("(module\n");
+        for import in self.imports.values() {
+            result += &format!("{import}\n");
+        }
+        let pages = (self.heap_start() * 8).div_ceil(65536);
+        result += &format!(
+            r#"(type $proc (func))
+(memory (export "memory") {pages})
+(global $ptr (mut i64) (i64.const {tape}))
+(global $reg (mut i64) (i64.const 0))
+(global $tmp (mut i64) (i64.const 0))
+(global $ref_ptr (mut i64) (i64.const 0))
+(global $ffi_ptr (export "ffi_ptr") (mut i64) (i64.const 0))
+(global $heap (mut i64) (i64.const {heap}))
+(global $free_list (mut i64) (i64.const 0))
+
+;; Get the byte address of a cell.
+(func $addr (param $i i64) (result i32)
+    (i32.wrap_i64 (i64.shl (local.get $i) (i64.const 3))))
+(func $get (param $i i64) (result i64)
+    (i64.load (call $addr (local.get $i))))
+(func $set (param $i i64) (param $value i64)
+    (i64.store (call $addr (local.get $i)) (local.get $value)))
+
+;; Division and remainder leave the register unchanged when dividing by zero.
+(func $div (param $a i64) (param $b i64) (result i64)
+    (if (i64.eqz (local.get $b)) (then (return (local.get $a))))
+    (if (i64.eq (local.get $b) (i64.const -1)) (then (return (i64.sub (i64.const 0) (local.get $a)))))
+    (i64.div_s (local.get $a) (local.get $b)))
+(func $rem (param $a i64) (param $b i64) (result i64)
+    (if (i64.eqz (local.get $b)) (then (return (local.get $a))))
+    (i64.rem_s (local.get $a) (local.get $b)))
+"#,
+            tape = self.tape_start(),
+            heap = self.heap_start(),
+        );
+
+        if !is_core {
+            result += r#"(func $fmod (param $a f64) (param $b f64) (result f64)
+    (f64.sub (local.get $a) (f64.mul (local.get $b) (f64.trunc (f64.div (local.get $a) (local.get $b))))))
+
+;; Allocate a block of cells. Each block is preceded by a cell with its size,
+;; and freed blocks are kept in a list (linked through their first cell) to be reused.
+(func $alloc (param $n i64) (result i64)
+    (local $previous i64) (local $block i64) (local $pages i64)
+    (if (i64.lt_s (local.get $n) (i64.const 1)) (then (local.set $n (i64.const 1))))
+    (local.set $block (global.get $free_list))
+    (block $done (loop $search
+        (br_if $done (i64.eqz (local.get $block)))
+        (if (i64.ge_s (call $get (i64.sub (local.get $block) (i64.const 1))) (local.get $n))
+            (then
+                (if (i64.eqz (local.get $previous))
+                    (then (global.set $free_list (call $get (local.get $block))))
+                    (else (call $set (local.get $previous) (call $get (local.get $block)))))
+                (return (local.get $block))))
+        (local.set $previous (local.get $block))
+        (local.set $block (call $get (local.get $block)))
+        (br $search)))
+    (local.set $block (i64.add (global.get $heap) (i64.const 1)))
+    (global.set $heap (i64.add (local.get $block) (local.get $n)))
+    (local.set $pages (i64.sub
+        (i64.shr_u (i64.add (i64.shl (global.get $heap) (i64.const 3)) (i64.const 65535)) (i64.const 16))
+        (i64.extend_i32_u (memory.size))))
+    (if (i64.gt_s (local.get $pages) (i64.const 0))
+        (then (if (i32.lt_s (memory.grow (i32.wrap_i64 (local.get $pages))) (i32.const 0)) (then (unreachable)))))
+    (call $set (i64.sub (local.get $block) (i64.const 1)) (local.get $n))
+    (local.get $block))
+(func $free (param $block i64)
+    (call $set (local.get $block) (global.get $free_list))
+    (global.set $free_list (local.get $block)))
+"#;
+        }
+        Some(result)
+    }
+
+    fn post_funs(&self, mut funs: Vec<i32>) -> Option<String> {
+        funs.sort();
+        let mut result = format!("(table {} funcref)\n", funs.len());
+        if !funs.is_empty() {
+            result += "(elem (i32.const 0)";
+            for fun in funs {
+                result += &format!(" $f{fun}");
+            }
+            result += ")\n";
+        }
+        result += "(func $main (export \"main\") (local $i i64)\n";
+        Some(result)
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        Some(")\n)\n".to_string())
+    }
+}
+
+impl CompiledTarget for Wasm {}
@@ -0,0 +1,572 @@
+//! # x86-64 Target
+//!
+//! An implementation of the virtual machine for x86-64 assembly.
+//!
+//! This allows the virtual machine to target GNU assembler (AT&T syntax)
+//! programs for the System V ABI, which can be assembled and linked with
+//! the system C compiler:
+//!
+//! ```bash
+//! $ cc out.s -o out -lm
+//! ```
+//!
+//! ## Machine State
+//!
+//! The state of the virtual machine is kept in callee-saved registers, so
+//! that it survives calls into the C standard library:
+//!
+//! | Register | Contents                                                 |
+//! |----------|----------------------------------------------------------|
+//! | `%r12`   | The tape pointer.                                        |
+//! | `%r13`   | The first cell of the register.                          |
+//! | `%rbx`   | The address of the other cells of the vector register.   |
+//! | `%r14`   | The top of the stack of pointers saved by `Deref`.       |
+//! | `%r15`   | The top of the FFI channel.                              |
+//! | `%rbp`   | The index of the cell in vector instructions.            |
+//!
+//! Like the C target, pointers are machine addresses. `Alloc` and `Free` use
+//! the C library's `malloc` and `free`, and `Get` and `Put` use its stdio.
+//!
+//! ## Functions
+//!
+//! Functions are compiled to procedures called with `call`. When the function
+//! being called is known ahead of time (the register was just set to a constant,
+//! as it is for calls to labels), the procedure is called directly. Otherwise,
+//! it is called through a table of the procedures, indexed by the register.
+//!
+//! Foreign functions are called by their names prefixed with `__`, like in the
+//! C target. They communicate through the global `ffi_channel` and `ffi_ptr`
+//! symbols, which hold the FFI channel and the address of its top cell.
+use super::{Architecture, CompiledTarget, SPARE_CELLS_PER_BLOCK};
+use crate::{
+    side_effects::{Input, InputMode, Output, OutputMode},
+    vm::{CoreOp, StandardOp},
+};
+use std::collections::BTreeSet;
+
+/// The type for the x86-64 target which implements the `Target` trait.
+/// This allows the compiler to target x86-64 GNU assembly for the System V ABI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct X86 {
+    /// The number of cells on the turing tape.
+    pub tape_cells: usize,
+    /// The maximum depth of the stack of dereferences.
+    pub refs_depth: usize,
+    /// The number of cells in the vector register.
+    pub register_cells: usize,
+    /// The number of cells in the channel for foreign function calls.
+    pub ffi_channel_cells: usize,
+
+    /// The number of labels generated so far.
+    labels: usize,
+    /// The labels of the `While` and `If` blocks which haven't ended yet.
+    blocks: Vec<usize>,
+    /// The constant the register was set to by the previous instruction, if any.
+    last_set: Option<i64>,
+    /// Is the code being compiled inside a function?
+    in_function: bool,
+    /// The functions which have been defined.
+    defined: BTreeSet<usize>,
+    /// The functions which are called directly.
+    called: BTreeSet<usize>,
+}
+
+impl Default for X86 {
+    fn default() -> Self {
+        Self {
+            tape_cells: 67108864,
+            refs_depth: 1024,
+            register_cells: 1024,
+            ffi_channel_cells: 256,
+            labels: 0,
+            blocks: vec![],
+            last_set: None,
+            in_function: false,
+            defined: BTreeSet::new(),
+            called: BTreeSet::new(),
+        }
+    }
+}
+
+impl X86 {
+    /// Create a new unique label number.
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels
+    }
+
+    /// Apply an operation to the first `n` cells of the register.
+    ///
+    /// The operation is a sequence of instructions using `{r}` for a cell of the
+    /// register, `{m}` for the matching cell on the tape, and `{l}` for a unique
+    /// label prefix. It may use `%rax` (unless it's the cell of the register),
+    /// and any other caller-saved register.
+    fn vector(&mut self, n: usize, op: &str) -> String {
+        let label = self.label();
+        let mut result = op
+            .replace("{r}", "%r13")
+            .replace("{m}", "(%r12)")
+            .replace("{l}", &format!(".Lop{label}"));
+        if n > 1 {
+            let label = self.label();
+            let op = op
+                .replace("{r}", "%rax")
+                .replace("{m}", "(%r12,%rbp,8)")
+                .replace("{l}", &format!(".Lop{label}"));
+            result += &format!(
+                "
+	mov $1, %ebp
+.Lvec{label}:
+	cmp ${n}, %rbp
+	jge .Lvec{label}_end
+	mov (%rbx,%rbp,8), %rax
+	{op}
+	mov %rax, (%rbx,%rbp,8)
+	inc %rbp
+	jmp .Lvec{label}
+.Lvec{label}_end:"
+            );
+        }
+        result
+    }
+
+    /// Apply a binary float operation to the first `n` cells of the register,
+    /// where the operation takes its operands in `%xmm0` and `%xmm1` and returns
+    /// its result in `%xmm0`.
+    fn float_vector(&mut self, n: usize, op: &str) -> String {
+        self.vector(
+            n,
+            &format!("movq {{r}}, %xmm0\n\tmovsd {{m}}, %xmm1\n\t{op}\n\tmovq %xmm0, {{r}}"),
+        )
+    }
+
+    /// Call a function from the C library.
+    fn libc(function: &str) -> String {
+        format!("call {function}@PLT")
+    }
+
+    /// Call `printf` with the register, using the given format.
+    fn printf(format: &str, float: bool) -> String {
+        let value = if float {
+            "movq %r13, %xmm0\n\tmov $1, %eax"
+        } else {
+            "mov %r13, %rsi\n\txor %eax, %eax"
+        };
+        format!(
+            "lea {format}(%rip), %rdi\n\t{value}\n\t{}",
+            Self::libc("printf")
+        )
+    }
+
+    /// Call `scanf` to read a cell into the register, using the given format.
+    fn scanf(format: &str) -> String {
+        format!(
+            "lea {format}(%rip), %rdi\n\tlea sage_tmp(%rip), %rsi\n\txor %eax, %eax\n\t{}\n\tmov sage_tmp(%rip), %r13",
+            Self::libc("scanf")
+        )
+    }
+
+    /// Return from the current function, or end the program from `main`.
+    fn ret(&self) -> String {
+        if self.in_function {
+            "add $8, %rsp\n\tret".to_string()
+        } else {
+            "jmp sage_exit".to_string()
+        }
+    }
+}
+
+impl Architecture for X86 {
+    fn reserve_call_stack(&mut self, cells: usize) {
+        self.tape_cells += cells;
+    }
+
+    fn supports_input(&self, i: &Input) -> bool {
+        matches!(
+            i.mode,
+            InputMode::StdinChar | InputMode::StdinFloat | InputMode::StdinInt
+        )
+    }
+
+    fn supports_output(&self, o: &Output) -> bool {
+        matches!(
+            o.mode,
+            OutputMode::StdoutChar | OutputMode::StdoutFloat | OutputMode::StdoutInt
+        )
+    }
+
+    fn op(&mut self, op: &CoreOp) -> String {
+        let last_set = self.last_set.take();
+        match op {
+            CoreOp::Comment(text) => {
+                // Comments don't change the register.
+                self.last_set = last_set;
+                format!("# {}", text.replace('\n', "\n# ").replace('\r', ""))
+            }
+            CoreOp::While => {
+                let label = self.label();
+                self.blocks.push(label);
+                format!(".Lwhile{label}:\n\ttest %r13, %r13\n\tjz .Lwhile{label}_end")
+            }
+            CoreOp::If => {
+                let label = self.label();
+                self.blocks.push(label);
+                format!("test %r13, %r13\n\tjz .Lif{label}_else")
+            }
+            CoreOp::Else => {
+                let label = self.blocks.last().copied().unwrap_or_default();
+                format!("jmp .Lif{label}_end\n.Lif{label}_else:")
+            }
+            CoreOp::Set(n) => {
+                if n.len() == 1 {
+                    self.last_set = Some(n[0]);
+                }
+                let mut result = format!("mov ${}, %r13", n[0]);
+                for (i, val) in n.iter().enumerate().skip(1) {
+                    result += &format!("\n\tmov ${val}, %rax\n\tmov %rax, {}(%rbx)", i * 8);
+                }
+                result
+            }
+            CoreOp::Call => match last_set.and_then(|n| usize::try_from(n).ok()) {
+                Some(n) => {
+                    self.called.insert(n);
+                    format!("call sage_f{n}")
+                }
+                None => "lea sage_funs(%rip), %rax\n\tcall *(%rax,%r13,8)".to_string(),
+            },
+            CoreOp::Return => self.ret(),
+            CoreOp::Halt => format!("mov %r13, %rdi\n\t{}", Self::libc("exit")),
+            CoreOp::Store(n) => {
+                let mut result = "mov %r13, (%r12)".to_string();
+                if *n > 1 {
+                    result += &format!(
+                        "\n\tlea 8(%rbx), %rsi\n\tlea 8(%r12), %rdi\n\tmov ${}, %ecx\n\trep movsq",
+                        n - 1
+                    );
+                }
+                result
+            }
+            CoreOp::Load(n) => {
+                let mut result = "mov (%r12), %r13".to_string();
+                if *n > 1 {
+                    result += &format!(
+                        "\n\tlea 8(%r12), %rsi\n\tlea 8(%rbx), %rdi\n\tmov ${}, %ecx\n\trep movsq",
+                        n - 1
+                    );
+                }
+                result
+            }
+
+            CoreOp::Move(n) => match i32::try_from(n * 8) {
+                Ok(offset) => format!("lea {offset}(%r12), %r12"),
+                Err(_) => format!("mov ${}, %rax\n\tadd %rax, %r12", n * 8),
+            },
+            CoreOp::Where => "mov %r12, %r13".to_string(),
+            CoreOp::Deref => "mov %r12, (%r14)\n\tadd $8, %r14\n\tmov (%r12), %r12".to_string(),
+            CoreOp::Refer => "sub $8, %r14\n\tmov (%r14), %r12".to_string(),
+            // Pointers are addresses, so offsets are scaled by the size of a cell.
+            CoreOp::Offset(offset, n) => {
+                self.vector(*n, &format!("mov ${}, %rdx\n\tadd %rdx, {{r}}", offset * 8))
+            }
+            CoreOp::Index(n) => self.vector(*n, "mov {m}, %rdx\n\tshl $3, %rdx\n\tadd %rdx, {r}"),
+
+            CoreOp::Add(n) => self.vector(*n, "add {m}, {r}"),
+            CoreOp::Sub(n) => self.vector(*n, "sub {m}, {r}"),
+            CoreOp::Mul(n) => self.vector(*n, "imul {m}, {r}"),
+            // Dividing by zero leaves the register unchanged, like in the interpreter.
+            CoreOp::Div(n) => self.vector(
+                *n,
+                "mov {m}, %rsi
+	test %rsi, %rsi
+	jz {l}_end
+	cmp $-1, %rsi
+	jne {l}_div
+	neg {r}
+	jmp {l}_end
+{l}_div:
+	mov {r}, %rax
+	cqo
+	idiv %rsi
+	mov %rax, {r}
+{l}_end:",
+            ),
+            CoreOp::Rem(n) => self.vector(
+                *n,
+                "mov {m}, %rsi
+	test %rsi, %rsi
+	jz {l}_end
+	cmp $-1, %rsi
+	jne {l}_rem
+	xor %edx, %edx
+	jmp {l}_done
+{l}_rem:
+	mov {r}, %rax
+	cqo
+	idiv %rsi
+{l}_done:
+	mov %rdx, {r}
+{l}_end:",
+            ),
+            CoreOp::Neg(n) => self.vector(*n, "neg {r}"),
+            CoreOp::Inc(n) => self.vector(*n, "inc {r}"),
+            CoreOp::Dec(n) => self.vector(*n, "dec {r}"),
+
+            CoreOp::Swap(n) => self.vector(*n, "mov {m}, %rdx\n\tmov {r}, {m}\n\tmov %rdx, {r}"),
+
+            CoreOp::And(n) => self.vector(
+                *n,
+                "cmpq $0, {m}\n\tsetne %cl\n\ttest {r}, {r}\n\tsetne %dl\n\tand %cl, %dl\n\tmovzbq %dl, {r}",
+            ),
+            CoreOp::Or(n) => self.vector(
+                *n,
+                "cmpq $0, {m}\n\tsetne %cl\n\ttest {r}, {r}\n\tsetne %dl\n\tor %cl, %dl\n\tmovzbq %dl, {r}",
+            ),
+            CoreOp::Not(n) => self.vector(*n, "test {r}, {r}\n\tsete %dl\n\tmovzbq %dl, {r}"),
+
+            CoreOp::BitwiseNand(n) => self.vector(*n, "and {m}, {r}\n\tnot {r}"),
+            CoreOp::BitwiseAnd(n) => self.vector(*n, "and {m}, {r}"),
+            CoreOp::BitwiseOr(n) => self.vector(*n, "or {m}, {r}"),
+            CoreOp::BitwiseXor(n) => self.vector(*n, "xor {m}, {r}"),
+            CoreOp::BitwiseNot(n) => self.vector(*n, "not {r}"),
+            CoreOp::LeftShift(n) => self.vector(*n, "mov {m}, %rcx\n\tshl %cl, {r}"),
+            CoreOp::LogicalRightShift(n) => self.vector(*n, "mov {m}, %rcx\n\tshr %cl, {r}"),
+            CoreOp::ArithmeticRightShift(n) => {
+                self.vector(*n, "mov {m}, %rcx\n\tsar %cl, {r}")
+            }
+            CoreOp::IsNonNegative(n) => {
+                self.vector(*n, "test {r}, {r}\n\tsetns %dl\n\tmovzbq %dl, {r}")
+            }
+
+            CoreOp::End | CoreOp::Function | CoreOp::Put(_) | CoreOp::Get(_) => {
+                unreachable!("Invalid core op for x86-64 target")
+            }
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        self.last_set = None;
+        Ok(match op {
+            StandardOp::Call(ffi) => format!(
+                "mov %r15, ffi_ptr(%rip)\n\tcall __{}\n\tmov ffi_ptr(%rip), %r15",
+                ffi.name
+            ),
+            StandardOp::Peek => self.peek()?,
+            StandardOp::Poke => self.poke()?,
+            StandardOp::Set(n) => {
+                let mut result = format!("mov ${}, %r13", n[0].to_bits() as i64);
+                for (i, val) in n.iter().enumerate().skip(1) {
+                    result += &format!(
+                        "\n\tmov ${}, %rax\n\tmov %rax, {}(%rbx)",
+                        val.to_bits() as i64,
+                        i * 8
+                    );
+                }
+                result
+            }
+
+            StandardOp::ToInt(n) => self.vector(*n, "movq {r}, %xmm0\n\tcvttsd2si %xmm0, {r}"),
+            StandardOp::ToFloat(n) => self.vector(*n, "cvtsi2sd {r}, %xmm0\n\tmovq %xmm0, {r}"),
+
+            StandardOp::Add(n) => self.float_vector(*n, "addsd %xmm1, %xmm0"),
+            StandardOp::Sub(n) => self.float_vector(*n, "subsd %xmm1, %xmm0"),
+            StandardOp::Mul(n) => self.float_vector(*n, "mulsd %xmm1, %xmm0"),
+            StandardOp::Div(n) => self.float_vector(*n, "divsd %xmm1, %xmm0"),
+            StandardOp::Rem(n) => self.float_vector(*n, &Self::libc("fmod")),
+            StandardOp::Pow(n) => self.float_vector(*n, &Self::libc("pow")),
+            StandardOp::Neg(n) => self.vector(*n, "btc $63, {r}"),
+            StandardOp::IsNonNegative(n) => self.vector(
+                *n,
+                "movq {r}, %xmm0\n\txorpd %xmm1, %xmm1\n\tucomisd %xmm1, %xmm0\n\tsetae %dl\n\tmovzbq %dl, {r}",
+            ),
+
+            StandardOp::Sin(n)
+            | StandardOp::Cos(n)
+            | StandardOp::Tan(n)
+            | StandardOp::ASin(n)
+            | StandardOp::ACos(n)
+            | StandardOp::ATan(n) => self.vector(
+                *n,
+                &format!(
+                    "movq {{r}}, %xmm0\n\t{}\n\tmovq %xmm0, {{r}}",
+                    Self::libc(op.name())
+                ),
+            ),
+
+            StandardOp::Alloc => format!(
+                "lea {}(,%r13,8), %rdi\n\t{}\n\tmov %rax, %r13",
+                SPARE_CELLS_PER_BLOCK * 8,
+                Self::libc("malloc")
+            ),
+            StandardOp::Free => format!("mov %r13, %rdi\n\t{}", Self::libc("free")),
+            StandardOp::CoreOp(_) => unreachable!("Core ops are built by `op`"),
+        })
+    }
+
+    fn end(&mut self, matching: &CoreOp, _fun: Option<usize>) -> String {
+        self.last_set = None;
+        match matching {
+            CoreOp::While => {
+                let label = self.blocks.pop().unwrap_or_default();
+                format!("jmp .Lwhile{label}\n.Lwhile{label}_end:")
+            }
+            CoreOp::If => {
+                let label = self.blocks.pop().unwrap_or_default();
+                format!(".Lif{label}_else:")
+            }
+            CoreOp::Else => {
+                let label = self.blocks.pop().unwrap_or_default();
+                format!(".Lif{label}_end:")
+            }
+            CoreOp::Function => {
+                let result = self.ret();
+                self.in_function = false;
+                result
+            }
+            _ => unreachable!("Invalid matching op for end"),
+        }
+    }
+
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        self.last_set = None;
+        self.in_function = true;
+        self.defined.insert(label_id);
+        // Keep the stack aligned to 16 bytes for calls into the C library.
+        format!("sage_f{label_id}:\n\tsub $8, %rsp")
+    }
+
+    fn name(&self) -> &str {
+        "x86-64"
+    }
+    fn version(&self) -> &str {
+        "1.0"
+    }
+
+    fn supports_floats(&self) -> bool {
+        true
+    }
+
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        self.last_set = None;
+        match src.mode {
+            InputMode::StdinChar => Ok(format!(
+                "{}\n\tcmp $-1, %eax\n\tmov $0, %edx\n\tcmove %edx, %eax\n\tmovslq %eax, %r13",
+                Self::libc("getchar")
+            )),
+            InputMode::StdinInt => Ok(Self::scanf("sage_int_format")),
+            InputMode::StdinFloat => Ok(Self::scanf("sage_float_input_format")),
+            _ => Err("Input not supported by this target".to_string()),
+        }
+    }
+
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        self.last_set = None;
+        match dst.mode {
+            OutputMode::StdoutChar => Ok(format!("mov %r13, %rdi\n\t{}", Self::libc("putchar"))),
+            OutputMode::StdoutInt => Ok(Self::printf("sage_int_format", false)),
+            OutputMode::StdoutFloat => Ok(Self::printf("sage_float_format", true)),
+            _ => Err("Output not supported by this target".to_string()),
+        }
+    }
+
+    fn peek(&mut self) -> Result<String, String> {
+        Ok("mov (%r15), %r13\n\tsub $8, %r15".to_string())
+    }
+
+    fn poke(&mut self) -> Result<String, String> {
+        Ok("add $8, %r15\n\tmov %r13, (%r15)".to_string())
+    }
+
+    fn prelude(&self, _is_core: bool) -> Option<String> {
+        Some(format!(
+            r#"	.section .bss
+	.align 16
+sage_tape:
+	.zero {tape}
+sage_refs:
+	.zero {refs}
+sage_vector_reg:
+	.zero {register}
+	.globl ffi_channel
+ffi_channel:
+	.zero {ffi_channel}
+sage_tmp:
+	.zero 8
+
+	.data
+	.align 8
+	.globl ffi_ptr
+ffi_ptr:
+	.quad ffi_channel
+
+	.section .rodata
+sage_int_format:
+	.string "%lld"
+sage_float_format:
+	.string "%.1lf"
+sage_float_input_format:
+	.string "%lf"
+
+	.text
+"#,
+            tape = self.tape_cells * 8,
+            refs = self.refs_depth * 8,
+            register = self.register_cells * 8,
+            ffi_channel = self.ffi_channel_cells * 8,
+        ))
+    }
+
+    fn post_funs(&self, mut funs: Vec<i32>) -> Option<String> {
+        funs.sort();
+        let mut result = String::from("\t.section .data.rel.ro\n\t.align 8\nsage_funs:\n");
+        for fun in funs {
+            result += &format!("\t.quad sage_f{fun}\n");
+        }
+        result += r#"
+	.text
+	.globl main
+	.type main, @function
+main:
+	push %rbx
+	push %rbp
+	push %r12
+	push %r13
+	push %r14
+	push %r15
+	sub $8, %rsp
+	lea sage_tape(%rip), %r12
+	xor %r13d, %r13d
+	lea sage_vector_reg(%rip), %rbx
+	lea sage_refs(%rip), %r14
+	lea ffi_channel(%rip), %r15
+"#;
+        Some(result)
+    }
+
+    fn postop(&self) -> Option<String> {
+        Some("\n".to_string())
+    }
+
+    fn postlude(&self, _is_core: bool) -> Option<String> {
+        let mut result = String::from(
+            r#"sage_exit:
+	add $8, %rsp
+	pop %r15
+	pop %r14
+	pop %r13
+	pop %r12
+	pop %rbp
+	pop %rbx
+	xor %eax, %eax
+	ret
+"#,
+        );
+        // Calling a function which doesn't exist aborts the program.
+        for fun in self.called.difference(&self.defined) {
+            result += &format!("sage_f{fun}:\n\t{}\n", Self::libc("abort"));
+        }
+        result += "\t.section .note.GNU-stack,\"\",@progbits\n";
+        Some(result)
+    }
+}
+
+impl CompiledTarget for X86 {}
@@ -0,0 +1,989 @@
+//! # Bytecode Module
+//!
+//! This module implements a compact binary encoding for virtual machine programs,
+//! so that programs can be shipped precompiled and loaded without parsing the
+//! textual `.vm.sg` format.
+//!
+//! ## Layout
+//!
+//! A bytecode file starts with a header:
+//!
+//! 1. The magic bytes `SGBC`.
+//! 2. The version of the format, as a varint.
+//! 3. One byte for the variant of the program: `0` for core, `1` for standard.
+//!
+//! The header is followed by two tables, each starting with its length as a varint:
+//!
+//! 1. The string table. Each string is its length in bytes (as a varint), followed
+//!    by its UTF-8 bytes. `Comment` instructions and custom I/O modes refer to
+//!    strings by their index in this table.
+//! 2. The FFI table. Each binding is the index of its name in the string table,
+//!    followed by its input and output cell counts. `Call` instructions in the
+//!    standard variant refer to bindings by their index in this table.
+//!
+//! Finally, the instructions are written as their count, followed by each instruction.
+//! Every instruction is a one byte opcode followed by its operands. Unsigned operands
+//! are LEB128 varints, signed operands are zigzag encoded varints, and floats are
+//! written as their eight little-endian bytes. Core instructions use opcodes below
+//! `0x80`, and standard instructions use the opcodes from `0x80` up.
+use super::{CoreOp, CoreProgram, StandardOp, StandardProgram};
+use crate::side_effects::{
+    Axis, Channel, Color, Direction, FFIBinding, Input, InputMode, Output, OutputMode,
+};
+use ::std::{collections::HashMap, fmt};
+
+/// The bytes every bytecode file starts with.
+const MAGIC: &[u8; 4] = b"SGBC";
+/// The version of the bytecode format written by this encoder.
+pub const BYTECODE_VERSION: u64 = 1;
+/// The variant byte of a core program.
+const CORE_VARIANT: u8 = 0;
+/// The variant byte of a standard program.
+const STD_VARIANT: u8 = 1;
+
+/// The opcodes of the instructions.
+mod opcode {
+    pub const COMMENT: u8 = 0x00;
+    pub const SET: u8 = 0x01;
+    pub const FUNCTION: u8 = 0x02;
+    pub const CALL: u8 = 0x03;
+    pub const RETURN: u8 = 0x04;
+    pub const HALT: u8 = 0x05;
+    pub const WHILE: u8 = 0x06;
+    pub const IF: u8 = 0x07;
+    pub const ELSE: u8 = 0x08;
+    pub const END: u8 = 0x09;
+    pub const STORE: u8 = 0x0a;
+    pub const LOAD: u8 = 0x0b;
+    pub const MOVE: u8 = 0x0c;
+    pub const WHERE: u8 = 0x0d;
+    pub const DEREF: u8 = 0x0e;
+    pub const REFER: u8 = 0x0f;
+    pub const INDEX: u8 = 0x10;
+    pub const OFFSET: u8 = 0x11;
+    pub const BITWISE_NAND: u8 = 0x12;
+    pub const BITWISE_AND: u8 = 0x13;
+    pub const BITWISE_OR: u8 = 0x14;
+    pub const BITWISE_XOR: u8 = 0x15;
+    pub const BITWISE_NOT: u8 = 0x16;
+    pub const LEFT_SHIFT: u8 = 0x17;
+    pub const LOGICAL_RIGHT_SHIFT: u8 = 0x18;
+    pub const ARITHMETIC_RIGHT_SHIFT: u8 = 0x19;
+    pub const AND: u8 = 0x1a;
+    pub const OR: u8 = 0x1b;
+    pub const NOT: u8 = 0x1c;
+    pub const ADD: u8 = 0x1d;
+    pub const SUB: u8 = 0x1e;
+    pub const MUL: u8 = 0x1f;
+    pub const DIV: u8 = 0x20;
+    pub const REM: u8 = 0x21;
+    pub const NEG: u8 = 0x22;
+    pub const INC: u8 = 0x23;
+    pub const DEC: u8 = 0x24;
+    pub const SWAP: u8 = 0x25;
+    pub const IS_NON_NEGATIVE: u8 = 0x26;
+    pub const GET: u8 = 0x27;
+    pub const PUT: u8 = 0x28;
+
+    pub const STD_SET: u8 = 0x80;
+    pub const STD_ALLOC: u8 = 0x81;
+    pub const STD_FREE: u8 = 0x82;
+    pub const STD_TO_INT: u8 = 0x83;
+    pub const STD_TO_FLOAT: u8 = 0x84;
+    pub const STD_ADD: u8 = 0x85;
+    pub const STD_SUB: u8 = 0x86;
+    pub const STD_MUL: u8 = 0x87;
+    pub const STD_DIV: u8 = 0x88;
+    pub const STD_REM: u8 = 0x89;
+    pub const STD_NEG: u8 = 0x8a;
+    pub const STD_IS_NON_NEGATIVE: u8 = 0x8b;
+    pub const STD_SIN: u8 = 0x8c;
+    pub const STD_COS: u8 = 0x8d;
+    pub const STD_TAN: u8 = 0x8e;
+    pub const STD_ASIN: u8 = 0x8f;
+    pub const STD_ACOS: u8 = 0x90;
+    pub const STD_ATAN: u8 = 0x91;
+    pub const STD_POW: u8 = 0x92;
+    pub const STD_PEEK: u8 = 0x93;
+    pub const STD_POKE: u8 = 0x94;
+    pub const STD_CALL: u8 = 0x95;
+}
+
+/// An error encountered while decoding bytecode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BytecodeError {
+    /// The bytes don't start with the magic bytes of a bytecode file.
+    NotBytecode,
+    /// The bytecode was written with a version of the format this decoder can't read.
+    UnsupportedVersion(u64),
+    /// The variant byte of the header is neither core nor standard.
+    UnknownVariant(u8),
+    /// A core program was expected, but the bytecode holds a standard program.
+    ExpectedCore,
+    /// The bytecode ended in the middle of a value.
+    UnexpectedEnd,
+    /// An instruction has an opcode which isn't defined (for the program's variant).
+    UnknownOpcode { opcode: u8, offset: usize },
+    /// A value at the given offset is malformed.
+    Invalid { what: &'static str, offset: usize },
+    /// There are bytes left over after the last instruction.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotBytecode => write!(f, "not a bytecode file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported bytecode version {version} (expected version {BYTECODE_VERSION})"
+            ),
+            Self::UnknownVariant(variant) => write!(f, "unknown program variant {variant}"),
+            Self::ExpectedCore => write!(f, "expected core program, got standard program"),
+            Self::UnexpectedEnd => write!(f, "unexpected end of bytecode"),
+            Self::UnknownOpcode { opcode, offset } => {
+                write!(f, "unknown opcode {opcode:#04x} at byte {offset}")
+            }
+            Self::Invalid { what, offset } => write!(f, "invalid {what} at byte {offset}"),
+            Self::TrailingBytes(offset) => write!(f, "trailing bytes after byte {offset}"),
+        }
+    }
+}
+
+impl CoreProgram {
+    /// Encode the program as bytecode.
+    pub fn to_bytecode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        for op in &self.0 {
+            encoder.core_op(op);
+        }
+        encoder.finish(CORE_VARIANT, self.0.len())
+    }
+
+    /// Decode a core program from bytecode.
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Self, BytecodeError> {
+        decode_bytecode(bytes)?.map_err(|_| BytecodeError::ExpectedCore)
+    }
+}
+
+impl StandardProgram {
+    /// Encode the program as bytecode.
+    pub fn to_bytecode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        for op in &self.0 {
+            encoder.std_op(op);
+        }
+        encoder.finish(STD_VARIANT, self.0.len())
+    }
+
+    /// Decode a standard program from bytecode. Core programs
+    /// are converted to the standard variant.
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Self, BytecodeError> {
+        Ok(match decode_bytecode(bytes)? {
+            Ok(core) => StandardProgram(core.0.into_iter().map(StandardOp::CoreOp).collect()),
+            Err(std) => std,
+        })
+    }
+}
+
+/// Decode a program from bytecode. The header decides whether
+/// a core or standard program is returned.
+pub fn decode_bytecode(
+    bytes: &[u8],
+) -> Result<Result<CoreProgram, StandardProgram>, BytecodeError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(BytecodeError::NotBytecode);
+    }
+    let mut decoder = Decoder::new(bytes);
+    decoder.take(MAGIC.len())?;
+    let version = decoder.uint()?;
+    if version != BYTECODE_VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let variant = decoder.byte()?;
+    if variant != CORE_VARIANT && variant != STD_VARIANT {
+        return Err(BytecodeError::UnknownVariant(variant));
+    }
+    decoder.tables()?;
+
+    let count = decoder.usize()?;
+    let result = if variant == CORE_VARIANT {
+        let mut code = vec![];
+        for _ in 0..count {
+            let offset = decoder.offset;
+            let opcode = decoder.byte()?;
+            code.push(decoder.core_op(opcode, offset)?);
+        }
+        Ok(CoreProgram(code))
+    } else {
+        let mut code = vec![];
+        for _ in 0..count {
+            code.push(decoder.std_op()?);
+        }
+        Err(StandardProgram(code))
+    };
+
+    if decoder.offset < bytes.len() {
+        return Err(BytecodeError::TrailingBytes(decoder.offset));
+    }
+    Ok(result)
+}
+
+/// Write an unsigned LEB128 varint.
+fn write_uint(bytes: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Write a signed varint, zigzag encoded so that small negative numbers stay small.
+fn write_int(bytes: &mut Vec<u8>, n: i64) {
+    write_uint(bytes, ((n << 1) ^ (n >> 63)) as u64)
+}
+
+/// Builds the tables and instructions of a bytecode program.
+#[derive(Default)]
+struct Encoder {
+    /// The string table.
+    strings: Vec<String>,
+    /// The index of each string in the string table.
+    string_ids: HashMap<String, usize>,
+    /// The FFI table.
+    bindings: Vec<FFIBinding>,
+    /// The index of each binding in the FFI table.
+    binding_ids: HashMap<FFIBinding, usize>,
+    /// The encoded instructions.
+    code: Vec<u8>,
+}
+
+impl Encoder {
+    /// Write the header and the tables, followed by the instructions.
+    fn finish(self, variant: u8, count: usize) -> Vec<u8> {
+        let mut result = MAGIC.to_vec();
+        write_uint(&mut result, BYTECODE_VERSION);
+        result.push(variant);
+
+        write_uint(&mut result, self.strings.len() as u64);
+        for string in &self.strings {
+            write_uint(&mut result, string.len() as u64);
+            result.extend_from_slice(string.as_bytes());
+        }
+        write_uint(&mut result, self.bindings.len() as u64);
+        for binding in &self.bindings {
+            write_uint(&mut result, self.string_ids[&binding.name] as u64);
+            write_uint(&mut result, binding.input_cells as u64);
+            write_uint(&mut result, binding.output_cells as u64);
+        }
+
+        write_uint(&mut result, count as u64);
+        result.extend(self.code);
+        result
+    }
+
+    /// Get the index of a string in the string table, adding it if it's new.
+    fn string(&mut self, string: &str) -> usize {
+        if let Some(&id) = self.string_ids.get(string) {
+            return id;
+        }
+        let id = self.strings.len();
+        self.strings.push(string.to_string());
+        self.string_ids.insert(string.to_string(), id);
+        id
+    }
+
+    /// Get the index of a binding in the FFI table, adding it if it's new.
+    fn binding(&mut self, binding: &FFIBinding) -> usize {
+        if let Some(&id) = self.binding_ids.get(binding) {
+            return id;
+        }
+        self.string(&binding.name);
+        let id = self.bindings.len();
+        self.bindings.push(binding.clone());
+        self.binding_ids.insert(binding.clone(), id);
+        id
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte)
+    }
+
+    fn uint(&mut self, n: usize) {
+        write_uint(&mut self.code, n as u64)
+    }
+
+    fn int(&mut self, n: i64) {
+        write_int(&mut self.code, n)
+    }
+
+    /// Write an instruction with a single size operand.
+    fn sized(&mut self, opcode: u8, n: usize) {
+        self.byte(opcode);
+        self.uint(n);
+    }
+
+    fn core_op(&mut self, op: &CoreOp) {
+        use opcode::*;
+        match op {
+            CoreOp::Comment(comment) => {
+                let id = self.string(comment);
+                self.sized(COMMENT, id);
+            }
+            CoreOp::Set(values) => {
+                self.sized(SET, values.len());
+                for &value in values {
+                    self.int(value);
+                }
+            }
+            CoreOp::Function => self.byte(FUNCTION),
+            CoreOp::Call => self.byte(CALL),
+            CoreOp::Return => self.byte(RETURN),
+            CoreOp::Halt => self.byte(HALT),
+            CoreOp::While => self.byte(WHILE),
+            CoreOp::If => self.byte(IF),
+            CoreOp::Else => self.byte(ELSE),
+            CoreOp::End => self.byte(END),
+            CoreOp::Store(n) => self.sized(STORE, *n),
+            CoreOp::Load(n) => self.sized(LOAD, *n),
+            CoreOp::Move(n) => {
+                self.byte(MOVE);
+                self.int(*n as i64);
+            }
+            CoreOp::Where => self.byte(WHERE),
+            CoreOp::Deref => self.byte(DEREF),
+            CoreOp::Refer => self.byte(REFER),
+            CoreOp::Index(n) => self.sized(INDEX, *n),
+            CoreOp::Offset(offset, size) => {
+                self.byte(OFFSET);
+                self.int(*offset as i64);
+                self.uint(*size);
+            }
+            CoreOp::BitwiseNand(n) => self.sized(BITWISE_NAND, *n),
+            CoreOp::BitwiseAnd(n) => self.sized(BITWISE_AND, *n),
+            CoreOp::BitwiseOr(n) => self.sized(BITWISE_OR, *n),
+            CoreOp::BitwiseXor(n) => self.sized(BITWISE_XOR, *n),
+            CoreOp::BitwiseNot(n) => self.sized(BITWISE_NOT, *n),
+            CoreOp::LeftShift(n) => self.sized(LEFT_SHIFT, *n),
+            CoreOp::LogicalRightShift(n) => self.sized(LOGICAL_RIGHT_SHIFT, *n),
+            CoreOp::ArithmeticRightShift(n) => self.sized(ARITHMETIC_RIGHT_SHIFT, *n),
+            CoreOp::And(n) => self.sized(AND, *n),
+            CoreOp::Or(n) => self.sized(OR, *n),
+            CoreOp::Not(n) => self.sized(NOT, *n),
+            CoreOp::Add(n) => self.sized(ADD, *n),
+            CoreOp::Sub(n) => self.sized(SUB, *n),
+            CoreOp::Mul(n) => self.sized(MUL, *n),
+            CoreOp::Div(n) => self.sized(DIV, *n),
+            CoreOp::Rem(n) => self.sized(REM, *n),
+            CoreOp::Neg(n) => self.sized(NEG, *n),
+            CoreOp::Inc(n) => self.sized(INC, *n),
+            CoreOp::Dec(n) => self.sized(DEC, *n),
+            CoreOp::Swap(n) => self.sized(SWAP, *n),
+            CoreOp::IsNonNegative(n) => self.sized(IS_NON_NEGATIVE, *n),
+            CoreOp::Get(input) => {
+                self.byte(GET);
+                self.input(input);
+            }
+            CoreOp::Put(output) => {
+                self.byte(PUT);
+                self.output(output);
+            }
+        }
+    }
+
+    fn std_op(&mut self, op: &StandardOp) {
+        use opcode::*;
+        match op {
+            StandardOp::CoreOp(op) => self.core_op(op),
+            StandardOp::Set(values) => {
+                self.sized(STD_SET, values.len());
+                for value in values {
+                    self.code.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            StandardOp::Alloc => self.byte(STD_ALLOC),
+            StandardOp::Free => self.byte(STD_FREE),
+            StandardOp::ToInt(n) => self.sized(STD_TO_INT, *n),
+            StandardOp::ToFloat(n) => self.sized(STD_TO_FLOAT, *n),
+            StandardOp::Add(n) => self.sized(STD_ADD, *n),
+            StandardOp::Sub(n) => self.sized(STD_SUB, *n),
+            StandardOp::Mul(n) => self.sized(STD_MUL, *n),
+            StandardOp::Div(n) => self.sized(STD_DIV, *n),
+            StandardOp::Rem(n) => self.sized(STD_REM, *n),
+            StandardOp::Neg(n) => self.sized(STD_NEG, *n),
+            StandardOp::IsNonNegative(n) => self.sized(STD_IS_NON_NEGATIVE, *n),
+            StandardOp::Sin(n) => self.sized(STD_SIN, *n),
+            StandardOp::Cos(n) => self.sized(STD_COS, *n),
+            StandardOp::Tan(n) => self.sized(STD_TAN, *n),
+            StandardOp::ASin(n) => self.sized(STD_ASIN, *n),
+            StandardOp::ACos(n) => self.sized(STD_ACOS, *n),
+            StandardOp::ATan(n) => self.sized(STD_ATAN, *n),
+            StandardOp::Pow(n) => self.sized(STD_POW, *n),
+            StandardOp::Peek => self.byte(STD_PEEK),
+            StandardOp::Poke => self.byte(STD_POKE),
+            StandardOp::Call(binding) => {
+                let id = self.binding(binding);
+                self.sized(STD_CALL, id);
+            }
+        }
+    }
+
+    /// Write an input source: the mode's tag, its argument (if it has one), and the channel.
+    fn input(&mut self, input: &Input) {
+        use InputMode::*;
+        self.byte(match &input.mode {
+            StdinChar => 0,
+            StdinInt => 1,
+            StdinFloat => 2,
+            Random => 3,
+            DPad(_) => 4,
+            Button => 5,
+            Keyboard => 6,
+            JoyStick(_) => 7,
+            Clock => 8,
+            Accelerometer(_) => 9,
+            Gyroscope(_) => 10,
+            Microphone => 11,
+            RedLight => 12,
+            GreenLight => 13,
+            BlueLight => 14,
+            Brightness => 15,
+            Humidity => 16,
+            Barometer => 17,
+            Magnetometer(_) => 18,
+            Thermometer => 19,
+            RainGauge => 20,
+            UVSensor => 21,
+            WindSpeed => 22,
+            WindDirection => 23,
+            PressureGauge => 24,
+            FlowSensor => 25,
+            VolumeSensor => 26,
+            WeightSensor => 27,
+            PHSensor => 28,
+            ConductivitySensor => 29,
+            Speedometer(_) => 30,
+            Odometer => 31,
+            Position(_) => 32,
+            Compass => 33,
+            Proximity => 34,
+            Altimeter => 35,
+            DepthSensor => 36,
+            AnalogPin => 37,
+            DigitalPin => 38,
+            Custom(_) => 39,
+        });
+        match &input.mode {
+            DPad(direction) | JoyStick(direction) => self.uint(direction_tag(*direction)),
+            Accelerometer(axis) | Gyroscope(axis) | Magnetometer(axis) | Position(axis) => {
+                self.uint(axis_tag(*axis))
+            }
+            // The axis is optional, so zero means no axis.
+            Speedometer(axis) => self.uint(axis.map_or(0, |axis| axis_tag(axis) + 1)),
+            Custom(name) => {
+                let id = self.string(name);
+                self.uint(id)
+            }
+            _ => {}
+        }
+        self.uint(input.channel.0);
+    }
+
+    /// Write an output destination: the mode's tag, its argument (if it has one), and the channel.
+    fn output(&mut self, output: &Output) {
+        use OutputMode::*;
+        self.byte(match &output.mode {
+            StdoutChar => 0,
+            StdoutInt => 1,
+            StdoutFloat => 2,
+            StderrChar => 3,
+            StderrInt => 4,
+            StderrFloat => 5,
+            PrinterChar => 6,
+            PrinterInt => 7,
+            PrinterFloat => 8,
+            Brightness => 9,
+            AnalogPin => 10,
+            DigitalPin => 11,
+            StepperMotor => 12,
+            Solenoid => 13,
+            Valve => 14,
+            MotorSpeed => 15,
+            Servo => 16,
+            Temperature => 17,
+            Pump => 18,
+            Fan => 19,
+            Blower => 20,
+            Heater => 21,
+            Cooler => 22,
+            Pressure => 23,
+            Buzzer => 24,
+            Bell => 25,
+            Note => 26,
+            SpeakerVolume => 27,
+            SpeakerFrequency => 28,
+            UpdateDisplay => 29,
+            ClearDisplay => 30,
+            SetCursorRow => 31,
+            SetCursorColumn => 32,
+            MoveCursorUp => 33,
+            MoveCursorDown => 34,
+            MoveCursorLeft => 35,
+            MoveCursorRight => 36,
+            SetCursorChar(_) => 37,
+            SetCursorPixel(_) => 38,
+            Custom(_) => 39,
+        });
+        match &output.mode {
+            SetCursorChar(color) | SetCursorPixel(color) => self.color(*color),
+            Custom(name) => {
+                let id = self.string(name);
+                self.uint(id)
+            }
+            _ => {}
+        }
+        self.uint(output.channel.0);
+    }
+
+    /// Write a color as its tag, followed by its components for RGB colors.
+    fn color(&mut self, color: Color) {
+        use Color::*;
+        match color {
+            Black => self.byte(0),
+            White => self.byte(1),
+            Red => self.byte(2),
+            Green => self.byte(3),
+            Blue => self.byte(4),
+            Yellow => self.byte(5),
+            Cyan => self.byte(6),
+            Magenta => self.byte(7),
+            Orange => self.byte(8),
+            RGB(r, g, b) => self.code.extend_from_slice(&[9, r, g, b]),
+        }
+    }
+}
+
+fn direction_tag(direction: Direction) -> usize {
+    match direction {
+        Direction::Up => 0,
+        Direction::Down => 1,
+        Direction::Left => 2,
+        Direction::Right => 3,
+    }
+}
+
+fn axis_tag(axis: Axis) -> usize {
+    match axis {
+        Axis::X => 0,
+        Axis::Y => 1,
+        Axis::Z => 2,
+    }
+}
+
+/// Reads the tables and instructions of a bytecode program.
+struct Decoder<'a> {
+    /// The bytecode being decoded.
+    bytes: &'a [u8],
+    /// The offset of the next byte to read.
+    offset: usize,
+    /// The string table.
+    strings: Vec<String>,
+    /// The FFI table.
+    bindings: Vec<FFIBinding>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            offset: 0,
+            strings: vec![],
+            bindings: vec![],
+        }
+    }
+
+    /// Read the string and FFI tables.
+    fn tables(&mut self) -> Result<(), BytecodeError> {
+        for _ in 0..self.usize()? {
+            let offset = self.offset;
+            let len = self.usize()?;
+            let bytes = self.take(len)?;
+            let string = ::std::str::from_utf8(bytes).map_err(|_| BytecodeError::Invalid {
+                what: "string",
+                offset,
+            })?;
+            self.strings.push(string.to_string());
+        }
+        for _ in 0..self.usize()? {
+            let name = self.string()?;
+            let input_cells = self.usize()?;
+            let output_cells = self.usize()?;
+            self.bindings
+                .push(FFIBinding::new(name, input_cells, output_cells));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        let result = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(result)
+    }
+
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<u64, BytecodeError> {
+        let offset = self.offset;
+        let mut result = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            // The tenth byte only has room for the top bit of the value.
+            if shift == 63 && byte & 0x7f > 1 {
+                break;
+            }
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(BytecodeError::Invalid {
+            what: "varint",
+            offset,
+        })
+    }
+
+    fn int(&mut self) -> Result<i64, BytecodeError> {
+        let n = self.uint()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    fn usize(&mut self) -> Result<usize, BytecodeError> {
+        let offset = self.offset;
+        usize::try_from(self.uint()?).map_err(|_| BytecodeError::Invalid {
+            what: "size",
+            offset,
+        })
+    }
+
+    fn isize(&mut self) -> Result<isize, BytecodeError> {
+        let offset = self.offset;
+        isize::try_from(self.int()?).map_err(|_| BytecodeError::Invalid {
+            what: "offset",
+            offset,
+        })
+    }
+
+    fn float(&mut self) -> Result<f64, BytecodeError> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Read a reference to the string table.
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let offset = self.offset;
+        let id = self.usize()?;
+        self.strings.get(id).cloned().ok_or(BytecodeError::Invalid {
+            what: "string index",
+            offset,
+        })
+    }
+
+    /// Read a reference to the FFI table.
+    fn binding(&mut self) -> Result<FFIBinding, BytecodeError> {
+        let offset = self.offset;
+        let id = self.usize()?;
+        self.bindings
+            .get(id)
+            .cloned()
+            .ok_or(BytecodeError::Invalid {
+                what: "FFI binding index",
+                offset,
+            })
+    }
+
+    /// Read the operands of a core instruction with the given opcode.
+    fn core_op(&mut self, opcode: u8, offset: usize) -> Result<CoreOp, BytecodeError> {
+        use opcode::*;
+        Ok(match opcode {
+            COMMENT => CoreOp::Comment(self.string()?),
+            SET => {
+                let len = self.usize()?;
+                let mut values = vec![];
+                for _ in 0..len {
+                    values.push(self.int()?);
+                }
+                CoreOp::Set(values)
+            }
+            FUNCTION => CoreOp::Function,
+            CALL => CoreOp::Call,
+            RETURN => CoreOp::Return,
+            HALT => CoreOp::Halt,
+            WHILE => CoreOp::While,
+            IF => CoreOp::If,
+            ELSE => CoreOp::Else,
+            END => CoreOp::End,
+            STORE => CoreOp::Store(self.usize()?),
+            LOAD => CoreOp::Load(self.usize()?),
+            MOVE => CoreOp::Move(self.isize()?),
+            WHERE => CoreOp::Where,
+            DEREF => CoreOp::Deref,
+            REFER => CoreOp::Refer,
+            INDEX => CoreOp::Index(self.usize()?),
+            OFFSET => CoreOp::Offset(self.isize()?, self.usize()?),
+            BITWISE_NAND => CoreOp::BitwiseNand(self.usize()?),
+            BITWISE_AND => CoreOp::BitwiseAnd(self.usize()?),
+            BITWISE_OR => CoreOp::BitwiseOr(self.usize()?),
+            BITWISE_XOR => CoreOp::BitwiseXor(self.usize()?),
+            BITWISE_NOT => CoreOp::BitwiseNot(self.usize()?),
+            LEFT_SHIFT => CoreOp::LeftShift(self.usize()?),
+            LOGICAL_RIGHT_SHIFT => CoreOp::LogicalRightShift(self.usize()?),
+            ARITHMETIC_RIGHT_SHIFT => CoreOp::ArithmeticRightShift(self.usize()?),
+            AND => CoreOp::And(self.usize()?),
+            OR => CoreOp::Or(self.usize()?),
+            NOT => CoreOp::Not(self.usize()?),
+            ADD => CoreOp::Add(self.usize()?),
+            SUB => CoreOp::Sub(self.usize()?),
+            MUL => CoreOp::Mul(self.usize()?),
+            DIV => CoreOp::Div(self.usize()?),
+            REM => CoreOp::Rem(self.usize()?),
+            NEG => CoreOp::Neg(self.usize()?),
+            INC => CoreOp::Inc(self.usize()?),
+            DEC => CoreOp::Dec(self.usize()?),
+            SWAP => CoreOp::Swap(self.usize()?),
+            IS_NON_NEGATIVE => CoreOp::IsNonNegative(self.usize()?),
+            GET => CoreOp::Get(self.input()?),
+            PUT => CoreOp::Put(self.output()?),
+            _ => return Err(BytecodeError::UnknownOpcode { opcode, offset }),
+        })
+    }
+
+    fn std_op(&mut self) -> Result<StandardOp, BytecodeError> {
+        use opcode::*;
+        let offset = self.offset;
+        let opcode = self.byte()?;
+        Ok(match opcode {
+            STD_SET => {
+                let len = self.usize()?;
+                let mut values = vec![];
+                for _ in 0..len {
+                    values.push(self.float()?);
+                }
+                StandardOp::Set(values)
+            }
+            STD_ALLOC => StandardOp::Alloc,
+            STD_FREE => StandardOp::Free,
+            STD_TO_INT => StandardOp::ToInt(self.usize()?),
+            STD_TO_FLOAT => StandardOp::ToFloat(self.usize()?),
+            STD_ADD => StandardOp::Add(self.usize()?),
+            STD_SUB => StandardOp::Sub(self.usize()?),
+            STD_MUL => StandardOp::Mul(self.usize()?),
+            STD_DIV => StandardOp::Div(self.usize()?),
+            STD_REM => StandardOp::Rem(self.usize()?),
+            STD_NEG => StandardOp::Neg(self.usize()?),
+            STD_IS_NON_NEGATIVE => StandardOp::IsNonNegative(self.usize()?),
+            STD_SIN => StandardOp::Sin(self.usize()?),
+            STD_COS => StandardOp::Cos(self.usize()?),
+            STD_TAN => StandardOp::Tan(self.usize()?),
+            STD_ASIN => StandardOp::ASin(self.usize()?),
+            STD_ACOS => StandardOp::ACos(self.usize()?),
+            STD_ATAN => StandardOp::ATan(self.usize()?),
+            STD_POW => StandardOp::Pow(self.usize()?),
+            STD_PEEK => StandardOp::Peek,
+            STD_POKE => StandardOp::Poke,
+            STD_CALL => StandardOp::Call(self.binding()?),
+            _ => StandardOp::CoreOp(self.core_op(opcode, offset)?),
+        })
+    }
+
+    fn direction(&mut self) -> Result<Direction, BytecodeError> {
+        let offset = self.offset;
+        Ok(match self.usize()? {
+            0 => Direction::Up,
+            1 => Direction::Down,
+            2 => Direction::Left,
+            3 => Direction::Right,
+            _ => {
+                return Err(BytecodeError::Invalid {
+                    what: "direction",
+                    offset,
+                })
+            }
+        })
+    }
+
+    fn axis(&mut self) -> Result<Axis, BytecodeError> {
+        let offset = self.offset;
+        Ok(match self.usize()? {
+            0 => Axis::X,
+            1 => Axis::Y,
+            2 => Axis::Z,
+            _ => {
+                return Err(BytecodeError::Invalid {
+                    what: "axis",
+                    offset,
+                })
+            }
+        })
+    }
+
+    fn color(&mut self) -> Result<Color, BytecodeError> {
+        let offset = self.offset;
+        Ok(match self.byte()? {
+            0 => Color::Black,
+            1 => Color::White,
+            2 => Color::Red,
+            3 => Color::Green,
+            4 => Color::Blue,
+            5 => Color::Yellow,
+            6 => Color::Cyan,
+            7 => Color::Magenta,
+            8 => Color::Orange,
+            9 => Color::RGB(self.byte()?, self.byte()?, self.byte()?),
+            _ => {
+                return Err(BytecodeError::Invalid {
+                    what: "color",
+                    offset,
+                })
+            }
+        })
+    }
+
+    fn input(&mut self) -> Result<Input, BytecodeError> {
+        use InputMode::*;
+        let offset = self.offset;
+        let mode = match self.byte()? {
+            0 => StdinChar,
+            1 => StdinInt,
+            2 => StdinFloat,
+            3 => Random,
+            4 => DPad(self.direction()?),
+            5 => Button,
+            6 => Keyboard,
+            7 => JoyStick(self.direction()?),
+            8 => Clock,
+            9 => Accelerometer(self.axis()?),
+            10 => Gyroscope(self.axis()?),
+            11 => Microphone,
+            12 => RedLight,
+            13 => GreenLight,
+            14 => BlueLight,
+            15 => Brightness,
+            16 => Humidity,
+            17 => Barometer,
+            18 => Magnetometer(self.axis()?),
+            19 => Thermometer,
+            20 => RainGauge,
+            21 => UVSensor,
+            22 => WindSpeed,
+            23 => WindDirection,
+            24 => PressureGauge,
+            25 => FlowSensor,
+            26 => VolumeSensor,
+            27 => WeightSensor,
+            28 => PHSensor,
+            29 => ConductivitySensor,
+            30 => {
+                let axis_offset = self.offset;
+                Speedometer(match self.usize()? {
+                    0 => None,
+                    1 => Some(Axis::X),
+                    2 => Some(Axis::Y),
+                    3 => Some(Axis::Z),
+                    _ => {
+                        return Err(BytecodeError::Invalid {
+                            what: "axis",
+                            offset: axis_offset,
+                        })
+                    }
+                })
+            }
+            31 => Odometer,
+            32 => Position(self.axis()?),
+            33 => Compass,
+            34 => Proximity,
+            35 => Altimeter,
+            36 => DepthSensor,
+            37 => AnalogPin,
+            38 => DigitalPin,
+            39 => Custom(self.string()?),
+            _ => {
+                return Err(BytecodeError::Invalid {
+                    what: "input mode",
+                    offset,
+                })
+            }
+        };
+        Ok(Input {
+            mode,
+            channel: Channel(self.usize()?),
+        })
+    }
+
+    fn output(&mut self) -> Result<Output, BytecodeError> {
+        use OutputMode::*;
+        let offset = self.offset;
+        let mode = match self.byte()? {
+            0 => StdoutChar,
+            1 => StdoutInt,
+            2 => StdoutFloat,
+            3 => StderrChar,
+            4 => StderrInt,
+            5 => StderrFloat,
+            6 => PrinterChar,
+            7 => PrinterInt,
+            8 => PrinterFloat,
+            9 => Brightness,
+            10 => AnalogPin,
+            11 => DigitalPin,
+            12 => StepperMotor,
+            13 => Solenoid,
+            14 => Valve,
+            15 => MotorSpeed,
+            16 => Servo,
+            17 => Temperature,
+            18 => Pump,
+            19 => Fan,
+            20 => Blower,
+            21 => Heater,
+            22 => Cooler,
+            23 => Pressure,
+            24 => Buzzer,
+            25 => Bell,
+            26 => Note,
+            27 => SpeakerVolume,
+            28 => SpeakerFrequency,
+            29 => UpdateDisplay,
+            30 => ClearDisplay,
+            31 => SetCursorRow,
+            32 => SetCursorColumn,
+            33 => MoveCursorUp,
+            34 => MoveCursorDown,
+            35 => MoveCursorLeft,
+            36 => MoveCursorRight,
+            37 => SetCursorChar(self.color()?),
+            38 => SetCursorPixel(self.color()?),
+            39 => Custom(self.string()?),
+            _ => {
+                return Err(BytecodeError::Invalid {
+                    what: "output mode",
+                    offset,
+                })
+            }
+        };
+        Ok(Output {
+            mode,
+            channel: Channel(self.usize()?),
+        })
+    }
+}
@@ -5,7 +5,9 @@
 //! for ***every*** target.
 use crate::side_effects::{Input, Output};
 
-use super::{Error, StandardOp, StandardProgram, VirtualMachineProgram};
+use super::{
+    optimize::optimize, Error, SourceMap, StandardOp, StandardProgram, VirtualMachineProgram,
+};
 use core::fmt;
 use std::{collections::HashMap, hash::Hash};
 use serde_derive::{Deserialize, Serialize};
@@ -32,33 +34,73 @@ impl CoreProgram {
     /// Flatten a core program so that all of its functions
     /// are defined sequentially at the beginning.
     pub fn flatten(self) -> Self {
-        Self(flatten(self.0).0)
+        Self(flatten(self.0, |op| Some(op)).0)
+    }
+
+    /// Flatten the program like `flatten`, and reorder the source map
+    /// of the program to match.
+    pub fn flatten_with_source_map(self, source_map: &SourceMap) -> (Self, SourceMap) {
+        let code = self.0.into_iter().enumerate().collect();
+        let (code, _, _) = flatten(code, |(_, op)| Some(op));
+        let source_map = source_map.reorder(code.iter().map(|(i, _)| *i));
+        let code = code.into_iter().map(|(_, op)| op).collect();
+        (Self(code), source_map)
+    }
+
+    /// Optimize the program with the peephole rewrites in the `optimize` module.
+    pub fn optimize(self) -> Self {
+        let code = self.0.into_iter().map(StandardOp::CoreOp);
+        Self(core_ops(optimize(code.enumerate().collect())))
+    }
+
+    /// Optimize the program like `optimize`, and reorder the source map
+    /// of the program to match.
+    pub fn optimize_with_source_map(self, source_map: &SourceMap) -> (Self, SourceMap) {
+        let code = self.0.into_iter().map(StandardOp::CoreOp);
+        let code = optimize(code.enumerate().collect());
+        let source_map = source_map.reorder(code.iter().map(|(i, _)| *i));
+        (Self(core_ops(code)), source_map)
     }
 
     /// Get the code outside of any functions.
     pub fn get_main(&self) -> Vec<CoreOp> {
-        flatten(self.0.clone()).2
+        flatten(self.0.clone(), |op| Some(op)).2
     }
 
     /// Get the code for each function.
     pub fn get_functions(&self) -> HashMap<i32, Vec<CoreOp>> {
-        flatten(self.0.clone()).1
+        flatten(self.0.clone(), |op| Some(op)).1
     }
 
     /// Get the code outside of any functions, and the code for each function.
     pub fn get_main_and_functions(self) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>) {
-        let (_, functions, main) = flatten(self.0);
+        let (_, functions, main) = flatten(self.0, |op| Some(op));
         (main, functions)
     }
 }
 
-/// Take all of the functions defined in a list of CoreOps,
+/// Get the core instructions back out of an optimized core program.
+fn core_ops(code: Vec<(usize, StandardOp)>) -> Vec<CoreOp> {
+    code.into_iter()
+        .filter_map(|(_, op)| match op {
+            StandardOp::CoreOp(op) => Some(op),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Take all of the functions defined in a list of instructions,
 /// and flatten their definitions. This will take nested functions
 /// and un-nest them while preserving the order in which functions are defined.
 ///
 /// All the function definitions will be placed at the top of the returned list.
-fn flatten(code: Vec<CoreOp>) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>, Vec<CoreOp>) {
-    let mut functions: HashMap<i32, Vec<CoreOp>> = HashMap::new();
+/// The `core_op` function gets the core instruction of each item in the list,
+/// if it is one; the other items are moved along with their neighbors.
+pub(super) fn flatten<T: Clone>(
+    code: Vec<T>,
+    core_op: impl Fn(&T) -> Option<&CoreOp>,
+) -> (Vec<T>, HashMap<i32, Vec<T>>, Vec<T>) {
+    let mut functions: HashMap<i32, Vec<T>> = HashMap::new();
 
     // The current function body we are in.
     let mut fun = -1;
@@ -72,8 +114,8 @@ fn flatten(code: Vec<CoreOp>) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>, Vec<Co
     // All of the instructions which are not part of a function definition.
     let mut main_instructions = vec![];
     for op in code {
-        match &op {
-            CoreOp::Function => {}
+        match core_op(&op) {
+            Some(CoreOp::Function) => {}
             _ => {
                 if scope_stack.is_empty() {
                     // If we are not defining a function,
@@ -83,8 +125,8 @@ fn flatten(code: Vec<CoreOp>) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>, Vec<Co
             }
         }
 
-        match op {
-            CoreOp::Function => {
+        match core_op(&op) {
+            Some(CoreOp::Function) => {
                 // If we are declaring a new function,
                 // push the info about the current scope onto the scope
                 // stack to resume later.
@@ -99,12 +141,12 @@ fn flatten(code: Vec<CoreOp>) -> (Vec<CoreOp>, HashMap<i32, Vec<CoreOp>>, Vec<Co
                     fun = functions.len() as i32
                 }
             }
-            CoreOp::If | CoreOp::While => {
+            Some(CoreOp::If | CoreOp::While) => {
                 // Increment the number of matching `End`
                 // instructions to end the scope.
                 matching_end += 1
             }
-            CoreOp::End => {
+            Some(CoreOp::End) => {
                 // If the scope has ended
                 if matching_end == 0 {
                     // Get the function body we're defining.
@@ -206,6 +248,8 @@ pub enum CoreOp {
     Call,
     /// Return from the current function.
     Return,
+    /// Stop the program, using the value of the register as its exit code.
+    Halt,
 
     /// Begin a "while the register is not zero" loop.
     While,
@@ -353,6 +397,82 @@ pub enum CoreOp {
     Put(Output),
 }
 
+impl CoreOp {
+    /// The number of cells on the tape, starting at the pointer,
+    /// that the instruction reads or writes.
+    pub fn tape_access(&self) -> usize {
+        match self {
+            CoreOp::Deref => 1,
+            CoreOp::Load(n)
+            | CoreOp::Store(n)
+            | CoreOp::Index(n)
+            | CoreOp::BitwiseNand(n)
+            | CoreOp::BitwiseAnd(n)
+            | CoreOp::BitwiseOr(n)
+            | CoreOp::BitwiseXor(n)
+            | CoreOp::LeftShift(n)
+            | CoreOp::LogicalRightShift(n)
+            | CoreOp::ArithmeticRightShift(n)
+            | CoreOp::Add(n)
+            | CoreOp::Sub(n)
+            | CoreOp::Mul(n)
+            | CoreOp::Div(n)
+            | CoreOp::Rem(n)
+            | CoreOp::And(n)
+            | CoreOp::Or(n)
+            | CoreOp::Swap(n) => *n,
+            _ => 0,
+        }
+    }
+
+    /// Get the name of the instruction, without its arguments.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoreOp::Comment(_) => "comment",
+            CoreOp::Set(_) => "set",
+            CoreOp::Function => "fun",
+            CoreOp::Call => "call",
+            CoreOp::Return => "ret",
+            CoreOp::Halt => "halt",
+            CoreOp::While => "while",
+            CoreOp::If => "if",
+            CoreOp::Else => "else",
+            CoreOp::End => "end",
+            CoreOp::Store(_) => "store",
+            CoreOp::Load(_) => "load",
+            CoreOp::Move(_) => "mov",
+            CoreOp::Offset(..) => "offset",
+            CoreOp::Where => "where",
+            CoreOp::Deref => "deref",
+            CoreOp::Refer => "ref",
+            CoreOp::Index(_) => "index",
+            CoreOp::BitwiseNand(_) => "bitwise-nand",
+            CoreOp::BitwiseAnd(_) => "bitwise-and",
+            CoreOp::BitwiseOr(_) => "bitwise-or",
+            CoreOp::BitwiseXor(_) => "bitwise-xor",
+            CoreOp::BitwiseNot(_) => "bitwise-not",
+            CoreOp::LeftShift(_) => "lsh",
+            CoreOp::LogicalRightShift(_) => "lrsh",
+            CoreOp::ArithmeticRightShift(_) => "arsh",
+            CoreOp::And(_) => "and",
+            CoreOp::Or(_) => "or",
+            CoreOp::Not(_) => "not",
+            CoreOp::Neg(_) => "neg",
+            CoreOp::Add(_) => "add",
+            CoreOp::Sub(_) => "sub",
+            CoreOp::Mul(_) => "mul",
+            CoreOp::Div(_) => "div",
+            CoreOp::Rem(_) => "rem",
+            CoreOp::Inc(_) => "inc",
+            CoreOp::Dec(_) => "dec",
+            CoreOp::Swap(_) => "swap",
+            CoreOp::IsNonNegative(_) => "gez",
+            CoreOp::Get(_) => "get",
+            CoreOp::Put(_) => "put",
+        }
+    }
+}
+
 impl fmt::Display for CoreOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -361,6 +481,7 @@ impl fmt::Display for CoreOp {
             CoreOp::Function => write!(f, "fun"),
             CoreOp::Call => write!(f, "call"),
             CoreOp::Return => write!(f, "ret"),
+            CoreOp::Halt => write!(f, "halt"),
             CoreOp::While => write!(f, "while"),
             CoreOp::If => write!(f, "if"),
             CoreOp::Else => write!(f, "else"),
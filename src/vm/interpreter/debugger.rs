@@ -0,0 +1,595 @@
+//! # Debugger Module
+//!
+//! This module implements an interactive step debugger on top of the
+//! interpreters. The debugger can stop on breakpoints set on instruction
+//! indices or on functions (by index or by their assembly label), and
+//! single-step, step over, or step out of function calls.
+//!
+//! While the program is stopped, the debugger can display the tape around
+//! the pointer, the register vector, the stack of dereferences, and the call
+//! stack. It can also save a snapshot of the machine to a file, to resume
+//! the program from later.
+//!
+//! Instruction indices are positions in the program's list of operations
+//! (comments included), which is the same index the interpreter uses as
+//! its instruction pointer.
+use super::{CoreInterpreter, Device, MachineState, RuntimeError, Snapshot, StandardInterpreter};
+use crate::vm::{CoreOp, CoreProgram, StandardOp, StandardProgram};
+
+use ::std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+};
+
+/// The number of cells shown on either side of the pointer by default.
+const DEFAULT_TAPE_RADIUS: usize = 8;
+/// The number of register cells shown by default.
+const DEFAULT_REGISTER_CELLS: usize = 8;
+/// The number of instructions listed on either side of the instruction pointer by default.
+const DEFAULT_LISTING_RADIUS: usize = 5;
+
+/// An interpreter which can be driven by the debugger.
+pub trait Debuggable {
+    /// The type of program the interpreter executes.
+    type Program;
+
+    /// Execute a single instruction.
+    fn step(&mut self, code: &Self::Program) -> Result<(), RuntimeError>;
+    /// Run the program until it finishes, and return its exit code.
+    fn run_to_end(&mut self, code: &Self::Program) -> Result<i64, RuntimeError>;
+    /// Get a read-only view of the machine's state.
+    fn state(&self) -> MachineState<'_>;
+    /// Take a snapshot of the machine's state.
+    fn snapshot(&self) -> Snapshot;
+    /// Get the number of instructions in the program.
+    fn len(code: &Self::Program) -> usize;
+    /// Get the instruction at the given index, formatted for display.
+    fn show_op(code: &Self::Program, i: usize) -> Option<String>;
+    /// Get the instruction at the given index, if it is a core instruction.
+    fn core_op(code: &Self::Program, i: usize) -> Option<&CoreOp>;
+}
+
+impl<T> Debuggable for CoreInterpreter<T>
+where
+    T: Device,
+{
+    type Program = CoreProgram;
+
+    fn step(&mut self, code: &CoreProgram) -> Result<(), RuntimeError> {
+        CoreInterpreter::step(self, code)
+    }
+
+    fn run_to_end(&mut self, code: &CoreProgram) -> Result<i64, RuntimeError> {
+        CoreInterpreter::run_to_end(self, code)
+    }
+
+    fn state(&self) -> MachineState<'_> {
+        CoreInterpreter::state(self)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        CoreInterpreter::snapshot(self)
+    }
+
+    fn len(code: &CoreProgram) -> usize {
+        code.0.len()
+    }
+
+    fn show_op(code: &CoreProgram, i: usize) -> Option<String> {
+        code.0.get(i).map(ToString::to_string)
+    }
+
+    fn core_op(code: &CoreProgram, i: usize) -> Option<&CoreOp> {
+        code.0.get(i)
+    }
+}
+
+impl<T> Debuggable for StandardInterpreter<T>
+where
+    T: Device,
+{
+    type Program = StandardProgram;
+
+    fn step(&mut self, code: &StandardProgram) -> Result<(), RuntimeError> {
+        StandardInterpreter::step(self, code)
+    }
+
+    fn run_to_end(&mut self, code: &StandardProgram) -> Result<i64, RuntimeError> {
+        StandardInterpreter::run_to_end(self, code)
+    }
+
+    fn state(&self) -> MachineState<'_> {
+        StandardInterpreter::state(self)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        StandardInterpreter::snapshot(self)
+    }
+
+    fn len(code: &StandardProgram) -> usize {
+        code.0.len()
+    }
+
+    fn show_op(code: &StandardProgram, i: usize) -> Option<String> {
+        code.0.get(i).map(ToString::to_string)
+    }
+
+    fn core_op(code: &StandardProgram, i: usize) -> Option<&CoreOp> {
+        match code.0.get(i) {
+            Some(StandardOp::CoreOp(op)) => Some(op),
+            _ => None,
+        }
+    }
+}
+
+/// A location in the program to stop at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Stop before executing the instruction at this index.
+    Instruction(usize),
+    /// Stop on entry to the Nth function defined in the program.
+    Function(usize),
+    /// Stop on entry to the function with this assembly label.
+    Label(String),
+}
+
+/// The reason the debugger stopped executing the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The requested step was completed.
+    Step,
+    /// The program reached a breakpoint at the given instruction index.
+    Breakpoint(usize),
+    /// The program finished executing.
+    Finished,
+    /// The program was terminated by a runtime error, so it can't run any further.
+    Terminated,
+}
+
+/// An interactive debugger for a virtual machine program.
+pub struct Debugger<'a, I>
+where
+    I: Debuggable,
+{
+    /// The interpreter running the program.
+    interpreter: I,
+    /// The program being debugged.
+    code: &'a I::Program,
+    /// The assembly labels of the functions, indexed by function number.
+    labels: Vec<String>,
+    /// The instruction indices of the `Function` and matching `End`
+    /// of each function, indexed by function number.
+    functions: Vec<(usize, usize)>,
+    /// The instruction indices to stop at.
+    breakpoints: BTreeSet<usize>,
+    /// Did the program fail with a runtime error?
+    failed: bool,
+}
+
+impl<'a, I> Debugger<'a, I>
+where
+    I: Debuggable,
+{
+    /// Create a debugger for the given program, running on the given interpreter.
+    pub fn new(interpreter: I, code: &'a I::Program) -> Self {
+        // Find the bounds of every function in the program. Functions are
+        // numbered in the order their `Function` instructions appear.
+        let mut functions = vec![];
+        let mut blocks = vec![];
+        for i in 0..I::len(code) {
+            match I::core_op(code, i) {
+                Some(CoreOp::Function) => {
+                    blocks.push(Some(functions.len()));
+                    functions.push((i, i));
+                }
+                Some(CoreOp::If | CoreOp::While) => blocks.push(None),
+                Some(CoreOp::End) => {
+                    if let Some(Some(n)) = blocks.pop() {
+                        functions[n].1 = i;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            interpreter,
+            code,
+            labels: vec![],
+            functions,
+            breakpoints: BTreeSet::new(),
+            failed: false,
+        }
+    }
+
+    /// Name the functions in the program with their assembly labels,
+    /// indexed by function number.
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Get the interpreter running the program.
+    pub fn interpreter(&self) -> &I {
+        &self.interpreter
+    }
+
+    /// Consume the debugger and return the interpreter running the program.
+    pub fn into_interpreter(self) -> I {
+        self.interpreter
+    }
+
+    /// Has the program finished executing, or been terminated by a runtime error?
+    pub fn is_done(&self) -> bool {
+        self.failed || self.interpreter.state().done
+    }
+
+    /// Add a breakpoint, and return the instruction index it stops at.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> Result<usize, String> {
+        let i = match breakpoint {
+            Breakpoint::Instruction(i) if i < I::len(self.code) => i,
+            Breakpoint::Instruction(i) => return Err(format!("no instruction #{i}")),
+            Breakpoint::Function(n) => match self.functions.get(n) {
+                // Stop on the first instruction of the function's body.
+                Some((start, _)) => start + 1,
+                None => return Err(format!("function {n} not defined")),
+            },
+            Breakpoint::Label(name) => match self.labels.iter().position(|l| *l == name) {
+                Some(n) => return self.add_breakpoint(Breakpoint::Function(n)),
+                None => return Err(format!("undefined label @{name}")),
+            },
+        };
+        self.breakpoints.insert(i);
+        Ok(i)
+    }
+
+    /// Remove the breakpoint at the given instruction index.
+    /// Returns whether there was a breakpoint to remove.
+    pub fn remove_breakpoint(&mut self, i: usize) -> bool {
+        self.breakpoints.remove(&i)
+    }
+
+    /// Get the instruction indices of all the breakpoints.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Get the function containing the given instruction index, if any.
+    pub fn function_at(&self, i: usize) -> Option<usize> {
+        // Functions may be nested, so pick the innermost one.
+        self.functions
+            .iter()
+            .enumerate()
+            .filter(|(_, (start, end))| *start <= i && i <= *end)
+            .max_by_key(|(_, (start, _))| *start)
+            .map(|(n, _)| n)
+    }
+
+    /// Get the name of the Nth function: its label if known, otherwise its number.
+    pub fn function_name(&self, n: usize) -> String {
+        match self.labels.get(n) {
+            Some(label) => format!("@{label}"),
+            None => format!("fn#{n}"),
+        }
+    }
+
+    /// Describe the code containing the given instruction index.
+    fn describe_location(&self, i: usize) -> String {
+        match self.function_at(i) {
+            Some(n) => format!("#{i} in {}", self.function_name(n)),
+            None => format!("#{i} in <main>"),
+        }
+    }
+
+    /// Execute instructions until `stop` returns true for the machine's state,
+    /// a breakpoint is reached, or the program ends.
+    fn run_until(
+        &mut self,
+        mut stop: impl FnMut(&MachineState) -> bool,
+    ) -> Result<StopReason, RuntimeError> {
+        loop {
+            if self.failed {
+                return Ok(StopReason::Terminated);
+            } else if self.is_done() {
+                return Ok(StopReason::Finished);
+            }
+            // The machine is left as it was when the error happened, so it can be
+            // inspected, but executing any more instructions would be meaningless.
+            if let Err(e) = self.interpreter.step(self.code) {
+                self.failed = true;
+                return Err(e);
+            }
+
+            let state = self.interpreter.state();
+            if state.done {
+                return Ok(StopReason::Finished);
+            } else if self.breakpoints.contains(&state.i) {
+                return Ok(StopReason::Breakpoint(state.i));
+            } else if stop(&state) {
+                return Ok(StopReason::Step);
+            }
+        }
+    }
+
+    /// Execute a single instruction.
+    pub fn step(&mut self) -> Result<StopReason, RuntimeError> {
+        self.run_until(|_| true)
+    }
+
+    /// Execute a single instruction. If the instruction calls a function,
+    /// run until the function returns.
+    pub fn step_over(&mut self) -> Result<StopReason, RuntimeError> {
+        let depth = self.interpreter.state().calls.len();
+        self.run_until(|state| state.calls.len() <= depth)
+    }
+
+    /// Run until the current function returns to its caller.
+    pub fn step_out(&mut self) -> Result<StopReason, RuntimeError> {
+        let depth = self.interpreter.state().calls.len();
+        if depth == 0 {
+            // There's no caller to return to, so run to the end of the program.
+            return self.resume();
+        }
+        self.run_until(|state| state.calls.len() < depth)
+    }
+
+    /// Run until a breakpoint is reached or the program ends.
+    pub fn resume(&mut self) -> Result<StopReason, RuntimeError> {
+        self.run_until(|_| false)
+    }
+
+    /// Show the instructions around the instruction pointer.
+    pub fn show_listing(&self, radius: usize) -> String {
+        let i = self.interpreter.state().i;
+        let mut result = String::new();
+        for j in i.saturating_sub(radius)..(i + radius + 1).min(I::len(self.code)) {
+            let marker = if j == i { "=>" } else { "  " };
+            let bp = if self.breakpoints.contains(&j) {
+                "*"
+            } else {
+                " "
+            };
+            let op = I::show_op(self.code, j).unwrap_or_default();
+            result += &format!("{marker}{bp}{j:08x}: {op}\n");
+        }
+        result
+    }
+
+    /// Show the cells of the tape within `radius` cells of the pointer.
+    pub fn show_tape(&self, radius: usize) -> String {
+        let state = self.interpreter.state();
+        let mut result = String::new();
+        for addr in state.pointer.saturating_sub(radius)..=state.pointer + radius {
+            // Cells past the end of the tape haven't been touched yet, so they're zero.
+            let val = state.cells.get(addr).copied().unwrap_or(0);
+            let marker = if addr == state.pointer { "=>" } else { "  " };
+            result += &format!("{marker}[{addr}] = {val}\n");
+        }
+        result
+    }
+
+    /// Show the first `n` cells of the register.
+    pub fn show_register(&self, n: usize) -> String {
+        let register = self.interpreter.state().register;
+        let shown = &register[..n.min(register.len())];
+        if shown.len() < register.len() {
+            format!("{shown:?} ({} more)\n", register.len() - shown.len())
+        } else {
+            format!("{shown:?}\n")
+        }
+    }
+
+    /// Show the stack of pointers saved by `Deref` instructions, innermost first.
+    pub fn show_refs(&self) -> String {
+        let refs = self.interpreter.state().refs;
+        if refs.is_empty() {
+            return String::from("deref stack is empty\n");
+        }
+        let mut result = String::new();
+        for (depth, addr) in refs.iter().enumerate().rev() {
+            result += &format!("#{depth}: {addr}\n");
+        }
+        result
+    }
+
+    /// Show the call stack, innermost frame first.
+    pub fn show_calls(&self) -> String {
+        let state = self.interpreter.state();
+        let mut result = format!(
+            "#{}: {}\n",
+            state.calls.len(),
+            self.describe_location(state.i)
+        );
+        for (depth, ret) in state.calls.iter().enumerate().rev() {
+            result += &format!("#{depth}: {}\n", self.describe_location(*ret));
+        }
+        result
+    }
+
+    /// Show the functions defined in the program.
+    pub fn show_functions(&self) -> String {
+        let mut result = String::new();
+        for (n, (start, end)) in self.functions.iter().enumerate() {
+            result += &format!("{}: #{start}..#{end}\n", self.function_name(n));
+        }
+        result
+    }
+
+    /// Show where the program stopped, and why.
+    fn show_stop(&self, reason: StopReason) -> String {
+        let i = self.interpreter.state().i;
+        match reason {
+            StopReason::Finished => match self.interpreter.state().exit_code {
+                Some(code) => format!("program exited with code {code}\n"),
+                None => String::from("program finished\n"),
+            },
+            StopReason::Terminated => String::from("program terminated\n"),
+            StopReason::Breakpoint(_) | StopReason::Step => {
+                let op = I::show_op(self.code, i).unwrap_or_default();
+                let prefix = if let StopReason::Breakpoint(_) = reason {
+                    "breakpoint at "
+                } else {
+                    ""
+                };
+                format!("{prefix}{}: {op}\n", self.describe_location(i))
+            }
+        }
+    }
+
+    /// Parse the location of a breakpoint: an instruction index,
+    /// a function label (`@name`), or a function number (`fn N`).
+    fn parse_breakpoint(args: &[&str]) -> Result<Breakpoint, String> {
+        match args {
+            [label] if label.starts_with('@') => Ok(Breakpoint::Label(label[1..].to_string())),
+            ["fn", n] => n
+                .parse()
+                .map(Breakpoint::Function)
+                .map_err(|_| format!("invalid function number {n:?}")),
+            [i] => parse_index(i).map(Breakpoint::Instruction),
+            _ => Err(String::from("usage: break <index> | @<label> | fn <n>")),
+        }
+    }
+
+    /// Execute a single debugger command, and return its output.
+    /// Returns `None` if the command quits the debugger.
+    fn command(&mut self, line: &str) -> Result<Option<String>, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (cmd, args) = match words.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Ok(Some(String::new())),
+        };
+        // Parse an optional numeric argument, falling back on a default.
+        let count = |default: usize| -> Result<usize, String> {
+            match args.first() {
+                Some(n) => n.parse().map_err(|_| format!("invalid number {n:?}")),
+                None => Ok(default),
+            }
+        };
+
+        Ok(Some(match cmd {
+            "help" | "h" | "?" => String::from(HELP),
+            "step" | "s" => {
+                let mut reason = StopReason::Step;
+                for _ in 0..count(1)? {
+                    reason = self.step().map_err(|e| e.to_string())?;
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                self.show_stop(reason)
+            }
+            "next" | "n" => {
+                let reason = self.step_over().map_err(|e| e.to_string())?;
+                self.show_stop(reason)
+            }
+            "finish" | "out" | "o" => {
+                let reason = self.step_out().map_err(|e| e.to_string())?;
+                self.show_stop(reason)
+            }
+            "continue" | "c" => {
+                let reason = self.resume().map_err(|e| e.to_string())?;
+                self.show_stop(reason)
+            }
+            "break" | "b" => {
+                let i = self.add_breakpoint(Self::parse_breakpoint(args)?)?;
+                format!("breakpoint set at {}\n", self.describe_location(i))
+            }
+            "delete" | "d" => {
+                let i = parse_index(args.first().copied().unwrap_or_default())?;
+                if self.remove_breakpoint(i) {
+                    format!("breakpoint at #{i} deleted\n")
+                } else {
+                    format!("no breakpoint at #{i}\n")
+                }
+            }
+            "breakpoints" | "bl" => self
+                .breakpoints()
+                .map(|i| format!("{}\n", self.describe_location(i)))
+                .collect(),
+            "list" | "l" => self.show_listing(count(DEFAULT_LISTING_RADIUS)?),
+            "tape" | "t" => self.show_tape(count(DEFAULT_TAPE_RADIUS)?),
+            "register" | "reg" | "r" => self.show_register(count(DEFAULT_REGISTER_CELLS)?),
+            "refs" => self.show_refs(),
+            "calls" | "backtrace" | "bt" => self.show_calls(),
+            "functions" | "fns" => self.show_functions(),
+            "snapshot" | "snap" => {
+                let path = args
+                    .first()
+                    .ok_or_else(|| String::from("usage: snapshot <file>"))?;
+                ::std::fs::write(path, self.interpreter.snapshot().to_json())
+                    .map_err(|e| format!("could not write snapshot to {path:?}: {e}"))?;
+                format!("snapshot written to {path:?}\n")
+            }
+            "quit" | "q" => return Ok(None),
+            _ => format!("unknown command {cmd:?}, try \"help\"\n"),
+        }))
+    }
+
+    /// Run the debugger interactively, reading commands from `input`
+    /// and writing their results to `output`. An empty line repeats the
+    /// last command.
+    pub fn run_interactive(
+        &mut self,
+        mut input: impl BufRead,
+        mut output: impl Write,
+    ) -> ::std::io::Result<()> {
+        write!(output, "{}", self.show_stop(StopReason::Step))?;
+
+        let mut last = String::new();
+        loop {
+            write!(output, "(sage-dbg) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                // The input was closed, so stop debugging.
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                line = last.clone();
+            } else {
+                last = line.clone();
+            }
+
+            match self.command(&line) {
+                Ok(Some(text)) => write!(output, "{text}")?,
+                Ok(None) => return Ok(()),
+                // Runtime errors end the program, but the user can still
+                // inspect the machine's state after the failure.
+                Err(e) => writeln!(output, "error: {e}")?,
+            }
+        }
+    }
+}
+
+/// Parse an instruction index, written in decimal or in hex with a `0x` prefix.
+fn parse_index(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid instruction index {s:?}"))
+}
+
+/// The help text for the interactive debugger.
+const HELP: &str = "\
+commands:
+  step [n], s [n]           execute the next n instructions
+  next, n                   execute the next instruction, stepping over calls
+  finish, out, o            run until the current function returns
+  continue, c               run until a breakpoint or the end of the program
+  break <index>, b <index>  stop before the instruction at an index (`0x` for hex)
+  break @<label>            stop on entry to the function with an assembly label
+  break fn <n>              stop on entry to the nth function
+  delete <index>, d <index> remove the breakpoint at an index
+  breakpoints, bl           list the breakpoints
+  list [n], l [n]           show the instructions around the instruction pointer
+  tape [n], t [n]           show the tape within n cells of the pointer
+  register [n], r [n]       show the first n cells of the register
+  refs                      show the stack of pointers saved by `deref`
+  calls, backtrace, bt      show the call stack
+  functions, fns            show the functions in the program
+  snapshot <file>           save the machine's state to a file, to resume from
+  quit, q                   stop debugging
+An empty line repeats the last command.
+";
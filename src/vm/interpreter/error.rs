@@ -0,0 +1,187 @@
+//! # Runtime Error Module
+//!
+//! This module defines the errors which stop the virtual machine's interpreters
+//! while they run a program, and the stack traces which say where they failed.
+use super::Limit;
+use crate::{side_effects::FFIBinding, vm::SourceFrame};
+use ::std::fmt;
+
+/// Where a running program was when it failed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StackTrace {
+    /// The index of the instruction which failed.
+    pub instruction: usize,
+    /// The call stack when the instruction failed. Each entry is the index
+    /// of a `Call` instruction which has not returned yet, innermost last.
+    pub calls: Vec<usize>,
+    /// The source code of the failed instruction, followed by the source code
+    /// of each call (innermost first). This is empty if the interpreter has no
+    /// source map for the program.
+    pub frames: Vec<SourceFrame>,
+}
+
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instruction #{}", self.instruction)?;
+        for call in self.calls.iter().rev() {
+            write!(f, ", called from instruction #{call}")?;
+        }
+        for frame in &self.frames {
+            write!(f, "\n    at {frame}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An error which stopped an interpreter while it was running a program.
+///
+/// Every error carries the stack trace of where it happened. Errors created
+/// by a `Device` can leave the trace empty (with `StackTrace::default()`),
+/// and the interpreter will fill it in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// The program used more of a resource than its `Limits` allow.
+    LimitExceeded {
+        limit: Limit,
+        max: usize,
+        trace: StackTrace,
+    },
+    /// The program moved the pointer to a negative index on the tape.
+    NegativePointer { trace: StackTrace },
+    /// The program used an address on the tape which is too far out for the
+    /// tape to be extended to, when the tape has `len` cells.
+    TapeOutOfBounds {
+        address: usize,
+        len: usize,
+        trace: StackTrace,
+    },
+    /// The program dereferenced a cell holding a negative address.
+    NegativeDeref { value: i64, trace: StackTrace },
+    /// The program used `Refer` without a matching `Deref`.
+    EmptyRefStack { trace: StackTrace },
+    /// The program called a function which isn't defined.
+    UndefinedFunction { function: i64, trace: StackTrace },
+    /// The program freed the same block of memory twice.
+    DoubleFree { addr: usize, trace: StackTrace },
+    /// The program freed an address which was not allocated on the heap.
+    InvalidFree { addr: usize, trace: StackTrace },
+    /// The program used a block of memory after freeing it.
+    UseAfterFree {
+        /// The address which was used.
+        addr: usize,
+        /// The address of the freed block.
+        block: usize,
+        /// The size of the freed block.
+        size: usize,
+        trace: StackTrace,
+    },
+    /// The device could not get input for the program.
+    Input { message: String, trace: StackTrace },
+    /// The device could not write the program's output.
+    Output { message: String, trace: StackTrace },
+    /// The program peeked at the FFI channel while it was empty.
+    EmptyFFIChannel { trace: StackTrace },
+    /// The program called a foreign function which the device doesn't provide.
+    FFINotFound {
+        binding: FFIBinding,
+        trace: StackTrace,
+    },
+    /// A foreign function failed.
+    FFI {
+        binding: FFIBinding,
+        message: String,
+        trace: StackTrace,
+    },
+}
+
+impl RuntimeError {
+    /// Get the stack trace of where the error happened.
+    pub fn trace(&self) -> &StackTrace {
+        match self {
+            Self::LimitExceeded { trace, .. }
+            | Self::NegativePointer { trace }
+            | Self::TapeOutOfBounds { trace, .. }
+            | Self::NegativeDeref { trace, .. }
+            | Self::EmptyRefStack { trace }
+            | Self::UndefinedFunction { trace, .. }
+            | Self::DoubleFree { trace, .. }
+            | Self::InvalidFree { trace, .. }
+            | Self::UseAfterFree { trace, .. }
+            | Self::Input { trace, .. }
+            | Self::Output { trace, .. }
+            | Self::EmptyFFIChannel { trace }
+            | Self::FFINotFound { trace, .. }
+            | Self::FFI { trace, .. } => trace,
+        }
+    }
+
+    /// Get a mutable reference to the stack trace of where the error happened.
+    fn trace_mut(&mut self) -> &mut StackTrace {
+        match self {
+            Self::LimitExceeded { trace, .. }
+            | Self::NegativePointer { trace }
+            | Self::TapeOutOfBounds { trace, .. }
+            | Self::NegativeDeref { trace, .. }
+            | Self::EmptyRefStack { trace }
+            | Self::UndefinedFunction { trace, .. }
+            | Self::DoubleFree { trace, .. }
+            | Self::InvalidFree { trace, .. }
+            | Self::UseAfterFree { trace, .. }
+            | Self::Input { trace, .. }
+            | Self::Output { trace, .. }
+            | Self::EmptyFFIChannel { trace }
+            | Self::FFINotFound { trace, .. }
+            | Self::FFI { trace, .. } => trace,
+        }
+    }
+
+    /// Set the stack trace of where the error happened.
+    pub fn at(mut self, trace: StackTrace) -> Self {
+        *self.trace_mut() = trace;
+        self
+    }
+
+    /// Describe the error, without saying where it happened.
+    pub fn message(&self) -> String {
+        match self {
+            Self::LimitExceeded { limit, max, .. } => {
+                format!("limit exceeded: {limit} (maximum is {max})")
+            }
+            Self::NegativePointer { .. } => {
+                String::from("tried to move the pointer to a negative index")
+            }
+            Self::TapeOutOfBounds { address, len, .. } => {
+                format!("address {address} is out of bounds of the tape of {len} cells")
+            }
+            Self::NegativeDeref { value, .. } => {
+                format!("tried to dereference the negative address {value}")
+            }
+            Self::EmptyRefStack { .. } => String::from("cannot Refer due to empty Deref stack"),
+            Self::UndefinedFunction { function, .. } => format!("function {function} not defined"),
+            Self::DoubleFree { addr, .. } => format!("double free of address {addr}"),
+            Self::InvalidFree { addr, .. } => {
+                format!("freed address {addr}, which was not allocated on the heap")
+            }
+            Self::UseAfterFree {
+                addr, block, size, ..
+            } => format!(
+                "use after free of address {addr} (in the block of {size} cells freed at address {block})"
+            ),
+            Self::Input { message, .. } => format!("input failed: {message}"),
+            Self::Output { message, .. } => format!("output failed: {message}"),
+            Self::EmptyFFIChannel { .. } => String::from("ffi channel is empty"),
+            Self::FFINotFound { binding, .. } => format!("ffi call not found: {}", binding.name),
+            Self::FFI {
+                binding, message, ..
+            } => format!("ffi call {} failed: {message}", binding.name),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message(), self.trace())
+    }
+}
+
+impl ::std::error::Error for RuntimeError {}
@@ -0,0 +1,145 @@
+//! # Heap Module
+//!
+//! This module implements the heap allocator used by the standard interpreter
+//! for the `Alloc` and `Free` instructions.
+//!
+//! The heap grows upward from the end of the tape in use when the first block is
+//! allocated (after the stack). Freed blocks are kept in a free list, coalesced with
+//! their free neighbors, and reused by later allocations using a best-fit search.
+//!
+//! In checked mode, freed blocks are never reused. Instead, they are remembered
+//! so that double frees and uses of freed memory can be reported.
+use super::{RuntimeError, StackTrace};
+use ::std::collections::{BTreeMap, BTreeSet};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+/// A heap allocator over the cells of the tape.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Heap {
+    /// The address just past the highest block ever allocated.
+    /// This is `None` until the first allocation.
+    top: Option<usize>,
+    /// The address and size of each allocated block.
+    allocated: BTreeMap<usize, usize>,
+    /// The address and size of each free block.
+    free_by_addr: BTreeMap<usize, usize>,
+    /// The size and address of each free block, for best-fit searches.
+    free_by_size: BTreeSet<(usize, usize)>,
+    /// Are heap errors reported, instead of ignored?
+    checked: bool,
+    /// The address and size of each freed block (only used in checked mode).
+    freed: BTreeMap<usize, usize>,
+}
+
+impl Heap {
+    /// Create a heap which reports double frees, frees of addresses not
+    /// allocated on the heap, and uses of freed memory.
+    pub(super) fn checked() -> Self {
+        Self {
+            checked: true,
+            ..Self::default()
+        }
+    }
+
+    /// Is the heap reporting errors?
+    pub(super) fn is_checked(&self) -> bool {
+        self.checked
+    }
+
+    /// Allocate a block of `size` cells, and return its address.
+    ///
+    /// `tape_len` is the current length of the tape. New blocks are never
+    /// placed below the end of the tape, unless they reuse freed blocks.
+    /// The tape must be extended to fit the block by the caller.
+    pub(super) fn alloc(&mut self, size: usize, tape_len: usize) -> usize {
+        // Give every block a unique address, even if it's empty.
+        let size = size.max(1);
+
+        // Find the smallest free block that fits.
+        if let Some(&(free_size, addr)) = self.free_by_size.range((size, 0)..).next() {
+            self.remove_free(addr, free_size);
+            if free_size > size {
+                // Return the unused end of the block to the free list.
+                self.insert_free(addr + size, free_size - size);
+            }
+            self.allocated.insert(addr, size);
+            return addr;
+        }
+
+        // Otherwise, allocate a new block at the top of the heap.
+        let addr = self.top.unwrap_or_default().max(tape_len.saturating_sub(1));
+        self.top = Some(addr + size);
+        self.allocated.insert(addr, size);
+        addr
+    }
+
+    /// Free the block at the given address.
+    pub(super) fn free(&mut self, addr: usize) -> Result<(), RuntimeError> {
+        let Some(size) = self.allocated.remove(&addr) else {
+            let trace = StackTrace::default();
+            let err = if self.freed.contains_key(&addr) {
+                RuntimeError::DoubleFree { addr, trace }
+            } else {
+                RuntimeError::InvalidFree { addr, trace }
+            };
+            if self.checked {
+                return Err(err);
+            }
+            warn!("{}", err.message());
+            return Ok(());
+        };
+
+        if self.checked {
+            // Never reuse freed memory, so that any use of it can be caught.
+            self.freed.insert(addr, size);
+            return Ok(());
+        }
+
+        // Merge the block with the free blocks on either side of it.
+        let (mut addr, mut size) = (addr, size);
+        if let Some((&prev, &prev_size)) = self.free_by_addr.range(..addr).next_back() {
+            if prev + prev_size == addr {
+                self.remove_free(prev, prev_size);
+                addr = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(&next_size) = self.free_by_addr.get(&(addr + size)) {
+            self.remove_free(addr + size, next_size);
+            size += next_size;
+        }
+        self.insert_free(addr, size);
+        Ok(())
+    }
+
+    /// Confirm that `len` cells starting at `addr` don't overlap a freed block.
+    /// This only finds errors in checked mode.
+    pub(super) fn check_access(&self, addr: usize, len: usize) -> Result<(), RuntimeError> {
+        if len == 0 {
+            return Ok(());
+        }
+        // Find the last freed block starting before the end of the accessed range.
+        if let Some((&start, &size)) = self.freed.range(..addr + len).next_back() {
+            if addr < start + size {
+                return Err(RuntimeError::UseAfterFree {
+                    addr: addr.max(start),
+                    block: start,
+                    size,
+                    trace: StackTrace::default(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_free(&mut self, addr: usize, size: usize) {
+        self.free_by_addr.insert(addr, size);
+        self.free_by_size.insert((size, addr));
+    }
+
+    fn remove_free(&mut self, addr: usize, size: usize) {
+        self.free_by_addr.remove(&addr);
+        self.free_by_size.remove(&(size, addr));
+    }
+}
@@ -14,38 +14,165 @@ mod core;
 pub use self::core::*;
 mod std;
 pub use self::std::*;
+mod debugger;
+pub use self::debugger::*;
+mod error;
+mod heap;
+mod profile;
+mod resolve;
+mod snapshot;
+pub use self::error::*;
+pub use self::profile::*;
+pub use self::snapshot::*;
 
 use ::std::{
     collections::{HashMap, VecDeque},
+    fmt,
     io::{stdin, stdout, Read, Write},
 };
 
 /// The amount by which the tape is extended whenever the pointer moves past the end
 pub(super) const TAPE_EXTENSION_SIZE: usize = 100000;
 
+/// Limits on the resources a program may use while it is interpreted.
+/// A limit of `None` leaves the resource unbounded, which is the default.
+///
+/// This allows untrusted programs to be interpreted without risking
+/// infinite loops or unbounded memory usage.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of instructions to execute.
+    pub max_instructions: Option<usize>,
+    /// The maximum number of cells on the turing tape.
+    pub max_tape_cells: Option<usize>,
+    /// The maximum depth of the call stack.
+    pub max_call_depth: Option<usize>,
+    /// The maximum depth of the stack of dereferences.
+    pub max_refs_depth: Option<usize>,
+}
+
+impl Limits {
+    /// Get the maximum allowed for a given resource, if it is limited.
+    pub fn get(&self, limit: Limit) -> Option<usize> {
+        match limit {
+            Limit::Instructions => self.max_instructions,
+            Limit::TapeCells => self.max_tape_cells,
+            Limit::CallDepth => self.max_call_depth,
+            Limit::RefsDepth => self.max_refs_depth,
+        }
+    }
+
+    /// Confirm that using `amount` of a resource is within its limit.
+    pub(super) fn check(&self, limit: Limit, amount: usize) -> Result<(), RuntimeError> {
+        match self.get(limit) {
+            Some(max) if amount > max => Err(RuntimeError::LimitExceeded {
+                limit,
+                max,
+                trace: StackTrace::default(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A resource whose usage can be limited by `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// The number of instructions executed.
+    Instructions,
+    /// The number of cells on the turing tape.
+    TapeCells,
+    /// The depth of the call stack.
+    CallDepth,
+    /// The depth of the stack of dereferences.
+    RefsDepth,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Instructions => write!(f, "executed instructions"),
+            Self::TapeCells => write!(f, "tape cells"),
+            Self::CallDepth => write!(f, "call depth"),
+            Self::RefsDepth => write!(f, "dereference depth"),
+        }
+    }
+}
+
+/// A read-only view of the state of an interpreter's machine.
+///
+/// This is used by tools like the debugger to inspect a running
+/// program without being able to modify it.
+#[derive(Debug, Clone, Copy)]
+pub struct MachineState<'a> {
+    /// The index of the next instruction to execute.
+    pub i: usize,
+    /// The current pointer on the turing tape.
+    pub pointer: usize,
+    /// The register vector.
+    pub register: &'a [i64],
+    /// The turing tape.
+    pub cells: &'a [i64],
+    /// The instruction pointers of the functions discovered so far.
+    pub functions: &'a [usize],
+    /// The call stack of return addresses.
+    pub calls: &'a [usize],
+    /// The stack of pointers saved by `Deref` instructions.
+    pub refs: &'a [usize],
+    /// Has the machine finished executing the program?
+    pub done: bool,
+    /// The exit code given by a `Halt` instruction, if the program halted.
+    pub exit_code: Option<i64>,
+}
+
 /// Create an input / output device for the virtual machine interpreter
 /// to operate on. The method `get` retrieves the device's input, and the
 /// function `put` writes to the devices output.
 ///
+/// Errors returned by a device don't need a stack trace; the interpreter
+/// fills it in with where the program was when the device failed.
+///
 /// TODO: Make a trait for a device with the standard variant, which requires
 /// `get_char`, `put_char`, `get_int`, `put_int`, `get_float`, and `put_float` methods.
 pub trait Device {
     /// Get the next input (from a given input source).
-    fn get(&mut self, src: Input) -> Result<i64, String>;
+    fn get(&mut self, src: Input) -> Result<i64, RuntimeError>;
     /// Put the given value to the given output destination.
-    fn put(&mut self, val: i64, dst: Output) -> Result<(), String>;
+    fn put(&mut self, val: i64, dst: Output) -> Result<(), RuntimeError>;
 
     /// Peek at the next value in the FFI buffer for the FFI function calls.
     /// Store the peeked value in the register.
-    fn peek(&mut self) -> Result<i64, String>;
+    fn peek(&mut self) -> Result<i64, RuntimeError>;
     /// Poke a value into the FFI buffer for the FFI function calls.
-    fn poke(&mut self, val: i64) -> Result<(), String>;
+    fn poke(&mut self, val: i64) -> Result<(), RuntimeError>;
 
     /// FFI call to the device. This will get the FFI binding for the device
     /// and call the function associated with the binding. If the tape is
     /// provided, the foreign function may mutate the tape. Otherwise all
     /// interaction with the FFI is done through the FFI channel.
-    fn ffi_call(&mut self, ffi: &FFIBinding, tape: Option<&mut Vec<i64>>) -> Result<(), String>;
+    fn ffi_call(
+        &mut self,
+        ffi: &FFIBinding,
+        tape: Option<&mut Vec<i64>>,
+    ) -> Result<(), RuntimeError>;
+}
+
+/// Check that the FFI channel holds all of the arguments of a foreign function
+/// before calling it, so the function doesn't run out of arguments partway through.
+fn check_ffi_arguments(ffi: &FFIBinding, channel: &VecDeque<i64>) -> Result<(), RuntimeError> {
+    if channel.len() < ffi.input_cells {
+        error!("Not enough arguments for FFI call: {:?}", ffi);
+        return Err(RuntimeError::FFI {
+            binding: ffi.clone(),
+            message: format!(
+                "expected {} arguments on the ffi channel, but found {}",
+                ffi.input_cells,
+                channel.len()
+            ),
+            trace: StackTrace::default(),
+        });
+    }
+    Ok(())
 }
 
 /// A device used for testing the compiler. This simply keeps a buffer
@@ -86,30 +213,30 @@ impl TestingDevice {
         }
     }
 
-    fn put_char(&mut self, ch: char) -> Result<(), String> {
+    fn put_char(&mut self, ch: char) -> Result<(), RuntimeError> {
         self.output.push((ch as u64 as i64, Output::stdout_char()));
         Ok(())
     }
 
-    fn put_int(&mut self, val: i64) -> Result<(), String> {
+    fn put_int(&mut self, val: i64) -> Result<(), RuntimeError> {
         for ch in val.to_string().chars() {
             self.put_char(ch)?
         }
         Ok(())
     }
 
-    fn put_float(&mut self, val: f64) -> Result<(), String> {
+    fn put_float(&mut self, val: f64) -> Result<(), RuntimeError> {
         for ch in format!("{val:?}").chars() {
             self.put_char(ch)?
         }
         Ok(())
     }
 
-    fn get_char(&mut self) -> Result<char, String> {
+    fn get_char(&mut self) -> Result<char, RuntimeError> {
         self.get(Input::stdin_char()).map(|n| n as u8 as char)
     }
 
-    fn get_int(&mut self) -> Result<i64, String> {
+    fn get_int(&mut self) -> Result<i64, RuntimeError> {
         let mut result: i64 = 0;
         loop {
             if self.input.is_empty() {
@@ -144,7 +271,7 @@ impl TestingDevice {
         Ok(result)
     }
 
-    fn get_float(&mut self) -> Result<f64, String> {
+    fn get_float(&mut self) -> Result<f64, RuntimeError> {
         let whole_part = self.get_int()? as f64;
 
         if self.input.is_empty() {
@@ -193,14 +320,17 @@ impl TestingDevice {
 
 /// Make the testing device work with the interpreter.
 impl Device for TestingDevice {
-    fn get(&mut self, src: Input) -> Result<i64, String> {
+    fn get(&mut self, src: Input) -> Result<i64, RuntimeError> {
         match src.mode {
             InputMode::StdinChar => {
                 if let Some(n) = self.input.pop_front() {
                     Ok(n)
                 } else {
                     error!("Tried to get character from empty input buffer");
-                    Err("input is empty".to_string())
+                    Err(RuntimeError::Input {
+                        message: String::from("input is empty"),
+                        trace: StackTrace::default(),
+                    })
                 }
             }
             InputMode::StdinInt => self.get_int(),
@@ -212,7 +342,7 @@ impl Device for TestingDevice {
         }
     }
 
-    fn put(&mut self, val: i64, dst: Output) -> Result<(), String> {
+    fn put(&mut self, val: i64, dst: Output) -> Result<(), RuntimeError> {
         match dst.mode {
             OutputMode::StdoutChar => {
                 self.output.push((val, dst));
@@ -227,28 +357,38 @@ impl Device for TestingDevice {
         }
     }
 
-    fn peek(&mut self) -> Result<i64, String> {
+    fn peek(&mut self) -> Result<i64, RuntimeError> {
         if let Some(n) = self.ffi_channel.pop_front() {
             Ok(n)
         } else {
             error!("Tried to peek from empty ffi channel");
-            Err("ffi channel is empty".to_string())
+            Err(RuntimeError::EmptyFFIChannel {
+                trace: StackTrace::default(),
+            })
         }
     }
 
-    fn poke(&mut self, val: i64) -> Result<(), String> {
+    fn poke(&mut self, val: i64) -> Result<(), RuntimeError> {
         self.ffi_channel.push_back(val);
         Ok(())
     }
 
-    fn ffi_call(&mut self, ffi: &FFIBinding, tape: Option<&mut Vec<i64>>) -> Result<(), String> {
+    fn ffi_call(
+        &mut self,
+        ffi: &FFIBinding,
+        tape: Option<&mut Vec<i64>>,
+    ) -> Result<(), RuntimeError> {
         if let Some(f) = self.ffi.get(ffi) {
             trace!("Calling FFI: {}", ffi);
+            check_ffi_arguments(ffi, &self.ffi_channel)?;
             f(&mut self.ffi_channel, tape);
             Ok(())
         } else {
             error!("FFI call not found: {:?}", ffi);
-            Err(format!("ffi call not found: {:?}", ffi))
+            Err(RuntimeError::FFINotFound {
+                binding: ffi.clone(),
+                trace: StackTrace::default(),
+            })
         }
     }
 }
@@ -297,24 +437,38 @@ impl StandardDevice {
         self.ffi.insert(ffi, f);
     }
 
-    fn get_char(&mut self) -> Result<char, String> {
+    /// Does the device have a binding for the foreign function?
+    pub fn supports_ffi(&self, ffi: &FFIBinding) -> bool {
+        self.ffi.contains_key(ffi)
+    }
+
+    fn get_char(&mut self) -> Result<char, RuntimeError> {
         let mut buf = [0];
         if stdout().flush().is_err() {
             error!("Could not flush output, do you have a terminal?");
-            return Err("Could not flush output".to_string());
+            return Err(RuntimeError::Output {
+                message: String::from("could not flush output"),
+                trace: StackTrace::default(),
+            });
         }
         if stdin().read(&mut buf).is_err() {
             error!("Could not flush output, do you have a terminal?");
-            return Err("Could not get user input".to_string());
+            return Err(RuntimeError::Input {
+                message: String::from("could not get user input"),
+                trace: StackTrace::default(),
+            });
         }
         Ok(buf[0] as char)
     }
 
-    fn get_int(&mut self) -> Result<i64, String> {
+    fn get_int(&mut self) -> Result<i64, RuntimeError> {
         let mut buf = [0];
         if stdout().flush().is_err() {
             error!("Could not flush output, do you have a terminal?");
-            return Err("Could not flush output".to_string());
+            return Err(RuntimeError::Output {
+                message: String::from("could not flush output"),
+                trace: StackTrace::default(),
+            });
         }
 
         while stdin().read(&mut buf).is_ok() && (buf[0] as char).is_whitespace() {}
@@ -340,15 +494,21 @@ impl StandardDevice {
         Ok(result)
     }
 
-    fn get_float(&mut self) -> Result<f64, String> {
+    fn get_float(&mut self) -> Result<f64, RuntimeError> {
         let mut buf = String::new();
         if stdout().flush().is_err() {
             error!("Could not flush output, do you have a terminal?");
-            return Err("Could not flush output".to_string());
+            return Err(RuntimeError::Output {
+                message: String::from("could not flush output"),
+                trace: StackTrace::default(),
+            });
         }
         if stdin().read_line(&mut buf).is_err() {
             error!("Could not flush output, do you have a terminal?");
-            return Err("Could not get user input".to_string());
+            return Err(RuntimeError::Input {
+                message: String::from("could not get user input"),
+                trace: StackTrace::default(),
+            });
         }
         Ok(buf.trim().parse::<f64>().unwrap_or_else(|s| {
             warn!("Could not parse float: {s:?}, defaulting to 0.0");
@@ -358,7 +518,7 @@ impl StandardDevice {
 }
 
 impl Device for StandardDevice {
-    fn get(&mut self, src: Input) -> Result<i64, String> {
+    fn get(&mut self, src: Input) -> Result<i64, RuntimeError> {
         Ok(match src.mode {
             InputMode::StdinChar => self.get_char()? as i64,
             InputMode::StdinInt => self.get_int()?,
@@ -374,7 +534,7 @@ impl Device for StandardDevice {
         })
     }
 
-    fn put(&mut self, val: i64, dst: Output) -> Result<(), String> {
+    fn put(&mut self, val: i64, dst: Output) -> Result<(), RuntimeError> {
         // Print the character without a newline
         match dst.mode {
             OutputMode::StdoutChar => print!("{}", val as u8 as char),
@@ -391,34 +551,47 @@ impl Device for StandardDevice {
             }
         }
         if stdout().flush().is_err() {
-            Err(String::from("could not flush output"))
+            Err(RuntimeError::Output {
+                message: String::from("could not flush output"),
+                trace: StackTrace::default(),
+            })
         } else {
             Ok(())
         }
     }
 
-    fn peek(&mut self) -> Result<i64, String> {
+    fn peek(&mut self) -> Result<i64, RuntimeError> {
         if let Some(n) = self.ffi_channel.pop_front() {
             Ok(n)
         } else {
             error!("Tried to peek from empty ffi channel");
-            Err("ffi channel is empty".to_string())
+            Err(RuntimeError::EmptyFFIChannel {
+                trace: StackTrace::default(),
+            })
         }
     }
 
-    fn poke(&mut self, val: i64) -> Result<(), String> {
+    fn poke(&mut self, val: i64) -> Result<(), RuntimeError> {
         self.ffi_channel.push_back(val);
         Ok(())
     }
 
-    fn ffi_call(&mut self, ffi: &FFIBinding, tape: Option<&mut Vec<i64>>) -> Result<(), String> {
+    fn ffi_call(
+        &mut self,
+        ffi: &FFIBinding,
+        tape: Option<&mut Vec<i64>>,
+    ) -> Result<(), RuntimeError> {
         if let Some(f) = self.ffi.get(ffi) {
             trace!("Calling FFI: {}", ffi);
+            check_ffi_arguments(ffi, &self.ffi_channel)?;
             f(&mut self.ffi_channel, tape);
             Ok(())
         } else {
             error!("FFI call not found: {:?}", ffi);
-            Err(format!("ffi call not found: {:?}", ffi))
+            Err(RuntimeError::FFINotFound {
+                binding: ffi.clone(),
+                trace: StackTrace::default(),
+            })
         }
     }
 }
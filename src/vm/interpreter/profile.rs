@@ -0,0 +1,215 @@
+//! # Profiler Module
+//!
+//! This module implements the profiler used by the interpreters. The profiler
+//! counts the instructions executed of each kind, and the instructions executed
+//! under each call stack. Functions are identified by their index in the program
+//! (the value of the register when they're called), and can be given names
+//! for the reports.
+//!
+//! The report can be printed as a table sorted by the number of instructions,
+//! or in the collapsed stack format used by flamegraph tools: one line per
+//! call stack, with the names of the functions (outermost first) separated
+//! by semicolons, followed by the number of instructions executed in it.
+use ::std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+/// The name used for the code outside of any function.
+const MAIN: &str = "<main>";
+
+/// The instructions executed by a function.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// The number of times the function was called.
+    pub calls: usize,
+    /// The number of instructions executed in the function itself.
+    pub exclusive: usize,
+    /// The number of instructions executed in the function,
+    /// and in all of the functions it called.
+    pub inclusive: usize,
+}
+
+/// A profile of the instructions executed by a program.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// The number of instructions executed of each kind.
+    instructions: HashMap<&'static str, usize>,
+    /// The number of calls to each function.
+    calls: HashMap<usize, usize>,
+    /// Each call stack seen so far (outermost first), and the number
+    /// of instructions executed in it.
+    stacks: Vec<(Vec<usize>, usize)>,
+    /// The index in `stacks` of each call stack.
+    stack_ids: HashMap<Vec<usize>, usize>,
+    /// The current call stack.
+    stack: Vec<usize>,
+    /// The index in `stacks` of the current call stack.
+    current: usize,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            instructions: HashMap::new(),
+            calls: HashMap::new(),
+            stacks: vec![(vec![], 0)],
+            stack_ids: HashMap::from([(vec![], 0)]),
+            stack: vec![],
+            current: 0,
+        }
+    }
+}
+
+impl Profile {
+    /// Count `count` executed instructions of a kind in the current call stack.
+    pub(super) fn record(&mut self, name: &'static str, count: usize) {
+        *self.instructions.entry(name).or_default() += count;
+        self.stacks[self.current].1 += count;
+    }
+
+    /// Enter a call to the function with the given index.
+    pub(super) fn call(&mut self, function: usize) {
+        *self.calls.entry(function).or_default() += 1;
+        self.stack.push(function);
+        self.switch_stack();
+    }
+
+    /// Return from the current function.
+    pub(super) fn ret(&mut self) {
+        self.stack.pop();
+        self.switch_stack();
+    }
+
+    /// Find (or create) the entry in `stacks` for the current call stack.
+    fn switch_stack(&mut self) {
+        self.current = match self.stack_ids.get(&self.stack) {
+            Some(&id) => id,
+            None => {
+                let id = self.stacks.len();
+                self.stacks.push((self.stack.clone(), 0));
+                self.stack_ids.insert(self.stack.clone(), id);
+                id
+            }
+        };
+    }
+
+    /// Get the total number of instructions executed.
+    pub fn total(&self) -> usize {
+        self.instructions.values().sum()
+    }
+
+    /// Get the number of instructions executed of each kind, most executed first.
+    pub fn instructions(&self) -> Vec<(&'static str, usize)> {
+        let mut result = self
+            .instructions
+            .iter()
+            .map(|(&name, &count)| (name, count))
+            .collect::<Vec<_>>();
+        result.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        result
+    }
+
+    /// Get the instructions executed by each function. The code outside
+    /// of any function is listed under `None`.
+    pub fn functions(&self) -> BTreeMap<Option<usize>, FunctionProfile> {
+        let mut result = BTreeMap::<Option<usize>, FunctionProfile>::new();
+        for (stack, count) in &self.stacks {
+            result.entry(stack.last().copied()).or_default().exclusive += count;
+            // Count the instructions once for each function in the stack,
+            // even if the function is in the stack more than once.
+            let mut seen = stack.clone();
+            seen.sort();
+            seen.dedup();
+            result.entry(None).or_default().inclusive += count;
+            for function in seen {
+                result.entry(Some(function)).or_default().inclusive += count;
+            }
+        }
+        for (&function, &calls) in &self.calls {
+            result.entry(Some(function)).or_default().calls = calls;
+        }
+        result
+    }
+
+    /// Get the name of a function, given the names of the functions in the program.
+    fn name(function: Option<usize>, names: &[String]) -> String {
+        match function {
+            None => MAIN.to_string(),
+            Some(i) => names
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| format!("function #{i}")),
+        }
+    }
+
+    /// Write a report of the profile, with the instructions executed by each
+    /// function and of each kind, most executed first. The Nth name in `names`
+    /// is the name of the Nth function in the program.
+    pub fn report(&self, names: &[String]) -> String {
+        let total = self.total().max(1) as f64;
+        let mut functions = self.functions().into_iter().collect::<Vec<_>>();
+        functions.sort_by(|(a, a_profile), (b, b_profile)| {
+            (b_profile.exclusive, b_profile.inclusive)
+                .cmp(&(a_profile.exclusive, a_profile.inclusive))
+                .then(a.cmp(b))
+        });
+
+        let mut result = String::new();
+        writeln!(result, "{} instructions executed", self.total()).unwrap();
+        writeln!(result).unwrap();
+        writeln!(
+            result,
+            "{:>12} {:>7} {:>12} {:>7} {:>9}  function",
+            "exclusive", "%", "inclusive", "%", "calls"
+        )
+        .unwrap();
+        for (function, profile) in functions {
+            writeln!(
+                result,
+                "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>9}  {}",
+                profile.exclusive,
+                profile.exclusive as f64 * 100.0 / total,
+                profile.inclusive,
+                profile.inclusive as f64 * 100.0 / total,
+                profile.calls,
+                Self::name(function, names)
+            )
+            .unwrap();
+        }
+        writeln!(result).unwrap();
+        writeln!(result, "{:>12} {:>7}  instruction", "count", "%").unwrap();
+        for (name, count) in self.instructions() {
+            writeln!(
+                result,
+                "{:>12} {:>6.2}%  {name}",
+                count,
+                count as f64 * 100.0 / total
+            )
+            .unwrap();
+        }
+        result
+    }
+
+    /// Write the profile in the collapsed stack format used by flamegraph tools.
+    /// The Nth name in `names` is the name of the Nth function in the program.
+    pub fn collapsed_stacks(&self, names: &[String]) -> String {
+        let mut lines = self
+            .stacks
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(stack, count)| {
+                let mut line = MAIN.to_string();
+                for &function in stack {
+                    line.push(';');
+                    line.push_str(&Self::name(Some(function), names));
+                }
+                format!("{line} {count}")
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        let mut result = lines.join("\n");
+        result.push('\n');
+        result
+    }
+}
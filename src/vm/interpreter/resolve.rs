@@ -0,0 +1,165 @@
+//! # Control Flow Module
+//!
+//! This module resolves the control flow of a flattened program before it runs.
+//! Without it, the interpreters find the instruction matching the start or end
+//! of a block by scanning the program for it, which happens every time a loop
+//! repeats, a branch is skipped, or a function is defined or called. Resolving
+//! the jumps once turns each of those scans into a lookup in a table.
+//!
+//! Runs of consecutive `Move` instructions are also resolved to their total
+//! offset, so that the interpreters can execute a run as a single move.
+//!
+//! The resolved control flow refers to the instructions by their indices in
+//! the original program, so stack traces, snapshots, breakpoints, and return
+//! addresses are the same as when the program is interpreted without it.
+use crate::vm::CoreOp;
+
+/// A run of consecutive `Move` instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct MoveRun {
+    /// The total offset of the moves.
+    pub(super) offset: isize,
+    /// The lowest offset reached after any of the moves, relative to the start of the run.
+    pub(super) lowest: isize,
+    /// The number of moves in the run.
+    pub(super) len: usize,
+}
+
+/// The precomputed jump targets of a program.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct ControlFlow {
+    /// The instruction each instruction jumps to, if it can jump.
+    /// - An `If` jumps to its matching `Else` (or its `End`, without an `Else`).
+    /// - An `Else`, `While`, or `Function` jumps to its matching `End`.
+    /// - An `End` jumps back to its matching `While`, if it ends a loop.
+    ///
+    /// A block without an `End` jumps to the end of the program.
+    jumps: Vec<Option<usize>>,
+    /// The instruction pointer of every function in the program, in order.
+    functions: Vec<usize>,
+    /// The run of moves starting at each instruction, if there are at least two.
+    moves: Vec<Option<MoveRun>>,
+}
+
+/// A block which hasn't been closed by an `End` yet.
+struct Block {
+    /// The index of the instruction which opened the block,
+    /// or `None` for the code outside of any block.
+    start: Option<usize>,
+    /// Is the block a `While` loop?
+    is_loop: bool,
+    /// The `Else` instructions in the block, which jump to its `End`.
+    elses: Vec<usize>,
+}
+
+impl ControlFlow {
+    /// Resolve the control flow of a program, given the core instruction
+    /// at each index (or `None` for instructions of another variant).
+    pub(super) fn resolve<'a>(ops: impl IntoIterator<Item = Option<&'a CoreOp>>) -> Self {
+        let ops = ops.into_iter().collect::<Vec<_>>();
+        let mut jumps = vec![None; ops.len()];
+        let mut functions = vec![];
+        let mut blocks = vec![Block {
+            start: None,
+            is_loop: false,
+            elses: vec![],
+        }];
+
+        for (i, op) in ops.iter().enumerate() {
+            match op {
+                Some(CoreOp::If) | Some(CoreOp::While) | Some(CoreOp::Function) => {
+                    if let Some(CoreOp::Function) = op {
+                        functions.push(i);
+                    }
+                    blocks.push(Block {
+                        start: Some(i),
+                        is_loop: matches!(op, Some(CoreOp::While)),
+                        elses: vec![],
+                    });
+                }
+                Some(CoreOp::Else) => {
+                    let block = blocks.last_mut().unwrap();
+                    // An `If` skips to the first `Else` in its block.
+                    if let Some(start) = block.start {
+                        if ops[start] == Some(&CoreOp::If) && jumps[start].is_none() {
+                            jumps[start] = Some(i);
+                        }
+                    }
+                    block.elses.push(i);
+                }
+                Some(CoreOp::End) => {
+                    let block = if blocks.len() > 1 {
+                        blocks.pop().unwrap()
+                    } else {
+                        // An unmatched `End` still ends the code before it.
+                        Block {
+                            start: None,
+                            is_loop: false,
+                            elses: blocks[0].elses.drain(..).collect(),
+                        }
+                    };
+                    for j in block.elses {
+                        jumps[j] = Some(i);
+                    }
+                    if let Some(start) = block.start {
+                        jumps[start].get_or_insert(i);
+                        if block.is_loop {
+                            jumps[i] = Some(start);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // The blocks left open jump past the end of the program.
+        for block in blocks {
+            for j in block.elses.into_iter().chain(block.start) {
+                jumps[j].get_or_insert(ops.len());
+            }
+        }
+
+        // Resolve the runs of moves, starting with the last instruction.
+        let mut moves = vec![None; ops.len()];
+        let mut next: Option<MoveRun> = None;
+        for (i, op) in ops.iter().enumerate().rev() {
+            next = match op {
+                Some(CoreOp::Move(n)) => Some(match next {
+                    Some(run) => MoveRun {
+                        offset: n + run.offset,
+                        lowest: (*n).min(n + run.lowest),
+                        len: run.len + 1,
+                    },
+                    None => MoveRun {
+                        offset: *n,
+                        lowest: *n,
+                        len: 1,
+                    },
+                }),
+                _ => None,
+            };
+            moves[i] = next.filter(|run| run.len > 1);
+        }
+
+        Self {
+            jumps,
+            functions,
+            moves,
+        }
+    }
+
+    /// Get the instruction that the instruction at index `i` jumps to.
+    pub(super) fn jump(&self, i: usize) -> Option<usize> {
+        self.jumps.get(i).copied().flatten()
+    }
+
+    /// Get the instruction pointer of the Nth function in the program.
+    pub(super) fn function(&self, n: usize) -> Option<usize> {
+        self.functions.get(n).copied()
+    }
+
+    /// Get the run of moves starting at index `i`, if there are at least two.
+    pub(super) fn moves(&self, i: usize) -> Option<MoveRun> {
+        self.moves.get(i).copied().flatten()
+    }
+}
@@ -0,0 +1,72 @@
+//! # Snapshot Module
+//!
+//! This module implements snapshots of the interpreters' state. A snapshot
+//! holds everything the machine needs to continue running a program: the tape,
+//! the pointer, the register, the functions discovered so far, the call stack,
+//! the stack of dereferences, and the instruction pointer (along with the heap
+//! of the standard interpreter).
+//!
+//! Snapshots don't include the program itself or the interpreter's device.
+//! To resume a program from a snapshot, restore the snapshot into a new
+//! interpreter of the same kind, and run the same program from there.
+use super::heap::Heap;
+use serde_derive::{Deserialize, Serialize};
+
+/// The state of an interpreter at some point in a program's execution.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The index of the next instruction to execute.
+    pub i: usize,
+    /// The current pointer on the turing tape.
+    pub pointer: usize,
+    /// The register vector.
+    pub register: Vec<i64>,
+    /// The turing tape, without the trailing cells that are zero.
+    pub cells: Vec<i64>,
+    /// The length of the tape when the snapshot was taken. The cells past
+    /// the end of `cells` are zero.
+    pub tape_len: usize,
+    /// The instruction pointers of the functions discovered so far.
+    pub functions: Vec<usize>,
+    /// The call stack of return addresses.
+    pub calls: Vec<usize>,
+    /// The stack of pointers saved by `Deref` instructions.
+    pub refs: Vec<usize>,
+    /// Has the machine finished executing the program?
+    pub done: bool,
+    /// The number of instructions executed so far.
+    pub steps: usize,
+    /// The exit code given by a `Halt` instruction, if the program halted.
+    pub exit_code: Option<i64>,
+    /// Was the snapshot taken by a standard interpreter?
+    pub standard: bool,
+    /// The heap of the standard interpreter. This is `None` for the core interpreter.
+    pub(super) heap: Option<Heap>,
+}
+
+impl Snapshot {
+    /// Write the snapshot as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("snapshots are always serializable")
+    }
+
+    /// Read a snapshot written by `to_json`.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("invalid snapshot: {e}"))
+    }
+
+    /// Check that the snapshot can be restored into a core or standard
+    /// interpreter, so that a standard program's heap is never dropped.
+    pub(super) fn check_variant(&self, standard: bool) -> Result<(), String> {
+        let name = |standard| if standard { "standard" } else { "core" };
+        if self.standard == standard {
+            Ok(())
+        } else {
+            Err(format!(
+                "cannot restore a {} interpreter's snapshot into a {} interpreter",
+                name(self.standard),
+                name(standard)
+            ))
+        }
+    }
+}
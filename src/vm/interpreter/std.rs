@@ -3,8 +3,12 @@
 //! This module implements an interpreter for the Standard virtual machine
 //! variant.
 
-use super::TAPE_EXTENSION_SIZE;
-use crate::vm::{CoreOp, Device, StandardDevice, StandardOp, StandardProgram};
+use super::{
+    heap::Heap,
+    resolve::{ControlFlow, MoveRun},
+    Limit, Limits, MachineState, Profile, RuntimeError, Snapshot, StackTrace, TAPE_EXTENSION_SIZE,
+};
+use crate::vm::{CoreOp, Device, SourceMap, StandardDevice, StandardOp, StandardProgram};
 
 /// A function to reinterpret the bits of an integer as a float.
 pub fn as_float(n: i64) -> f64 {
@@ -49,6 +53,18 @@ where
     i: usize,
     /// Is the interpreter finished interpreting?s
     done: bool,
+    /// The limits on the resources the program may use.
+    limits: Limits,
+    /// The source code each instruction was compiled from, for stack traces.
+    source_map: SourceMap,
+    /// The number of instructions executed so far.
+    steps: usize,
+    /// The exit code given by a `Halt` instruction, if the program halted.
+    exit_code: Option<i64>,
+    /// The allocator for the `Alloc` and `Free` instructions.
+    heap: Heap,
+    /// The profile of the instructions executed, if profiling is enabled.
+    profile: Option<Profile>,
 }
 
 impl<T> StandardInterpreter<T>
@@ -66,9 +82,130 @@ where
             refs: vec![],
             i: 0,
             done: false,
+            limits: Limits::default(),
+            source_map: SourceMap::default(),
+            steps: 0,
+            exit_code: None,
+            heap: Heap::default(),
+            profile: None,
         }
     }
 
+    /// Limit the resources the program may use. When the program exceeds
+    /// a limit, the interpreter stops with an error naming the limit.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Use a source map to give the stack traces of errors the source code
+    /// location of each frame.
+    pub fn with_source_map(mut self, source_map: SourceMap) -> Self {
+        self.source_map = source_map;
+        self
+    }
+
+    /// Check the program's use of the heap. When the program frees memory twice,
+    /// frees an address which was not allocated, or uses memory after freeing it,
+    /// the interpreter stops with an error naming the instruction. Following or
+    /// computing an address in freed memory (with `Deref`, `Where` or `Index`)
+    /// counts as using it.
+    ///
+    /// Freed memory is never reused in this mode.
+    pub fn with_heap_checks(mut self) -> Self {
+        self.heap = Heap::checked();
+        self
+    }
+
+    /// Count the instructions executed of each kind, and by each function.
+    pub fn with_profiling(mut self) -> Self {
+        self.profile = Some(Profile::default());
+        self
+    }
+
+    /// Get the profile of the instructions executed so far, if profiling is enabled.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Get the number of instructions executed so far.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Get a read-only view of the machine's current state.
+    pub fn state(&self) -> MachineState<'_> {
+        MachineState {
+            i: self.i,
+            pointer: self.pointer,
+            register: &self.register,
+            cells: &self.cells,
+            functions: &self.functions,
+            calls: &self.calls,
+            refs: &self.refs,
+            done: self.done,
+            exit_code: self.exit_code,
+        }
+    }
+
+    /// Take a snapshot of the machine's state, to restore later.
+    pub fn snapshot(&self) -> Snapshot {
+        // Leave out the zeroes at the end of the tape, and record its length instead.
+        let used = self
+            .cells
+            .iter()
+            .rposition(|&cell| cell != 0)
+            .map_or(0, |i| i + 1);
+        Snapshot {
+            i: self.i,
+            pointer: self.pointer,
+            register: self.register.clone(),
+            cells: self.cells[..used].to_vec(),
+            tape_len: self.cells.len(),
+            functions: self.functions.clone(),
+            calls: self.calls.clone(),
+            refs: self.refs.clone(),
+            done: self.done,
+            steps: self.steps,
+            exit_code: self.exit_code,
+            standard: true,
+            heap: Some(self.heap.clone()),
+        }
+    }
+
+    /// Restore the machine's state from a snapshot. The program continues
+    /// from where the snapshot was taken when the interpreter runs it again.
+    /// A snapshot taken by a core interpreter is rejected.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), String> {
+        snapshot.check_variant(true)?;
+        self.i = snapshot.i;
+        self.pointer = snapshot.pointer;
+        self.register = snapshot.register;
+        self.cells = snapshot.cells;
+        self.cells
+            .resize(snapshot.tape_len.max(self.cells.len()), 0);
+        self.functions = snapshot.functions;
+        self.calls = snapshot.calls;
+        self.refs = snapshot.refs;
+        self.done = snapshot.done;
+        self.steps = snapshot.steps;
+        self.exit_code = snapshot.exit_code;
+        if let Some(heap) = snapshot.heap {
+            self.heap = heap;
+        }
+        Ok(())
+    }
+
+    /// Get the interpreter's I/O device.
+    pub fn device(&self) -> &T {
+        &self.device
+    }
+
+    /// Consume the interpreter and return its I/O device.
+    pub fn into_device(self) -> T {
+        self.device
+    }
+
     fn reg_scalar(&self) -> i64 {
         self.register[0]
     }
@@ -95,19 +232,34 @@ where
     }
 
     /// Dereference the current pointer on the tape.
-    fn deref(&mut self) {
+    fn deref(&mut self) -> Result<(), RuntimeError> {
+        self.limits.check(Limit::RefsDepth, self.refs.len() + 1)?;
         // Add the old pointer to the dereference stack.
         self.refs.push(self.pointer);
-        let cell = *self.get_cell();
+        let cell = *self.get_cell()?;
         if cell < 0 {
-            panic!("Dereferencing negative cell with value {cell:?}");
+            return Err(RuntimeError::NegativeDeref {
+                value: cell,
+                trace: StackTrace::default(),
+            });
         }
         // Set the pointer to the address on the tape.
-        self.pointer = *self.get_cell() as usize;
+        self.pointer = cell as usize;
+        Ok(())
+    }
+
+    /// In checked mode, confirm that an address the program follows or computes
+    /// isn't in a freed block, even before the program uses it.
+    fn check_address(&self, addr: i64) -> Result<(), RuntimeError> {
+        if self.heap.is_checked() && addr >= 0 {
+            self.heap.check_access(addr as usize, 1)
+        } else {
+            Ok(())
+        }
     }
 
     /// Undo a dereference.
-    fn refer(&mut self) -> Result<(), String> {
+    fn refer(&mut self) -> Result<(), RuntimeError> {
         // Get the previous value of the pointer before
         // the last dereference instruction.
         if let Some(old) = self.refs.pop() {
@@ -115,12 +267,19 @@ where
             Ok(())
         } else {
             // There was no previous dereference, throw an error
-            Err(String::from("cannot Refer due to empty Deref stack"))
+            Err(RuntimeError::EmptyRefStack {
+                trace: StackTrace::default(),
+            })
         }
     }
 
     /// Call the Nth function defined in the program, where N is the value of the register.
-    fn call(&mut self, code: &StandardProgram) -> Result<(), String> {
+    fn call(
+        &mut self,
+        code: &StandardProgram,
+        flow: Option<&ControlFlow>,
+    ) -> Result<(), RuntimeError> {
+        self.limits.check(Limit::CallDepth, self.calls.len() + 1)?;
         // If the function has been defined
         if self.functions.len() > self.reg_scalar() as usize {
             // Push the current instruction pointer to the call stack
@@ -132,22 +291,31 @@ where
 
             // Push the return address onto the call stack.
             self.calls.push(self.i);
-            // Scan all the function definitions from the start of the program until we find it.
-            self.i = 0;
-            let mut count = -1;
-            while count < self.reg_scalar() {
-                // Every time we find a function, it will increment `count`.
-                match self.fetch(code) {
-                    Some(StandardOp::CoreOp(CoreOp::Function)) => {
-                        count += 1;
-                    }
-                    Some(_) => {}
-                    None => return Err(format!("function {} not defined", self.reg_scalar())),
-                }
-                // If `count` hasn't reached the function we want,
-                // keep going.
-                if count < self.reg_scalar() {
-                    self.i += 1;
+            match flow.zip(usize::try_from(self.reg_scalar()).ok()) {
+                // Look up the function in the resolved control flow.
+                Some((flow, n)) => match flow.function(n) {
+                    Some(i) => self.i = i,
+                    None => return Err(self.undefined_function()),
+                },
+                None => {
+                    // Scan all the function definitions from the start of the program until we find it.
+                    self.i = 0;
+                    let mut count = -1;
+                    while count < self.reg_scalar() {
+                        // Every time we find a function, it will increment `count`.
+                        match self.fetch(code) {
+                            Some(StandardOp::CoreOp(CoreOp::Function)) => {
+                                count += 1;
+                            }
+                            Some(_) => {}
+                            None => return Err(self.undefined_function()),
+                        }
+                        // If `count` hasn't reached the function we want,
+                        // keep going.
+                        if count < self.reg_scalar() {
+                            self.i += 1;
+                        }
+                    }
                 }
             }
 
@@ -160,6 +328,16 @@ where
         }
     }
 
+    /// Give up on a call to a function which isn't defined.
+    fn undefined_function(&mut self) -> RuntimeError {
+        // Restore the instruction pointer for the stack trace.
+        self.i = self.calls.pop().unwrap_or_default();
+        RuntimeError::UndefinedFunction {
+            function: self.reg_scalar(),
+            trace: StackTrace::default(),
+        }
+    }
+
     /// Return from the current function.
     fn ret(&mut self) {
         // If we're returning from a function, jump to the old instruction pointer.
@@ -172,7 +350,11 @@ where
     }
 
     /// Jump to the matching "Else" for this "If" instruction.
-    fn jmp_to_else(&mut self, code: &StandardProgram) {
+    fn jmp_to_else(&mut self, code: &StandardProgram, flow: Option<&ControlFlow>) {
+        if let Some(i) = flow.and_then(|flow| flow.jump(self.i)) {
+            self.i = i;
+            return;
+        }
         let mut matching = 1;
         while matching > 0 {
             self.i += 1;
@@ -189,7 +371,11 @@ where
 
     /// Jump to the matching "End" for this "Else", "While",
     /// or "Function" instruction.
-    fn jmp_to_end(&mut self, code: &StandardProgram) {
+    fn jmp_to_end(&mut self, code: &StandardProgram, flow: Option<&ControlFlow>) {
+        if let Some(i) = flow.and_then(|flow| flow.jump(self.i)) {
+            self.i = i;
+            return;
+        }
         let mut matching = 1;
         while matching > 0 {
             self.i += 1;
@@ -252,27 +438,139 @@ where
         }
     }
 
+    /// Get the run of moves starting at the current instruction, if it can be
+    /// executed at once: the run can't move the pointer below zero or
+    /// execute more instructions than the limit partway through.
+    fn collapsible_moves(&self, flow: Option<&ControlFlow>) -> Option<MoveRun> {
+        flow.and_then(|flow| flow.moves(self.i)).filter(|run| {
+            self.pointer as isize + run.lowest >= 0
+                && self
+                    .limits
+                    .check(Limit::Instructions, self.steps + run.len - 1)
+                    .is_ok()
+        })
+    }
+
     /// Get the current cell pointed to on the turing tape.
-    fn get_cell(&mut self) -> &mut i64 {
-        while self.pointer >= self.cells.len() {
-            self.cells.extend(vec![0; TAPE_EXTENSION_SIZE]);
+    fn get_cell(&mut self) -> Result<&mut i64, RuntimeError> {
+        let (_, cells) = self.operands(1)?;
+        Ok(&mut cells[0])
+    }
+
+    /// Get the register, and the `n` cells on the tape starting at the pointer,
+    /// for an instruction to use. The tape is extended to hold the cells, and
+    /// the register is padded with zeroes to hold at least `n` cells.
+    fn operands(&mut self, n: usize) -> Result<(&mut Vec<i64>, &mut [i64]), RuntimeError> {
+        let end = self
+            .pointer
+            .checked_add(n)
+            .ok_or(RuntimeError::TapeOutOfBounds {
+                address: self.pointer,
+                len: self.cells.len(),
+                trace: StackTrace::default(),
+            })?;
+        self.extend_tape(end)?;
+        if self.register.len() < n {
+            self.register.resize(n, 0);
         }
+        Ok((&mut self.register, &mut self.cells[self.pointer..end]))
+    }
 
-        &mut self.cells[self.pointer]
+    /// Extend the turing tape so that it has at least `len` cells.
+    fn extend_tape(&mut self, len: usize) -> Result<(), RuntimeError> {
+        self.limits.check(Limit::TapeCells, len)?;
+        if len > self.cells.len() {
+            // Extend the tape in large chunks, but never past the limit.
+            let chunks = (len - self.cells.len()).div_ceil(TAPE_EXTENSION_SIZE);
+            let mut new_len = self
+                .cells
+                .len()
+                .saturating_add(chunks.saturating_mul(TAPE_EXTENSION_SIZE));
+            if let Some(max) = self.limits.max_tape_cells {
+                new_len = new_len.min(max);
+            }
+            // An address too far out to allocate is an error, instead of aborting.
+            let extension = new_len - self.cells.len();
+            if self.cells.try_reserve_exact(extension).is_err() {
+                return Err(RuntimeError::TapeOutOfBounds {
+                    address: len - 1,
+                    len: self.cells.len(),
+                    trace: StackTrace::default(),
+                });
+            }
+            self.cells.resize(new_len, 0);
+        }
+        Ok(())
     }
 
     /// Run a core program using this interpreter and its device.
-    pub fn run(mut self, code: &StandardProgram) -> Result<T, String> {
+    pub fn run(self, code: &StandardProgram) -> Result<T, RuntimeError> {
+        self.run_with_exit_code(code).map(|(device, _)| device)
+    }
+
+    /// Run a program using this interpreter and its device, and return the device
+    /// along with the program's exit code. The exit code is zero unless the program
+    /// stopped with a `Halt` instruction.
+    pub fn run_with_exit_code(mut self, code: &StandardProgram) -> Result<(T, i64), RuntimeError> {
+        let exit_code = self.run_to_end(code)?;
+        Ok((self.device, exit_code))
+    }
+
+    /// Run a program until it finishes, without giving up the interpreter,
+    /// and return the program's exit code.
+    ///
+    /// The program's control flow is resolved before it runs, so that
+    /// jumps and calls don't have to scan the program for their targets.
+    pub fn run_to_end(&mut self, code: &StandardProgram) -> Result<i64, RuntimeError> {
+        let flow = ControlFlow::resolve(code.0.iter().map(StandardOp::as_core_op));
         while !self.done {
-            self.step(code)?
+            self.step_with(code, Some(&flow))?
         }
-
-        Ok(self.device)
+        Ok(self.exit_code.unwrap_or(0))
     }
 
     /// Run a single step of the interpreter.
-    fn step(&mut self, code: &StandardProgram) -> Result<(), String> {
+    pub fn step(&mut self, code: &StandardProgram) -> Result<(), RuntimeError> {
+        self.step_with(code, None)
+    }
+
+    /// Run a single step of the interpreter, using the program's
+    /// resolved control flow if it's given.
+    fn step_with(
+        &mut self,
+        code: &StandardProgram,
+        flow: Option<&ControlFlow>,
+    ) -> Result<(), RuntimeError> {
+        let (instruction, steps) = (self.i, self.steps);
+        let (depth, function) = (self.calls.len(), self.reg_scalar());
+        self.execute(code, flow)
+            .map_err(|e| e.at(self.source_map.stack_trace(instruction, &self.calls)))?;
+        if let (Some(profile), Some(op)) = (&mut self.profile, code.0.get(instruction)) {
+            // A collapsed run of moves counts as each of its instructions.
+            profile.record(op.name(), self.steps - steps);
+            // A call or return changes the depth of the call stack.
+            if self.calls.len() > depth {
+                profile.call(function as usize)
+            } else if self.calls.len() < depth {
+                profile.ret()
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute the current instruction. Errors are returned without
+    /// a stack trace; `step` adds it.
+    fn execute(
+        &mut self,
+        code: &StandardProgram,
+        flow: Option<&ControlFlow>,
+    ) -> Result<(), RuntimeError> {
         if let Some(op) = self.fetch(code) {
+            self.steps += 1;
+            self.limits.check(Limit::Instructions, self.steps)?;
+            if self.heap.is_checked() {
+                self.heap.check_access(self.pointer, op.tape_access())?;
+            }
             match op {
                 StandardOp::CoreOp(core_op) => match core_op {
                     CoreOp::Comment(_) => {}
@@ -282,24 +580,33 @@ where
                             self.functions.push(self.i);
                             self.functions.sort()
                         }
-                        self.jmp_to_end(code)
+                        self.jmp_to_end(code, flow)
                     }
-                    CoreOp::Call => self.call(code)?,
+                    CoreOp::Call => self.call(code, flow)?,
                     CoreOp::Return => self.ret(),
+                    CoreOp::Halt => {
+                        self.exit_code = Some(self.reg_scalar());
+                        self.done = true
+                    }
                     CoreOp::While => {
                         if self.reg_scalar() == 0 {
-                            self.jmp_to_end(code)
+                            self.jmp_to_end(code, flow)
                         }
                     }
                     CoreOp::If => {
                         if self.reg_scalar() == 0 {
-                            self.jmp_to_else(code)
+                            self.jmp_to_else(code, flow)
                         }
                     }
-                    CoreOp::Else => self.jmp_to_end(code),
+                    CoreOp::Else => self.jmp_to_end(code, flow),
                     CoreOp::End => {
                         if self.reg_scalar() != 0 {
-                            if let Some(StandardOp::CoreOp(CoreOp::While)) =
+                            if let Some(flow) = flow {
+                                // Only the end of a loop has a jump target.
+                                if let Some(i) = flow.jump(self.i) {
+                                    self.i = i
+                                }
+                            } else if let Some(StandardOp::CoreOp(CoreOp::While)) =
                                 self.get_matching_for_end(code)
                             {
                                 self.jmp_back_to_matching(code)
@@ -308,77 +615,81 @@ where
                     }
 
                     CoreOp::Load(n) => {
-                        while self.pointer + n >= self.cells.len() {
-                            self.cells.extend(vec![0; TAPE_EXTENSION_SIZE]);
-                        }
-
-                        self.reg_mut_vector().clear();
-
-                        for i in 0..*n {
-                            let val = self.cells[self.pointer + i];
-                            self.reg_mut_vector().push(val);
-                        }
+                        let (reg, cells) = self.operands(*n)?;
+                        reg.clear();
+                        reg.extend_from_slice(cells);
                     }
 
                     CoreOp::Store(n) => {
-                        while self.pointer + n >= self.cells.len() {
-                            self.cells.extend(vec![0; TAPE_EXTENSION_SIZE]);
-                        }
-
-                        for i in 0..*n {
-                            let val = self.reg_vector()[i];
-                            self.cells[self.pointer + i] = val;
-                        }
+                        let (reg, cells) = self.operands(*n)?;
+                        cells.copy_from_slice(&reg[..*n]);
                     }
                     // CoreOp::Load(n) => *self.get_cell() = self.reg_scalar(),
                     // CoreOp::Store(n) => self.register = *self.get_cell(),
                     CoreOp::Move(n) => {
-                        if *n >= 0 {
+                        if let Some(run) = self.collapsible_moves(flow) {
+                            // Execute the whole run of moves at once.
+                            self.pointer = (self.pointer as isize + run.offset) as usize;
+                            self.steps += run.len - 1;
+                            self.i += run.len - 1;
+                        } else if *n >= 0 {
                             self.pointer += *n as usize
                         } else {
                             if self.pointer < -*n as usize {
-                                return Err(format!(
-                                    "Instruction #{} tried to move the pointer to a negative index.",
-                                    self.i
-                                ));
+                                return Err(RuntimeError::NegativePointer {
+                                    trace: StackTrace::default(),
+                                });
                             }
                             self.pointer -= -*n as usize
                         }
                     }
 
-                    CoreOp::Where => *self.reg_mut_scalar() = self.pointer as i64,
+                    CoreOp::Where => {
+                        self.check_address(self.pointer as i64)?;
+                        *self.reg_mut_scalar() = self.pointer as i64
+                    }
                     CoreOp::Offset(offset, n) => {
                         for i in 0..*n {
                             self.reg_mut_vector()[i] += *offset as i64;
                         }
                     }
-                    CoreOp::Deref => self.deref(),
+                    CoreOp::Deref => {
+                        self.deref()?;
+                        self.check_address(self.pointer as i64)?
+                    }
                     CoreOp::Refer => self.refer()?,
 
                     CoreOp::Index(n) => {
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r += *c;
+                        }
                         for i in 0..*n {
-                            self.reg_mut_vector()[i] += self.cells[self.pointer + i];
+                            self.check_address(self.reg_vector()[i])?;
                         }
                     }
                     CoreOp::BitwiseNand(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] =
-                                !(self.reg_vector()[i] & self.cells[self.pointer + i]);
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r = !(*r & *c);
                         }
                     }
                     CoreOp::BitwiseAnd(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] &= self.cells[self.pointer + i];
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r &= *c;
                         }
                     }
                     CoreOp::BitwiseOr(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] |= self.cells[self.pointer + i];
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r |= *c;
                         }
                     }
                     CoreOp::BitwiseXor(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] ^= self.cells[self.pointer + i];
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r ^= *c;
                         }
                     }
                     CoreOp::BitwiseNot(n) => {
@@ -387,63 +698,59 @@ where
                         }
                     }
                     CoreOp::LeftShift(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] <<= self.cells[self.pointer + i];
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r <<= *c;
                         }
                     }
                     // CoreOp::LogicalRightShift => {
                     //     *self.reg_mut_scalar() = (self.reg_scalar() as u64 >> *self.get_cell() as u64) as i64
                     // }
                     CoreOp::LogicalRightShift(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] = (self.reg_vector()[i] as u64
-                                >> self.cells[self.pointer + i] as u64)
-                                as i64;
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r = (*r as u64 >> *c as u64) as i64;
                         }
                     }
 
                     CoreOp::ArithmeticRightShift(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] >>= self.cells[self.pointer + i];
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r >>= *c;
                         }
                     }
 
                     CoreOp::Add(n) => {
-                        for i in 0..*n {
-                            let val = self.cells[self.pointer + i];
-                            self.reg_mut_vector()[i] =
-                                self.reg_mut_vector()[i].overflowing_add(val).0;
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r = r.overflowing_add(*c).0;
                         }
                     }
                     CoreOp::Sub(n) => {
-                        for i in 0..*n {
-                            let val = self.cells[self.pointer + i];
-                            self.reg_mut_vector()[i] =
-                                self.reg_mut_vector()[i].overflowing_sub(val).0;
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r = r.overflowing_sub(*c).0;
                         }
                     }
                     CoreOp::Mul(n) => {
-                        for i in 0..*n {
-                            let val = self.cells[self.pointer + i];
-                            self.reg_mut_vector()[i] =
-                                self.reg_mut_vector()[i].overflowing_mul(val).0;
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r = r.overflowing_mul(*c).0;
                         }
                     }
                     CoreOp::Div(n) => {
-                        for i in 0..*n {
-                            let val = self.cells[self.pointer + i];
-                            if val != 0 {
-                                self.reg_mut_vector()[i] =
-                                    self.reg_mut_vector()[i].overflowing_div(val).0
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            if *c != 0 {
+                                *r = r.overflowing_div(*c).0
                             }
                         }
                     }
                     CoreOp::Rem(n) => {
-                        for i in 0..*n {
-                            let val = self.cells[self.pointer + i];
-                            if val != 0 {
-                                self.reg_mut_vector()[i] =
-                                    self.reg_mut_vector()[i].overflowing_rem(val).0
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            if *c != 0 {
+                                *r = r.overflowing_rem(*c).0
                             }
                         }
                     }
@@ -454,17 +761,15 @@ where
                     }
 
                     CoreOp::And(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] = i64::from(
-                                self.reg_vector()[i] != 0 && self.cells[self.pointer + i] != 0,
-                            );
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r = i64::from(*r != 0 && *c != 0);
                         }
                     }
                     CoreOp::Or(n) => {
-                        for i in 0..*n {
-                            self.reg_mut_vector()[i] = i64::from(
-                                self.reg_vector()[i] != 0 || self.cells[self.pointer + i] != 0,
-                            );
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                            *r = i64::from(*r != 0 || *c != 0);
                         }
                     }
                     CoreOp::Not(n) => {
@@ -485,10 +790,9 @@ where
                     }
 
                     CoreOp::Swap(n) => {
-                        for i in 0..*n {
-                            let temp = self.reg_vector()[i];
-                            self.reg_mut_vector()[i] = self.cells[self.pointer + i];
-                            self.cells[self.pointer + i] = temp;
+                        let (reg, cells) = self.operands(*n)?;
+                        for (r, c) in reg.iter_mut().zip(cells.iter_mut()) {
+                            std::mem::swap(r, c);
                         }
                     }
 
@@ -516,38 +820,33 @@ where
                     }
                 }
                 StandardOp::Add(n) => {
-                    for i in 0..*n {
-                        let a = as_float(self.reg_vector()[i]);
-                        let b = as_float(self.cells[self.pointer + i]);
-                        self.reg_mut_vector()[i] = as_int(a + b);
+                    let (reg, cells) = self.operands(*n)?;
+                    for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                        *r = as_int(as_float(*r) + as_float(*c));
                     }
                 }
                 StandardOp::Sub(n) => {
-                    for i in 0..*n {
-                        let a = as_float(self.reg_vector()[i]);
-                        let b = as_float(self.cells[self.pointer + i]);
-                        self.reg_mut_vector()[i] = as_int(a - b);
+                    let (reg, cells) = self.operands(*n)?;
+                    for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                        *r = as_int(as_float(*r) - as_float(*c));
                     }
                 }
                 StandardOp::Mul(n) => {
-                    for i in 0..*n {
-                        let a = as_float(self.reg_vector()[i]);
-                        let b = as_float(self.cells[self.pointer + i]);
-                        self.reg_mut_vector()[i] = as_int(a * b);
+                    let (reg, cells) = self.operands(*n)?;
+                    for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                        *r = as_int(as_float(*r) * as_float(*c));
                     }
                 }
                 StandardOp::Div(n) => {
-                    for i in 0..*n {
-                        let a = as_float(self.reg_vector()[i]);
-                        let b = as_float(self.cells[self.pointer + i]);
-                        self.reg_mut_vector()[i] = as_int(a / b);
+                    let (reg, cells) = self.operands(*n)?;
+                    for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                        *r = as_int(as_float(*r) / as_float(*c));
                     }
                 }
                 StandardOp::Rem(n) => {
-                    for i in 0..*n {
-                        let a = as_float(self.reg_vector()[i]);
-                        let b = as_float(self.cells[self.pointer + i]);
-                        self.reg_mut_vector()[i] = as_int(a % b);
+                    let (reg, cells) = self.operands(*n)?;
+                    for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                        *r = as_int(as_float(*r) % as_float(*c));
                     }
                 }
 
@@ -593,11 +892,9 @@ where
                     }
                 }
                 StandardOp::Pow(n) => {
-                    for i in 0..*n {
-                        self.reg_mut_vector()[i] = as_int(
-                            as_float(self.reg_vector()[i])
-                                .powf(as_float(self.cells[self.pointer + i])),
-                        );
+                    let (reg, cells) = self.operands(*n)?;
+                    for (r, c) in reg.iter_mut().zip(cells.iter()) {
+                        *r = as_int(as_float(*r).powf(as_float(*c)));
                     }
                 }
 
@@ -612,16 +909,27 @@ where
                     // If the virtual machine doesn't have a thousand cells,
                     // allocate some.
                     if self.cells.len() < 30000 {
+                        self.limits
+                            .check(Limit::TapeCells, self.cells.len() + 30000)?;
                         self.cells.extend(vec![0; 30000]);
                     }
-                    // Save the address of where the new cells will start.
-                    let result = self.cells.len() - 1;
-                    // Allocate new space at the end of the type.
-                    self.cells.extend(vec![0; self.reg_scalar() as usize]);
+                    let size = self.reg_scalar().max(0) as usize;
+                    let result = self.heap.alloc(size, self.cells.len());
+                    // Make room for the new block at the end of the tape.
+                    let end = result + size.max(1) + 1;
+                    if end > self.cells.len() {
+                        self.limits.check(Limit::TapeCells, end)?;
+                        self.cells.resize(end, 0);
+                    }
+                    // Reused blocks may still hold old data, so clear them.
+                    self.cells[result..result + size].fill(0);
                     // Store the address of the new space in the register.
                     *self.reg_mut_scalar() = result as i64;
                 }
-                StandardOp::Free => {}
+                StandardOp::Free => {
+                    let addr = self.reg_scalar().max(0) as usize;
+                    self.heap.free(addr)?;
+                }
                 StandardOp::Call(binding) => {
                     self.device.ffi_call(binding, Some(&mut self.cells))?;
                 }
@@ -65,6 +65,14 @@ pub use self::std::*;
 mod interpreter;
 pub use interpreter::*;
 
+mod source_map;
+pub use source_map::*;
+
+mod optimize;
+
+mod bytecode;
+pub use bytecode::*;
+
 /// An error generated by the virtual machine.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Error {
@@ -136,6 +144,10 @@ pub trait VirtualMachineProgram {
         self.op(CoreOp::Return);
     }
 
+    fn halt(&mut self) {
+        self.op(CoreOp::Halt);
+    }
+
     fn where_is_pointer(&mut self) {
         self.op(CoreOp::Where);
     }
@@ -0,0 +1,125 @@
+//! # Peephole Optimizer
+//!
+//! The assembler lowers every access to an assembly location into sequences of
+//! `Move`, `Deref`, `Refer`, `Store`, and `Load` instructions, which often undo
+//! each other. This pass cleans them up by rewriting pairs of neighboring
+//! instructions, until none of the rewrites apply.
+//!
+//! Neighboring instructions are always executed together: control flow only
+//! enters or leaves straight-line code at `Function`, `While`, `If`, `Else`,
+//! and `End`, which are never combined with the instructions around them
+//! (except for an empty `Else`). The rewrites are:
+//!
+//! | Instructions           | Rewritten to           | Because                                     |
+//! |------------------------|------------------------|---------------------------------------------|
+//! | `Move(0)`              |                        | It doesn't move the pointer.                |
+//! | `Comment(_)`           |                        | Comments would keep rewrites from applying. |
+//! | `Move(a) Move(b)`      | `Move(a + b)`          | The moves add up.                           |
+//! | `Move(_) Refer`        | `Refer`                | `Refer` restores the pointer.               |
+//! | `Deref Refer`          |                        | `Refer` undoes the `Deref`.                 |
+//! | `Move(n) op`           | `op Move(n)`           | `op` only uses the register.                |
+//! | `Set(_) Set(a)`        | `Set(a)`               | The register is overwritten.                |
+//! | `Set(a) Load(n)`       | `Load(n)`              | The register is overwritten.                |
+//! | `Store(n) Store(n)`    | `Store(n)`             | The same cells are stored twice.            |
+//! | `Load(n) Load(n)`      | `Load(n)`              | The same cells are loaded twice.            |
+//! | `Store(n) Load(n)`     | `Store(n)`             | The register already holds the cells.       |
+//! | `Load(n) Store(n)`     | `Load(n)`              | The cells already hold the register.        |
+//! | `Set(a) Inc(n)`, ...   | `Set(b)`               | Unary operations on constants are folded.   |
+//! | `Else End`             | `End`                  | The `Else` branch is empty.                 |
+//!
+//! Moving a `Move` past the instructions which only use the register (`Set`,
+//! `Offset`, and the unary operations below) lets it combine with the
+//! instructions after them, and lets them combine with each other. The folded unary operations are
+//! `Inc`, `Dec`, `Neg`, `Not`, `BitwiseNot`, and `IsNonNegative`, when they
+//! only use cells of the register that were `Set`.
+use super::{CoreOp, StandardOp};
+
+/// Optimize a list of instructions, each with the index of the instruction it was
+/// compiled from. Instructions made by combining others take the last one's index.
+pub(super) fn optimize(code: Vec<(usize, StandardOp)>) -> Vec<(usize, StandardOp)> {
+    let mut result: Vec<(usize, StandardOp)> = vec![];
+    // The instructions left to optimize, with the next one last.
+    let mut pending = code;
+    pending.reverse();
+
+    while let Some((i, op)) = pending.pop() {
+        let Some(core_op) = op.as_core_op() else {
+            result.push((i, op));
+            continue;
+        };
+        if is_redundant(core_op) {
+            continue;
+        }
+        let last = result.last().and_then(|(_, last)| last.as_core_op());
+        if let Some(ops) = last.and_then(|last| combine(last, core_op)) {
+            // The combined instructions may combine with the instruction before them, too.
+            result.pop();
+            pending.extend(ops.into_iter().rev().map(|op| (i, StandardOp::CoreOp(op))));
+        } else {
+            result.push((i, op));
+        }
+    }
+    result
+}
+
+/// Can the instruction be removed without changing what the program does?
+fn is_redundant(op: &CoreOp) -> bool {
+    matches!(op, CoreOp::Move(0) | CoreOp::Comment(_))
+}
+
+/// Rewrite two neighboring instructions into equivalent instructions,
+/// if they can be simplified.
+fn combine(last: &CoreOp, op: &CoreOp) -> Option<Vec<CoreOp>> {
+    Some(match (last, op) {
+        (CoreOp::Move(a), CoreOp::Move(b)) => vec![CoreOp::Move(a + b)],
+        (CoreOp::Move(_), CoreOp::Refer) => vec![CoreOp::Refer],
+        (CoreOp::Deref, CoreOp::Refer) => vec![],
+        (CoreOp::Move(n), op) if only_uses_register(op) => vec![op.clone(), CoreOp::Move(*n)],
+
+        (CoreOp::Set(_), CoreOp::Set(_) | CoreOp::Load(_)) => vec![op.clone()],
+        (CoreOp::Store(m), CoreOp::Store(n) | CoreOp::Load(n))
+        | (CoreOp::Load(m), CoreOp::Store(n) | CoreOp::Load(n))
+            if m == n =>
+        {
+            vec![last.clone()]
+        }
+
+        (CoreOp::Set(values), op) => vec![CoreOp::Set(fold(values, op)?)],
+        (CoreOp::Else, CoreOp::End) => vec![CoreOp::End],
+        _ => return None,
+    })
+}
+
+/// Does the instruction only use the register, and not the pointer or the tape?
+fn only_uses_register(op: &CoreOp) -> bool {
+    matches!(
+        op,
+        CoreOp::Set(_)
+            | CoreOp::Offset(..)
+            | CoreOp::Inc(_)
+            | CoreOp::Dec(_)
+            | CoreOp::Neg(_)
+            | CoreOp::Not(_)
+            | CoreOp::BitwiseNot(_)
+            | CoreOp::IsNonNegative(_)
+    )
+}
+
+/// Apply a unary operation to the constant values set in the register.
+fn fold(values: &[i64], op: &CoreOp) -> Option<Vec<i64>> {
+    let (n, f): (usize, fn(i64) -> i64) = match op {
+        CoreOp::Inc(n) => (*n, |x| x.wrapping_add(1)),
+        CoreOp::Dec(n) => (*n, |x| x.wrapping_sub(1)),
+        CoreOp::Neg(n) => (*n, i64::wrapping_neg),
+        CoreOp::Not(n) => (*n, |x| (x == 0) as i64),
+        CoreOp::BitwiseNot(n) => (*n, |x| !x),
+        CoreOp::IsNonNegative(n) => (*n, |x| (x >= 0) as i64),
+        _ => return None,
+    };
+    if n > values.len() {
+        return None;
+    }
+    let mut result = values.to_vec();
+    result[..n].iter_mut().for_each(|x| *x = f(*x));
+    Some(result)
+}
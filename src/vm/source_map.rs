@@ -0,0 +1,117 @@
+//! # Source Map Module
+//!
+//! This module maps the instructions of a virtual machine program back to
+//! the source code they were compiled from. The map is kept beside the
+//! program (instead of inside it), keyed by instruction index, so that
+//! programs without any source code information aren't changed at all.
+//!
+//! The interpreters use the source map to give each stack trace the source
+//! code location of every frame, and the name of the function it was in.
+use super::StackTrace;
+use crate::parse::SourceCodeLocation;
+use ::std::{collections::BTreeMap, fmt};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// The source code an instruction was compiled from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SourceFrame {
+    /// The name of the function the instruction belongs to.
+    /// This is `None` for code outside of any function.
+    pub function: Option<String>,
+    /// The location of the expression which the instruction implements.
+    pub location: Option<SourceCodeLocation>,
+}
+
+impl SourceFrame {
+    /// Get the name of the function to show in a stack trace.
+    pub fn function_name(&self) -> &str {
+        self.function.as_deref().unwrap_or("<main>")
+    }
+}
+
+impl fmt::Display for SourceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.function_name())?;
+        match &self.location {
+            Some(SourceCodeLocation {
+                line,
+                column,
+                filename,
+                ..
+            }) => write!(
+                f,
+                " ({}:{}:{})",
+                filename.as_deref().unwrap_or("unknown"),
+                // Locations count lines and columns from zero, but editors count them from one.
+                line + 1,
+                column + 1
+            ),
+            None => write!(f, " (unknown location)"),
+        }
+    }
+}
+
+/// A side table from the instruction indices of a virtual machine program
+/// to the source code they were compiled from.
+///
+/// Consecutive instructions usually come from the same source code, so the map
+/// only stores the frame of the first instruction of each run.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMap(BTreeMap<usize, SourceFrame>);
+
+impl SourceMap {
+    /// Does the map have any source code information?
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Mark the instruction at the given index, and all of the instructions
+    /// after it (until the next mark), as compiled from the given source code.
+    pub fn insert(&mut self, instruction: usize, frame: SourceFrame) {
+        // Don't start a new run if the frame hasn't changed.
+        let previous = self.0.range(..instruction).next_back().map(|(_, f)| f);
+        if previous.unwrap_or(&SourceFrame::default()) == &frame {
+            self.0.remove(&instruction);
+            return;
+        }
+        self.0.insert(instruction, frame);
+    }
+
+    /// Get the source code the instruction at the given index was compiled from.
+    pub fn get(&self, instruction: usize) -> Option<&SourceFrame> {
+        self.0
+            .range(..=instruction)
+            .next_back()
+            .map(|(_, frame)| frame)
+            .filter(|frame| **frame != SourceFrame::default())
+    }
+
+    /// Build a map for the program whose Nth instruction was the `order[N]`th
+    /// instruction of the program this map was built for.
+    pub(super) fn reorder(&self, order: impl IntoIterator<Item = usize>) -> Self {
+        let mut result = Self::default();
+        for (i, old) in order.into_iter().enumerate() {
+            result.insert(i, self.get(old).cloned().unwrap_or_default());
+        }
+        result
+    }
+
+    /// Create the stack trace of an instruction, given the call stack
+    /// (innermost last) of the instruction.
+    pub fn stack_trace(&self, instruction: usize, calls: &[usize]) -> StackTrace {
+        let frames = if self.is_empty() {
+            vec![]
+        } else {
+            ::std::iter::once(instruction)
+                .chain(calls.iter().rev().copied())
+                .map(|i| self.get(i).cloned().unwrap_or_default())
+                .collect()
+        };
+        StackTrace {
+            instruction,
+            calls: calls.to_vec(),
+            frames,
+        }
+    }
+}
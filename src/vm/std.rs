@@ -35,7 +35,9 @@
 //! This way, a developer can write a program in such a manner that user input
 //! cannot be confused with custom encoded instructions sent to and from the I/O device
 //! using `Put` and `Get`.
-use super::{CoreOp, CoreProgram, Error, VirtualMachineProgram};
+use super::{
+    core::flatten, optimize::optimize, CoreOp, CoreProgram, Error, SourceMap, VirtualMachineProgram,
+};
 use crate::side_effects::*;
 use core::fmt;
 use std::collections::HashMap;
@@ -119,130 +121,51 @@ impl StandardProgram {
     /// Flatten a core program so that all of its functions
     /// are defined sequentially at the beginning.
     pub fn flatten(self) -> Self {
-        Self(flatten(self.0).0)
+        Self(flatten(self.0, StandardOp::as_core_op).0)
+    }
+
+    /// Flatten the program like `flatten`, and reorder the source map
+    /// of the program to match.
+    pub fn flatten_with_source_map(self, source_map: &SourceMap) -> (Self, SourceMap) {
+        let code = self.0.into_iter().enumerate().collect();
+        let (code, _, _) = flatten(code, |(_, op)| op.as_core_op());
+        let source_map = source_map.reorder(code.iter().map(|(i, _)| *i));
+        let code = code.into_iter().map(|(_, op)| op).collect();
+        (Self(code), source_map)
+    }
+
+    /// Optimize the program with the peephole rewrites in the `optimize` module.
+    pub fn optimize(self) -> Self {
+        let code = optimize(self.0.into_iter().enumerate().collect());
+        Self(code.into_iter().map(|(_, op)| op).collect())
+    }
+
+    /// Optimize the program like `optimize`, and reorder the source map
+    /// of the program to match.
+    pub fn optimize_with_source_map(self, source_map: &SourceMap) -> (Self, SourceMap) {
+        let code = optimize(self.0.into_iter().enumerate().collect());
+        let source_map = source_map.reorder(code.iter().map(|(i, _)| *i));
+        let code = code.into_iter().map(|(_, op)| op).collect();
+        (Self(code), source_map)
     }
 
     /// Get the code outside of any functions.
     pub fn get_main(&self) -> Vec<StandardOp> {
-        flatten(self.0.clone()).2
+        flatten(self.0.clone(), StandardOp::as_core_op).2
     }
 
     /// Get the code for each function.
     pub fn get_functions(&self) -> HashMap<i32, Vec<StandardOp>> {
-        flatten(self.0.clone()).1
+        flatten(self.0.clone(), StandardOp::as_core_op).1
     }
 
     /// Get the code outside of any functions, and the code for each function.
     pub fn get_main_and_functions(self) -> (Vec<StandardOp>, HashMap<i32, Vec<StandardOp>>) {
-        let (_, functions, main) = flatten(self.0);
+        let (_, functions, main) = flatten(self.0, StandardOp::as_core_op);
         (main, functions)
     }
 }
 
-/// Take all of the functions defined in a list of StandardOps,
-/// and flatten their definitions. This will take nested functions
-/// and un-nest them while preserving the order in which functions are defined.
-///
-/// All the function definitions will be placed at the top of the returned list.
-fn flatten(
-    code: Vec<StandardOp>,
-) -> (
-    Vec<StandardOp>,
-    HashMap<i32, Vec<StandardOp>>,
-    Vec<StandardOp>,
-) {
-    let mut functions: HashMap<i32, Vec<StandardOp>> = HashMap::new();
-
-    // The current function body we are in.
-    let mut fun = -1;
-    // Keep track of when we end the current function,
-    // instead of just an if-else-conditional or a while loop.
-    // This is essentially the number of end statements remaining before
-    // we can end the scope.
-    let mut matching_end = 0;
-    // Keep track of each `matching_end`, and the scope we were previously in, for each nested scope.
-    let mut scope_stack = vec![];
-    // The main instructions of the program. (Not in a function.)
-    let mut main_instructions = vec![];
-    for std_op in code {
-        match &std_op {
-            StandardOp::CoreOp(CoreOp::Function) => {}
-            _ => {
-                if scope_stack.is_empty() {
-                    // If we are not defining a function,
-                    // push the instruction to the main instructions.
-                    main_instructions.push(std_op.clone());
-                }
-            }
-        }
-
-        if let StandardOp::CoreOp(op) = &std_op {
-            match op {
-                CoreOp::Function => {
-                    // If we are declaring a new function,
-                    // push the info about the current scope onto the scope
-                    // stack to resume later.
-                    scope_stack.push((fun, matching_end));
-                    // Reset the matching-end counter for the new scope.
-                    matching_end = 0;
-                    // Start defining the next function.
-                    fun += 1;
-                    // If that function is already defined,
-                    // just go past the last function defined.
-                    if functions.contains_key(&fun) {
-                        fun = functions.len() as i32
-                    }
-                }
-                CoreOp::If | CoreOp::While => {
-                    // Increment the number of matching `End`
-                    // instructions to end the scope.
-                    matching_end += 1
-                }
-                CoreOp::End => {
-                    // If the scope has ended
-                    if matching_end == 0 {
-                        // Get the function body we're defining.
-                        functions.entry(fun).or_default().push(std_op);
-                        // Resume flattening the previous scope.
-                        (fun, matching_end) = scope_stack.pop().unwrap();
-                        continue;
-                    } else {
-                        // Otherwise, the scope is still going.
-                        // Decrement the counter and continue.
-                        matching_end -= 1;
-                    }
-                }
-                _ => {}
-            }
-        }
-
-        // Insert the current instruction to the right function's definition.
-        functions.entry(fun).or_default().push(std_op);
-    }
-
-    // Clone the functions so that we can remove them from the map.
-    let mut result_functions = functions.clone();
-    result_functions.remove(&-1);
-
-    // The final output code.
-    let mut result = vec![];
-
-    // For every function, insert its body into the resulting output code.
-    for i in 0..=functions.len() as i32 {
-        if let Some(body) = functions.remove(&i) {
-            result.extend(body);
-        }
-    }
-
-    // Insert the remaining code into the output code.
-    if let Some(body) = functions.remove(&-1) {
-        result.extend(body);
-    }
-
-    // Return the output code
-    (result, result_functions, main_instructions)
-}
-
 impl fmt::Display for StandardProgram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut comment_count = 0;
@@ -363,6 +286,68 @@ pub enum StandardOp {
     Call(FFIBinding),
 }
 
+impl StandardOp {
+    /// Get the core instruction, if this is one.
+    pub fn as_core_op(&self) -> Option<&CoreOp> {
+        match self {
+            Self::CoreOp(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    /// Does the instruction operate on floating point values?
+    pub fn is_float(&self) -> bool {
+        !matches!(
+            self,
+            Self::CoreOp(_) | Self::Alloc | Self::Free | Self::Peek | Self::Poke | Self::Call(_)
+        )
+    }
+
+    /// The number of cells on the tape, starting at the pointer,
+    /// that the instruction reads or writes.
+    pub fn tape_access(&self) -> usize {
+        match self {
+            StandardOp::CoreOp(op) => op.tape_access(),
+            StandardOp::Add(n)
+            | StandardOp::Sub(n)
+            | StandardOp::Mul(n)
+            | StandardOp::Div(n)
+            | StandardOp::Rem(n)
+            | StandardOp::Pow(n) => *n,
+            _ => 0,
+        }
+    }
+
+    /// Get the name of the instruction, without its arguments.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StandardOp::CoreOp(op) => op.name(),
+            StandardOp::Set(_) => "set-f",
+            StandardOp::Alloc => "alloc",
+            StandardOp::Free => "free",
+            StandardOp::ToInt(_) => "to-int",
+            StandardOp::ToFloat(_) => "to-float",
+            StandardOp::Add(_) => "add-f",
+            StandardOp::Sub(_) => "sub-f",
+            StandardOp::Mul(_) => "mul-f",
+            StandardOp::Div(_) => "div-f",
+            StandardOp::Rem(_) => "rem-f",
+            StandardOp::Neg(_) => "neg-f",
+            StandardOp::IsNonNegative(_) => "gez-f",
+            StandardOp::Sin(_) => "sin",
+            StandardOp::Cos(_) => "cos",
+            StandardOp::Tan(_) => "tan",
+            StandardOp::ASin(_) => "asin",
+            StandardOp::ACos(_) => "acos",
+            StandardOp::ATan(_) => "atan",
+            StandardOp::Pow(_) => "pow",
+            StandardOp::Peek => "peek",
+            StandardOp::Poke => "poke",
+            StandardOp::Call(_) => "ffi-call",
+        }
+    }
+}
+
 impl fmt::Display for StandardOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
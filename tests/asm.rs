@@ -1,7 +1,7 @@
 use sage::{
     asm::*,
     parse::parse_asm,
-    side_effects::{Input, Output},
+    side_effects::{FFIBinding, FFISignature, Input, Output},
     vm::{CoreInterpreter, TestingDevice},
 };
 
@@ -365,3 +365,169 @@ fn test_factorial() {
 
     assert_eq!(&device.output_str(), "3628800")
 }
+
+#[test]
+fn test_remove_unreachable_functions() {
+    let program = r#"
+    fun @unused
+        call @helper
+    end
+    fun @helper
+        set A, 1
+        put-int A
+    end
+    fun @outer
+        fun @inner
+            set A, '\n'
+            put-char A
+        end
+        call @inner
+    end
+    fun @square
+        mul [FP], [FP]
+    end
+
+    set A, 7 push A
+    call @square
+    pop A
+    put-int A
+    call @inner
+    "#;
+
+    let mut asm_core = parse_asm(program).unwrap().unwrap();
+    assert_eq!(
+        asm_core.function_labels(),
+        vec!["unused", "helper", "outer", "inner", "square"]
+    );
+    asm_core.remove_unreachable_functions();
+    // Functions declared inside unreachable functions can still be reached.
+    assert_eq!(asm_core.function_labels(), vec!["inner", "square"]);
+
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&asm_core.assemble(32).unwrap())
+        .unwrap();
+    assert_eq!(&device.output_str(), "49\n");
+
+    // Foreign functions only called by unreachable functions are forgotten.
+    let mut asm_std = StandardProgram::default();
+    for name in ["unused", "used"] {
+        asm_std.op(CoreOp::Fn(name.to_string()));
+        let binding = FFIBinding::new(name.to_string(), 0, 0);
+        asm_std.std_op(StandardOp::Call(binding)).unwrap();
+        asm_std.op(CoreOp::End);
+        asm_std.declare_foreign_function(FFISignature::new(name.to_string(), vec![], None));
+    }
+    asm_std.op(CoreOp::CallLabel("used".to_string()));
+    asm_std.remove_unreachable_functions();
+    assert_eq!(asm_std.function_labels(), vec!["used"]);
+    assert_eq!(
+        asm_std.foreign_functions(),
+        vec![FFISignature::new("used".to_string(), vec![], None)]
+    );
+}
+
+#[test]
+fn test_remove_unreachable_functions_from_example() {
+    let code = ::std::fs::read_to_string("examples/asm/dead-functions.asm.sg").unwrap();
+    let mut asm_core = parse_asm(&code).unwrap().unwrap();
+    let before = asm_core.assemble(32).unwrap();
+    asm_core.remove_unreachable_functions();
+    // The address of `shout` is loaded, but never used.
+    assert_eq!(
+        asm_core.function_labels(),
+        vec!["newline", "whisper", "countdown"]
+    );
+    let after = asm_core.assemble(32).unwrap();
+    assert!(after.0.len() < before.0.len());
+
+    for vm_code in [before, after] {
+        let device = CoreInterpreter::new(TestingDevice::new(""))
+            .run(&vm_code)
+            .unwrap();
+        assert_eq!(&device.output_str(), ".\n3\n2\n1\n");
+    }
+}
+
+#[test]
+fn test_allocate_registers() {
+    let program = r#"
+    set A, 3 push A
+    set A, 4 push A
+    add [SP], [SP - 1]
+    pop
+    push [SP]
+    mul [SP], [SP - 1]
+    pop
+    pop A
+    put-int A
+    "#;
+
+    let mut asm_core = parse_asm(program).unwrap().unwrap();
+    asm_core.allocate_registers();
+    // Every cell pushed to the stack is kept in a register instead.
+    assert!(!asm_core
+        .code
+        .iter()
+        .any(|op| matches!(op, CoreOp::Push(..) | CoreOp::Pop(..))));
+
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&asm_core.assemble(32).unwrap())
+        .unwrap();
+    assert_eq!(&device.output_str(), "49");
+
+    // Cells on the stack could be local variables, which the frame pointer refers to.
+    let program = r#"
+    set A, 3 push A
+    mov [FP], B
+    pop A
+    "#;
+    let mut asm_core = parse_asm(program).unwrap().unwrap();
+    let before = asm_core.code.clone();
+    asm_core.allocate_registers();
+    assert_eq!(asm_core.code, before);
+}
+
+#[test]
+fn test_lift() {
+    let factorial = r#"
+    fun @fact
+        if [FP]
+            mov [FP], A
+            dec A
+            push A
+            call @fact
+            mul [FP + 1], [FP]
+            pop
+        else
+            set [FP], 1
+        end
+    end
+
+    set A, 10 push A
+    call @fact
+    pop A
+    put-int A
+    "#;
+
+    let vm_code = parse_asm(factorial)
+        .unwrap()
+        .unwrap()
+        .assemble(5000)
+        .unwrap();
+    let lifted = CoreProgram::lift(&vm_code).unwrap();
+    // Functions are named by their index, and the bootstrap code is removed.
+    let expected = factorial.replace("@fact", "@fun0");
+    assert_eq!(lifted.code, parse_asm(&expected).unwrap().unwrap().code);
+
+    let device = CoreInterpreter::new(TestingDevice::new(""))
+        .run(&lifted.assemble(5000).unwrap())
+        .unwrap();
+    assert_eq!(&device.output_str(), "3628800");
+
+    // Swapping the register with the tape can't be lifted.
+    let vm_code = sage::vm::CoreProgram(vec![sage::vm::CoreOp::Swap(1)]);
+    assert!(matches!(
+        CoreProgram::lift(&vm_code),
+        Err(Error::CannotLift(_, 0, _))
+    ));
+}
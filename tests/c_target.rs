@@ -24,11 +24,7 @@ fn test_c_target_frontend_examples() {
     );
     builder.init();
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(16)
-        .stack_size(512 * 1024 * 1024)
-        .build_global()
-        .unwrap();
+    init_thread_pool();
     // Compiling most examples overflows the tiny stack for tests.
     // So, we spawn a new thread with a larger stack size.
     let child = std::thread::Builder::new()
@@ -40,6 +36,15 @@ fn test_c_target_frontend_examples() {
     child.join().unwrap();
 }
 
+/// Give the threads used to type check programs a large stack.
+/// Tests which compile frontend code share the pool, so the first one builds it.
+fn init_thread_pool() {
+    let _ = rayon::ThreadPoolBuilder::new()
+        .num_threads(16)
+        .stack_size(512 * 1024 * 1024)
+        .build_global();
+}
+
 fn test_c_target_frontend_examples_helper() {
     let mut total_failures: i32 = 0;
     let mut total_attempts = 0;
@@ -116,8 +121,8 @@ fn test_c_target_frontend_examples_helper() {
             .unwrap();
 
             let c_code = match vm_code {
-                Ok(vm_code) => C.build_core(&vm_code.flatten()).unwrap(),
-                Err(vm_code) => C.build_std(&vm_code.flatten()).unwrap(),
+                Ok(vm_code) => C::default().build_core(&vm_code.flatten()).unwrap(),
+                Err(vm_code) => C::default().build_std(&vm_code.flatten()).unwrap(),
             };
 
             // Write the C code to a file.
@@ -150,15 +155,10 @@ fn test_c_target_frontend_examples_helper() {
                 Err(e) => panic!("Could not run C code for `{path:?}`: {e}"),
             };
 
-            if c_exe
-                .stdin
-                .as_mut()
-                .unwrap()
-                .write_all(INPUT.as_bytes())
-                .is_err()
-            {
-                panic!("Could not write to stdin of program `{path:?}`");
-            }
+            // Some examples (like `const-generics-array.sg`) exit without reading
+            // their input, so writing it may fail with a broken pipe. The output
+            // is what's checked, so the error is ignored.
+            let _ = c_exe.stdin.as_mut().unwrap().write_all(INPUT.as_bytes());
 
             // Get stdout from the C program.
             let c_output = c_exe.wait_with_output().unwrap().stdout;
@@ -193,3 +193,222 @@ fn test_c_target_frontend_examples_helper() {
         );
     }
 }
+
+/// Compile some C code with GCC, and run it.
+fn run_c_code(name: &str, c_code: String) -> std::process::Output {
+    let c_code_path = format!("tmp_c_code_{name}.c");
+    let c_exe_path = format!("tmp_c_code_{name}.exe");
+    std::fs::write(&c_code_path, c_code).unwrap();
+    let c_compile_output = std::process::Command::new("gcc")
+        .arg(&c_code_path)
+        .arg("-o")
+        .arg(&c_exe_path)
+        .arg("-lm")
+        .output()
+        .unwrap();
+    if !c_compile_output.status.success() {
+        panic!("Could not compile C code for `{name}`: {c_compile_output:?}");
+    }
+    let output = std::process::Command::new(format!("./{c_exe_path}"))
+        .output()
+        .unwrap();
+    std::fs::remove_file(&c_code_path).unwrap();
+    std::fs::remove_file(&c_exe_path).unwrap();
+    output
+}
+
+#[test]
+fn test_c_target_checked() {
+    use sage::vm::{CoreOp, CoreProgram, StandardOp, StandardProgram};
+
+    // The sizes of the program's memory are part of the target's configuration.
+    let mut small = C {
+        tape_cells: 16,
+        refs_depth: 4,
+        register_cells: 8,
+        ffi_channel_cells: 4,
+        max_functions: 2,
+        checked: true,
+        foreign_functions: vec![],
+    };
+    let empty = CoreProgram(vec![]);
+    let c_code = C::default().build_core(&empty).unwrap();
+    assert!(c_code.contains("#define SAGE_TAPE_CELLS 67108864"));
+    assert!(!c_code.contains("sage_overflow"));
+    let c_code = small.build_core(&empty).unwrap();
+    assert!(c_code.contains("#define SAGE_TAPE_CELLS 16"));
+    assert!(c_code.contains("#define SAGE_MAX_FUNCTIONS 2"));
+
+    // The call stack is carved out of the tape, so it only grows a tape too small to hold it.
+    let c_code = C::default()
+        .with_call_stack(CALL_STACK_SIZE)
+        .build_core(&empty)
+        .unwrap();
+    assert!(c_code.contains("#define SAGE_TAPE_CELLS 67108864"));
+    let c_code = small
+        .clone()
+        .with_call_stack(CALL_STACK_SIZE)
+        .build_core(&empty)
+        .unwrap();
+    assert!(c_code.contains(&format!("#define SAGE_TAPE_CELLS {CALL_STACK_SIZE}")));
+
+    let assert_overflow = |name: &str, c_code: String, memory: &str| {
+        let output = run_c_code(name, c_code);
+        assert!(!output.status.success(), "`{name}` did not abort");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("error: {memory} overflow")),
+            "`{name}` printed {stderr:?}"
+        );
+    };
+
+    // Walking off the end of the tape.
+    let walk = CoreProgram(vec![
+        CoreOp::Set(vec![1]),
+        CoreOp::While,
+        CoreOp::Move(1),
+        CoreOp::Store(1),
+        CoreOp::End,
+    ]);
+    assert_overflow("checked_tape", small.build_core(&walk).unwrap(), "tape");
+
+    // The pointer may be one past the end of the tape, but not use the cell there.
+    for (name, op) in [
+        ("checked_store", CoreOp::Store(1)),
+        ("checked_load", CoreOp::Load(1)),
+        ("checked_add", CoreOp::Add(1)),
+    ] {
+        let past_end = CoreProgram(vec![CoreOp::Move(16), op]);
+        assert_overflow(name, small.build_core(&past_end).unwrap(), "tape");
+    }
+
+    // Dereferencing a cell which points to itself, forever.
+    let derefs = CoreProgram(vec![
+        CoreOp::Where,
+        CoreOp::Store(1),
+        CoreOp::Set(vec![1]),
+        CoreOp::While,
+        CoreOp::Deref,
+        CoreOp::End,
+    ]);
+    assert_overflow(
+        "checked_refs",
+        small.build_core(&derefs).unwrap(),
+        "ref stack",
+    );
+
+    // Calling a function past the end of the function table.
+    let call = CoreProgram(vec![CoreOp::Set(vec![5]), CoreOp::Call]);
+    assert_overflow(
+        "checked_call",
+        small.build_core(&call).unwrap(),
+        "function table",
+    );
+
+    // Poking values into the FFI channel, forever.
+    let pokes = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(vec![1])),
+        StandardOp::CoreOp(CoreOp::While),
+        StandardOp::Poke,
+        StandardOp::CoreOp(CoreOp::End),
+    ]);
+    assert_overflow(
+        "checked_ffi",
+        small.build_std(&pokes).unwrap(),
+        "FFI channel",
+    );
+
+    // Programs which stay within their memory run normally.
+    let program = CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Set(vec![33]),
+        CoreOp::Put(sage::side_effects::Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Move(16),
+        CoreOp::Move(-1),
+        CoreOp::Where,
+        CoreOp::Store(1),
+        CoreOp::Deref,
+        CoreOp::Refer,
+        CoreOp::Set(vec![0]),
+        CoreOp::Call,
+    ]);
+    let output = run_c_code("checked_ok", small.build_core(&program.flatten()).unwrap());
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"!");
+}
+
+#[test]
+fn test_c_target_foreign_functions() {
+    init_thread_pool();
+    // Foreign functions declared in Sage can be bound to the native functions with the same name.
+    let src = r#"
+extern fun hypot(x: Float, y: Float): Float;
+extern fun labs(n: Int): Int;
+
+print(hypot(3.0, 4.0), " ", labs(-7), "\n");
+"#;
+    let asm_code = parse_frontend(src, None)
+        .unwrap()
+        .compile(false)
+        .unwrap()
+        .unwrap_err();
+    let foreign_functions = asm_code.foreign_functions();
+    assert_eq!(foreign_functions.len(), 2);
+    let vm_code = asm_code.assemble(CALL_STACK_SIZE).unwrap().flatten();
+
+    for checked in [false, true] {
+        let mut target = C {
+            checked,
+            foreign_functions: foreign_functions.clone(),
+            ..C::default()
+        };
+        let c_code = target.build_std(&vm_code).unwrap();
+        assert!(c_code.contains("sage_ffi_hypot();"));
+        assert!(c_code.contains("extern double hypot(double, double);"));
+        assert!(c_code.contains("extern int64_t labs(int64_t);"));
+        let output = run_c_code(&format!("foreign_functions_{checked}"), c_code);
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "5.0 7\n");
+    }
+}
+
+#[test]
+fn test_c_target_hand_written_ffi() {
+    init_thread_pool();
+    // By default, foreign functions call the glue code written in `ffi.h`.
+    let src = read_to_string("examples/frontend/memcpy.sg").unwrap();
+    let asm_code = parse_frontend(&src, None)
+        .unwrap()
+        .compile(false)
+        .unwrap()
+        .unwrap_err();
+    let vm_code = asm_code.assemble(CALL_STACK_SIZE).unwrap().flatten();
+    let c_code = C::default().build_std(&vm_code).unwrap();
+    assert!(c_code.contains("__unsafe_memcpy();"));
+    assert!(!c_code.contains("sage_ffi_"));
+
+    // Build the program next to the examples' `ffi.h`.
+    let dir = std::env::temp_dir().join(format!("sage_c_ffi_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("examples/frontend/ffi.h", dir.join("ffi.h")).unwrap();
+    std::fs::write(dir.join("memcpy.c"), c_code).unwrap();
+    let c_compile_output = std::process::Command::new("gcc")
+        .arg(dir.join("memcpy.c"))
+        .arg("-o")
+        .arg(dir.join("memcpy.exe"))
+        .arg("-lm")
+        .output()
+        .unwrap();
+    assert!(c_compile_output.status.success(), "{c_compile_output:?}");
+    let output = std::process::Command::new(dir.join("memcpy.exe"))
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "src: [1, 2, 3, 4, 5]\ndst: [0, 0, 0, 0, 0]\nsrc: [1, 2, 3, 4, 5]\ndst: [1, 2, 3, 4, 5]\n"
+    );
+}
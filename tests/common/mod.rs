@@ -0,0 +1,288 @@
+//! Helpers for the tests of the targets which build the examples, run
+//! the output with the target's toolchain, and compare it against the
+//! correct output in `examples/test-output`.
+#![allow(dead_code)]
+
+use log::warn;
+use sage::{
+    lir::Compile,
+    parse::*,
+    side_effects::{Input, Output as SageOutput},
+    targets::CompiledTarget,
+    vm::{CoreOp, CoreProgram, StandardOp, StandardProgram},
+};
+use std::{
+    fs::{read_dir, read_to_string},
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+pub const INPUT: &str = "2 4 8 16 32 64 128 256 512 1024 2048 4096";
+pub const CALL_STACK_SIZE: usize = 8192;
+
+/// A virtual machine program of either variant.
+pub type Program = Result<CoreProgram, StandardProgram>;
+
+/// Run a command with the given input.
+pub fn run_with_input(command: &mut Command, input: &str) -> Output {
+    let mut exe = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The program may exit without reading its input.
+    let _ = exe.stdin.take().unwrap().write_all(input.as_bytes());
+    exe.wait_with_output().unwrap()
+}
+
+/// Get the example source files in a directory which have a correct output to compare against.
+pub fn examples(dir: &str) -> Vec<(String, String, String)> {
+    let mut result = vec![];
+    for entry in read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if !path.is_file() || path.extension().and_then(|ext| ext.to_str()) != Some("sg") {
+            continue;
+        }
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let correct_output_path = PathBuf::from("examples/test-output")
+            .join(&file_name)
+            .with_extension("txt");
+        let Ok(correct_output) = read_to_string(&correct_output_path) else {
+            warn!("Could not read output text file `{correct_output_path:?}` to compare against. Skipping this test.");
+            continue;
+        };
+        let code = read_to_string(&path).unwrap();
+        result.push((file_name, code, correct_output.replace("\r\n", "\n")));
+    }
+    result.sort();
+    result
+}
+
+/// Build the examples in `examples/asm` or `examples/frontend` with `run`,
+/// which runs the program and returns what it prints. Returns the number of
+/// examples with the wrong output, and the number of examples.
+pub fn run_examples(dir: &str, run: impl Fn(&str, Program) -> String) -> (usize, usize) {
+    let mut total_failures = 0;
+    let mut total_attempts = 0;
+
+    for (name, code, correct_output) in examples(dir) {
+        let path = PathBuf::from(dir).join(&name);
+        let program = if dir.starts_with("examples/asm") {
+            match parse_asm(code).unwrap() {
+                Ok(asm_code) => Ok(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+                Err(asm_code) => Err(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+            }
+        } else {
+            match parse_frontend(code, path.to_str())
+                .unwrap_or_else(|_| panic!("Could not parse `{path:?}`"))
+                .compile(false)
+                .unwrap_or_else(|e| panic!("Could not compile `{path:?}`: {e}"))
+            {
+                Ok(asm_code) => Ok(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+                Err(asm_code) => Err(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+            }
+        };
+
+        let output = run(&name, program);
+        if output != correct_output {
+            warn!(
+                "Output did not match correct output for program `{path:?}`:\n{output}\n  !=   \n{correct_output}"
+            );
+            total_failures += 1;
+        }
+        total_attempts += 1;
+    }
+    (total_failures, total_attempts)
+}
+
+/// Run a test in a thread with a large stack.
+pub fn with_large_stack(test: fn()) {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(16)
+        .stack_size(512 * 1024 * 1024)
+        .build_global()
+        .unwrap();
+    // Compiling most examples overflows the tiny stack for tests.
+    // So, we spawn a new thread with a larger stack size.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test)
+        .unwrap();
+
+    // Wait for the thread to finish.
+    child.join().unwrap();
+}
+
+/// Is the command installed? Tests which need it are skipped if it isn't.
+pub fn installed(command: &str) -> bool {
+    let installed = Command::new(command)
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !installed {
+        warn!("`{command}` is not installed. Skipping this test.");
+    }
+    installed
+}
+
+/// A target which builds scripts, and the interpreter which runs them.
+pub struct ScriptTarget<T> {
+    /// The target which builds the scripts.
+    pub target: T,
+    /// The command which runs a script, like `python3` or `node`.
+    pub interpreter: &'static str,
+    /// The file extension of the scripts.
+    pub extension: &'static str,
+}
+
+impl<T: CompiledTarget + Clone> ScriptTarget<T> {
+    /// Build a program with the target.
+    pub fn build(&self, program: Program) -> String {
+        match program {
+            Ok(program) => self.target.clone().build_core(&program.flatten()),
+            Err(program) => self.target.clone().build_std(&program.flatten()),
+        }
+        .unwrap()
+    }
+
+    /// Build a program, and run the script with the given input.
+    pub fn run(&self, name: &str, program: Program, input: &str) -> Output {
+        let path = format!("tmp_{}_code_{name}.{}", self.interpreter, self.extension);
+        std::fs::write(&path, self.build(program)).unwrap();
+        let output = run_with_input(Command::new(self.interpreter).arg(&path), input);
+
+        std::fs::remove_file(&path).unwrap();
+        output
+    }
+
+    /// Run the examples in `examples/asm`.
+    pub fn test_asm_examples(&self) {
+        if !installed(self.interpreter) {
+            return;
+        }
+        let (total_failures, total_attempts) = run_examples("examples/asm/", |name, program| {
+            String::from_utf8(self.run(name, program, INPUT).stdout).unwrap()
+        });
+
+        // `string.asm.sg` prints out pointers, which depend on the allocator.
+        if total_failures > 1 {
+            panic!(
+                "Too many failures in asm examples: {total_failures} failures out of {total_attempts} attempts"
+            );
+        }
+    }
+
+    /// Run the examples in `examples/frontend`. This needs a large stack.
+    pub fn test_frontend_examples(&self) {
+        if !installed(self.interpreter) {
+            return;
+        }
+        let (total_failures, total_attempts) =
+            run_examples("examples/frontend/", |name, program| {
+                String::from_utf8(self.run(name, program, INPUT).stdout).unwrap()
+            });
+
+        // Some of the examples print out pointers, which depend on the allocator.
+        if total_failures as f64 / total_attempts as f64 > 0.1 {
+            panic!(
+                "Too many failures in frontend examples: {total_failures} failures out of {total_attempts} attempts"
+            );
+        }
+    }
+
+    /// Run a program with nested blocks, function calls, integer division and halting.
+    pub fn test_blocks(&self) {
+        if !installed(self.interpreter) {
+            return;
+        }
+        use CoreOp::*;
+        let program = CoreProgram(vec![
+            // Function 0 prints whether the character under the pointer is zero.
+            Function,
+            Load(1),
+            If,
+            If,
+            End,
+            Set(vec![b'y' as i64]),
+            Else,
+            Set(vec![b'n' as i64]),
+            End,
+            Put(SageOutput::stdout_char()),
+            End,
+            Get(Input::stdin_char()),
+            Store(1),
+            Set(vec![0]),
+            Call,
+            Set(vec![0]),
+            Store(1),
+            Set(vec![0]),
+            Call,
+            // Integer division truncates, and wraps.
+            Set(vec![-7, i64::MIN]),
+            Store(2),
+            Set(vec![2, -1]),
+            Swap(2),
+            Div(2),
+            Store(2),
+            Put(SageOutput::stdout_int()),
+            Move(1),
+            Load(1),
+            Put(SageOutput::stdout_int()),
+            Move(-1),
+            // The remainder has the sign of the dividend.
+            Set(vec![7]),
+            Rem(1),
+            Put(SageOutput::stdout_int()),
+            Set(vec![3]),
+            Halt,
+            Put(SageOutput::stdout_int()),
+        ]);
+
+        let output = self.run("blocks", Ok(program), "x");
+        assert_eq!(output.status.code(), Some(3), "{output:?}");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "yn-3-92233720368547758081"
+        );
+    }
+
+    /// Run a program with the standard floating point operations.
+    pub fn test_std_ops(&self) {
+        if !installed(self.interpreter) {
+            return;
+        }
+        use StandardOp::*;
+        type Core = sage::vm::CoreOp;
+        let program = StandardProgram(vec![
+            Set(vec![1.5, -2.25]),
+            CoreOp(Core::Store(2)),
+            Mul(2),
+            Add(2),
+            CoreOp(Core::Put(SageOutput::stdout_float())),
+            CoreOp(Core::Set(vec![b' ' as i64])),
+            CoreOp(Core::Put(SageOutput::stdout_char())),
+            CoreOp(Core::Set(vec![0])),
+            CoreOp(Core::Store(1)),
+            Set(vec![1.0]),
+            Div(1),
+            ToInt(1),
+            CoreOp(Core::Put(SageOutput::stdout_int())),
+            CoreOp(Core::Set(vec![b' ' as i64])),
+            CoreOp(Core::Put(SageOutput::stdout_char())),
+            CoreOp(Core::Get(Input::stdin_float())),
+            CoreOp(Core::Store(1)),
+            Set(vec![2.0]),
+            Pow(1),
+            CoreOp(Core::Put(SageOutput::stdout_float())),
+        ]);
+
+        let output = self.run("std_ops", Err(program), "3.0\n");
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "3.75 9223372036854775807 8.0"
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use sage::{
+    parse::SourceCodeLocation,
+    side_effects::{FFIBinding, Input, InputMode, Output, OutputMode},
+    targets::*,
+    vm::{CoreOp, SourceFrame, SourceMap, StandardDevice, StandardOp, StandardProgram},
+};
+
+/// A target like C, but without floats or a foreign function named `missing`.
+struct Limited(C);
+
+impl Architecture for Limited {
+    fn name(&self) -> &str {
+        "Limited"
+    }
+    fn version(&self) -> &str {
+        self.0.version()
+    }
+    fn supports_floats(&self) -> bool {
+        false
+    }
+    fn supports_input(&self, src: &Input) -> bool {
+        self.0.supports_input(src)
+    }
+    fn supports_output(&self, dst: &Output) -> bool {
+        self.0.supports_output(dst)
+    }
+    fn supports_ffi(&self, binding: &FFIBinding) -> bool {
+        binding.name != "missing"
+    }
+    fn get(&mut self, src: &Input) -> Result<String, String> {
+        self.0.get(src)
+    }
+    fn put(&mut self, dst: &Output) -> Result<String, String> {
+        self.0.put(dst)
+    }
+    fn peek(&mut self) -> Result<String, String> {
+        self.0.peek()
+    }
+    fn poke(&mut self) -> Result<String, String> {
+        self.0.poke()
+    }
+    fn declare_proc(&mut self, label_id: usize) -> String {
+        self.0.declare_proc(label_id)
+    }
+    fn end(&mut self, matching: &CoreOp, fun: Option<usize>) -> String {
+        self.0.end(matching, fun)
+    }
+    fn op(&mut self, op: &CoreOp) -> String {
+        self.0.op(op)
+    }
+    fn std_op(&mut self, op: &StandardOp) -> Result<String, String> {
+        self.0.std_op(op)
+    }
+}
+
+impl CompiledTarget for Limited {}
+
+/// A frame for code in `main` at the given line of `test.sg`.
+fn frame(line: usize) -> SourceFrame {
+    SourceFrame {
+        function: None,
+        location: Some(SourceCodeLocation {
+            line,
+            column: 4,
+            offset: line * 10,
+            length: Some(3),
+            filename: Some("test.sg".to_string()),
+        }),
+    }
+}
+
+#[test]
+fn test_check_compatibility() {
+    type Core = sage::vm::CoreOp;
+    let lamp = Output::new(OutputMode::Custom("lamp".to_string()), 0);
+    let program = StandardProgram(vec![
+        // Line 0: supported, or lowered to supported I/O.
+        StandardOp::CoreOp(Core::Get(Input::stdin_int())),
+        StandardOp::CoreOp(Core::Put(Output::new(OutputMode::Servo, 1))),
+        // Line 1: the same output twice is only reported once.
+        StandardOp::CoreOp(Core::Put(lamp.clone())),
+        StandardOp::CoreOp(Core::Put(lamp.clone())),
+        // Line 2: an input with nothing to fall back on, and a float instruction.
+        StandardOp::CoreOp(Core::Get(Input::new(
+            InputMode::Custom("knob".to_string()),
+            2,
+        ))),
+        StandardOp::Sin(1),
+        // Line 3: the same output again, and foreign functions.
+        StandardOp::CoreOp(Core::Put(lamp.clone())),
+        StandardOp::Call(FFIBinding::new("present".to_string(), 1, 1)),
+        StandardOp::Call(FFIBinding::new("missing".to_string(), 1, 1)),
+    ]);
+    let mut source_map = SourceMap::default();
+    for (instruction, line) in [(0, 0), (2, 1), (4, 2), (6, 3)] {
+        source_map.insert(instruction, frame(line));
+    }
+
+    let report = check_compatibility(&Limited(C::default()), &program, &source_map);
+    assert!(!report.is_compatible());
+    assert_eq!(
+        report
+            .issues
+            .iter()
+            .map(|issue| (issue.instruction, issue.incompatibility.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (2, Incompatibility::Output(lamp.clone())),
+            (
+                4,
+                Incompatibility::Input(Input::new(InputMode::Custom("knob".to_string()), 2))
+            ),
+            (5, Incompatibility::Float(StandardOp::Sin(1))),
+            (6, Incompatibility::Output(lamp)),
+            (
+                8,
+                Incompatibility::Ffi(FFIBinding::new("missing".to_string(), 1, 1))
+            ),
+        ]
+    );
+    assert_eq!(
+        report.to_string(),
+        "Found 5 incompatibilities with target Limited:
+  output `lamp #0` is not supported at <main> (test.sg:2:5)
+  input `knob #2` is not supported at <main> (test.sg:3:5)
+  floating point instruction `sin 1` is not supported at <main> (test.sg:3:5)
+  output `lamp #0` is not supported at <main> (test.sg:4:5)
+  foreign function `missing` cannot be provided at <main> (test.sg:4:5)"
+    );
+
+    // Building the program stops at the first of these problems.
+    assert!(Limited(C::default()).build_std(&program).is_err());
+
+    // Without a source map, every instruction with a problem is reported.
+    let report = check_compatibility(&Limited(C::default()), &program, &SourceMap::default());
+    assert_eq!(report.issues.len(), 6);
+    assert_eq!(
+        report.issues[1].to_string(),
+        "output `lamp #0` is not supported at instruction #3"
+    );
+
+    let report = check_compatibility(&C::default(), &program, &source_map);
+    assert_eq!(report.issues.len(), 3);
+    assert_eq!(
+        check_compatibility(&Rust::default(), &program, &source_map).to_string(),
+        "The program is compatible with target Rust"
+    );
+}
+
+#[test]
+fn test_check_ffi_compatibility() {
+    let bindings = [
+        FFIBinding::new("square_root".to_string(), 1, 1),
+        FFIBinding::new("pass".to_string(), 0, 0),
+        FFIBinding::new("toString".to_string(), 0, 1),
+        FFIBinding::new("sum".to_string(), 1000, 1),
+        FFIBinding::new("memcpy".to_string(), 3, 0),
+    ];
+    let program = StandardProgram(bindings.iter().cloned().map(StandardOp::Call).collect());
+    let unsupported = |report: CompatibilityReport| {
+        report
+            .issues
+            .into_iter()
+            .map(|issue| issue.incompatibility)
+            .collect::<Vec<_>>()
+    };
+    let ffi = |i: usize| Incompatibility::Ffi(bindings[i].clone());
+
+    // The interpreter only has bindings for a few foreign functions.
+    assert_eq!(
+        unsupported(check_interpreter_compatibility(
+            &StandardDevice::default(),
+            &program,
+            &SourceMap::default()
+        )),
+        vec![ffi(1), ffi(2), ffi(3), ffi(4)]
+    );
+    // Python can't call a function named after a keyword.
+    assert_eq!(
+        unsupported(check_compatibility(
+            &Python::default(),
+            &program,
+            &SourceMap::default()
+        )),
+        vec![ffi(1)]
+    );
+    // JavaScript can't tell a missing function from an inherited property.
+    assert_eq!(
+        unsupported(check_compatibility(
+            &JavaScript::default(),
+            &program,
+            &SourceMap::default()
+        )),
+        vec![ffi(2)]
+    );
+    // The arguments don't fit in the FFI channel of a WebAssembly module.
+    assert_eq!(
+        unsupported(check_compatibility(
+            &Wasm::default(),
+            &program,
+            &SourceMap::default()
+        )),
+        vec![ffi(3)]
+    );
+    // SageOS only provides the foreign functions its runtime defines.
+    #[cfg(feature = "sage-os")]
+    assert_eq!(
+        unsupported(check_compatibility(
+            &SageOS::default(),
+            &program,
+            &SourceMap::default()
+        )),
+        vec![ffi(0), ffi(1), ffi(2), ffi(3)]
+    );
+}
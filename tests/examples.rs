@@ -1,7 +1,7 @@
-use sage::{lir::Compile, parse::*, vm::*};
+use sage::{asm, lir::Compile, parse::*, vm::*};
 use std::{
     fs::{read_dir, read_to_string},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use log::warn;
@@ -9,6 +9,154 @@ use log::warn;
 const INPUT: &str = "2 4 8 16 32 64 128 256 512 1024 2048 4096";
 const CALL_STACK_SIZE: usize = 8192;
 
+/// Run a program with the testing device.
+fn run(path: &Path, vm_code: &Result<CoreProgram, StandardProgram>) -> TestingDevice {
+    match vm_code {
+        Ok(vm_code) => CoreInterpreter::new(TestingDevice::new(INPUT))
+            .run(vm_code)
+            .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
+        Err(vm_code) => StandardInterpreter::new(TestingDevice::new(INPUT))
+            .run(vm_code)
+            .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
+    }
+}
+
+/// The number of instructions in the examples, before and after optimizing them.
+#[derive(Default)]
+struct InstructionCounts {
+    before: usize,
+    after: usize,
+}
+
+impl InstructionCounts {
+    /// Check that a pass over the examples removed instructions.
+    fn check(&self, pass: &str) {
+        warn!(
+            "{pass} removed {} of {} instructions",
+            self.before - self.after,
+            self.before
+        );
+        assert!(
+            self.after < self.before,
+            "{pass} did not remove any instructions"
+        );
+    }
+}
+
+/// Count the instructions in a program, without its comments.
+fn count_instructions(vm_code: &Result<CoreProgram, StandardProgram>) -> usize {
+    match vm_code {
+        Ok(vm_code) => vm_code
+            .0
+            .iter()
+            .filter(|op| !matches!(op, CoreOp::Comment(_)))
+            .count(),
+        Err(vm_code) => vm_code
+            .0
+            .iter()
+            .filter(|op| !matches!(op, StandardOp::CoreOp(CoreOp::Comment(_))))
+            .count(),
+    }
+}
+
+/// Optimize a program, and check that it prints the same output
+/// with fewer instructions.
+fn check_optimized(
+    path: &Path,
+    vm_code: Result<CoreProgram, StandardProgram>,
+    device: &TestingDevice,
+    counts: &mut InstructionCounts,
+) {
+    let before = count_instructions(&vm_code);
+    let vm_code = match vm_code {
+        Ok(vm_code) => Ok(vm_code.optimize()),
+        Err(vm_code) => Err(vm_code.optimize()),
+    };
+    let after = count_instructions(&vm_code);
+    assert!(
+        after <= before,
+        "Optimizing `{path:?}` added instructions: {before} -> {after}"
+    );
+    counts.before += before;
+    counts.after += after;
+    check_lifted(path, &vm_code, device);
+
+    let optimized = run(path, &vm_code);
+    if optimized.output_vals() != device.output_vals() {
+        panic!(
+            "{:?} != {:?}, optimized output did not match output for program {path:?}",
+            optimized.output_str(),
+            device.output_str()
+        )
+    }
+}
+
+/// Lift a program back into assembly code, and check that it prints
+/// the same output after assembling it again.
+fn check_lifted(
+    path: &Path,
+    vm_code: &Result<CoreProgram, StandardProgram>,
+    device: &TestingDevice,
+) {
+    let vm_code = match vm_code {
+        Ok(vm_code) => asm::CoreProgram::lift(vm_code)
+            .and_then(|asm_code| asm_code.assemble(CALL_STACK_SIZE))
+            .map(Ok),
+        Err(vm_code) => asm::StandardProgram::lift(vm_code)
+            .and_then(|asm_code| asm_code.assemble(CALL_STACK_SIZE))
+            .map(Err),
+    }
+    .unwrap_or_else(|e| panic!("Could not lift `{path:?}`: {e}"));
+
+    let lifted = run(path, &vm_code);
+    if lifted.output_vals() != device.output_vals() {
+        panic!(
+            "{:?} != {:?}, lifted output did not match output for program {path:?}",
+            lifted.output_str(),
+            device.output_str()
+        )
+    }
+}
+
+/// Keep the stack cells of a program in registers, and check that it prints
+/// the same output with fewer instructions.
+fn check_registers_allocated(
+    path: &Path,
+    mut asm_code: Result<asm::CoreProgram, asm::StandardProgram>,
+    before: usize,
+    device: &TestingDevice,
+    counts: &mut InstructionCounts,
+) {
+    let vm_code = match &mut asm_code {
+        Ok(asm_code) => {
+            asm_code.allocate_registers();
+            asm_code.assemble(CALL_STACK_SIZE).map(Ok)
+        }
+        Err(asm_code) => {
+            asm_code.allocate_registers();
+            asm_code.assemble(CALL_STACK_SIZE).map(Err)
+        }
+    }
+    .unwrap_or_else(|e| panic!("Could not assemble `{path:?}` with registers allocated: {e}"));
+    let after = count_instructions(&vm_code);
+    assert!(
+        after <= before,
+        "Allocating registers for `{path:?}` added instructions: {before} -> {after}"
+    );
+    counts.before += before;
+    counts.after += after;
+    check_lifted(path, &vm_code, device);
+
+    let allocated = run(path, &vm_code);
+    if allocated.output_vals() != device.output_vals() {
+        panic!(
+            "{:?} != {:?}, output with registers allocated did not match output for program {path:?}",
+            allocated.output_str(),
+            device.output_str()
+        )
+    }
+}
+
 #[test]
 fn test_frontend_examples() {
     // Enable logging
@@ -41,6 +189,8 @@ fn test_frontend_examples() {
 }
 
 fn test_frontend_examples_helper() {
+    let mut counts = InstructionCounts::default();
+    let mut allocated = InstructionCounts::default();
     for entry in read_dir("examples/frontend/").unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
@@ -120,31 +270,30 @@ fn test_frontend_examples_helper() {
             }
             let asm_code = asm_code.unwrap();
 
-            let vm_code = match asm_code {
+            let vm_code = match &asm_code {
                 Ok(core_asm_code) => core_asm_code.assemble(CALL_STACK_SIZE).map(Ok),
                 Err(std_asm_code) => std_asm_code.assemble(CALL_STACK_SIZE).map(Err),
             }
             .unwrap();
 
-            let device = match vm_code {
-                Ok(vm_code) => CoreInterpreter::new(TestingDevice::new(INPUT))
-                    .run(&vm_code)
-                    .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
-                Err(vm_code) => StandardInterpreter::new(TestingDevice::new(INPUT))
-                    .run(&vm_code)
-                    .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
-            };
+            let device = run(&path, &vm_code);
 
             let output_text = device.output_str();
             if device.output_vals() != correct_output {
                 panic!("{output_text:?} != {correct_output_text:?}, device output did not match correct output for program {path:?}")
             }
+            let before = count_instructions(&vm_code);
+            check_registers_allocated(&path, asm_code, before, &device, &mut allocated);
+            check_lifted(&path, &vm_code, &device);
+            check_optimized(&path, vm_code, &device, &mut counts);
 
             if let Some(correct_error) = correct_error {
                 panic!("Expected error `{correct_error:?}` but got output `{output_text:?}` for program `{path:?}`")
             }
         }
     }
+    counts.check("Optimizing");
+    allocated.check("Allocating registers");
 }
 
 #[test]
@@ -182,6 +331,8 @@ fn test_lir_examples() {
 }
 
 fn test_lir_examples_helper() {
+    let mut counts = InstructionCounts::default();
+    let mut allocated = InstructionCounts::default();
     for entry in read_dir("examples/lir/").unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
@@ -247,31 +398,30 @@ fn test_lir_examples_helper() {
             }
             let asm_code = asm_code.unwrap();
 
-            let vm_code = match asm_code {
+            let vm_code = match &asm_code {
                 Ok(core_asm_code) => core_asm_code.assemble(CALL_STACK_SIZE).map(Ok),
                 Err(std_asm_code) => std_asm_code.assemble(CALL_STACK_SIZE).map(Err),
             }
             .unwrap();
 
-            let device = match vm_code {
-                Ok(vm_code) => CoreInterpreter::new(TestingDevice::new(INPUT))
-                    .run(&vm_code)
-                    .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
-                Err(vm_code) => StandardInterpreter::new(TestingDevice::new(INPUT))
-                    .run(&vm_code)
-                    .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
-            };
+            let device = run(&path, &vm_code);
 
             let output_text = device.output_str();
             if device.output_vals() != correct_output {
                 panic!("{output_text:?} != {correct_output_text:?}, device output did not match correct output for program {path:?}")
             }
+            let before = count_instructions(&vm_code);
+            check_registers_allocated(&path, asm_code, before, &device, &mut allocated);
+            check_lifted(&path, &vm_code, &device);
+            check_optimized(&path, vm_code, &device, &mut counts);
 
             if let Some(correct_error) = correct_error {
                 panic!("Expected error `{correct_error:?}` but got output `{output_text:?}` for program `{path:?}`")
             }
         }
     }
+    counts.check("Optimizing");
+    allocated.check("Allocating registers");
 }
 
 #[test]
@@ -288,6 +438,7 @@ fn test_asm_examples() {
 }
 
 fn test_asm_examples_helper() {
+    let mut counts = InstructionCounts::default();
     for entry in read_dir("examples/asm/").unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
@@ -357,23 +508,19 @@ fn test_asm_examples_helper() {
 
             let vm_code = vm_code.unwrap();
 
-            let device = match vm_code {
-                Ok(vm_code) => CoreInterpreter::new(TestingDevice::new(INPUT))
-                    .run(&vm_code)
-                    .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
-                Err(vm_code) => StandardInterpreter::new(TestingDevice::new(INPUT))
-                    .run(&vm_code)
-                    .unwrap_or_else(|_| panic!("Could not interpret code in `{path:?}`")),
-            };
+            let device = run(&path, &vm_code);
 
             let output_text = device.output_str();
             if device.output_vals() != correct_output {
                 panic!("{output_text:?} != {correct_output_text:?}, device output did not match correct output for program {path:?}")
             }
+            check_lifted(&path, &vm_code, &device);
+            check_optimized(&path, vm_code, &device, &mut counts);
 
             if let Some(correct_error) = correct_error {
                 panic!("Expected error `{correct_error:?}` but got output `{output_text:?}` for program `{path:?}`")
             }
         }
     }
+    counts.check("Optimizing");
 }
@@ -0,0 +1,97 @@
+mod common;
+
+use common::*;
+use sage::{
+    side_effects::{Color, Input, InputMode, Output, OutputMode},
+    targets::*,
+    vm::{CoreOp, CoreProgram},
+};
+use std::process::Command;
+
+/// A program with I/O that none of the targets support natively.
+fn program() -> CoreProgram {
+    use CoreOp::*;
+    CoreProgram(vec![
+        // Sensors are read from the input, and actuators are logged.
+        Get(Input::new(InputMode::Thermometer, 0)),
+        Put(Output::new(OutputMode::Servo, 1)),
+        // Keys are read as characters, and pixels are drawn as characters.
+        Get(Input::new(InputMode::Keyboard, 0)),
+        Put(Output::new(OutputMode::SetCursorPixel(Color::Red), 0)),
+        Put(Output::new(OutputMode::SetCursorPixel(Color::Blue), 0)),
+    ])
+}
+
+#[test]
+fn test_io_fallback() {
+    use CoreOp::*;
+    let program = program();
+    assert_eq!(
+        lower_core_io(&Python::default(), &program).0,
+        vec![
+            Get(Input::stdin_float()),
+            Put(Output::stderr_float()),
+            Get(Input::stdin_char()),
+            Put(Output::stdout_char()),
+            Put(Output::stdout_char()),
+        ]
+    );
+
+    if !installed("python3") {
+        return;
+    }
+    let python_code_path = "tmp_python_code_io_fallback.py";
+    std::fs::write(
+        python_code_path,
+        Python::default().build_core(&program).unwrap(),
+    )
+    .unwrap();
+    let output = run_with_input(Command::new("python3").arg(python_code_path), "1.5\n#");
+    std::fs::remove_file(python_code_path).unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "##");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "1.5");
+}
+
+#[test]
+fn test_io_fallback_in_c() {
+    use CoreOp::*;
+    // The C target writes to standard error natively, so actuators are logged there.
+    assert_eq!(
+        lower_core_io(&C::default(), &program()).0,
+        vec![
+            Get(Input::stdin_float()),
+            Put(Output::stderr_float()),
+            Get(Input::stdin_char()),
+            Put(Output::stdout_char()),
+            Put(Output::stdout_char()),
+        ]
+    );
+}
+
+#[test]
+fn test_io_fallback_without_stderr() {
+    use CoreOp::*;
+    // The x86 target has no standard error, so actuators are logged on standard output.
+    assert_eq!(
+        lower_core_io(&X86::default(), &program()).0,
+        vec![
+            Get(Input::stdin_float()),
+            Put(Output::stdout_float()),
+            Get(Input::stdin_char()),
+            Put(Output::stdout_char()),
+            Put(Output::stdout_char()),
+        ]
+    );
+}
+
+#[test]
+fn test_io_without_fallback() {
+    // Custom modes have nothing to fall back on.
+    let program = CoreProgram(vec![CoreOp::Put(Output::new(
+        OutputMode::Custom("lamp".to_string()),
+        0,
+    ))]);
+    assert!(Python::default().build_core(&program).is_err());
+    assert!(C::default().build_core(&program).is_err());
+}
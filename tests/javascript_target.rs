@@ -0,0 +1,33 @@
+mod common;
+
+use common::*;
+use sage::targets::JavaScript;
+
+/// The JavaScript target, and the interpreter which runs its scripts.
+fn javascript() -> ScriptTarget<JavaScript> {
+    ScriptTarget {
+        target: JavaScript::default(),
+        interpreter: "node",
+        extension: "js",
+    }
+}
+
+#[test]
+fn test_javascript_target_asm_examples() {
+    javascript().test_asm_examples();
+}
+
+#[test]
+fn test_javascript_target_frontend_examples() {
+    with_large_stack(|| javascript().test_frontend_examples());
+}
+
+#[test]
+fn test_javascript_target_blocks() {
+    javascript().test_blocks();
+}
+
+#[test]
+fn test_javascript_target_std_ops() {
+    javascript().test_std_ops();
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::*;
+use sage::targets::Python;
+
+/// The Python target, and the interpreter which runs its scripts.
+fn python() -> ScriptTarget<Python> {
+    ScriptTarget {
+        target: Python::default(),
+        interpreter: "python3",
+        extension: "py",
+    }
+}
+
+#[test]
+fn test_python_target_asm_examples() {
+    python().test_asm_examples();
+}
+
+#[test]
+fn test_python_target_frontend_examples() {
+    with_large_stack(|| python().test_frontend_examples());
+}
+
+#[test]
+fn test_python_target_blocks() {
+    python().test_blocks();
+}
+
+#[test]
+fn test_python_target_std_ops() {
+    python().test_std_ops();
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use common::*;
+use sage::{
+    side_effects::{FFIBinding, Input, Output, OutputMode},
+    targets::*,
+    vm::{CoreOp, CoreProgram, StandardOp, StandardProgram},
+};
+use std::process::Command;
+
+/// Compile some Rust code with `rustc`, and run it with the given input.
+fn run_rust(name: &str, rust_code: String, input: &str) -> std::process::Output {
+    let rust_code_path = format!("tmp_rust_code_{name}.rs");
+    let exe_path = format!("tmp_rust_code_{name}.exe");
+    std::fs::write(&rust_code_path, rust_code).unwrap();
+    let compile_output = Command::new("rustc")
+        .arg("--edition=2021")
+        .arg("--crate-name=sage_program")
+        .arg(&rust_code_path)
+        .arg("-o")
+        .arg(&exe_path)
+        .output()
+        .unwrap();
+    if !compile_output.status.success() {
+        panic!("Could not compile Rust code for `{name}`: {compile_output:?}");
+    }
+
+    let output = run_with_input(&mut Command::new(format!("./{exe_path}")), input);
+
+    std::fs::remove_file(&rust_code_path).unwrap();
+    std::fs::remove_file(&exe_path).unwrap();
+    output
+}
+
+/// Build a program for the Rust target.
+fn build(program: Program) -> String {
+    match program {
+        Ok(program) => Rust::default().build_core(&program.flatten()),
+        Err(program) => Rust::default().build_std(&program.flatten()),
+    }
+    .unwrap()
+}
+
+#[test]
+fn test_rust_target_frontend_examples() {
+    with_large_stack(test_rust_target_frontend_examples_helper);
+}
+
+fn test_rust_target_frontend_examples_helper() {
+    let (total_failures, total_attempts) = run_examples("examples/frontend/", |name, program| {
+        String::from_utf8(run_rust(name, build(program), INPUT).stdout).unwrap()
+    });
+
+    // Some of the examples print out pointers, which depend on the allocator.
+    if total_failures as f64 / total_attempts as f64 > 0.1 {
+        panic!(
+            "Too many failures in frontend examples: {total_failures} failures out of {total_attempts} attempts"
+        );
+    }
+}
+
+#[test]
+fn test_rust_target_calls() {
+    use CoreOp::*;
+    let program = CoreProgram(vec![
+        // Function 0 prints the character under the pointer.
+        Function,
+        Load(1),
+        Put(Output::stdout_char()),
+        Return,
+        End,
+        // Function 1 halts the program from inside a loop.
+        Function,
+        Set(vec![1]),
+        While,
+        Set(vec![3]),
+        Halt,
+        End,
+        End,
+        Set(vec![b'a' as i64]),
+        Store(1),
+        Set(vec![0]),
+        Call,
+        // Call function 0 with an index read from the input.
+        Get(Input::stdin_char()),
+        Store(1),
+        Set(vec![0]),
+        Call,
+        Set(vec![1]),
+        Call,
+        Set(vec![b'!' as i64]),
+        Put(Output::stdout_char()),
+    ]);
+
+    let rust_code = Rust::default().build_core(&program).unwrap();
+    assert!(rust_code.contains("static FUNS: [fn(&mut Machine); 2] = [f0, f1];"));
+
+    let output = run_rust("calls", rust_code, "b");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ab");
+}
+
+#[test]
+fn test_rust_target_ffi() {
+    use StandardOp::*;
+    type Core = sage::vm::CoreOp;
+    let program = StandardProgram(vec![
+        Set(vec![1.5, -2.25]),
+        CoreOp(Core::Store(2)),
+        Mul(2),
+        CoreOp(Core::Store(2)),
+        Poke,
+        CoreOp(Core::Move(1)),
+        CoreOp(Core::Load(1)),
+        Poke,
+        Call(FFIBinding::new("add".to_string(), 2, 1)),
+        Peek,
+        CoreOp(Core::Put(Output::stdout_float())),
+        CoreOp(Core::Set(vec![3])),
+        Alloc,
+        CoreOp(Core::Store(1)),
+        CoreOp(Core::Set(vec![7])),
+        CoreOp(Core::Deref),
+        CoreOp(Core::Store(1)),
+        CoreOp(Core::Put(Output::new(OutputMode::StdoutInt, 2))),
+        CoreOp(Core::Refer),
+        CoreOp(Core::Load(1)),
+        Free,
+    ]);
+
+    let mut rust_code = Rust::default().build_std(&program).unwrap();
+    assert!(rust_code.contains("fn add(&mut self, tape: &mut Vec<Cell>);"));
+    assert!(!rust_code.contains("fn main()"));
+
+    // Foreign functions are implemented by the user's device.
+    rust_code += r#"
+#[derive(Default)]
+struct Host {
+    channel: Vec<Cell>,
+    output: String,
+}
+
+impl Device for Host {
+    fn get(&mut self, _mode: &str, _channel: usize) -> Cell {
+        0
+    }
+    fn put(&mut self, val: Cell, mode: &str, channel: usize) {
+        match mode {
+            "stdout.float" => self.output += &format!("{:?} ", to_float(val)),
+            _ => self.output += &format!("{mode}#{channel}={val} "),
+        }
+    }
+    fn peek(&mut self) -> Cell {
+        self.channel.pop().unwrap()
+    }
+    fn poke(&mut self, val: Cell) {
+        self.channel.push(val);
+    }
+}
+
+impl Ffi for Host {
+    fn add(&mut self, _tape: &mut Vec<Cell>) {
+        let a = to_float(self.peek());
+        let b = to_float(self.peek());
+        self.poke(to_cell(a + b));
+    }
+}
+
+fn main() {
+    let mut host = Host::default();
+    let code = run(&mut host);
+    print!("{}{code}", host.output);
+}
+"#;
+    let output = run_rust("ffi", rust_code, "");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "7.3125 stdout.int#2=7 0"
+    );
+}
@@ -0,0 +1,30 @@
+use sage::{frontend::new_lisp_env, targets::*, vm::CoreProgram};
+use ::sage_lisp::Expr;
+
+#[test]
+fn test_sage_lisp_target_validate() {
+    // A backend without any entry points is rejected up front.
+    let error = SageLisp::new(new_lisp_env()).validate().unwrap_err();
+    assert!(error.contains("`build-core` or `build-std`"), "{error}");
+    let error = SageLisp::load(new_lisp_env(), &[]).err().unwrap();
+    assert!(error.contains("`build-core` or `build-std`"), "{error}");
+
+    // A backend which can only build core programs can't build standard ones.
+    let mut env = new_lisp_env();
+    env.bind_builtin("build-core", |_, _| Expr::String("core".to_string()));
+    let mut backend = SageLisp::new(env);
+    assert!(backend.validate().is_ok());
+    let error = backend.build_std(&CoreProgram(vec![]).into()).unwrap_err();
+    assert!(error.contains("`build-std`"), "{error}");
+}
+
+#[test]
+fn test_sage_lisp_target_load_errors() {
+    // Errors in backend files name the file they're in.
+    let files = [(
+        "backend.lisp".to_string(),
+        "; An unfinished backend.\n(define (build-std program)".to_string(),
+    )];
+    let error = SageLisp::load(new_lisp_env(), &files).err().unwrap();
+    assert!(error.contains("`backend.lisp`"), "{error}");
+}
@@ -0,0 +1,40 @@
+/*
+ * The SageOS system calls, implemented on top of the C standard library,
+ * so that programs built for SageOS can be tested on the host.
+ */
+#include <stdint.h>
+#include <stdbool.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <time.h>
+
+typedef struct {
+    uint32_t x, y, width, height;
+} Rectangle;
+
+typedef struct {
+    uint8_t r, g, b, a;
+} Pixel;
+
+typedef struct {
+    uint16_t type, code;
+    uint32_t value;
+} VirtioInputEvent;
+
+static bool path_exists(const char *path) { return false; }
+static bool path_is_dir(const char *path) { return false; }
+static bool path_is_file(const char *path) { return false; }
+static int path_list_dir(const char *path, char *buf, uint64_t size, bool full) { return -1; }
+static int spawn_process(const char *path) { return -1; }
+static int get_pid() { return 1; }
+static int next_pid(int pid) { return -1; }
+static int get_env(const char *name, char *value) { return -1; }
+static int put_env(const char *name, const char *value) { return -1; }
+static int pid_get_env(int pid, const char *name, char *value) { return -1; }
+static int pid_put_env(int pid, const char *name, const char *value) { return -1; }
+static void screen_draw_rect(const Pixel *buf, const Rectangle *rect, uint64_t x_scale, uint64_t y_scale) {}
+static void screen_flush(const Rectangle *rect) {}
+static void screen_get_dims(Rectangle *rect) { rect->x = rect->y = rect->width = rect->height = 0; }
+static uint64_t get_time() { return time(NULL); }
+static void get_keyboard_event(VirtioInputEvent *event) { event->type = event->code = event->value = 0; }
+static void get_tablet_event(VirtioInputEvent *event) { event->type = event->code = event->value = 0; }
@@ -0,0 +1,239 @@
+#![cfg(feature = "sage-os")]
+
+use log::warn;
+use sage::{
+    lir::Compile,
+    parse::*,
+    side_effects::{FFIBinding, Input, Output},
+    targets::*,
+    vm::{StandardOp, StandardProgram},
+};
+use std::{
+    fs::{read_dir, read_to_string},
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+const INPUT: &str = "2 4 8 16 32 64 128 256 512 1024 2048 4096";
+const CALL_STACK_SIZE: usize = 8192;
+
+/// The SageOS target, configured for running programs on the host.
+fn target() -> SageOS {
+    SageOS {
+        echo: false,
+        ..SageOS::default()
+    }
+    .with_call_stack(CALL_STACK_SIZE)
+}
+
+/// Compile some SageOS code with GCC, using the system calls in `tests/sage_os/sage.h`.
+/// Returns the path of the executable.
+fn compile_sage_os_code(name: &str, c_code: String) -> String {
+    let c_code_path = format!("tmp_sage_os_code_{name}.c");
+    let exe_path = format!("tmp_sage_os_code_{name}.exe");
+    std::fs::write(&c_code_path, c_code).unwrap();
+    let compile_output = Command::new("gcc")
+        .arg(&c_code_path)
+        .arg("-I")
+        .arg("tests/sage_os")
+        .arg("-o")
+        .arg(&exe_path)
+        .arg("-lm")
+        .output()
+        .unwrap();
+    std::fs::remove_file(&c_code_path).unwrap();
+    if !compile_output.status.success() {
+        panic!("Could not compile SageOS code for `{name}`: {compile_output:?}");
+    }
+    exe_path
+}
+
+/// Compile some SageOS code, and run it with the given input.
+fn run_sage_os_code(name: &str, c_code: String, input: &str) -> std::process::Output {
+    let exe_path = compile_sage_os_code(name, c_code);
+    let mut exe = Command::new(format!("./{exe_path}"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The program may exit without reading its input.
+    let _ = exe.stdin.take().unwrap().write_all(input.as_bytes());
+    let output = exe.wait_with_output().unwrap();
+
+    std::fs::remove_file(&exe_path).unwrap();
+    output
+}
+
+/// Compile the frontend code in a file to SageOS code.
+fn build_frontend(path: &PathBuf) -> String {
+    let frontend_src = read_to_string(path).unwrap();
+    let frontend_code = parse_frontend(&frontend_src, path.to_str())
+        .unwrap_or_else(|_| panic!("Could not parse `{path:?}`"));
+    let asm_code = frontend_code
+        .compile(false)
+        .unwrap_or_else(|e| panic!("Could not compile `{path:?}`: {e}"));
+    match asm_code {
+        Ok(core_asm_code) => {
+            target().build_core(&core_asm_code.assemble(CALL_STACK_SIZE).unwrap().flatten())
+        }
+        Err(std_asm_code) => {
+            target().build_std(&std_asm_code.assemble(CALL_STACK_SIZE).unwrap().flatten())
+        }
+    }
+    .unwrap()
+}
+
+/// Run a test in a new thread, because compiling most examples
+/// overflows the tiny stack for tests.
+fn with_big_stack(test: fn()) {
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test)
+        .unwrap();
+
+    // Wait for the thread to finish.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_sage_os_target_frontend_examples() {
+    with_big_stack(test_sage_os_target_frontend_examples_helper);
+}
+
+fn test_sage_os_target_frontend_examples_helper() {
+    let mut total_failures: i32 = 0;
+    let mut total_attempts = 0;
+
+    for entry in read_dir("examples/frontend/").unwrap() {
+        let path = entry.unwrap().path();
+        if !path.is_file() || path.extension().and_then(|ext| ext.to_str()) != Some("sg") {
+            continue;
+        }
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let correct_output_path = PathBuf::from("examples/test-output")
+            .join(&file_name)
+            .with_extension("txt");
+        let Ok(correct_output) = read_to_string(&correct_output_path) else {
+            warn!("Could not read output text file `{correct_output_path:?}` to compare against. Skipping this test.");
+            continue;
+        };
+        let correct_output = correct_output.replace("\r\n", "\n");
+
+        let output = run_sage_os_code(&file_name, build_frontend(&path), INPUT);
+        let output = String::from_utf8_lossy(&output.stdout);
+        if output != correct_output {
+            warn!(
+                "Output did not match correct output for program `{path:?}`:\n{output}\n  !=   \n{correct_output}"
+            );
+            total_failures += 1;
+        }
+        total_attempts += 1;
+    }
+
+    // Like the C target, some of the examples print out pointers, which are machine addresses.
+    if total_failures as f64 / total_attempts as f64 > 0.3 {
+        panic!(
+            "Too many failures in frontend examples: {total_failures} failures out of {total_attempts} attempts"
+        );
+    }
+}
+
+#[test]
+fn test_sage_os_target_runtime() {
+    type Core = sage::vm::CoreOp;
+    let core = StandardOp::CoreOp;
+    let putint = FFIBinding::new("putint".to_string(), 2, 0);
+    let space = [
+        core(Core::Set(vec![b' ' as i64])),
+        core(Core::Put(Output::stdout_char())),
+    ];
+    let mut program = vec![
+        // Read values from the console, and write them back out.
+        core(Core::Get(Input::stdin_int())),
+        core(Core::Put(Output::stdout_int())),
+    ];
+    program.extend(space.clone());
+    program.extend([
+        core(Core::Get(Input::stdin_float())),
+        core(Core::Put(Output::stdout_float())),
+    ]);
+    program.extend(space.clone());
+    program.extend([
+        core(Core::Get(Input::stdin_char())),
+        core(Core::Get(Input::stdin_char())),
+        core(Core::Put(Output::stdout_char())),
+    ]);
+    program.extend(space.clone());
+    program.extend([
+        // Allocating memory after freeing it doesn't clobber the other blocks.
+        core(Core::Set(vec![4])),
+        StandardOp::Alloc,
+        core(Core::Store(1)),
+        core(Core::Move(1)),
+        core(Core::Set(vec![2])),
+        StandardOp::Alloc,
+        core(Core::Store(1)),
+        core(Core::Set(vec![7])),
+        core(Core::Deref),
+        core(Core::Store(1)),
+        core(Core::Refer),
+        core(Core::Move(-1)),
+        core(Core::Load(1)),
+        StandardOp::Free,
+        core(Core::Set(vec![16])),
+        StandardOp::Alloc,
+        core(Core::Store(1)),
+        core(Core::Set(vec![-1, -1, -1, -1, -1, -1, -1, -1])),
+        core(Core::Deref),
+        core(Core::Store(8)),
+        core(Core::Refer),
+        core(Core::Move(1)),
+        core(Core::Deref),
+        core(Core::Load(1)),
+        core(Core::Refer),
+        core(Core::Put(Output::stdout_int())),
+    ]);
+    program.extend(space.clone());
+    program.extend([
+        // Foreign functions take their arguments from the FFI channel.
+        core(Core::Set(vec![255])),
+        StandardOp::Poke,
+        core(Core::Set(vec![16])),
+        StandardOp::Poke,
+        StandardOp::Call(putint),
+    ]);
+    program.extend(space);
+    program.extend([
+        // The memory which is used and remaining adds up to the whole heap.
+        StandardOp::Call(FFIBinding::new("used_memory".to_string(), 0, 1)),
+        StandardOp::Peek,
+        core(Core::Store(1)),
+        StandardOp::Call(FFIBinding::new("remaining_memory".to_string(), 0, 1)),
+        StandardOp::Peek,
+        core(Core::Add(1)),
+        core(Core::Put(Output::stdout_int())),
+    ]);
+
+    let c_code = target().build_std(&StandardProgram(program)).unwrap();
+    let output = run_sage_os_code("runtime", c_code, "-42\n2.5\nxyz\n");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("-42 2.5 x 7 ff {}", target().heap_bytes)
+    );
+
+    // Programs which need more memory than the heap has are stopped.
+    let mut tiny = SageOS {
+        heap_bytes: 1024,
+        ..target()
+    };
+    let c_code = tiny.build_std(&StandardProgram(vec![])).unwrap();
+    let output = run_sage_os_code("tiny", c_code, "");
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "error: the heap is too small for the program\n"
+    );
+}
@@ -1,4 +1,9 @@
-use sage::{side_effects::Output, vm::*};
+use sage::{
+    lir::Compile,
+    parse::{parse_asm, parse_frontend_minimal, parse_vm, SourceCodeLocation},
+    side_effects::{Color, FFIBinding, Input, InputMode, Output, OutputMode},
+    vm::*,
+};
 
 #[test]
 fn test_add() {
@@ -134,3 +139,948 @@ fn test_pointers() {
 
     assert_eq!(device.output_vals(), vec![b, a]);
 }
+
+/// A program which calls a function that stores 5 on the tape,
+/// and then prints the stored value.
+fn function_call_program() -> CoreProgram {
+    CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Set(vec![5]),
+        CoreOp::Store(1),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(vec![0]),
+        CoreOp::Call,
+        CoreOp::Load(1),
+        CoreOp::Put(Output::stdout_char()),
+    ])
+}
+
+#[test]
+fn test_debugger_breakpoints() {
+    let program = function_call_program();
+    let mut debugger = Debugger::new(CoreInterpreter::new(TestingDevice::default()), &program)
+        .with_labels(vec![String::from("store_five")]);
+
+    // Stop on entry to the function, and then step out of it.
+    assert_eq!(
+        debugger.add_breakpoint(Breakpoint::Label("store_five".into())),
+        Ok(1)
+    );
+    assert_eq!(debugger.resume(), Ok(StopReason::Breakpoint(1)));
+    assert_eq!(debugger.interpreter().state().calls, &[6]);
+    assert_eq!(debugger.step_out(), Ok(StopReason::Step));
+    assert_eq!(debugger.interpreter().state().i, 7);
+    assert_eq!(debugger.resume(), Ok(StopReason::Finished));
+
+    let device = debugger.into_interpreter().into_device();
+    assert_eq!(device.output_vals(), vec![5]);
+}
+
+#[test]
+fn test_debugger_step_over() {
+    let program = function_call_program();
+    let mut debugger = Debugger::new(CoreInterpreter::new(TestingDevice::default()), &program);
+
+    assert_eq!(debugger.add_breakpoint(Breakpoint::Instruction(6)), Ok(6));
+    assert_eq!(debugger.resume(), Ok(StopReason::Breakpoint(6)));
+    // Stepping over the call runs the whole function.
+    assert_eq!(debugger.step_over(), Ok(StopReason::Step));
+    assert_eq!(debugger.interpreter().state().i, 7);
+    assert_eq!(debugger.interpreter().state().cells[0], 5);
+}
+
+#[test]
+fn test_debugger_interactive() {
+    let program = function_call_program();
+    let mut debugger = Debugger::new(CoreInterpreter::new(TestingDevice::default()), &program);
+
+    let commands = "break fn 0\ncontinue\nstep\nregister\nbt\nquit\n";
+    let mut output = vec![];
+    debugger
+        .run_interactive(commands.as_bytes(), &mut output)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains("breakpoint set at #1 in fn#0"));
+    assert!(output.contains("breakpoint at #1 in fn#0: set [5]"));
+    assert!(output.contains("[5]\n"));
+    assert!(output.contains("#1: #2 in fn#0\n#0: #6 in <main>\n"));
+}
+
+#[test]
+fn test_debugger_after_error() {
+    // Loading from an address past the end of the tape fails.
+    let program = CoreProgram(vec![
+        CoreOp::Set(vec![i64::MAX]),
+        CoreOp::Store(1),
+        CoreOp::Deref,
+        CoreOp::Load(1),
+        CoreOp::Put(Output::stdout_char()),
+    ]);
+    let mut debugger = Debugger::new(CoreInterpreter::new(TestingDevice::default()), &program);
+
+    assert_eq!(debugger.step(), Ok(StopReason::Step));
+    assert_eq!(debugger.step(), Ok(StopReason::Step));
+    assert_eq!(debugger.step(), Ok(StopReason::Step));
+    assert!(debugger.step().is_err());
+    assert!(debugger.is_done());
+    // The failed instruction isn't executed again.
+    assert_eq!(debugger.step(), Ok(StopReason::Terminated));
+    assert_eq!(debugger.resume(), Ok(StopReason::Terminated));
+
+    let mut debugger = Debugger::new(CoreInterpreter::new(TestingDevice::default()), &program);
+    let mut output = vec![];
+    debugger
+        .run_interactive("continue\ncontinue\nstep\n".as_bytes(), &mut output)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.matches("error: ").count(), 1, "{output}");
+    assert_eq!(
+        output.matches("program terminated\n").count(),
+        2,
+        "{output}"
+    );
+}
+
+#[test]
+fn test_limits() {
+    let run = |program: Vec<CoreOp>, limits: Limits| {
+        CoreInterpreter::new(TestingDevice::default())
+            .with_limits(limits)
+            .run(&CoreProgram(program))
+            .map(|device| device.output_vals())
+            .map_err(|e| match e {
+                RuntimeError::LimitExceeded { limit, max, .. } => (limit, max),
+                e => panic!("unexpected error: {e}"),
+            })
+    };
+
+    // An infinite loop stops once it runs out of instructions.
+    let infinite_loop = vec![CoreOp::Set(vec![1]), CoreOp::While, CoreOp::End];
+    let limits = Limits {
+        max_instructions: Some(1000),
+        ..Limits::default()
+    };
+    assert_eq!(run(infinite_loop, limits), Err((Limit::Instructions, 1000)));
+
+    // Infinite recursion stops once the call stack is too deep.
+    let infinite_recursion = vec![
+        CoreOp::Function,
+        CoreOp::Call,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(vec![0]),
+        CoreOp::Call,
+    ];
+    let limits = Limits {
+        max_call_depth: Some(64),
+        ..Limits::default()
+    };
+    assert_eq!(run(infinite_recursion, limits), Err((Limit::CallDepth, 64)));
+
+    // Dereferencing without referring stops once the deref stack is too deep.
+    let infinite_derefs = vec![
+        CoreOp::Set(vec![1]),
+        CoreOp::While,
+        CoreOp::Deref,
+        CoreOp::End,
+    ];
+    let limits = Limits {
+        max_refs_depth: Some(16),
+        ..Limits::default()
+    };
+    assert_eq!(run(infinite_derefs, limits), Err((Limit::RefsDepth, 16)));
+
+    // Storing past the end of the allowed tape fails, but storing within it doesn't.
+    let limits = Limits {
+        max_tape_cells: Some(100),
+        ..Limits::default()
+    };
+    let store_at = |n| {
+        vec![
+            CoreOp::Move(n),
+            CoreOp::Set(vec![n as i64]),
+            CoreOp::Store(1),
+            CoreOp::Load(1),
+            CoreOp::Put(Output::stdout_char()),
+        ]
+    };
+    assert_eq!(run(store_at(99), limits), Ok(vec![99]));
+    assert_eq!(run(store_at(100), limits), Err((Limit::TapeCells, 100)));
+}
+
+#[test]
+fn test_heap() {
+    let op = StandardOp::CoreOp;
+    let alloc = |n| {
+        vec![
+            op(CoreOp::Set(vec![n])),
+            StandardOp::Alloc,
+            op(CoreOp::Put(Output::stdout_char())),
+        ]
+    };
+    let run = |program: Vec<Vec<StandardOp>>, checked: bool| {
+        let mut interpreter = StandardInterpreter::new(TestingDevice::default());
+        if checked {
+            interpreter = interpreter.with_heap_checks();
+        }
+        interpreter
+            .run(&StandardProgram(program.concat()))
+            .map(|device| device.output_vals())
+    };
+
+    // Freed blocks are reused by later allocations, and neighboring freed blocks are merged.
+    let output = run(
+        vec![
+            alloc(4),
+            vec![op(CoreOp::Store(1)), op(CoreOp::Move(1))],
+            alloc(4),
+            vec![StandardOp::Free],
+            alloc(2),
+            vec![StandardOp::Free, op(CoreOp::Move(-1)), op(CoreOp::Load(1))],
+            vec![StandardOp::Free],
+            alloc(8),
+        ],
+        false,
+    )
+    .unwrap();
+    assert_eq!(output[1], output[0] + 4);
+    assert_eq!(output[2], output[1]);
+    assert_eq!(output[3], output[0]);
+
+    // In checked mode, freed blocks are never reused.
+    let output = run(vec![alloc(4), vec![StandardOp::Free], alloc(4)], true).unwrap();
+    assert_ne!(output[0], output[1]);
+
+    // Freeing a block twice is an error.
+    let result = run(
+        vec![alloc(4), vec![StandardOp::Free, StandardOp::Free]],
+        true,
+    );
+    assert_eq!(
+        result,
+        Err(RuntimeError::DoubleFree {
+            addr: 29999,
+            trace: StackTrace {
+                instruction: 4,
+                calls: vec![],
+                frames: vec![]
+            }
+        })
+    );
+
+    // Freeing an address which isn't on the heap is an error.
+    let result = run(vec![vec![op(CoreOp::Set(vec![5])), StandardOp::Free]], true);
+    assert_eq!(
+        result,
+        Err(RuntimeError::InvalidFree {
+            addr: 5,
+            trace: StackTrace {
+                instruction: 1,
+                calls: vec![],
+                frames: vec![]
+            }
+        })
+    );
+
+    // Using a freed block is an error.
+    let result = run(
+        vec![
+            alloc(4),
+            vec![
+                op(CoreOp::Store(1)),
+                StandardOp::Free,
+                op(CoreOp::Deref),
+                op(CoreOp::Load(1)),
+            ],
+        ],
+        true,
+    );
+    assert!(matches!(
+        result,
+        Err(RuntimeError::UseAfterFree { size: 4, .. })
+    ));
+
+    // So is computing an address in a freed block with `Index`.
+    let result = run(
+        vec![
+            alloc(4),
+            vec![
+                StandardOp::Free,
+                op(CoreOp::Set(vec![2])),
+                op(CoreOp::Store(1)),
+                op(CoreOp::Set(vec![29999])),
+                op(CoreOp::Index(1)),
+            ],
+        ],
+        true,
+    );
+    assert_eq!(
+        result,
+        Err(RuntimeError::UseAfterFree {
+            addr: 30001,
+            block: 29999,
+            size: 4,
+            trace: StackTrace {
+                instruction: 7,
+                calls: vec![],
+                frames: vec![]
+            }
+        })
+    );
+}
+
+#[test]
+fn test_halt() {
+    // Halting stops the program before the final output, and returns the exit code.
+    let program = CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Set(vec![3]),
+        CoreOp::Halt,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(vec![1]),
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::Set(vec![0]),
+        CoreOp::Call,
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+
+    let (device, exit_code) = CoreInterpreter::new(TestingDevice::default())
+        .run_with_exit_code(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "1");
+    assert_eq!(exit_code, 3);
+
+    let program = StandardProgram::from(program);
+    let (device, exit_code) = StandardInterpreter::new(TestingDevice::default())
+        .run_with_exit_code(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "1");
+    assert_eq!(exit_code, 3);
+
+    // Programs which finish without halting exit with zero.
+    let program = CoreProgram(vec![CoreOp::Set(vec![5])]);
+    let (_, exit_code) = CoreInterpreter::new(TestingDevice::default())
+        .run_with_exit_code(&program)
+        .unwrap();
+    assert_eq!(exit_code, 0);
+}
+
+#[test]
+fn test_runtime_error() {
+    // Errors carry the failing instruction and the calls which led to it.
+    let program = CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Set(vec![5]),
+        CoreOp::Call,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(vec![0]),
+        CoreOp::Call,
+    ]);
+    let err = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap_err();
+    assert_eq!(
+        err,
+        RuntimeError::UndefinedFunction {
+            function: 5,
+            trace: StackTrace {
+                instruction: 2,
+                calls: vec![6],
+                frames: vec![]
+            }
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "function 5 not defined at instruction #2, called from instruction #6"
+    );
+
+    // Errors from the device are given the trace of the instruction which used it.
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(vec![1])),
+        StandardOp::Peek,
+    ]);
+    let err = StandardInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap_err();
+    assert_eq!(
+        err,
+        RuntimeError::EmptyFFIChannel {
+            trace: StackTrace {
+                instruction: 1,
+                calls: vec![],
+                frames: vec![]
+            }
+        }
+    );
+
+    // A foreign function fails when its arguments aren't all on the channel.
+    let add = FFIBinding::new("add".to_string(), 2, 1);
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(vec![1])),
+        StandardOp::Poke,
+        StandardOp::Call(add.clone()),
+    ]);
+    let err = StandardInterpreter::new(StandardDevice::default())
+        .run(&program)
+        .unwrap_err();
+    assert_eq!(
+        err,
+        RuntimeError::FFI {
+            binding: add,
+            message: "expected 2 arguments on the ffi channel, but found 1".to_string(),
+            trace: StackTrace {
+                instruction: 2,
+                calls: vec![],
+                frames: vec![]
+            }
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "ffi call add failed: expected 2 arguments on the ffi channel, but found 1 at instruction #2"
+    );
+}
+
+#[test]
+fn test_tape_out_of_bounds() {
+    // Instructions which read past the end of the tape extend it.
+    let program = CoreProgram(vec![
+        CoreOp::Move(10),
+        CoreOp::Set(vec![3]),
+        CoreOp::Add(4),
+        CoreOp::Swap(2),
+        CoreOp::Index(2),
+        CoreOp::Put(Output::stdout_int()),
+    ]);
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "3");
+
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Move(10)),
+        StandardOp::Set(vec![1.5]),
+        StandardOp::Pow(3),
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_float())),
+    ]);
+    let device = StandardInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_str(), "1.0");
+
+    // An address too far out to extend the tape to is an error.
+    let program = CoreProgram(vec![
+        CoreOp::Set(vec![i64::MAX]),
+        CoreOp::Store(1),
+        CoreOp::Deref,
+        CoreOp::Add(1),
+    ]);
+    let err = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            RuntimeError::TapeOutOfBounds {
+                address,
+                trace: StackTrace { instruction: 3, .. },
+                ..
+            } if address == i64::MAX as usize
+        ),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn test_source_map() {
+    let frame = |function: &str, line| SourceFrame {
+        function: Some(function.to_string()),
+        location: Some(SourceCodeLocation {
+            line,
+            column: 0,
+            offset: 0,
+            length: None,
+            filename: Some("test.sg".to_string()),
+        }),
+    };
+
+    // The frames of a stack trace come from the source map, innermost first.
+    let program = CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Set(vec![5]),
+        CoreOp::Call,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(vec![0]),
+        CoreOp::Call,
+    ]);
+    let mut source_map = SourceMap::default();
+    source_map.insert(0, frame("f", 0));
+    source_map.insert(5, frame("main", 3));
+    assert_eq!(source_map.get(3), Some(&frame("f", 0)));
+    assert_eq!(source_map.get(6), Some(&frame("main", 3)));
+
+    let err = CoreInterpreter::new(TestingDevice::default())
+        .with_source_map(source_map)
+        .run(&program)
+        .unwrap_err();
+    assert_eq!(err.trace().frames, vec![frame("f", 0), frame("main", 3)]);
+    assert_eq!(
+        err.to_string(),
+        "function 5 not defined at instruction #2, called from instruction #6\n    at f (test.sg:1:1)\n    at main (test.sg:4:1)"
+    );
+
+    // Compiling the program overflows the tiny stack for tests.
+    // So, we spawn a new thread with a larger stack size.
+    std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_compiled_source_map)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+fn test_compiled_source_map() {
+    // Compiled programs get their source map from the locations in the source code.
+    let code = "fun countdown(n: Int) {\n    countdown(n + 1);\n}\n\ncountdown(1);";
+    let (program, source_map) = parse_frontend_minimal(code, Some("countdown.sg"))
+        .unwrap()
+        .compile(true)
+        .unwrap()
+        .unwrap()
+        .assemble_with_source_map(8192)
+        .unwrap();
+    let err = CoreInterpreter::new(TestingDevice::default())
+        .with_limits(Limits {
+            max_call_depth: Some(4),
+            ..Limits::default()
+        })
+        .with_source_map(source_map)
+        .run(&program)
+        .unwrap_err();
+    let frames = &err.trace().frames;
+    // The failing call is made four calls deep, so it has five frames.
+    assert_eq!(frames.len(), 5);
+    assert_eq!(frames[0].function_name(), "countdown");
+    assert_eq!(frames[0].location.as_ref().unwrap().line, 1);
+    assert_eq!(frames[4].function_name(), "<main>");
+    assert_eq!(frames[4].location.as_ref().unwrap().line, 4);
+}
+
+#[test]
+fn test_snapshot() {
+    // A program resumed from a snapshot continues from where the snapshot was taken.
+    let program = function_call_program();
+    let mut interpreter = CoreInterpreter::new(TestingDevice::default());
+    for _ in 0..4 {
+        interpreter.step(&program).unwrap();
+    }
+    assert_eq!(interpreter.state().calls, &[6]);
+    let snapshot = Snapshot::from_json(&interpreter.snapshot().to_json()).unwrap();
+    assert_eq!(snapshot, interpreter.snapshot());
+
+    let mut resumed = CoreInterpreter::new(TestingDevice::default());
+    resumed.restore(snapshot).unwrap();
+    assert_eq!(resumed.state().i, interpreter.state().i);
+    assert_eq!(resumed.steps(), 4);
+    assert_eq!(resumed.run(&program).unwrap().output_vals(), vec![5]);
+
+    // The standard interpreter's snapshots include the heap, so freed blocks
+    // are still reused after resuming.
+    let op = StandardOp::CoreOp;
+    let program = StandardProgram(vec![
+        op(CoreOp::Set(vec![4])),
+        StandardOp::Alloc,
+        op(CoreOp::Put(Output::stdout_char())),
+        StandardOp::Free,
+        op(CoreOp::Set(vec![4])),
+        StandardOp::Alloc,
+        op(CoreOp::Put(Output::stdout_char())),
+    ]);
+    let mut interpreter = StandardInterpreter::new(TestingDevice::default());
+    for _ in 0..4 {
+        interpreter.step(&program).unwrap();
+    }
+    let first = interpreter.device().output_vals()[0];
+    let mut resumed = StandardInterpreter::new(TestingDevice::default());
+    let snapshot = Snapshot::from_json(&interpreter.snapshot().to_json()).unwrap();
+    resumed.restore(snapshot.clone()).unwrap();
+    assert_eq!(resumed.state().cells.len(), interpreter.state().cells.len());
+    assert_eq!(resumed.run(&program).unwrap().output_vals(), vec![first]);
+
+    // A snapshot can't be restored into the other kind of interpreter, which
+    // would drop or invent the heap.
+    let mut core = CoreInterpreter::new(TestingDevice::default());
+    assert!(core.restore(snapshot).is_err());
+    let mut standard = StandardInterpreter::new(TestingDevice::default());
+    assert!(standard
+        .restore(CoreInterpreter::new(TestingDevice::default()).snapshot())
+        .is_err());
+
+    assert!(Snapshot::from_json("{}").is_err());
+}
+
+#[test]
+fn test_profile() {
+    let program = function_call_program();
+    let mut interpreter = CoreInterpreter::new(TestingDevice::default()).with_profiling();
+    assert_eq!(interpreter.run_to_end(&program), Ok(0));
+    let profile = interpreter.profile().unwrap();
+
+    // Every executed instruction is counted by its kind.
+    assert_eq!(profile.total(), interpreter.steps());
+    assert_eq!(profile.instructions()[0], ("set", 2));
+
+    // The function's instructions are counted in both itself and its caller.
+    let functions = profile.functions();
+    assert_eq!(
+        functions[&Some(0)],
+        FunctionProfile {
+            calls: 1,
+            exclusive: 3,
+            inclusive: 3,
+        }
+    );
+    assert_eq!(
+        functions[&None],
+        FunctionProfile {
+            calls: 0,
+            exclusive: 5,
+            inclusive: 8,
+        }
+    );
+
+    let names = vec![String::from("store_five")];
+    assert_eq!(
+        profile.collapsed_stacks(&names),
+        "<main> 5\n<main>;store_five 3\n"
+    );
+    assert_eq!(
+        profile.collapsed_stacks(&[]),
+        "<main> 5\n<main>;function #0 3\n"
+    );
+    assert!(profile
+        .report(&names)
+        .starts_with("8 instructions executed\n"));
+
+    // Compiling the program overflows the tiny stack for tests.
+    // So, we spawn a new thread with a larger stack size.
+    std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(test_compiled_profile)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+fn test_compiled_profile() {
+    // Compiled programs name their functions after the procedures in the source code.
+    let code = "fun countdown(n: Int) {\n    if n > 0 {\n        countdown(n - 1);\n    }\n}\n\ncountdown(1);\ncountdown(0);";
+    let program = parse_frontend_minimal(code, Some("countdown.sg"))
+        .unwrap()
+        .compile(true)
+        .unwrap()
+        .unwrap();
+    let names = program.function_names();
+    assert_eq!(names, vec![String::from("countdown")]);
+
+    let mut interpreter = CoreInterpreter::new(TestingDevice::default()).with_profiling();
+    interpreter
+        .run_to_end(&program.assemble(8192).unwrap())
+        .unwrap();
+    let profile = interpreter.profile().unwrap();
+    let countdown = profile.functions()[&Some(0)];
+    assert_eq!(countdown.calls, 3);
+    // The function only calls itself, so counting its recursive
+    // calls once gives the same inclusive and exclusive instructions.
+    assert_eq!(countdown.inclusive, countdown.exclusive);
+
+    let stacks = profile.collapsed_stacks(&names);
+    assert!(stacks
+        .lines()
+        .any(|line| line.starts_with("<main>;countdown ")));
+    assert!(stacks
+        .lines()
+        .any(|line| line.starts_with("<main>;countdown;countdown ")));
+}
+
+#[test]
+fn test_bytecode() {
+    // Every assembly example survives a round trip through bytecode.
+    for entry in std::fs::read_dir("examples/asm").unwrap() {
+        let path = entry.unwrap().path();
+        let program = parse_asm(std::fs::read_to_string(&path).unwrap()).unwrap();
+        match program {
+            Ok(asm) => {
+                let core = asm.assemble(8192).unwrap();
+                assert!(
+                    CoreProgram::from_bytecode(&core.to_bytecode()) == Ok(core),
+                    "{path:?}"
+                );
+            }
+            Err(asm) => {
+                let std = asm.assemble(8192).unwrap();
+                assert!(
+                    StandardProgram::from_bytecode(&std.to_bytecode()) == Ok(std),
+                    "{path:?}"
+                );
+            }
+        }
+    }
+
+    // Standard programs keep their floats, FFI bindings, and custom I/O modes.
+    let binding = FFIBinding::new("add_floats".to_string(), 2, 1);
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Comment("add two floats".to_string())),
+        StandardOp::Set(vec![1.5, -0.25]),
+        StandardOp::Call(binding.clone()),
+        StandardOp::Call(binding),
+        StandardOp::CoreOp(CoreOp::Offset(-3, 2)),
+        StandardOp::CoreOp(CoreOp::Set(vec![i64::MIN, -1, 0, i64::MAX])),
+        StandardOp::CoreOp(CoreOp::Get(Input::new(
+            InputMode::Custom("sensor".to_string()),
+            7,
+        ))),
+        StandardOp::CoreOp(CoreOp::Put(Output::new(
+            OutputMode::SetCursorPixel(Color::RGB(1, 2, 3)),
+            0,
+        ))),
+        StandardOp::Pow(1),
+    ]);
+    let bytes = program.to_bytecode();
+    assert!(bytes.starts_with(b"SGBC"));
+    assert!(StandardProgram::from_bytecode(&bytes) == Ok(program));
+    assert_eq!(
+        CoreProgram::from_bytecode(&bytes).err(),
+        Some(BytecodeError::ExpectedCore)
+    );
+
+    // Core programs can be loaded as standard programs.
+    let core = function_call_program();
+    assert!(
+        StandardProgram::from_bytecode(&core.to_bytecode())
+            == Ok(StandardProgram(
+                core.0.into_iter().map(StandardOp::CoreOp).collect()
+            ))
+    );
+
+    // Malformed bytecode is rejected.
+    assert_eq!(
+        decode_bytecode(b"set [1]").err(),
+        Some(BytecodeError::NotBytecode)
+    );
+    assert_eq!(
+        decode_bytecode(&bytes[..bytes.len() - 1]).err(),
+        Some(BytecodeError::UnexpectedEnd)
+    );
+    let mut extra = bytes.clone();
+    extra.push(0);
+    assert_eq!(
+        decode_bytecode(&extra).err(),
+        Some(BytecodeError::TrailingBytes(bytes.len()))
+    );
+    let mut version = bytes;
+    version[4] = 99;
+    assert_eq!(
+        decode_bytecode(&version).err(),
+        Some(BytecodeError::UnsupportedVersion(99))
+    );
+
+    // Varints which don't fit in 64 bits are rejected, instead of losing their top bits.
+    let mut overlong = b"SGBC".to_vec();
+    overlong.extend([0xff; 9]);
+    overlong.push(0x7f);
+    assert_eq!(
+        decode_bytecode(&overlong).err(),
+        Some(BytecodeError::Invalid {
+            what: "varint",
+            offset: 4
+        })
+    );
+    let mut too_long = b"SGBC".to_vec();
+    too_long.extend([0x80; 10]);
+    too_long.push(0);
+    assert_eq!(
+        decode_bytecode(&too_long).err(),
+        Some(BytecodeError::Invalid {
+            what: "varint",
+            offset: 4
+        })
+    );
+}
+
+#[test]
+fn test_resolved_control_flow() {
+    // Running a program to the end resolves its control flow first, which must
+    // behave exactly like stepping through the program one instruction at a time.
+    fn compare_core(program: &CoreProgram, limits: Limits) {
+        let mut resolved = CoreInterpreter::new(TestingDevice::new("hello world!"))
+            .with_limits(limits)
+            .with_profiling();
+        let mut stepped = CoreInterpreter::new(TestingDevice::new("hello world!"))
+            .with_limits(limits)
+            .with_profiling();
+        let result = resolved.run_to_end(program);
+        let expected = loop {
+            if stepped.state().done {
+                break Ok(stepped.state().exit_code.unwrap_or(0));
+            }
+            if let Err(e) = stepped.step(program) {
+                break Err(e);
+            }
+        };
+        assert_eq!(result, expected);
+        assert_eq!(resolved.snapshot(), stepped.snapshot());
+        assert_eq!(
+            resolved.device().output_vals(),
+            stepped.device().output_vals()
+        );
+        let (resolved, stepped) = (resolved.profile().unwrap(), stepped.profile().unwrap());
+        assert_eq!(resolved.instructions(), stepped.instructions());
+        assert_eq!(resolved.functions(), stepped.functions());
+    }
+
+    fn compare_std(program: &StandardProgram) {
+        let resolved = StandardInterpreter::new(TestingDevice::new("hello world!"))
+            .run(program)
+            .unwrap();
+        let mut stepped = StandardInterpreter::new(TestingDevice::new("hello world!"));
+        while !stepped.state().done {
+            stepped.step(program).unwrap();
+        }
+        assert_eq!(resolved.output_vals(), stepped.device().output_vals());
+    }
+
+    for entry in std::fs::read_dir("examples/asm").unwrap() {
+        let path = entry.unwrap().path();
+        match parse_asm(std::fs::read_to_string(&path).unwrap()).unwrap() {
+            Ok(asm) => compare_core(&asm.assemble(8192).unwrap(), Limits::default()),
+            Err(asm) => compare_std(&asm.assemble(8192).unwrap()),
+        }
+    }
+
+    // Nested branches and loops, calls to functions defined later, and runs of moves.
+    let program = CoreProgram(vec![
+        CoreOp::Function,
+        CoreOp::Move(2),
+        CoreOp::Move(-2),
+        CoreOp::Inc(1),
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(vec![3]),
+        CoreOp::While,
+        CoreOp::Store(1),
+        CoreOp::If,
+        CoreOp::Set(vec![0]),
+        CoreOp::If,
+        CoreOp::Else,
+        CoreOp::Call,
+        CoreOp::End,
+        CoreOp::Else,
+        CoreOp::Halt,
+        CoreOp::End,
+        CoreOp::Move(1),
+        CoreOp::Move(1),
+        CoreOp::Move(-2),
+        CoreOp::Load(1),
+        CoreOp::Dec(1),
+        CoreOp::End,
+        CoreOp::Set(vec![1]),
+        CoreOp::Call,
+    ]);
+    compare_core(&program, Limits::default());
+
+    // Runs the instruction limit ends partway through.
+    for max in 0..60 {
+        let limits = Limits {
+            max_instructions: Some(max),
+            ..Limits::default()
+        };
+        compare_core(&program, limits);
+    }
+
+    // A run of moves which only goes below zero partway through.
+    let program = CoreProgram(vec![CoreOp::Move(1), CoreOp::Move(-2), CoreOp::Move(3)]);
+    compare_core(&program, Limits::default());
+}
+
+#[test]
+fn test_optimize() {
+    // Locations are lowered into moves and dereferences which undo each other.
+    let program = CoreProgram(vec![
+        CoreOp::Comment("push 2".to_string()),
+        CoreOp::Move(3),
+        CoreOp::Deref,
+        CoreOp::Refer,
+        CoreOp::Move(-3),
+        CoreOp::Set(vec![1]),
+        CoreOp::Set(vec![2]),
+        CoreOp::Store(1),
+        CoreOp::Load(1),
+        CoreOp::Move(1),
+        CoreOp::Set(vec![-4]),
+        CoreOp::Neg(1),
+        CoreOp::Store(1),
+        CoreOp::Move(0),
+        CoreOp::Load(1),
+        CoreOp::Add(1),
+        CoreOp::If,
+        CoreOp::Put(Output::stdout_int()),
+        CoreOp::Else,
+        CoreOp::End,
+    ]);
+    let optimized = program.clone().optimize();
+    assert_eq!(
+        optimized.0,
+        vec![
+            CoreOp::Set(vec![2]),
+            CoreOp::Store(1),
+            CoreOp::Set(vec![4]),
+            CoreOp::Move(1),
+            CoreOp::Store(1),
+            CoreOp::Add(1),
+            CoreOp::If,
+            CoreOp::Put(Output::stdout_int()),
+            CoreOp::End,
+        ]
+    );
+    let run = |program: &CoreProgram| {
+        CoreInterpreter::new(TestingDevice::default())
+            .run(program)
+            .unwrap()
+            .output_vals()
+    };
+    assert_eq!(run(&optimized), run(&program));
+
+    // The source map follows the instructions that are kept.
+    let mut source_map = SourceMap::default();
+    source_map.insert(
+        10,
+        SourceFrame {
+            function: Some("negate".to_string()),
+            location: None,
+        },
+    );
+    let (_, source_map) = program.optimize_with_source_map(&source_map);
+    assert_eq!(source_map.get(1), None);
+    assert_eq!(source_map.get(2).unwrap().function_name(), "negate");
+}
+
+#[test]
+fn test_display_ffi_call() {
+    // Foreign function calls are displayed the way they're parsed.
+    let program = StandardProgram(vec![
+        StandardOp::Call(FFIBinding::new("square".to_string(), 1, 1)),
+        StandardOp::Peek,
+    ]);
+    let Ok(Err(parsed)) = parse_vm(program.to_string()) else {
+        panic!("Could not parse the standard program:\n{program}");
+    };
+    assert_eq!(parsed.0, program.0);
+}
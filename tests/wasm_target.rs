@@ -0,0 +1,142 @@
+mod common;
+
+use common::*;
+use sage::{
+    lir::Compile,
+    parse::*,
+    side_effects::{FFIBinding, Input, Output},
+    targets::*,
+    vm::*,
+};
+use std::{
+    fs::{read_dir, read_to_string},
+    path::Path,
+};
+
+/// Assemble a WebAssembly text module and validate it.
+fn validate(name: &str, wat: &str) {
+    let wasm = wat::parse_str(wat)
+        .unwrap_or_else(|e| panic!("Could not assemble the module for `{name}`: {e}\n{wat}"));
+    wasmparser::Validator::new()
+        .validate_all(&wasm)
+        .unwrap_or_else(|e| panic!("Invalid module for `{name}`: {e}\n{wat}"));
+}
+
+/// Build a program for a WebAssembly target with a small tape.
+fn build(program: Program) -> String {
+    let mut target = Wasm::default();
+    target.tape_cells = 4096;
+    match program {
+        Ok(program) => target.build_core(&program.flatten()),
+        Err(program) => target.build_std(&program.flatten()),
+    }
+    .unwrap()
+}
+
+/// Get all the assembly example source files, including those without a correct output.
+fn asm_examples() -> Vec<(String, String)> {
+    let mut result = vec![];
+    for entry in read_dir("examples/asm").unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if path.is_file() && name.ends_with(".asm.sg") {
+            result.push((name, read_to_string(&path).unwrap()));
+        }
+    }
+    result.sort();
+    result
+}
+
+#[test]
+fn test_wasm_target_asm_examples() {
+    for (name, code) in asm_examples() {
+        let program = match parse_asm(code).unwrap() {
+            Ok(asm_code) => Ok(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+            Err(asm_code) => Err(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+        };
+        validate(&name, &build(program));
+    }
+}
+
+#[test]
+fn test_wasm_target_frontend_examples() {
+    with_large_stack(|| {
+        // The examples with a correct output are expected to compile.
+        for (name, code, _) in examples("examples/frontend") {
+            let path = Path::new("examples/frontend").join(&name);
+            let asm_code = parse_frontend(code, path.to_str())
+                .unwrap_or_else(|_| panic!("Could not parse `{path:?}`"))
+                .compile(false)
+                .unwrap_or_else(|e| panic!("Could not compile `{path:?}`: {e}"));
+            let program = match asm_code {
+                Ok(asm_code) => Ok(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+                Err(asm_code) => Err(asm_code.assemble(CALL_STACK_SIZE).unwrap()),
+            };
+            validate(&name, &build(program));
+        }
+    });
+}
+
+#[test]
+fn test_wasm_target_std_ops() {
+    use StandardOp::*;
+    let program = StandardProgram(vec![
+        Set(vec![1.5, -0.25, f64::INFINITY, f64::NAN]),
+        CoreOp(sage::vm::CoreOp::Store(4)),
+        Add(4),
+        Rem(2),
+        Pow(1),
+        Sin(2),
+        ATan(1),
+        IsNonNegative(3),
+        ToFloat(1),
+        ToInt(4),
+        CoreOp(sage::vm::CoreOp::Put(Output::stdout_float())),
+        CoreOp(sage::vm::CoreOp::Get(Input::stdin_float())),
+        Alloc,
+        CoreOp(sage::vm::CoreOp::Deref),
+        Poke,
+        Call(FFIBinding::new("square".to_string(), 1, 1)),
+        Peek,
+        CoreOp(sage::vm::CoreOp::Refer),
+        Free,
+        CoreOp(sage::vm::CoreOp::Swap(3)),
+        CoreOp(sage::vm::CoreOp::Load(3)),
+        CoreOp(sage::vm::CoreOp::Div(3)),
+        CoreOp(sage::vm::CoreOp::Halt),
+    ]);
+
+    let wat = build(Err(program));
+    for import in [
+        r#"(import "sage" "put_stdout_float""#,
+        r#"(import "sage" "get_stdin_float""#,
+        r#"(import "sage" "halt""#,
+        r#"(import "ffi" "square""#,
+        r#"(import "math" "pow""#,
+        r#"(import "math" "sin""#,
+        r#"(import "math" "atan""#,
+    ] {
+        assert!(wat.contains(import), "missing {import} in\n{wat}");
+    }
+    assert!(!wat.contains(r#"(import "math" "cos""#));
+    validate("std ops", &wat);
+}
+
+#[test]
+fn test_wasm_target_imports_per_build() {
+    use sage::vm::CoreOp::*;
+    let mut target = Wasm::default();
+    target.tape_cells = 4096;
+    let wat = target
+        .build_core(&CoreProgram(vec![Put(Output::stdout_int())]))
+        .unwrap();
+    assert!(wat.contains(r#"(import "sage" "put_stdout_int""#));
+
+    // The imports of the last program are not carried over to the next one.
+    let wat = target
+        .build_core(&CoreProgram(vec![Get(Input::stdin_int())]))
+        .unwrap();
+    assert!(wat.contains(r#"(import "sage" "get_stdin_int""#));
+    assert!(!wat.contains(r#"(import "sage" "put_stdout_int""#));
+    validate("imports per build", &wat);
+}
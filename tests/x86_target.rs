@@ -0,0 +1,168 @@
+#![cfg(all(target_arch = "x86_64", target_os = "linux"))]
+
+mod common;
+
+use common::*;
+use sage::{
+    side_effects::{Input, Output},
+    targets::*,
+    vm::{CoreOp, CoreProgram, StandardOp, StandardProgram},
+};
+use std::process::Command;
+
+/// Assemble and link some assembly code with the system C compiler,
+/// and run it with the given input.
+fn run_asm(name: &str, asm_code: String, input: &str) -> std::process::Output {
+    let asm_code_path = format!("tmp_x86_code_{name}.s");
+    let exe_path = format!("tmp_x86_code_{name}.exe");
+    std::fs::write(&asm_code_path, asm_code).unwrap();
+    let compile_output = Command::new("cc")
+        .arg(&asm_code_path)
+        .arg("-o")
+        .arg(&exe_path)
+        .arg("-lm")
+        .output()
+        .unwrap();
+    if !compile_output.status.success() {
+        panic!("Could not assemble x86 code for `{name}`: {compile_output:?}");
+    }
+
+    let output = run_with_input(&mut Command::new(format!("./{exe_path}")), input);
+
+    std::fs::remove_file(&asm_code_path).unwrap();
+    std::fs::remove_file(&exe_path).unwrap();
+    output
+}
+
+/// Build a program for the x86-64 target.
+fn build(program: Program) -> String {
+    match program {
+        Ok(program) => X86::default().build_core(&program.flatten()),
+        Err(program) => X86::default().build_std(&program.flatten()),
+    }
+    .unwrap()
+}
+
+#[test]
+fn test_x86_target_frontend_examples() {
+    with_large_stack(test_x86_target_frontend_examples_helper);
+}
+
+fn test_x86_target_frontend_examples_helper() {
+    let (total_failures, total_attempts) = run_examples("examples/frontend/", |name, program| {
+        String::from_utf8(run_asm(name, build(program), INPUT).stdout).unwrap()
+    });
+
+    // Like the C target, some of the examples print out pointers, which are machine addresses.
+    if total_failures as f64 / total_attempts as f64 > 0.3 {
+        panic!(
+            "Too many failures in frontend examples: {total_failures} failures out of {total_attempts} attempts"
+        );
+    }
+}
+
+#[test]
+fn test_x86_target_calls() {
+    use CoreOp::*;
+    let program = CoreProgram(vec![
+        // Function 0 prints the character under the pointer.
+        Function,
+        Load(1),
+        Put(Output::stdout_char()),
+        Return,
+        End,
+        // Function 1 calls function 0 twice.
+        Function,
+        Set(vec![0]),
+        Call,
+        Set(vec![0]),
+        Call,
+        End,
+        Set(vec![b'a' as i64]),
+        Store(1),
+        Set(vec![1]),
+        Call,
+        // Call function 0 with an index which isn't known ahead of time.
+        Get(Input::stdin_char()),
+        Store(1),
+        Move(1),
+        Load(1),
+        Move(-1),
+        Call,
+        Set(vec![0]),
+        Halt,
+        Set(vec![b'!' as i64]),
+        Put(Output::stdout_char()),
+    ]);
+
+    let asm_code = X86::default().build_core(&program).unwrap();
+    assert!(asm_code.contains("call sage_f0"));
+    assert!(asm_code.contains("call sage_f1"));
+    assert!(asm_code.contains("call *(%rax,%r13,8)"));
+
+    let output = run_asm("calls", asm_code, "b");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "aab");
+}
+
+#[test]
+fn test_x86_target_std_ops() {
+    use StandardOp::*;
+    type Core = sage::vm::CoreOp;
+    let core = StandardOp::CoreOp;
+    let space = [
+        core(Core::Set(vec![b' ' as i64])),
+        core(Core::Put(Output::stdout_char())),
+    ];
+    let mut program = vec![
+        Set(vec![1.5, -2.25, 9.0]),
+        core(Core::Store(3)),
+        Mul(3),
+        Neg(1),
+        ToInt(3),
+        core(Core::Store(3)),
+        // Print the vector of integers.
+        core(Core::Put(Output::stdout_int())),
+        core(Core::Move(1)),
+        core(Core::Load(1)),
+        core(Core::Put(Output::stdout_int())),
+        core(Core::Move(1)),
+        core(Core::Load(1)),
+        core(Core::Put(Output::stdout_int())),
+        core(Core::Move(-2)),
+    ];
+    program.extend(space.clone());
+    program.extend([
+        // Allocate some memory, and add vectors in it.
+        core(Core::Set(vec![2])),
+        Alloc,
+        core(Core::Store(1)),
+        core(Core::Set(vec![10, 20])),
+        core(Core::Deref),
+        core(Core::Store(2)),
+        core(Core::Set(vec![1, 2])),
+        core(Core::Add(2)),
+        core(Core::Store(2)),
+        core(Core::Put(Output::stdout_int())),
+        core(Core::Move(1)),
+        core(Core::Load(1)),
+        core(Core::Put(Output::stdout_int())),
+        core(Core::Move(-1)),
+        core(Core::Refer),
+        core(Core::Load(1)),
+        Free,
+    ]);
+    program.extend(space);
+    program.extend([
+        Set(vec![2.0]),
+        core(Core::Store(1)),
+        Set(vec![3.0]),
+        Pow(1),
+        core(Core::Put(Output::stdout_float())),
+    ]);
+
+    let asm_code = X86::default().build_std(&StandardProgram(program)).unwrap();
+    let output = run_asm("std_ops", asm_code, "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "-2581 1122 9.0");
+}